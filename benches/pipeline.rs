@@ -0,0 +1,333 @@
+//! Baselines for the event pipeline: how fast a `Machine` turns a stream
+//! of key events into output, how long matching the held keys against
+//! the mappings takes on its own, and how much the `MappingIndex` saves
+//! over evaluating every mapping in a large config.
+//!
+//! Run with `cargo bench --features bench`.
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use evremap::mapping::{ActiveState, Conditions, MappingContext, Modifiers};
+use evremap::remapper::{
+    apply_mapping_to_held_keys, compute_keys_based_on_state, EvaluationBudget, MappingIndex,
+    Scratch,
+};
+use evremap::{EvKeyEvent, KeyCode, KeyEventType, Machine, Mapping, MappingConfig, TimeVal};
+use std::collections::HashSet;
+
+const LETTERS: [KeyCode; 26] = [
+    KeyCode::KEY_A,
+    KeyCode::KEY_B,
+    KeyCode::KEY_C,
+    KeyCode::KEY_D,
+    KeyCode::KEY_E,
+    KeyCode::KEY_F,
+    KeyCode::KEY_G,
+    KeyCode::KEY_H,
+    KeyCode::KEY_I,
+    KeyCode::KEY_J,
+    KeyCode::KEY_K,
+    KeyCode::KEY_L,
+    KeyCode::KEY_M,
+    KeyCode::KEY_N,
+    KeyCode::KEY_O,
+    KeyCode::KEY_P,
+    KeyCode::KEY_Q,
+    KeyCode::KEY_R,
+    KeyCode::KEY_S,
+    KeyCode::KEY_T,
+    KeyCode::KEY_U,
+    KeyCode::KEY_V,
+    KeyCode::KEY_W,
+    KeyCode::KEY_X,
+    KeyCode::KEY_Y,
+    KeyCode::KEY_Z,
+];
+
+const FUNCTION_KEYS: [KeyCode; 10] = [
+    KeyCode::KEY_F1,
+    KeyCode::KEY_F2,
+    KeyCode::KEY_F3,
+    KeyCode::KEY_F4,
+    KeyCode::KEY_F5,
+    KeyCode::KEY_F6,
+    KeyCode::KEY_F7,
+    KeyCode::KEY_F8,
+    KeyCode::KEY_F9,
+    KeyCode::KEY_F10,
+];
+
+const MEDIA_KEYS: [KeyCode; 10] = [
+    KeyCode::KEY_MUTE,
+    KeyCode::KEY_VOLUMEDOWN,
+    KeyCode::KEY_VOLUMEUP,
+    KeyCode::KEY_PREVIOUSSONG,
+    KeyCode::KEY_PLAYPAUSE,
+    KeyCode::KEY_NEXTSONG,
+    KeyCode::KEY_BRIGHTNESSDOWN,
+    KeyCode::KEY_BRIGHTNESSUP,
+    KeyCode::KEY_SEARCH,
+    KeyCode::KEY_HOMEPAGE,
+];
+
+fn remap(input: &[KeyCode], output: &[KeyCode]) -> Mapping {
+    Mapping::Remap {
+        input: input.iter().copied().collect(),
+        output: output.iter().copied().collect(),
+        when: Conditions::default(),
+        on_release: false,
+    }
+}
+
+/// 50 rules of the kinds a typical config has: a CapsLock chord for most
+/// letters, function keys turned into media keys, a few two-key chords,
+/// and CapsLock itself as Ctrl.
+fn representative_mappings() -> Vec<Mapping> {
+    let mut mappings = vec![];
+    for pair in LETTERS.windows(2).take(20) {
+        mappings.push(remap(&[KeyCode::KEY_CAPSLOCK, pair[0]], &[pair[1]]));
+    }
+    for (function, media) in FUNCTION_KEYS.iter().zip(MEDIA_KEYS) {
+        mappings.push(remap(&[*function], &[media]));
+    }
+    for (i, pair) in LETTERS.chunks(2).take(10).enumerate() {
+        mappings.push(remap(pair, &[FUNCTION_KEYS[i]]));
+    }
+    for pair in LETTERS[20..].windows(2).take(4) {
+        mappings.push(remap(
+            &[KeyCode::KEY_LEFTALT, pair[0]],
+            &[KeyCode::KEY_LEFTCTRL, pair[1]],
+        ));
+    }
+    for key in [
+        KeyCode::KEY_H,
+        KeyCode::KEY_J,
+        KeyCode::KEY_K,
+        KeyCode::KEY_L,
+    ] {
+        mappings.push(remap(&[KeyCode::KEY_RIGHTALT, key], &[KeyCode::KEY_LEFT]));
+    }
+    mappings.push(remap(&[KeyCode::KEY_CAPSLOCK], &[KeyCode::KEY_LEFTCTRL]));
+    mappings.push(remap(&[KeyCode::KEY_RIGHTCTRL], &[KeyCode::KEY_COMPOSE]));
+    assert_eq!(mappings.len(), 50);
+    mappings
+}
+
+/// Every chord of two and three of the first eight letters, 84 in all,
+/// which all match once the eight of them are held.
+fn overlapping_chords() -> Vec<Mapping> {
+    let keys = &LETTERS[..8];
+    let mut mappings = vec![];
+    for a in 0..keys.len() {
+        for b in a + 1..keys.len() {
+            mappings.push(remap(&[keys[a], keys[b]], &[LETTERS[8 + a]]));
+            for c in b + 1..keys.len() {
+                mappings.push(remap(&[keys[a], keys[b], keys[c]], &[LETTERS[16 + b]]));
+            }
+        }
+    }
+    mappings
+}
+
+/// 500 chords of two of the letters and function keys, each onto a key
+/// that is the input of other chords, like a large generated config.
+fn synthetic_mappings() -> Vec<Mapping> {
+    let keys: Vec<KeyCode> = LETTERS.iter().chain(&FUNCTION_KEYS).copied().collect();
+    let pairs = (0..keys.len()).flat_map(|a| (a + 1..keys.len()).map(move |b| (a, b)));
+    let mappings: Vec<Mapping> = pairs
+        .take(500)
+        .enumerate()
+        .map(|(idx, (a, b))| remap(&[keys[a], keys[b]], &[keys[idx % keys.len()]]))
+        .collect();
+    assert_eq!(mappings.len(), 500);
+    mappings
+}
+
+struct EventStream {
+    events: Vec<EvKeyEvent>,
+    usec: i64,
+}
+
+impl EventStream {
+    fn new() -> Self {
+        EventStream {
+            events: vec![],
+            usec: 0,
+        }
+    }
+
+    fn push(&mut self, ev_key: KeyCode, key_event_type: KeyEventType) {
+        self.usec += 15_000;
+        self.events.push(EvKeyEvent {
+            time: TimeVal::new(self.usec / 1_000_000, self.usec % 1_000_000),
+            ev_key,
+            key_event_type,
+        });
+    }
+
+    fn tap(&mut self, key: KeyCode) {
+        self.push(key, KeyEventType::Press);
+        self.push(key, KeyEventType::Release);
+    }
+}
+
+/// Typing letters, with now and then a CapsLock chord, a held key that
+/// auto-repeats, and a function key.
+fn realistic_stream() -> Vec<EvKeyEvent> {
+    let mut stream = EventStream::new();
+    for round in 0..20 {
+        for key in LETTERS.iter().skip(round % 5).step_by(3) {
+            stream.tap(*key);
+        }
+        stream.push(KeyCode::KEY_CAPSLOCK, KeyEventType::Press);
+        stream.tap(LETTERS[round % 20]);
+        stream.push(KeyCode::KEY_CAPSLOCK, KeyEventType::Release);
+        stream.push(KeyCode::KEY_BACKSPACE, KeyEventType::Press);
+        for _ in 0..10 {
+            stream.push(KeyCode::KEY_BACKSPACE, KeyEventType::Repeat);
+        }
+        stream.push(KeyCode::KEY_BACKSPACE, KeyEventType::Release);
+        stream.tap(FUNCTION_KEYS[round % 10]);
+    }
+    stream.events
+}
+
+/// Pressing the eight keys of `overlapping_chords` one after the other,
+/// then releasing them in the same order.
+fn chord_stream() -> Vec<EvKeyEvent> {
+    let mut stream = EventStream::new();
+    for _ in 0..20 {
+        for key in &LETTERS[..8] {
+            stream.push(*key, KeyEventType::Press);
+        }
+        for key in &LETTERS[..8] {
+            stream.push(*key, KeyEventType::Release);
+        }
+    }
+    stream.events
+}
+
+fn bench_insert(c: &mut Criterion, name: &str, mappings: Vec<Mapping>, events: &[EvKeyEvent]) {
+    let config = MappingConfig {
+        mappings,
+        ..Default::default()
+    };
+    let mut group = c.benchmark_group("insert");
+    group.throughput(Throughput::Elements(events.len() as u64));
+    group.bench_function(name, |b| {
+        b.iter_batched(
+            || Machine::from_config(&config),
+            |mut machine| {
+                for event in events {
+                    black_box(machine.insert(event.clone()));
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+fn bench_matching(c: &mut Criterion, name: &str, mappings: &[Mapping], held: &[KeyCode]) {
+    let index = MappingIndex::new(mappings);
+    let modifiers = Modifiers::default();
+    let held: HashSet<KeyCode> = held.iter().copied().collect();
+    let active = ActiveState::default();
+    let context = MappingContext::new(&held, &active);
+    let mut budget = EvaluationBudget::default();
+    let mut scratch = Scratch::default();
+
+    let mut group = c.benchmark_group(name);
+    group.bench_function("apply_mapping_to_held_keys", |b| {
+        b.iter(|| {
+            apply_mapping_to_held_keys(
+                mappings,
+                &index,
+                &modifiers,
+                black_box(&context),
+                &mut budget,
+                &mut scratch,
+            );
+            black_box(&scratch);
+        })
+    });
+    let output_keys = HashSet::new();
+    let time = TimeVal::new(0, 0);
+    group.bench_function("compute_keys_based_on_state", |b| {
+        b.iter(|| {
+            black_box(compute_keys_based_on_state(
+                mappings,
+                &index,
+                &modifiers,
+                black_box(&context),
+                &output_keys,
+                &time,
+                &mut budget,
+                &mut scratch,
+            ))
+        })
+    });
+    group.finish();
+}
+
+/// Matching a chord near the end of `synthetic_mappings` with the index
+/// and with every mapping evaluated, as before there was an index.
+fn bench_index(c: &mut Criterion) {
+    let mappings = synthetic_mappings();
+    let modifiers = Modifiers::default();
+    let held = HashSet::from([KeyCode::KEY_F8, KeyCode::KEY_F9]);
+    let active = ActiveState::default();
+    let context = MappingContext::new(&held, &active);
+    let mut budget = EvaluationBudget::default();
+    let mut scratch = Scratch::default();
+
+    let mut group = c.benchmark_group("synthetic_500");
+    for (name, index) in [
+        ("indexed", MappingIndex::new(&mappings)),
+        ("exhaustive", MappingIndex::exhaustive(&mappings)),
+    ] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                apply_mapping_to_held_keys(
+                    &mappings,
+                    &index,
+                    &modifiers,
+                    black_box(&context),
+                    &mut budget,
+                    &mut scratch,
+                );
+                black_box(&scratch);
+            })
+        });
+    }
+    group.finish();
+}
+
+fn pipeline(c: &mut Criterion) {
+    bench_insert(
+        c,
+        "representative",
+        representative_mappings(),
+        &realistic_stream(),
+    );
+    bench_insert(
+        c,
+        "overlapping_chords",
+        overlapping_chords(),
+        &chord_stream(),
+    );
+    bench_matching(
+        c,
+        "representative",
+        &representative_mappings(),
+        &[KeyCode::KEY_CAPSLOCK, KeyCode::KEY_H],
+    );
+    bench_matching(
+        c,
+        "overlapping_chords",
+        &overlapping_chords(),
+        &LETTERS[..8],
+    );
+}
+
+criterion_group!(benches, pipeline, bench_index);
+criterion_main!(benches);
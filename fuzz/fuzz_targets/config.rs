@@ -0,0 +1,14 @@
+//! Feeds arbitrary input to the config parser, which must only ever fail
+//! with an error rather than panic.
+//!
+//! Run with `cargo +nightly fuzz run config`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let toml = String::from_utf8_lossy(data);
+    let _ = evremap::mapping::parse_key(&toml);
+    let _ = evremap::MappingConfig::from_toml(&toml);
+});
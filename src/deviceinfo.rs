@@ -1,13 +1,20 @@
+use crate::mapping::{all_key_names, key_name, DeviceSelector, EventCode, KeyCode};
 use anyhow::{bail, Context, Result};
 use evdev_rs::{Device, DeviceWrapper};
 use std::cmp::Ordering;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How long to wait between attempts to open the devices at startup.
+pub const GRAB_RETRY_INTERVAL: Duration = Duration::from_millis(500);
 
 #[derive(Debug, Clone)]
 pub struct DeviceInfo {
     pub name: String,
     pub path: PathBuf,
     pub phys: String,
+    /// The EV_KEY codes the device reports as supported.
+    pub keys: Vec<KeyCode>,
 }
 
 impl DeviceInfo {
@@ -16,10 +23,17 @@ impl DeviceInfo {
         let input = Device::new_from_file(f)
             .with_context(|| format!("failed to create new Device from file {}", path.display()))?;
 
+        let keys = all_key_names()
+            .into_iter()
+            .map(|(key, _)| key)
+            .filter(|key| input.has_event_code(&EventCode::EV_KEY(*key)))
+            .collect();
+
         Ok(Self {
             name: input.name().unwrap_or("").to_string(),
             phys: input.phys().unwrap_or("").to_string(),
             path,
+            keys,
         })
     }
 
@@ -31,21 +45,23 @@ impl DeviceInfo {
                 Some(idx) => return Ok(devices.remove(idx)),
                 None => {
                     bail!(
-                        "Requested device `{}` with phys=`{}` was not found",
+                        "Requested device `{}` with phys=`{}` was not found. {}",
                         name,
-                        phys
+                        phys,
+                        describe_devices_seen(&devices)
                     );
                 }
             }
         }
 
+        let seen = describe_devices_seen(&devices);
         let mut devices_with_name: Vec<_> = devices
             .into_iter()
             .filter(|item| item.name == name)
             .collect();
 
         if devices_with_name.is_empty() {
-            bail!("No device found with name `{}`", name);
+            bail!("No device found with name `{}`. {}", name, seen);
         }
 
         if devices_with_name.len() > 1 {
@@ -85,7 +101,7 @@ impl DeviceInfo {
 
             match DeviceInfo::with_path(path) {
                 Ok(item) => devices.push(item),
-                Err(err) => log::error!("{:#}", err),
+                Err(err) => log::warn!("Skipping device: {:#}", err),
             }
         }
 
@@ -101,7 +117,75 @@ impl DeviceInfo {
     }
 }
 
-fn event_number_from_path(path: &PathBuf) -> u32 {
+/// The devices present whose name is one of `names`.
+pub fn devices_named(names: &[String]) -> Result<Vec<DeviceInfo>> {
+    Ok(DeviceInfo::obtain_device_list()?
+        .into_iter()
+        .filter(|dev| names.contains(&dev.name))
+        .collect())
+}
+
+/// Where the device described by `selector` currently is.
+pub fn find_device_path(selector: &DeviceSelector) -> Result<PathBuf> {
+    match (&selector.device_path, &selector.device_name) {
+        (Some(path), _) if path.exists() => Ok(path.clone()),
+        (Some(path), _) => bail!("{} does not exist", path.display()),
+        (None, Some(name)) => Ok(DeviceInfo::with_name(name, selector.phys.as_deref())?.path),
+        (None, None) => bail!("No device_name or device_path to look for"),
+    }
+}
+
+/// Call `attempt` until it succeeds, every `interval` until `deadline`,
+/// logging each failure along the way, and return the error of the last
+/// attempt if none succeeded by then. Without a deadline it is attempted
+/// just once. Used to wait for devices that are not ready yet at boot.
+pub fn retry_until<T>(
+    deadline: Option<Instant>,
+    interval: Duration,
+    mut attempt: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let err = match attempt() {
+            Ok(value) => return Ok(value),
+            Err(err) => err,
+        };
+        let left = deadline.map_or(Duration::ZERO, |deadline| {
+            deadline.saturating_duration_since(Instant::now())
+        });
+        if left.is_zero() {
+            return Err(err);
+        }
+        log::warn!(
+            "Attempt {} failed, retrying for another {:.1}s: {:#}",
+            attempts,
+            left.as_secs_f64(),
+            err
+        );
+        std::thread::sleep(interval.min(left));
+    }
+}
+
+fn describe_devices_seen(devices: &[DeviceInfo]) -> String {
+    if devices.is_empty() {
+        return "No devices were seen; do you have permission to open them?".to_string();
+    }
+    let described: Vec<String> = devices
+        .iter()
+        .map(|dev| {
+            format!(
+                "`{}` (phys=`{}`) at {}",
+                dev.name,
+                dev.phys,
+                dev.path.display()
+            )
+        })
+        .collect();
+    format!("Devices seen: {}", described.join(", "))
+}
+
+fn event_number_from_path(path: &Path) -> u32 {
     match path.to_str() {
         Some(s) => match s.rfind("event") {
             Some(idx) => s[idx + 5..].parse().unwrap_or(0),
@@ -113,11 +197,45 @@ fn event_number_from_path(path: &PathBuf) -> u32 {
 
 pub fn list_devices() -> Result<()> {
     let devices = DeviceInfo::obtain_device_list()?;
+    // Printed as TOML so that the relevant lines can be pasted
+    // straight into a config file.
     for item in &devices {
-        println!("Name: {}", item.name);
-        println!("Path: {}", item.path.display());
-        println!("Phys: {}", item.phys);
+        println!("# Path: {}", item.path.display());
+        println!("device_name = {:?}", item.name);
+        println!("phys = {:?}", item.phys);
+        let keys: Vec<String> = item.keys.iter().map(|key| key_name(*key)).collect();
+        println!("# Keys: {}", keys.join(" "));
         println!();
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retries_until_the_deadline() {
+        let mut attempts = 0;
+        let found = retry_until(
+            Some(Instant::now() + Duration::from_secs(10)),
+            Duration::from_millis(1),
+            || {
+                attempts += 1;
+                match attempts {
+                    3 => Ok(attempts),
+                    _ => bail!("not yet"),
+                }
+            },
+        );
+        assert_eq!(found.unwrap(), 3);
+
+        let mut attempts = 0;
+        let gave_up = retry_until(None, Duration::from_millis(1), || -> Result<()> {
+            attempts += 1;
+            bail!("no device")
+        });
+        assert_eq!(gave_up.unwrap_err().to_string(), "no device");
+        assert_eq!(attempts, 1);
+    }
+}
@@ -0,0 +1,42 @@
+//! The remapping logic of evremap, for feeding key events from a source
+//! of your own, such as a compositor or a test harness.
+//!
+//! Load a [`MappingConfig`], make a [`Machine`] of it, and pass each key
+//! event to [`Machine::insert`], which returns the events to emit instead.
+//! Some decisions, such as whether a dual role key is tapped or held,
+//! depend on time passing with no events; call [`Machine::tick`] once
+//! [`Machine::time_until_tick`] has passed.
+//!
+//! ```
+//! use evremap::{EvKeyEvent, KeyCode, KeyEventType, Machine, Mapping, MappingConfig, TimeVal};
+//!
+//! let config = MappingConfig {
+//!     mappings: vec![Mapping::Remap {
+//!         input: [KeyCode::KEY_CAPSLOCK].into(),
+//!         output: [KeyCode::KEY_LEFTCTRL].into(),
+//!         when: Default::default(),
+//!         on_release: false,
+//!     }],
+//!     ..Default::default()
+//! };
+//! let mut machine = Machine::from_config(&config);
+//! let output = machine.insert(EvKeyEvent {
+//!     time: TimeVal::new(0, 0),
+//!     ev_key: KeyCode::KEY_CAPSLOCK,
+//!     key_event_type: KeyEventType::Press,
+//! });
+//! assert_eq!(output[0].ev_key, KeyCode::KEY_LEFTCTRL);
+//! ```
+//!
+//! Reading from evdev devices and writing to a uinput device, as the
+//! evremap binary does, is behind the default `linux-driver` feature.
+
+#[cfg(feature = "linux-driver")]
+pub mod deviceinfo;
+pub mod mapping;
+pub mod remapper;
+pub mod validate;
+
+pub use evdev_rs::TimeVal;
+pub use mapping::{KeyCode, Mapping, MappingConfig};
+pub use remapper::{EvKeyEvent, KeyEventType, Machine};
@@ -0,0 +1,247 @@
+use anyhow::{Context, Result};
+use evremap::mapping::{LogFile, LogFormat};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+/// Once set, everything is logged here rather than to stderr.
+static FILE_LOGGER: OnceLock<env_logger::Logger> = OnceLock::new();
+
+/// Once set, everything not logged to a file is logged to stderr as JSON.
+static JSON_LOGGER: OnceLock<env_logger::Logger> = OnceLock::new();
+
+/// Whether loggers built from now on write JSON.
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Logs to stderr until `log_to_file` is called, and to the file after,
+/// as text until `set_format` asks for JSON.
+struct Logger {
+    stderr: env_logger::Logger,
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.stderr.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        match (FILE_LOGGER.get(), JSON_LOGGER.get()) {
+            (Some(file), _) => file.log(record),
+            (None, Some(json)) => json.log(record),
+            (None, None) => self.stderr.log(record),
+        }
+    }
+
+    fn flush(&self) {
+        match (FILE_LOGGER.get(), JSON_LOGGER.get()) {
+            (Some(file), _) => file.flush(),
+            (None, Some(json)) => json.flush(),
+            (None, None) => self.stderr.flush(),
+        }
+    }
+}
+
+/// A builder filtered by `EVREMAP_LOG`, info and up by default.
+fn builder() -> env_logger::Builder {
+    let mut builder = env_logger::Builder::new();
+    // Arash note: Change to log::LevelFilter::Trace to get more detailed logs.
+    builder.filter_level(log::LevelFilter::Info);
+    let env = env_logger::Env::new()
+        .filter("EVREMAP_LOG")
+        .write_style("EVREMAP_LOG_STYLE");
+    builder.parse_env(env);
+    if JSON.load(Ordering::SeqCst) {
+        builder.format(write_json);
+    }
+    builder
+}
+
+/// Write `record` as a JSON object on a line of its own.
+fn write_json(buf: &mut env_logger::fmt::Formatter, record: &log::Record) -> std::io::Result<()> {
+    let mut object = serde_json::Map::new();
+    object.insert(
+        "timestamp".to_string(),
+        buf.timestamp_micros().to_string().into(),
+    );
+    object.insert("level".to_string(), record.level().as_str().into());
+    object.insert("target".to_string(), record.target().into());
+    object.insert("message".to_string(), record.args().to_string().into());
+    // A field can't fail to be added.
+    let _ = record.key_values().visit(&mut JsonFields(&mut object));
+    writeln!(buf, "{}", serde_json::Value::Object(object))
+}
+
+/// Adds the key-values of a record to a JSON object, numbers and booleans
+/// as such and anything else as a string.
+struct JsonFields<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl<'kvs> log::kv::VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(
+        &mut self,
+        key: log::kv::Key<'kvs>,
+        value: log::kv::Value<'kvs>,
+    ) -> Result<(), log::kv::Error> {
+        let value = if let Some(value) = value.to_i64() {
+            value.into()
+        } else if let Some(value) = value.to_u64() {
+            value.into()
+        } else if let Some(value) = value.to_f64() {
+            value.into()
+        } else if let Some(value) = value.to_bool() {
+            value.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.insert(key.as_str().to_string(), value);
+        Ok(())
+    }
+}
+
+/// Log to stderr, as filtered by `EVREMAP_LOG`.
+pub fn setup() {
+    let stderr = builder().build();
+    log::set_max_level(stderr.filter());
+    log::set_boxed_logger(Box::new(Logger { stderr })).expect("the logger is only set up once");
+}
+
+/// Log in `format` from now on, to stderr and to the file of a later
+/// `log_to_file`. Switching to JSON can only be done once.
+pub fn set_format(format: LogFormat) {
+    if format == LogFormat::Json && JSON_LOGGER.get().is_none() {
+        JSON.store(true, Ordering::SeqCst);
+        let _ = JSON_LOGGER.set(builder().build());
+    }
+}
+
+/// Log to `log_file` from now on, with the same filter as before. This
+/// can only be done once; later calls keep logging to the first file.
+pub fn log_to_file(log_file: &LogFile) -> Result<()> {
+    if FILE_LOGGER.get().is_some() {
+        return Ok(());
+    }
+    let file = RotatingFile::open(log_file)?;
+    log::info!("Logging to {}", log_file.path.display());
+    let logger = builder()
+        .write_style(env_logger::WriteStyle::Never)
+        .target(env_logger::Target::Pipe(Box::new(file)))
+        .build();
+    let _ = FILE_LOGGER.set(logger);
+    Ok(())
+}
+
+/// A file that is rotated as configured by a `LogFile` once writing to
+/// it would take it past its maximum size.
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(log_file: &LogFile) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_file.path)
+            .context(format!("opening log file {}", log_file.path.display()))?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: log_file.path.clone(),
+            max_size: log_file.max_size,
+            keep: log_file.keep,
+            file,
+            size,
+        })
+    }
+
+    /// `<path>.<n>`, the `n`th most recently rotated file.
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        if self.keep > 0 {
+            for n in (1..self.keep).rev() {
+                let from = self.rotated(n);
+                if from.exists() {
+                    std::fs::rename(from, self.rotated(n + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields_keep_their_json_type() {
+        let fields: &[(&str, log::kv::Value)] = &[
+            ("rule", log::kv::Value::from(3u64)),
+            ("time_us", log::kv::Value::from(-5i64)),
+            ("key", log::kv::Value::from("KEY_A")),
+        ];
+        let record = log::Record::builder().key_values(&fields).build();
+        let mut object = serde_json::Map::new();
+        record
+            .key_values()
+            .visit(&mut JsonFields(&mut object))
+            .unwrap();
+        assert_eq!(
+            serde_json::Value::Object(object),
+            serde_json::json!({"rule": 3, "time_us": -5, "key": "KEY_A"})
+        );
+    }
+
+    #[test]
+    fn rotates_past_max_size_keeping_the_newest() {
+        let dir = std::env::temp_dir().join(format!("evremap-logging-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        let mut file = RotatingFile::open(&LogFile {
+            path: dir.join("evremap.log"),
+            max_size: 8,
+            keep: 2,
+        })
+        .unwrap();
+        for line in ["one\n", "two\n", "three\n", "four\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("evremap.log"), "four\n");
+        assert_eq!(read("evremap.log.1"), "three\n");
+        assert_eq!(read("evremap.log.2"), "one\ntwo\n");
+        assert!(!dir.join("evremap.log.3").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
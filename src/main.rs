@@ -1,14 +1,13 @@
-use crate::deviceinfo::DeviceInfo;
-use crate::mapping::*;
-// use crate::remapper;
 use anyhow::{Context, Result};
 use clap::Parser;
+use evremap::deviceinfo::{self, DeviceInfo};
+use evremap::mapping::*;
+use evremap::{remapper, validate};
 use std::path::PathBuf;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-mod deviceinfo;
-mod mapping;
-mod remapper;
+mod logging;
+mod profiles;
 
 /// Remap libinput evdev keyboard inputs
 #[derive(Debug, Parser)]
@@ -35,13 +34,36 @@ enum Opt {
         phys: Option<String>,
     },
 
+    /// Print the events of a device as they come, with key events by
+    /// their KEY_XXX name, to see which keys are which. The device is
+    /// not grabbed, so it keeps working as usual
+    Monitor {
+        /// The device path (eg: /dev/input/event3), or the device name
+        #[arg(name = "DEVICE")]
+        device: String,
+
+        /// Specify the phys device in case multiple devices have
+        /// the same name
+        #[arg(long)]
+        phys: Option<String>,
+    },
+
     /// Load a remapper config and run the remapper.
     /// This usually requires running as root to obtain exclusive access
     /// to the input devices.
     Remap {
-        /// Specify the configuration file to be loaded
+        /// Specify the configuration file to be loaded, which is reloaded
+        /// whenever it changes, or a directory of `<name>.toml` profiles
+        /// that is watched for changes. Defaults to
+        /// `$XDG_CONFIG_HOME/evremap/config.toml`, or else
+        /// `/etc/evremap.toml`
         #[arg(name = "CONFIG-FILE")]
-        config_file: PathBuf,
+        config_file: Option<PathBuf>,
+
+        /// The profile to start with when CONFIG-FILE is a directory.
+        /// Defaults to the one named in its `active` file, or `default`
+        #[arg(long)]
+        profile: Option<String>,
 
         /// Number of seconds for user to release keys on startup
         #[arg(short, long, default_value = "2")]
@@ -55,6 +77,11 @@ enum Opt {
         #[arg(long)]
         phys: Option<String>,
 
+        /// Use this device path (eg: /dev/input/event3) rather than
+        /// looking the device up by name and phys
+        #[arg(long)]
+        device_path: Option<PathBuf>,
+
         /// If the device isn't found on startup, wait forever
         /// until the device is plugged in. This works by polling
         /// the set of devices every few seconds. It is not as
@@ -62,33 +89,149 @@ enum Opt {
         /// but is simpler to setup ad-hoc.
         #[arg(long)]
         wait_for_device: bool,
+
+        /// Keep trying to find, open and grab the devices for this many
+        /// seconds before giving up, for devices that aren't ready yet
+        /// when evremap starts at boot
+        #[arg(long)]
+        grab_timeout: Option<f64>,
+
+        /// Measure the time from reading each key event until its output
+        /// is written. Percentiles are logged every 1000 events, and a
+        /// histogram is printed on SIGUSR1 and on exit
+        #[arg(long)]
+        latency: bool,
+
+        /// Rather than remapping, print what each key event of the
+        /// device is remapped to. The device is not grabbed, so it
+        /// keeps working as usual
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Log to this file rather than to stderr, rotating it once it
+        /// grows past 10MiB. Overrides `log_file` of the config file
+        #[arg(long)]
+        log_file: Option<PathBuf>,
+
+        /// Log each record as a JSON object on a line of its own, with
+        /// fields such as the key and event type of the events traced at
+        /// the trace level. Overrides `log_format` of the config file
+        #[arg(long)]
+        log_json: bool,
+    },
+
+    /// Switch the active profile of an evremap watching a directory
+    /// of profiles
+    SwitchProfile {
+        /// The directory of profiles
+        #[arg(name = "PROFILE-DIR")]
+        profile_dir: PathBuf,
+
+        /// The name of the profile to switch to
+        #[arg(name = "PROFILE")]
+        profile: String,
+    },
+
+    /// Check a remapper config for mistakes beyond syntax errors, such
+    /// as ambiguous remaps, and exit with an error if there are any
+    Validate {
+        /// Specify the configuration file to be checked. Defaults to the
+        /// one `remap` would load
+        #[arg(name = "CONFIG-FILE")]
+        config_file: Option<PathBuf>,
+    },
+
+    /// Record the key events of a device to a JSON-lines file, one event
+    /// per line, until interrupted. The recording can be fed through a
+    /// config with `replay`, eg: to reproduce a stuck key. The device is
+    /// not grabbed, so it keeps working as usual
+    Record {
+        /// The device path (eg: /dev/input/event3), or the device name
+        #[arg(name = "DEVICE")]
+        device: String,
+
+        /// The JSON-lines file to write the events to
+        #[arg(name = "EVENTS-FILE")]
+        events_file: PathBuf,
+
+        /// Specify the phys device in case multiple devices have
+        /// the same name
+        #[arg(long)]
+        phys: Option<String>,
+    },
+
+    /// Feed a recording of key events through the remapper and print
+    /// the translation, without touching any device.
+    Replay {
+        /// Specify the configuration file to be loaded
+        #[arg(name = "CONFIG-FILE")]
+        config_file: PathBuf,
+
+        /// The JSON-lines file holding the recorded events
+        #[arg(name = "EVENTS-FILE")]
+        events_file: PathBuf,
+
+        /// Reproduce the original delays between events rather than
+        /// replaying as fast as possible
+        #[arg(long)]
+        realtime: bool,
+    },
+
+    /// Feed key events read from stdin, in the JSON-lines format of
+    /// `record`, through the remapper as they come, and write the events
+    /// it emits to stdout in the same format, without touching any
+    /// device. Handy for testing configs from a shell script
+    Pipe {
+        /// Specify the configuration file to be loaded
+        #[arg(name = "CONFIG-FILE")]
+        config_file: PathBuf,
+    },
+
+    /// Show what the remapper emits for human-readable key actions
+    /// such as `Ctrl+0`, one result line per action. Handy for
+    /// documenting the behavior of a config.
+    Transcript {
+        /// Specify the configuration file to be loaded
+        #[arg(name = "CONFIG-FILE")]
+        config_file: PathBuf,
+
+        /// Key actions, each a `+` separated list of keys pressed
+        /// together, eg: `Ctrl+0`
+        #[arg(name = "ACTION", required = true)]
+        actions: Vec<String>,
     },
 }
 
 pub fn list_keys() -> Result<()> {
-    let mut keys: Vec<String> = EventCode::EV_KEY(KeyCode::KEY_RESERVED)
-        .iter()
-        .filter_map(|code| match code {
-            EventCode::EV_KEY(_) => Some(format!("{}", code)),
-            _ => None,
-        })
-        .collect();
-    keys.sort();
-    for key in keys {
-        println!("{}", key);
+    let default_modifiers = Modifiers::default();
+    let (modifiers, others): (Vec<_>, Vec<_>) = all_key_names()
+        .into_iter()
+        .partition(|(key, _)| default_modifiers.contains(key));
+    println!("# Modifiers (unless configured otherwise)");
+    for (_, name) in modifiers {
+        println!("{}", name);
+    }
+    println!();
+    println!("# Other keys");
+    for (_, name) in others {
+        println!("{}", name);
+    }
+    println!();
+    println!("# Aliases, besides those defined in [aliases]");
+    for (alias, name) in BUILTIN_ALIASES {
+        println!("{} = {}", alias, name);
     }
     Ok(())
 }
 
-fn setup_logger() {
-    let mut builder = env_logger::Builder::new();
-    // Arash note: Change to log::LevelFilter::Trace to get more detailed logs.
-    builder.filter_level(log::LevelFilter::Info);
-    let env = env_logger::Env::new()
-        .filter("EVREMAP_LOG")
-        .write_style("EVREMAP_LOG_STYLE");
-    builder.parse_env(env);
-    builder.init();
+/// The device at `device` if that is a path, or else the one named so.
+fn device_by_path_or_name(device: &str, phys: Option<&str>) -> Result<DeviceInfo> {
+    let path = PathBuf::from(device);
+    if path.exists() {
+        DeviceInfo::with_path(path)
+    } else {
+        get_device(device, phys, false)
+    }
 }
 
 fn get_device(
@@ -146,7 +289,7 @@ fn debug_events(device: DeviceInfo) -> Result<()> {
 }
 
 fn main() -> Result<()> {
-    setup_logger();
+    logging::setup();
     let opt = Opt::parse();
 
     match opt {
@@ -156,17 +299,43 @@ fn main() -> Result<()> {
             let device_info = get_device(&device_name, phys.as_deref(), false)?;
             debug_events(device_info)
         }
+        Opt::Monitor { device, phys } => {
+            let device_info = device_by_path_or_name(&device, phys.as_deref())?;
+            remapper::monitor(&device_info.path)
+        }
         Opt::Remap {
             config_file,
+            profile,
             delay,
             device_name,
             phys,
+            device_path,
             wait_for_device,
-        } => Ok({
-            let mut mapping_config = MappingConfig::from_file(&config_file).context(format!(
-                "loading MappingConfig from {}",
-                config_file.display()
-            ))?;
+            grab_timeout,
+            latency,
+            dry_run,
+            log_file,
+            log_json,
+        } => {
+            let (mut mapping_config, profile_updates) = match config_file {
+                Some(dir) if dir.is_dir() => {
+                    let (config, updates) = profiles::watch(&dir, profile.as_deref())?;
+                    (config, Some(updates))
+                }
+                Some(config_file) => {
+                    let config = MappingConfig::from_file(&config_file).context(format!(
+                        "loading MappingConfig from {}",
+                        config_file.display()
+                    ))?;
+                    let updates = profiles::watch_file(&config_file)?;
+                    (config, Some(updates))
+                }
+                None => {
+                    let (config, config_file) = MappingConfig::from_default_locations()?;
+                    let updates = profiles::watch_file(&config_file)?;
+                    (config, Some(updates))
+                }
+            };
 
             if let Some(device) = device_name {
                 mapping_config.device_name = Some(device);
@@ -174,22 +343,158 @@ fn main() -> Result<()> {
             if let Some(phys) = phys {
                 mapping_config.phys = Some(phys);
             }
+            if let Some(device_path) = device_path {
+                mapping_config.device_path = Some(device_path);
+            }
+            if let Some(path) = log_file {
+                mapping_config.log_file = Some(match mapping_config.log_file {
+                    Some(log_file) => LogFile { path, ..log_file },
+                    None => LogFile::new(path),
+                });
+            }
+            if log_json {
+                mapping_config.log_format = Some(LogFormat::Json);
+            }
+            logging::set_format(mapping_config.log_format.unwrap_or_default());
+            if let Some(log_file) = &mapping_config.log_file {
+                logging::log_to_file(log_file)?;
+            }
 
-            let device_name = mapping_config.device_name.as_deref().ok_or_else(|| {
-                anyhow::anyhow!(
+            let device_name = mapping_config.device_name.as_deref();
+            let main_named = device_name.is_some() || mapping_config.device_path.is_some();
+            if !main_named && (mapping_config.devices.is_empty() || dry_run) {
+                anyhow::bail!(
                     "device_name is missing; \
                         specify it either in the config file or via the --device-name \
-                        command line option"
-                )
-            })?;
+                        command line option, or give an explicit --device-path"
+                );
+            }
+
+            for finding in validate::validate(&mapping_config) {
+                log::warn!("{}", finding);
+            }
 
-            log::warn!("Short delay: release any keys now!");
-            std::thread::sleep(Duration::from_secs_f64(delay));
+            if !dry_run {
+                log::warn!("Short delay: release any keys now!");
+                std::thread::sleep(Duration::from_secs_f64(delay));
+            }
 
-            let device_info =
-                get_device(device_name, mapping_config.phys.as_deref(), wait_for_device)?;
+            let grab_deadline =
+                grab_timeout.map(|secs| Instant::now() + Duration::from_secs_f64(secs));
+            let device_paths = deviceinfo::retry_until(
+                grab_deadline,
+                deviceinfo::GRAB_RETRY_INTERVAL,
+                || -> Result<Vec<PathBuf>> {
+                    let mut device_paths = vec![];
+                    if main_named {
+                        let device_info = match (&mapping_config.device_path, device_name) {
+                            (Some(path), _) => DeviceInfo::with_path(path.clone())?,
+                            (None, Some(device_name)) => get_device(
+                                device_name,
+                                mapping_config.phys.as_deref(),
+                                wait_for_device,
+                            )?,
+                            (None, None) => unreachable!("checked above"),
+                        };
+                        device_paths.push(device_info.path);
+                    }
+                    if dry_run {
+                        return Ok(device_paths);
+                    }
+                    for selector in mapping_config
+                        .device_selectors()
+                        .iter()
+                        .skip(device_paths.len())
+                    {
+                        device_paths.push(deviceinfo::find_device_path(selector)?);
+                    }
+                    Ok(device_paths)
+                },
+            )?;
+            if dry_run {
+                return remapper::dry_run(&device_paths[0], &mapping_config);
+            }
 
-            remapper::run_forever(device_info.path, &mapping_config.mappings)?;
-        }),
+            remapper::run_forever(
+                device_paths,
+                &mapping_config,
+                profile_updates,
+                latency,
+                grab_deadline,
+            )?;
+            Ok(())
+        }
+        Opt::SwitchProfile {
+            profile_dir,
+            profile,
+        } => profiles::request_switch(&profile_dir, &profile),
+        Opt::Validate { config_file } => {
+            let (mapping_config, config_file) = match config_file {
+                Some(config_file) => (
+                    MappingConfig::from_file(&config_file).context(format!(
+                        "loading MappingConfig from {}",
+                        config_file.display()
+                    ))?,
+                    config_file,
+                ),
+                None => MappingConfig::from_default_locations()?,
+            };
+            let findings = validate::validate(&mapping_config);
+            for finding in &findings {
+                println!("{}", finding);
+            }
+            let errors = findings
+                .iter()
+                .filter(|finding| finding.severity == validate::Severity::Error)
+                .count();
+            if errors > 0 {
+                anyhow::bail!("{} has {} error(s)", config_file.display(), errors);
+            }
+            Ok(())
+        }
+        Opt::Record {
+            device,
+            events_file,
+            phys,
+        } => {
+            let device_info = device_by_path_or_name(&device, phys.as_deref())?;
+            remapper::record(&device_info.path, &events_file)
+        }
+        Opt::Replay {
+            config_file,
+            events_file,
+            realtime,
+        } => {
+            let mapping_config = MappingConfig::from_file(&config_file).context(format!(
+                "loading MappingConfig from {}",
+                config_file.display()
+            ))?;
+            remapper::replay_file(&mapping_config, &events_file, realtime)
+        }
+        Opt::Pipe { config_file } => {
+            let mapping_config = MappingConfig::from_file(&config_file).context(format!(
+                "loading MappingConfig from {}",
+                config_file.display()
+            ))?;
+            remapper::pipe(
+                &mapping_config,
+                std::io::stdin().lock(),
+                &mut std::io::stdout().lock(),
+            )
+        }
+        Opt::Transcript {
+            config_file,
+            actions,
+        } => {
+            let mapping_config = MappingConfig::from_file(&config_file).context(format!(
+                "loading MappingConfig from {}",
+                config_file.display()
+            ))?;
+            let results = remapper::run_transcript(&mapping_config, &actions)?;
+            for (action, result) in actions.iter().zip(results) {
+                println!("{} => {}", action, result);
+            }
+            Ok(())
+        }
     }
 }
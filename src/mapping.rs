@@ -1,43 +1,911 @@
 use anyhow::Context;
-pub use evdev_rs::enums::{EventCode, EventType, EV_KEY as KeyCode};
+pub use evdev_rs::enums::{
+    EventCode, EventType, EV_KEY as KeyCode, EV_LED as LedCode, EV_REL as RelCode,
+};
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
-use std::collections::HashSet;
-use std::path::Path;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use thiserror::Error;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct MappingConfig {
     pub device_name: Option<String>,
     pub phys: Option<String>,
+    /// Explicit device to use, instead of matching on name and phys.
+    pub device_path: Option<PathBuf>,
     pub mappings: Vec<Mapping>,
+    /// Maximum number of mappings evaluated per event, if any.
+    pub evaluation_budget: Option<usize>,
+    /// Which of several remaps that match the held keys wins.
+    pub match_strategy: MatchStrategy,
+    /// How long a dual role key may be held and still count as a tap.
+    pub dual_role_timeout_ms: Option<u64>,
+    /// Release every key held in the output, as the panic chord does, once
+    /// one has been held this long with no input event since, not even an
+    /// auto-repeat. Off by default; a safety net against stuck keys for
+    /// inputs that repeat the keys held on them, which a held mouse button
+    /// doesn't.
+    pub stuck_key_timeout_ms: Option<u64>,
+    /// How many virtual output devices to spread emitted events over.
+    pub virtual_devices: Option<usize>,
+    /// Name the virtual devices this and give them nothing but the keys
+    /// that may be emitted, rather than cloning the input device with all
+    /// of its capabilities. Events other than EV_KEY can't be passed
+    /// through such a device.
+    pub output_device_name: Option<String>,
+    /// Ids for the virtual devices to report rather than those of the
+    /// first input, which they report otherwise.
+    pub output_id: Option<OutputId>,
+    /// Delay between the steps of a `Mapping::Macro`, and between the
+    /// frames of other bursts of emitted events, such as taps.
+    pub macro_delay_ms: Option<u64>,
+    /// Values written for key presses, releases and repeats.
+    pub output_values: OutputValues,
+    /// Have the virtual devices repeat held keys themselves, at this rate,
+    /// rather than passing on the repeats of the inputs.
+    pub output_repeat: Option<OutputRepeat>,
+    /// How often to look for the input device after it disappeared.
+    pub reconnect_interval_ms: Option<u64>,
+    pub modifiers: Modifiers,
+    /// Keys that, pressed together, switch between remapping and passing
+    /// every event through unchanged.
+    pub bypass: HashSet<KeyCode>,
+    /// Keys that, pressed together, release every key held in the output
+    /// and forget about the held keys, to recover from stuck keys.
+    pub panic: HashSet<KeyCode>,
+    /// Keys that are passed on as they are, without going through the
+    /// mappings at all. As the mappings never see them, a mapping with one
+    /// of them in its input never fires, and they don't count as another
+    /// key pressed, e.g. for turning a dual role key into a hold.
+    pub passthrough: HashSet<KeyCode>,
+    /// Whether events other than EV_KEY are passed on; the default is yes.
+    pub forward_non_key: Option<bool>,
+    /// Whether key events with a value other than release, press or
+    /// repeat are passed on as they are; the default is yes.
+    pub forward_unknown_values: Option<bool>,
+    /// Whether the input devices are grabbed, so that the system only gets
+    /// the remapped events; the default is yes. Otherwise the system gets
+    /// the events of the inputs as well, and the virtual devices only add
+    /// the keys the mappings emit that aren't held on the inputs already.
+    /// The input of a remap then still goes through along with its output,
+    /// and a key held on an input can't be released for it, so this suits
+    /// adding chords of keys that do nothing on their own. Events other
+    /// than EV_KEY aren't passed on, as the system gets them anyway.
+    pub grab: Option<bool>,
+    /// The command that prints the window class of the foreground
+    /// application, for remaps with an `app`; by default it is looked up
+    /// with `xprop`, which only works on X11.
+    pub app_command: Option<Vec<String>>,
+    /// What to do on recovering from dropped events or a lost device.
+    pub recovery_hook: RecoveryHook,
+    /// More devices whose events are combined with those of the main one,
+    /// e.g. the other half of a split keyboard.
+    pub extra_devices: Vec<DeviceSelector>,
+    /// Devices with mappings of their own, remapped after the main and
+    /// extra devices. A `[[device]]` block that selects no device is not
+    /// one of these: its remaps apply to every device, and are part of
+    /// `mappings`.
+    pub devices: Vec<DeviceConfig>,
+    /// How relative axes, like scroll wheels, are remapped. Axes without
+    /// one are passed through like other events that aren't EV_KEY.
+    pub rel_mappings: Vec<RelMapping>,
+    /// Keys that move a relative axis, such as keys that scroll. Like
+    /// `passthrough` keys, the mappings never see them.
+    pub key_rel_mappings: Vec<KeyRelMapping>,
+    /// Whether all devices share one set of held keys and layers, rather
+    /// than each device being remapped on its own; the default is no.
+    pub shared_state: Option<bool>,
+    /// Log to this file rather than to stderr.
+    pub log_file: Option<LogFile>,
+    /// How log records are written; the default is as text.
+    pub log_format: Option<LogFormat>,
 }
 
 impl MappingConfig {
+    /// Whether the config names a main device, which it needn't when it
+    /// has `devices` of their own.
+    pub fn has_main_device(&self) -> bool {
+        self.device_name.is_some() || self.device_path.is_some() || self.devices.is_empty()
+    }
+
+    /// The main device, if any, followed by the extra devices and then the
+    /// devices with mappings of their own.
+    pub fn device_selectors(&self) -> Vec<DeviceSelector> {
+        let main = DeviceSelector {
+            device_name: self.device_name.clone(),
+            phys: self.phys.clone(),
+            device_path: self.device_path.clone(),
+        };
+        self.has_main_device()
+            .then_some(main)
+            .into_iter()
+            .chain(self.extra_devices.iter().cloned())
+            .chain(self.devices.iter().map(|device| device.selector.clone()))
+            .collect()
+    }
+
+    /// Whether any remap, of any device, is conditioned on the foreground
+    /// application.
+    pub fn uses_apps(&self) -> bool {
+        self.mappings
+            .iter()
+            .chain(self.devices.iter().flat_map(|device| &device.mappings))
+            .any(|mapping| matches!(mapping, Mapping::Remap { when, .. } if !when.apps.is_empty()))
+    }
+}
+
+/// A device with mappings of its own, from a `[[device]]` block of the
+/// config. Its mappings go before those of the config as a whole, which
+/// apply to it as well: first those of the default `[[device]]` block,
+/// the one without a name, phys or path, then the top-level ones.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceConfig {
+    pub selector: DeviceSelector,
+    pub mappings: Vec<Mapping>,
+}
+
+/// How remaps that overlap, like one for `KEY_A` and one for
+/// `KEY_LEFTCTRL`+`KEY_A`, are resolved. Remaps are applied one after the
+/// other, each replacing its non-modifier inputs with its output, so once
+/// a remap has consumed `KEY_A` no later remap that needs it can match.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchStrategy {
+    /// Remaps apply in the order of the config, so the first one that
+    /// matches wins.
+    #[default]
+    FirstMatch,
+    /// The most specific remaps apply first: those with the most
+    /// conditions, such as a layer, then those with the most input keys.
+    /// Remaps that are equally specific keep the order of the config.
+    LongestMatch,
+}
+
+/// Which input device to use: the one at `device_path` if given, otherwise
+/// the one with `device_name` and, if given, `phys`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DeviceSelector {
+    #[serde(default)]
+    pub device_name: Option<String>,
+    #[serde(default)]
+    pub phys: Option<String>,
+    #[serde(default)]
+    pub device_path: Option<PathBuf>,
+}
+
+impl DeviceSelector {
+    /// Whether this selects no device at all, as for the default
+    /// `[[device]]` block.
+    pub fn is_empty(&self) -> bool {
+        self.device_name.is_none() && self.phys.is_none() && self.device_path.is_none()
+    }
+}
+
+/// A file to log to, which is rotated once it grows past `max_size` bytes:
+/// it is renamed to `<path>.1`, the previous `<path>.1` to `<path>.2`, and
+/// so on, keeping `keep` rotated files.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct LogFile {
+    pub path: PathBuf,
+    #[serde(default = "LogFile::default_max_size")]
+    pub max_size: u64,
+    #[serde(default = "LogFile::default_keep")]
+    pub keep: usize,
+}
+
+impl LogFile {
+    fn default_max_size() -> u64 {
+        10 * 1024 * 1024
+    }
+
+    fn default_keep() -> usize {
+        3
+    }
+
+    /// Logging to `path`, rotated at the default size.
+    pub fn new(path: PathBuf) -> Self {
+        LogFile {
+            path,
+            max_size: Self::default_max_size(),
+            keep: Self::default_keep(),
+        }
+    }
+}
+
+/// How log records are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// A line of text per record, for people to read.
+    #[default]
+    Text,
+    /// A JSON object per line, for log pipelines to analyze: the time,
+    /// level, target and message of the record, and the fields that some
+    /// records have, such as the key, event type and time of the events
+    /// traced as they come in and go out, and the position of the
+    /// mapping that fired for a key press.
+    Json,
+}
+
+/// Signals, beyond logging, that evremap recovered from a resync or a
+/// reconnect, so that monitoring can pick it up.
+#[derive(Debug, Clone, Default)]
+pub struct RecoveryHook {
+    /// Key to tap on the output device.
+    pub key: Option<KeyCode>,
+    /// File to append a line to.
+    pub status_file: Option<PathBuf>,
+}
+
+/// Keys that are pressed before and released after other keys, and that
+/// remain held when used as the input of a mapping.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Modifiers {
+    keys: HashSet<KeyCode>,
+}
+
+impl Modifiers {
+    pub fn new(keys: impl IntoIterator<Item = KeyCode>) -> Self {
+        Modifiers {
+            keys: keys.into_iter().collect(),
+        }
+    }
+
+    pub fn contains(&self, key: &KeyCode) -> bool {
+        self.keys.contains(key)
+    }
+}
+
+impl Default for Modifiers {
+    fn default() -> Self {
+        Modifiers::new([
+            KeyCode::KEY_FN,
+            KeyCode::KEY_LEFTALT,
+            KeyCode::KEY_RIGHTALT,
+            KeyCode::KEY_LEFTMETA,
+            KeyCode::KEY_RIGHTMETA,
+            KeyCode::KEY_LEFTCTRL,
+            KeyCode::KEY_RIGHTCTRL,
+            KeyCode::KEY_LEFTSHIFT,
+            KeyCode::KEY_RIGHTSHIFT,
+        ])
+    }
+}
+
+/// The `value` written for each kind of key event. Some custom HID
+/// consumers expect something other than the standard 1/0/2.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct OutputValues {
+    pub press: i32,
+    pub release: i32,
+    pub repeat: i32,
+}
+
+impl Default for OutputValues {
+    fn default() -> Self {
+        OutputValues {
+            press: 1,
+            release: 0,
+            repeat: 2,
+        }
+    }
+}
+
+/// The EV_REP settings of the virtual devices, with which the kernel
+/// repeats the keys held on them.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+pub struct OutputRepeat {
+    /// How long a key is held before it starts repeating.
+    pub delay_ms: u32,
+    /// How long between repeats.
+    pub period_ms: u32,
+}
+
+/// The ids of the virtual devices, as in `struct input_id`, that tools
+/// such as libinput key their per-device settings and quirks off. Those
+/// left out are copied from the first input.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Deserialize)]
+pub struct OutputId {
+    /// The bus type, such as 3 for USB.
+    pub bustype: Option<u16>,
+    pub vendor: Option<u16>,
+    pub product: Option<u16>,
+    pub version: Option<u16>,
+}
+
+/// Where to look for a config file when none is given, in order:
+/// `$XDG_CONFIG_HOME/evremap/config.toml`, with `XDG_CONFIG_HOME` defaulting
+/// to `~/.config`, then `/etc/evremap.toml`.
+pub fn default_config_locations() -> Vec<PathBuf> {
+    config_locations(
+        std::env::var_os("XDG_CONFIG_HOME"),
+        std::env::var_os("HOME"),
+    )
+}
+
+fn config_locations(
+    xdg_config_home: Option<std::ffi::OsString>,
+    home: Option<std::ffi::OsString>,
+) -> Vec<PathBuf> {
+    // The spec says to ignore a relative XDG_CONFIG_HOME.
+    let config_home = xdg_config_home
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| home.map(|home| PathBuf::from(home).join(".config")));
+    config_home
+        .map(|dir| dir.join("evremap").join("config.toml"))
+        .into_iter()
+        .chain([PathBuf::from("/etc/evremap.toml")])
+        .collect()
+}
+
+impl MappingConfig {
+    /// Load the first config file of `default_config_locations` that
+    /// exists, returning it along with its path.
+    pub fn from_default_locations() -> anyhow::Result<(Self, PathBuf)> {
+        let locations = default_config_locations();
+        let Some(path) = locations.iter().find(|path| path.exists()) else {
+            let searched: Vec<String> = locations
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect();
+            return Err(ConfigError::NoConfigFound(searched.join(" or ")).into());
+        };
+        log::info!("Using the config file {}", path.display());
+        let config = Self::from_file(path)
+            .context(format!("loading MappingConfig from {}", path.display()))?;
+        Ok((config, path.clone()))
+    }
+
     pub fn from_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
-        let path = path.as_ref();
-        let toml_data = std::fs::read_to_string(path)
-            .context(format!("reading toml from {}", path.display()))?;
-        let config_file: ConfigFile =
-            toml::from_str(&toml_data).context(format!("parsing toml from {}", path.display()))?;
+        let (config_file, _) = ConfigFile::load(path.as_ref(), &mut vec![])?;
+        Ok(Self::from_config_file(config_file))
+    }
+
+    /// Parse a config from `toml_data` rather than from a file. Its
+    /// `include`s are not followed, as there is no directory to find them
+    /// in.
+    pub fn from_toml(toml_data: &str) -> anyhow::Result<Self> {
+        let path = Path::new("<toml>");
+        let preamble: Preamble = parse_toml(path, toml_data, &HashMap::new())?;
+        for (alias, key) in &preamble.aliases {
+            parse_key(key).context(format!("alias `{}`", alias))?;
+        }
+        let config_file = parse_toml(path, toml_data, &preamble.aliases)?;
+        Ok(Self::from_config_file(config_file))
+    }
+
+    fn from_config_file(config_file: ConfigFile) -> Self {
+        let (default_devices, devices): (Vec<_>, Vec<_>) = config_file
+            .device
+            .into_iter()
+            .partition(|device| device.selector.is_empty());
         let mut mappings = vec![];
-        for remap in config_file.remap {
-            mappings.push(remap.into());
+        for dual_role in config_file.dual_role {
+            mappings.push(dual_role.into());
+        }
+        // The remaps of the default device block take precedence over the
+        // top-level ones, but not over those of the device's own block.
+        mappings.extend(remaps(
+            default_devices
+                .into_iter()
+                .flat_map(|device| device.remap)
+                .chain(config_file.remap)
+                .collect(),
+        ));
+        for layer in config_file.layer {
+            mappings.push(Mapping::Layer {
+                input: layer.input.into(),
+                layer: layer.name,
+            });
+        }
+        for lock in config_file.layer_lock {
+            mappings.push(Mapping::LayerLock {
+                input: lock.input.into(),
+                layer: lock.name,
+                led: lock.led.map(|led| led.code),
+            });
+        }
+        for tiered in config_file.tiered {
+            mappings.push(tiered.into());
+        }
+        for ignore in config_file.ignore {
+            mappings.push(Mapping::Ignore {
+                input: ignore.input.into(),
+            });
         }
-        Ok(Self {
+        for probe in config_file.key_name_probe {
+            mappings.push(Mapping::KeyNameProbe {
+                input: probe.input.into(),
+            });
+        }
+        for invert in config_file.invert_modifier {
+            mappings.push(Mapping::InvertModifier {
+                input: invert.input.into(),
+                modifier: invert.modifier.into(),
+            });
+        }
+        for one_shot in config_file.one_shot {
+            mappings.push(Mapping::OneShot {
+                input: one_shot.input.into(),
+                modifier: one_shot.modifier.into(),
+                lock_on_double_tap: one_shot.lock_on_double_tap,
+            });
+        }
+        for macro_config in config_file.macros {
+            mappings.push(macro_config.into());
+        }
+        for unicode in config_file.unicode {
+            mappings.push(Mapping::Unicode {
+                input: unicode.input.into(),
+                codepoint: unicode.codepoint,
+                method: unicode.method,
+            });
+        }
+        for text in config_file.text {
+            mappings.push(Mapping::Text {
+                input: text.input.into(),
+                text: text.text,
+            });
+        }
+        for combo in config_file.combo {
+            let input: Vec<KeyCode> = combo.input.into_iter().map(Into::into).collect();
+            mappings.push(Mapping::Combo {
+                input: input.iter().copied().collect(),
+                output: combo.output.into_iter().map(Into::into).collect(),
+                window_ms: combo.window_ms.unwrap_or(DEFAULT_COMBO_WINDOW_MS),
+                order: combo.in_order.then_some(input),
+            });
+        }
+        for tap_dance in config_file.tap_dance {
+            mappings.push(Mapping::TapDance {
+                input: tap_dance.input.into(),
+                taps: tap_dance
+                    .taps
+                    .into_iter()
+                    .map(|keys| keys.into_iter().map(Into::into).collect())
+                    .collect(),
+                timeout_ms: tap_dance.timeout_ms.unwrap_or(DEFAULT_TAP_DANCE_TIMEOUT_MS),
+            });
+        }
+        for deferred in config_file.deferred_passthrough {
+            mappings.push(Mapping::DeferredPassthrough {
+                input: deferred.input.into(),
+                cancel: deferred.cancel.into_iter().map(Into::into).collect(),
+            });
+        }
+        Self {
             device_name: config_file.device_name,
             phys: config_file.phys,
+            device_path: config_file.device_path,
             mappings,
-        })
+            evaluation_budget: config_file.evaluation_budget,
+            match_strategy: config_file.match_strategy.unwrap_or_default(),
+            dual_role_timeout_ms: config_file.dual_role_timeout_ms,
+            stuck_key_timeout_ms: config_file.stuck_key_timeout_ms,
+            virtual_devices: config_file.virtual_devices,
+            output_device_name: config_file.output_device_name,
+            output_id: config_file.output_id,
+            macro_delay_ms: config_file.macro_delay_ms,
+            output_values: config_file.output_values.unwrap_or_default(),
+            output_repeat: config_file.output_repeat,
+            reconnect_interval_ms: config_file.reconnect_interval_ms,
+            modifiers: match config_file.modifiers {
+                Some(keys) => Modifiers::new(keys.into_iter().map(Into::into)),
+                None => Modifiers::default(),
+            },
+            bypass: config_file
+                .bypass
+                .into_iter()
+                .flatten()
+                .map(Into::into)
+                .collect(),
+            panic: config_file
+                .panic
+                .into_iter()
+                .flatten()
+                .map(Into::into)
+                .collect(),
+            passthrough: config_file
+                .passthrough
+                .into_iter()
+                .flatten()
+                .map(Into::into)
+                .collect(),
+            forward_non_key: config_file.forward_non_key,
+            forward_unknown_values: config_file.forward_unknown_values,
+            grab: config_file.grab,
+            app_command: config_file.app_command,
+            recovery_hook: config_file
+                .recovery_hook
+                .map(Into::into)
+                .unwrap_or_default(),
+            extra_devices: config_file.extra_device,
+            devices: devices
+                .into_iter()
+                .map(|device| DeviceConfig {
+                    selector: device.selector,
+                    mappings: remaps(device.remap),
+                })
+                .collect(),
+            shared_state: config_file.shared_state,
+            log_file: config_file.log_file,
+            log_format: config_file.log_format,
+            rel_mappings: config_file.rel_remap.into_iter().map(Into::into).collect(),
+            key_rel_mappings: config_file
+                .key_to_rel
+                .into_iter()
+                .map(|key_to_rel| KeyRelMapping {
+                    input: key_to_rel.input.into(),
+                    output: key_to_rel.output.code,
+                    value: key_to_rel.value,
+                })
+                .collect(),
+        }
     }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Mapping {
-    // Arash note: I removed DualRole to simplify the code base.
+    /// Holding `input` emits `hold`, while a quick tap emits `tap`. How
+    /// other keys pressed meanwhile decide between the two is up to `mode`.
+    DualRole {
+        input: KeyCode,
+        hold: Vec<KeyCode>,
+        tap: Vec<KeyCode>,
+        mode: DualRoleMode,
+    },
     Remap {
         input: HashSet<KeyCode>,
         output: HashSet<KeyCode>,
+        when: Conditions,
+        /// Whether `output` is tapped when `input` is released instead of
+        /// being held along with it.
+        on_release: bool,
+    },
+    /// A set of tiers where each tier adds its `output` once its `input`
+    /// and the inputs of all earlier tiers are held.
+    Tiered { tiers: Vec<MappingTier> },
+    /// `input` does nothing at all; neither its press nor its release
+    /// are emitted.
+    Ignore { input: KeyCode },
+    /// Pressing `input` types the `KEY_XXX` name of the previously
+    /// pressed key; handy to discover key names live.
+    KeyNameProbe { input: KeyCode },
+    /// Pressing `input` flips whether `modifier` is held for the next
+    /// key press: it is added if not held, and removed if held.
+    InvertModifier { input: KeyCode, modifier: KeyCode },
+    /// Holding `input` activates `layer`, so that the remaps of that layer
+    /// apply.
+    Layer { input: KeyCode, layer: String },
+    /// Tapping `input` locks `layer` on until it is tapped again, turning
+    /// `led` on on the input devices while it is.
+    LayerLock {
+        input: KeyCode,
+        layer: String,
+        led: Option<LedCode>,
+    },
+    /// Tapping `input` holds `modifier` for just the next key press, like
+    /// sticky keys. With `lock_on_double_tap`, tapping it again before
+    /// then keeps `modifier` held until the next tap.
+    OneShot {
+        input: KeyCode,
+        modifier: KeyCode,
+        lock_on_double_tap: bool,
+    },
+    /// `input` does nothing when pressed, and is tapped when released,
+    /// unless one of the `cancel` keys was pressed while it was held.
+    DeferredPassthrough {
+        input: KeyCode,
+        cancel: HashSet<KeyCode>,
+    },
+    /// Pressing all of `input` within `window_ms` of the first of them
+    /// holds `output` instead, until one of them is released. Otherwise
+    /// the keys go through as they are. With an `order`, which lists the
+    /// keys of `input`, they must also be pressed in that order.
+    Combo {
+        input: HashSet<KeyCode>,
+        output: HashSet<KeyCode>,
+        window_ms: u64,
+        order: Option<Vec<KeyCode>>,
     },
+    /// Tapping `input` n times in a row, each tap within `timeout_ms` of
+    /// the previous one, taps the keys of `taps[n - 1]`. The taps resolve
+    /// once the timeout passes, another key is pressed, or there are as
+    /// many of them as entries in `taps`.
+    TapDance {
+        input: KeyCode,
+        taps: Vec<Vec<KeyCode>>,
+        timeout_ms: u64,
+    },
+    /// Pressing `input` plays `sequence` one step at a time. Keys the
+    /// sequence leaves pressed are released along with `input`.
+    Macro {
+        input: KeyCode,
+        sequence: Vec<MacroStep>,
+    },
+    /// Pressing `input` types `codepoint` by entering its hex code the way
+    /// `method` takes it, like a `Macro` of those keys.
+    Unicode {
+        input: KeyCode,
+        codepoint: char,
+        method: UnicodeMethod,
+    },
+    /// Pressing `input` types `text`, holding Shift for the characters
+    /// that take it on a US layout, like a `Macro` of those keys.
+    Text { input: KeyCode, text: String },
+}
+
+/// What makes a `Mapping::DualRole` key that another key is pressed
+/// along with a hold rather than a tap.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DualRoleMode {
+    /// Pressing another key while it is held.
+    #[default]
+    HoldPreferred,
+    /// Only holding it for longer than the timeout. Other keys pressed in
+    /// the meantime are held back until it is released or the time is up.
+    TapPreferred,
+    /// Like `TapPreferred`, but pressing and releasing another key while
+    /// it is held also makes it a hold, right away. Releasing it while the
+    /// other key is still held, as when rolling over, makes it a tap.
+    PermissiveHold,
+}
+
+/// How a `Mapping::Unicode` enters its codepoint.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnicodeMethod {
+    /// Ctrl+Shift+U, then the hex digits and Space, as IBus takes it.
+    #[default]
+    Ibus,
+    /// The hex digits while Ctrl+Shift stay held after the U, as GTK's own
+    /// input method takes it; releasing Ctrl+Shift commits the character.
+    Gtk,
+}
+
+/// Remaps the movement of the relative axis `input`. `invert` flips the
+/// direction of movement before anything else. Then each step in the
+/// positive direction taps `positive`, and each step in the negative
+/// direction taps `negative`; when those are empty the movement is passed
+/// on instead.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RelMapping {
+    pub input: RelCode,
+    pub invert: bool,
+    pub positive: Vec<KeyCode>,
+    pub negative: Vec<KeyCode>,
+}
+
+/// Pressing `input`, and each auto-repeat of it, moves the relative axis
+/// `output` by `value`. On a scroll wheel, a `value` of 1 is one detent.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct KeyRelMapping {
+    pub input: KeyCode,
+    pub output: RelCode,
+    pub value: i32,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MacroStep {
+    Press(KeyCode),
+    Release(KeyCode),
+}
+
+impl MacroStep {
+    pub fn key(&self) -> KeyCode {
+        match self {
+            MacroStep::Press(key) | MacroStep::Release(key) => *key,
+        }
+    }
+}
+
+/// Conditions, beyond its input keys being held, that must all hold
+/// for a mapping to apply.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Conditions {
+    /// Layers that must be active. Layers are also what modes and toggles
+    /// would be, so there are no conditions of their own for those: a
+    /// `Mapping::Layer` is active while its key is held, like a mode, and
+    /// a `Mapping::LayerLock` from one press to the next, like a toggle.
+    pub layers: Vec<String>,
+    pub leds: Vec<LedCode>,
+    /// How long no key may have been pressed before a key of the input,
+    /// so that it only applies at the start of a chord, like a leader key.
+    pub idle_ms: Option<u64>,
+    /// Keys that must not be held, such as Shift for a remap of the
+    /// unshifted key only.
+    pub unless: HashSet<KeyCode>,
+    /// Window classes of the applications it applies in, such as
+    /// `kitty`; it applies in any application if there are none.
+    pub apps: HashSet<String>,
+}
+
+impl Conditions {
+    fn satisfied_by(&self, input: &HashSet<KeyCode>, context: &MappingContext) -> bool {
+        let active = context.active;
+        self.layers.iter().all(|l| active.layers.contains(l))
+            && self.leds.iter().all(|led| active.leds.contains(led))
+            && self.idle_ms.is_none_or(|idle_ms| {
+                input.iter().any(|key| {
+                    active
+                        .idle_before
+                        .get(key)
+                        .is_some_and(|idle| *idle >= Duration::from_millis(idle_ms))
+                })
+            })
+            && self.unless.is_disjoint(context.held)
+            && (self.apps.is_empty()
+                || active
+                    .app
+                    .as_ref()
+                    .is_some_and(|app| self.apps.contains(app)))
+    }
+}
+
+/// The layers and LEDs that are currently active.
+#[derive(Debug, Clone, Default)]
+pub struct ActiveState {
+    pub layers: HashSet<String>,
+    pub leds: HashSet<LedCode>,
+    /// For each held key, how long no key had been pressed before it was.
+    pub idle_before: HashMap<KeyCode, Duration>,
+    /// The window class of the foreground application, if known.
+    pub app: Option<String>,
+}
+
+/// Everything a mapping may be conditioned on.
+#[derive(Debug, Clone, Copy)]
+pub struct MappingContext<'a> {
+    pub held: &'a HashSet<KeyCode>,
+    pub active: &'a ActiveState,
+}
+
+impl<'a> MappingContext<'a> {
+    pub fn new(held: &'a HashSet<KeyCode>, active: &'a ActiveState) -> Self {
+        MappingContext { held, active }
+    }
+
+    /// The same context, but with `held` as the held keys.
+    pub fn with_held<'b>(&self, held: &'b HashSet<KeyCode>) -> MappingContext<'b>
+    where
+        'a: 'b,
+    {
+        MappingContext {
+            held,
+            active: self.active,
+        }
+    }
+}
+
+impl Mapping {
+    /// Whether the inputs of this mapping are held and all of its
+    /// conditions are met in `context`.
+    pub fn matches(&self, context: &MappingContext) -> bool {
+        match self {
+            Mapping::Remap { input, when, .. } => {
+                input.is_subset(context.held) && when.satisfied_by(input, context)
+            }
+            Mapping::Tiered { tiers } => tiers
+                .first()
+                .is_some_and(|tier| tier.input.is_subset(context.held)),
+            Mapping::Combo { input, .. } => input.is_subset(context.held),
+            Mapping::DualRole { input, .. }
+            | Mapping::KeyNameProbe { input }
+            | Mapping::Ignore { input }
+            | Mapping::InvertModifier { input, .. }
+            | Mapping::OneShot { input, .. }
+            | Mapping::Layer { input, .. }
+            | Mapping::LayerLock { input, .. }
+            | Mapping::DeferredPassthrough { input, .. }
+            | Mapping::TapDance { input, .. }
+            | Mapping::Macro { input, .. }
+            | Mapping::Unicode { input, .. }
+            | Mapping::Text { input, .. } => context.held.contains(input),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MappingTier {
+    pub input: HashSet<KeyCode>,
+    pub output: HashSet<KeyCode>,
+}
+
+/// Every EV_KEY code along with its canonical `KEY_XXX` name,
+/// sorted by name.
+pub fn all_key_names() -> Vec<(KeyCode, String)> {
+    let mut keys: Vec<(KeyCode, String)> = EventCode::EV_KEY(KeyCode::KEY_RESERVED)
+        .iter()
+        .filter_map(|code| match code {
+            EventCode::EV_KEY(key) => Some((key, format!("{}", code))),
+            _ => None,
+        })
+        .collect();
+    keys.sort_by(|a, b| a.1.cmp(&b.1));
+    keys
+}
+
+/// The canonical `KEY_XXX` name of `key`.
+pub fn key_name(key: KeyCode) -> String {
+    format!("{}", EventCode::EV_KEY(key))
+}
+
+/// Short names that may be used in place of `KEY_XXX` names.
+pub const BUILTIN_ALIASES: &[(&str, &str)] = &[
+    ("ctrl", "KEY_LEFTCTRL"),
+    ("lctrl", "KEY_LEFTCTRL"),
+    ("rctrl", "KEY_RIGHTCTRL"),
+    ("shift", "KEY_LEFTSHIFT"),
+    ("lshift", "KEY_LEFTSHIFT"),
+    ("rshift", "KEY_RIGHTSHIFT"),
+    ("alt", "KEY_LEFTALT"),
+    ("lalt", "KEY_LEFTALT"),
+    ("ralt", "KEY_RIGHTALT"),
+    ("altgr", "KEY_RIGHTALT"),
+    ("meta", "KEY_LEFTMETA"),
+    ("super", "KEY_LEFTMETA"),
+    ("cmd", "KEY_LEFTMETA"),
+    ("win", "KEY_LEFTMETA"),
+    ("rmeta", "KEY_RIGHTMETA"),
+    ("fn", "KEY_FN"),
+    ("esc", "KEY_ESC"),
+    ("enter", "KEY_ENTER"),
+    ("return", "KEY_ENTER"),
+    ("tab", "KEY_TAB"),
+    ("space", "KEY_SPACE"),
+    ("backspace", "KEY_BACKSPACE"),
+    ("del", "KEY_DELETE"),
+    ("delete", "KEY_DELETE"),
+    ("ins", "KEY_INSERT"),
+    ("insert", "KEY_INSERT"),
+    ("caps", "KEY_CAPSLOCK"),
+    ("capslock", "KEY_CAPSLOCK"),
+    ("up", "KEY_UP"),
+    ("down", "KEY_DOWN"),
+    ("left", "KEY_LEFT"),
+    ("right", "KEY_RIGHT"),
+    ("home", "KEY_HOME"),
+    ("end", "KEY_END"),
+    ("pgup", "KEY_PAGEUP"),
+    ("pageup", "KEY_PAGEUP"),
+    ("pgdn", "KEY_PAGEDOWN"),
+    ("pagedown", "KEY_PAGEDOWN"),
+];
+
+thread_local! {
+    /// The `[aliases]` of the config file being parsed, which take
+    /// precedence over `BUILTIN_ALIASES`.
+    static CONFIG_ALIASES: RefCell<HashMap<String, String>> = RefCell::new(HashMap::new());
+}
+
+/// Run `parse` with `aliases` resolved by `KeyCodeWrapper`.
+fn with_aliases<T>(aliases: HashMap<String, String>, parse: impl FnOnce() -> T) -> T {
+    let previous = CONFIG_ALIASES.replace(aliases);
+    let result = parse();
+    CONFIG_ALIASES.set(previous);
+    result
+}
+
+/// The parts of a config file that are read ahead of the rest: the
+/// `include`d files, and the `[aliases]` needed to parse the keys.
+#[derive(Debug, Deserialize)]
+struct Preamble {
+    #[serde(default)]
+    include: Vec<PathBuf>,
+    #[serde(default)]
+    aliases: HashMap<String, String>,
+}
+
+/// Parse a `KEY_XXX` name, or an alias of one, the same way the config
+/// file does.
+pub fn parse_key(name: &str) -> Result<KeyCode, ConfigError> {
+    KeyCodeWrapper::try_from(name.to_string()).map(Into::into)
 }
 
 #[derive(Debug, Deserialize)]
@@ -46,9 +914,9 @@ struct KeyCodeWrapper {
     pub code: KeyCode,
 }
 
-impl Into<KeyCode> for KeyCodeWrapper {
-    fn into(self) -> KeyCode {
-        self.code
+impl From<KeyCodeWrapper> for KeyCode {
+    fn from(wrapper: KeyCodeWrapper) -> KeyCode {
+        wrapper.code
     }
 }
 
@@ -58,17 +926,158 @@ pub enum ConfigError {
     InvalidKey(String),
     #[error("Impossible: parsed KEY_XXX but not into an EV_KEY")]
     ImpossibleParseKey,
+    #[error("Invalid relative axis `{0}`, such as REL_WHEEL or REL_HWHEEL.")]
+    InvalidRel(String),
+    #[error("Invalid LED `{0}`, such as LED_NUML or LED_CAPSL.")]
+    InvalidLed(String),
+    #[error("Include cycle: {0}")]
+    IncludeCycle(String),
+    #[error("No config file was given, and there is none at {0}")]
+    NoConfigFound(String),
 }
 
+/// Written as `@sep` in a config, this key is not held but pulsed to
+/// delimit groups in a sequence of output keys. KEY_RESERVED produces no
+/// character, and the pulse is followed by its own SYN_REPORT.
+pub const SEPARATOR_KEY: KeyCode = KeyCode::KEY_RESERVED;
+
 impl std::convert::TryFrom<String> for KeyCodeWrapper {
     type Error = ConfigError;
     fn try_from(s: String) -> Result<KeyCodeWrapper, Self::Error> {
-        match EventCode::from_str(&EventType::EV_KEY, &s) {
+        if s == "@sep" {
+            return Ok(KeyCodeWrapper {
+                code: SEPARATOR_KEY,
+            });
+        }
+        let name = CONFIG_ALIASES
+            .with(|aliases| aliases.borrow().get(&s).cloned())
+            .unwrap_or(s);
+        let name = BUILTIN_ALIASES
+            .iter()
+            .find(|(alias, _)| *alias == name)
+            .map_or(name.as_str(), |(_, key)| key);
+        if let Some(number) = raw_key_code(name) {
+            return number
+                .and_then(evdev_rs::enums::int_to_ev_key)
+                .map(|code| KeyCodeWrapper { code })
+                .ok_or_else(|| ConfigError::InvalidKey(name.to_string()));
+        }
+        match event_code(&EventType::EV_KEY, name) {
             Some(code) => match code {
                 EventCode::EV_KEY(code) => Ok(KeyCodeWrapper { code }),
                 _ => Err(ConfigError::ImpossibleParseKey),
             },
-            None => Err(ConfigError::InvalidKey(s)),
+            None => Err(ConfigError::InvalidKey(name.to_string())),
+        }
+    }
+}
+
+/// The code of type `ev_type` called `name`. evdev-rs hands `name` to
+/// libevdev as a C string, and panics if it holds a NUL, which no code's
+/// name does.
+fn event_code(ev_type: &EventType, name: &str) -> Option<EventCode> {
+    if name.contains('\0') {
+        return None;
+    }
+    EventCode::from_str(ev_type, name)
+}
+
+/// The number of a key given by its raw code, as `code:183` or `0xb7`, for
+/// keys without a `KEY_XXX` name; `None` if `name` isn't of that form, and
+/// `Some(None)` if it is but the number doesn't parse.
+fn raw_key_code(name: &str) -> Option<Option<u32>> {
+    if let Some(hex) = name.strip_prefix("0x") {
+        Some(u32::from_str_radix(hex, 16).ok())
+    } else {
+        name.strip_prefix("code:")
+            .map(|decimal| decimal.parse().ok())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "String")]
+struct RelCodeWrapper {
+    pub code: RelCode,
+}
+
+impl std::convert::TryFrom<String> for RelCodeWrapper {
+    type Error = ConfigError;
+    fn try_from(s: String) -> Result<RelCodeWrapper, Self::Error> {
+        match event_code(&EventType::EV_REL, &s) {
+            Some(EventCode::EV_REL(code)) => Ok(RelCodeWrapper { code }),
+            _ => Err(ConfigError::InvalidRel(s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+struct LedCodeWrapper {
+    pub code: LedCode,
+}
+
+impl std::convert::TryFrom<String> for LedCodeWrapper {
+    type Error = ConfigError;
+    fn try_from(s: String) -> Result<LedCodeWrapper, Self::Error> {
+        match event_code(&EventType::EV_LED, &s) {
+            Some(EventCode::EV_LED(code)) => Ok(LedCodeWrapper { code }),
+            _ => Err(ConfigError::InvalidLed(s)),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RelRemapConfig {
+    input: RelCodeWrapper,
+    #[serde(default)]
+    invert: bool,
+    #[serde(default)]
+    positive: Vec<KeyCodeWrapper>,
+    #[serde(default)]
+    negative: Vec<KeyCodeWrapper>,
+}
+
+#[derive(Debug, Deserialize)]
+struct KeyToRelConfig {
+    input: KeyCodeWrapper,
+    output: RelCodeWrapper,
+    #[serde(default = "KeyToRelConfig::default_value")]
+    value: i32,
+}
+
+impl KeyToRelConfig {
+    fn default_value() -> i32 {
+        1
+    }
+}
+
+impl From<RelRemapConfig> for RelMapping {
+    fn from(config: RelRemapConfig) -> RelMapping {
+        RelMapping {
+            input: config.input.code,
+            invert: config.invert,
+            positive: config.positive.into_iter().map(Into::into).collect(),
+            negative: config.negative.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DualRoleConfig {
+    input: KeyCodeWrapper,
+    hold: Vec<KeyCodeWrapper>,
+    tap: Vec<KeyCodeWrapper>,
+    #[serde(default)]
+    mode: DualRoleMode,
+}
+
+impl From<DualRoleConfig> for Mapping {
+    fn from(dual_role: DualRoleConfig) -> Mapping {
+        Mapping::DualRole {
+            input: dual_role.input.into(),
+            hold: dual_role.hold.into_iter().map(Into::into).collect(),
+            tap: dual_role.tap.into_iter().map(Into::into).collect(),
+            mode: dual_role.mode,
         }
     }
 }
@@ -77,18 +1086,239 @@ impl std::convert::TryFrom<String> for KeyCodeWrapper {
 struct RemapConfig {
     input: Vec<KeyCodeWrapper>,
     output: Vec<KeyCodeWrapper>,
+    #[serde(default)]
+    layer: Option<String>,
+    /// LEDs that must be on, such as `LED_NUML`, for the remap to apply.
+    #[serde(default)]
+    when_led: Vec<LedCodeWrapper>,
+    /// Only apply when no key was pressed for this long before the input,
+    /// as for a leader key.
+    #[serde(default)]
+    idle_ms: Option<u64>,
+    /// Keys that must not be held for the remap to apply.
+    #[serde(default)]
+    unless: Vec<KeyCodeWrapper>,
+    /// Window classes of the applications the remap applies in.
+    #[serde(default)]
+    app: Vec<String>,
+    /// Tap the output when the input is released, rather than holding it
+    /// while the input is held.
+    #[serde(default)]
+    on_release: bool,
 }
 
-impl Into<Mapping> for RemapConfig {
-    fn into(self) -> Mapping {
+impl From<RemapConfig> for Mapping {
+    fn from(remap: RemapConfig) -> Mapping {
         Mapping::Remap {
-            input: self.input.into_iter().map(Into::into).collect(),
-            output: self.output.into_iter().map(Into::into).collect(),
+            input: remap.input.into_iter().map(Into::into).collect(),
+            output: remap.output.into_iter().map(Into::into).collect(),
+            when: Conditions {
+                layers: remap.layer.into_iter().collect(),
+                leds: remap.when_led.into_iter().map(|led| led.code).collect(),
+                idle_ms: remap.idle_ms,
+                unless: remap.unless.into_iter().map(Into::into).collect(),
+                apps: remap.app.into_iter().collect(),
+            },
+            on_release: remap.on_release,
+        }
+    }
+}
+
+/// The mappings of `remaps`, with those of a layer or conditioned on LEDs,
+/// idle time, keys not being held or the application first so that they
+/// take precedence over the unconditional ones for the same keys.
+fn remaps(remaps: Vec<RemapConfig>) -> Vec<Mapping> {
+    let (conditional, base): (Vec<_>, Vec<_>) = remaps.into_iter().partition(|remap| {
+        remap.layer.is_some()
+            || !remap.when_led.is_empty()
+            || remap.idle_ms.is_some()
+            || !remap.unless.is_empty()
+            || !remap.app.is_empty()
+    });
+    conditional
+        .into_iter()
+        .chain(base)
+        .map(Into::into)
+        .collect()
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceBlockConfig {
+    #[serde(flatten)]
+    selector: DeviceSelector,
+    #[serde(default)]
+    remap: Vec<RemapConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LayerConfig {
+    input: KeyCodeWrapper,
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct LayerLockConfig {
+    input: KeyCodeWrapper,
+    name: String,
+    #[serde(default)]
+    led: Option<LedCodeWrapper>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TierConfig {
+    input: Vec<KeyCodeWrapper>,
+    #[serde(default)]
+    output: Vec<KeyCodeWrapper>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TieredConfig {
+    tiers: Vec<TierConfig>,
+}
+
+impl From<TieredConfig> for Mapping {
+    fn from(tiered: TieredConfig) -> Mapping {
+        Mapping::Tiered {
+            tiers: tiered
+                .tiers
+                .into_iter()
+                .map(|tier| MappingTier {
+                    input: tier.input.into_iter().map(Into::into).collect(),
+                    output: tier.output.into_iter().map(Into::into).collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct IgnoreConfig {
+    input: KeyCodeWrapper,
+}
+
+#[derive(Debug, Deserialize)]
+struct KeyNameProbeConfig {
+    input: KeyCodeWrapper,
+}
+
+#[derive(Debug, Deserialize)]
+struct InvertModifierConfig {
+    input: KeyCodeWrapper,
+    modifier: KeyCodeWrapper,
+}
+
+#[derive(Debug, Deserialize)]
+struct OneShotConfig {
+    input: KeyCodeWrapper,
+    modifier: KeyCodeWrapper,
+    #[serde(default)]
+    lock_on_double_tap: bool,
+}
+
+/// A step of a macro sequence: `+KEY_X` presses, `-KEY_X` releases, and
+/// a plain `KEY_X` presses and then releases the key.
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "String")]
+struct MacroStepsWrapper {
+    steps: Vec<MacroStep>,
+}
+
+impl std::convert::TryFrom<String> for MacroStepsWrapper {
+    type Error = ConfigError;
+    fn try_from(s: String) -> Result<MacroStepsWrapper, Self::Error> {
+        let steps = if let Some(name) = s.strip_prefix('+') {
+            vec![MacroStep::Press(parse_key(name)?)]
+        } else if let Some(name) = s.strip_prefix('-') {
+            vec![MacroStep::Release(parse_key(name)?)]
+        } else {
+            let key = parse_key(&s)?;
+            vec![MacroStep::Press(key), MacroStep::Release(key)]
+        };
+        Ok(MacroStepsWrapper { steps })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct MacroConfig {
+    input: KeyCodeWrapper,
+    sequence: Vec<MacroStepsWrapper>,
+}
+
+impl From<MacroConfig> for Mapping {
+    fn from(config: MacroConfig) -> Mapping {
+        Mapping::Macro {
+            input: config.input.into(),
+            sequence: config.sequence.into_iter().flat_map(|w| w.steps).collect(),
         }
     }
 }
 
 #[derive(Debug, Deserialize)]
+struct UnicodeConfig {
+    input: KeyCodeWrapper,
+    codepoint: char,
+    #[serde(default)]
+    method: UnicodeMethod,
+}
+
+#[derive(Debug, Deserialize)]
+struct TextConfig {
+    input: KeyCodeWrapper,
+    text: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RecoveryHookConfig {
+    #[serde(default)]
+    key: Option<KeyCodeWrapper>,
+    #[serde(default)]
+    status_file: Option<PathBuf>,
+}
+
+impl From<RecoveryHookConfig> for RecoveryHook {
+    fn from(config: RecoveryHookConfig) -> Self {
+        RecoveryHook {
+            key: config.key.map(Into::into),
+            status_file: config.status_file,
+        }
+    }
+}
+
+/// How soon after the first key of a `Mapping::Combo` the others must be
+/// pressed, unless configured otherwise.
+pub const DEFAULT_COMBO_WINDOW_MS: u64 = 50;
+
+#[derive(Debug, Deserialize)]
+struct ComboConfig {
+    input: Vec<KeyCodeWrapper>,
+    output: Vec<KeyCodeWrapper>,
+    #[serde(default)]
+    window_ms: Option<u64>,
+    /// Only fire when the keys are pressed in the order of `input`.
+    #[serde(default)]
+    in_order: bool,
+}
+
+/// How soon after a tap of a `Mapping::TapDance` the next one must
+/// follow, unless configured otherwise.
+pub const DEFAULT_TAP_DANCE_TIMEOUT_MS: u64 = 200;
+
+#[derive(Debug, Deserialize)]
+struct TapDanceConfig {
+    input: KeyCodeWrapper,
+    taps: Vec<Vec<KeyCodeWrapper>>,
+    #[serde(default)]
+    timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeferredPassthroughConfig {
+    input: KeyCodeWrapper,
+    #[serde(default)]
+    cancel: Vec<KeyCodeWrapper>,
+}
+
+#[derive(Debug, Default, Deserialize)]
 struct ConfigFile {
     #[serde(default)]
     device_name: Option<String>,
@@ -96,6 +1326,863 @@ struct ConfigFile {
     #[serde(default)]
     phys: Option<String>,
 
+    #[serde(default)]
+    device_path: Option<PathBuf>,
+
+    #[serde(default)]
+    dual_role: Vec<DualRoleConfig>,
+
     #[serde(default)]
     remap: Vec<RemapConfig>,
+
+    #[serde(default)]
+    tiered: Vec<TieredConfig>,
+
+    #[serde(default)]
+    key_name_probe: Vec<KeyNameProbeConfig>,
+
+    #[serde(default)]
+    ignore: Vec<IgnoreConfig>,
+
+    #[serde(default)]
+    invert_modifier: Vec<InvertModifierConfig>,
+
+    #[serde(default)]
+    one_shot: Vec<OneShotConfig>,
+
+    #[serde(default)]
+    layer: Vec<LayerConfig>,
+
+    #[serde(default)]
+    layer_lock: Vec<LayerLockConfig>,
+
+    #[serde(default)]
+    combo: Vec<ComboConfig>,
+
+    #[serde(default)]
+    tap_dance: Vec<TapDanceConfig>,
+
+    #[serde(default)]
+    deferred_passthrough: Vec<DeferredPassthroughConfig>,
+
+    #[serde(default, rename = "macro")]
+    macros: Vec<MacroConfig>,
+
+    #[serde(default)]
+    unicode: Vec<UnicodeConfig>,
+
+    #[serde(default)]
+    text: Vec<TextConfig>,
+
+    #[serde(default)]
+    macro_delay_ms: Option<u64>,
+
+    #[serde(default)]
+    output_values: Option<OutputValues>,
+
+    #[serde(default)]
+    output_repeat: Option<OutputRepeat>,
+
+    #[serde(default)]
+    reconnect_interval_ms: Option<u64>,
+
+    #[serde(default)]
+    modifiers: Option<Vec<KeyCodeWrapper>>,
+
+    #[serde(default)]
+    bypass: Option<Vec<KeyCodeWrapper>>,
+
+    #[serde(default)]
+    panic: Option<Vec<KeyCodeWrapper>>,
+
+    #[serde(default)]
+    passthrough: Option<Vec<KeyCodeWrapper>>,
+
+    #[serde(default)]
+    forward_non_key: Option<bool>,
+
+    #[serde(default)]
+    forward_unknown_values: Option<bool>,
+
+    #[serde(default)]
+    grab: Option<bool>,
+
+    #[serde(default)]
+    app_command: Option<Vec<String>>,
+
+    #[serde(default)]
+    recovery_hook: Option<RecoveryHookConfig>,
+
+    #[serde(default)]
+    extra_device: Vec<DeviceSelector>,
+
+    #[serde(default)]
+    device: Vec<DeviceBlockConfig>,
+
+    #[serde(default)]
+    shared_state: Option<bool>,
+
+    #[serde(default)]
+    log_file: Option<LogFile>,
+
+    #[serde(default)]
+    log_format: Option<LogFormat>,
+
+    #[serde(default)]
+    rel_remap: Vec<RelRemapConfig>,
+
+    #[serde(default)]
+    key_to_rel: Vec<KeyToRelConfig>,
+
+    #[serde(default)]
+    evaluation_budget: Option<usize>,
+
+    #[serde(default)]
+    match_strategy: Option<MatchStrategy>,
+
+    #[serde(default)]
+    dual_role_timeout_ms: Option<u64>,
+
+    #[serde(default)]
+    stuck_key_timeout_ms: Option<u64>,
+
+    #[serde(default)]
+    virtual_devices: Option<usize>,
+
+    #[serde(default)]
+    output_device_name: Option<String>,
+
+    #[serde(default)]
+    output_id: Option<OutputId>,
+}
+
+/// Fields whose values are keys, or lists of keys, or in some tables the
+/// codes of the `REL_FIELDS` and `LED_FIELDS` instead.
+const KEY_FIELDS: &[&str] = &[
+    "input",
+    "output",
+    "hold",
+    "tap",
+    "taps",
+    "modifier",
+    "cancel",
+    "modifiers",
+    "bypass",
+    "panic",
+    "passthrough",
+    "key",
+    "positive",
+    "negative",
+    "unless",
+    "when_led",
+    "led",
+];
+
+/// The key fields that, in the tables named before the dot, hold `REL_*`
+/// codes rather than keys.
+const REL_FIELDS: &[&str] = &["rel_remap.input", "key_to_rel.output"];
+
+/// The key fields that hold `LED_*` codes rather than keys, in any table.
+const LED_FIELDS: &[&str] = &["when_led", "led"];
+
+/// Parse `toml_data`, read from `path`, with `aliases` for its keys.
+fn parse_toml<T: DeserializeOwned>(
+    path: &Path,
+    toml_data: &str,
+    aliases: &HashMap<String, String>,
+) -> anyhow::Result<T> {
+    with_aliases(aliases.clone(), || toml::from_str(toml_data)).map_err(|err| {
+        anyhow::Error::new(err.clone()).context(parse_error_context(path, toml_data, &err, aliases))
+    })
+}
+
+/// Describe where parsing `toml_data` from `path` failed with `err`: the
+/// line and column, and for an invalid key the field it is in, such as
+/// `remap[2].output`.
+fn parse_error_context(
+    path: &Path,
+    toml_data: &str,
+    err: &toml::de::Error,
+    aliases: &HashMap<String, String>,
+) -> String {
+    let mut context = format!("parsing toml from {}", path.display());
+    if let Some(span) = err.span() {
+        let before = &toml_data[..span.start.min(toml_data.len())];
+        let line = before.matches('\n').count() + 1;
+        let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+        context.push_str(&format!(" at line {}, column {}", line, column));
+    }
+    if let Ok(value) = toml_data.parse::<toml::Table>() {
+        let field = with_aliases(aliases.clone(), || find_invalid_key(&value, ""));
+        if let Some(field) = field {
+            context.push_str(&format!(" in `{}`", field));
+        }
+    }
+    context
+}
+
+/// The path of the first key field in `table` that holds an invalid key.
+fn find_invalid_key(table: &toml::Table, prefix: &str) -> Option<String> {
+    for (name, value) in table {
+        let path = format!("{}{}", prefix, name);
+        let found = match value {
+            toml::Value::Table(table) => find_invalid_key(table, &format!("{}.", path)),
+            toml::Value::Array(items) if !KEY_FIELDS.contains(&name.as_str()) => {
+                items.iter().enumerate().find_map(|(idx, item)| match item {
+                    toml::Value::Table(table) => {
+                        find_invalid_key(table, &format!("{}[{}].", path, idx))
+                    }
+                    _ => None,
+                })
+            }
+            toml::Value::Array(items) => items
+                .iter()
+                .flat_map(|item| match item {
+                    // The taps of a tap dance are lists of keys themselves.
+                    toml::Value::Array(keys) => keys.iter().collect(),
+                    item => vec![item],
+                })
+                .any(|item| matches!(item, toml::Value::String(key) if is_invalid_code(&path, key)))
+                .then_some(path),
+            toml::Value::String(key) if KEY_FIELDS.contains(&name.as_str()) => {
+                is_invalid_code(&path, key).then_some(path)
+            }
+            _ => None,
+        };
+        if found.is_some() {
+            return found;
+        }
+    }
+    None
+}
+
+/// Whether `code`, in the key field at `path`, is invalid: a `REL_*` code
+/// in the `REL_FIELDS`, an `LED_*` code in the `LED_FIELDS`, and a key
+/// everywhere else.
+fn is_invalid_code(path: &str, code: &str) -> bool {
+    let field = path
+        .split('.')
+        .map(|part| part.split('[').next().unwrap_or(part))
+        .collect::<Vec<_>>()
+        .join(".");
+    let name = field.rsplit('.').next().unwrap_or(&field);
+    if REL_FIELDS.contains(&field.as_str()) {
+        RelCodeWrapper::try_from(code.to_string()).is_err()
+    } else if LED_FIELDS.contains(&name) {
+        LedCodeWrapper::try_from(code.to_string()).is_err()
+    } else {
+        parse_key(code).is_err()
+    }
+}
+
+impl ConfigFile {
+    /// Load the config file at `path` on top of the files it includes,
+    /// which are relative to its directory, returning it along with the
+    /// aliases it and the files it includes define. `including` holds the
+    /// files that include this one, to detect cycles.
+    fn load(
+        path: &Path,
+        including: &mut Vec<PathBuf>,
+    ) -> anyhow::Result<(ConfigFile, HashMap<String, String>)> {
+        let canonical = path
+            .canonicalize()
+            .context(format!("reading toml from {}", path.display()))?;
+        if including.contains(&canonical) {
+            let chain: Vec<String> = including
+                .iter()
+                .chain([&canonical])
+                .map(|path| path.display().to_string())
+                .collect();
+            return Err(ConfigError::IncludeCycle(chain.join(" -> ")).into());
+        }
+        let toml_data = std::fs::read_to_string(path)
+            .context(format!("reading toml from {}", path.display()))?;
+        let preamble: Preamble = parse_toml(path, &toml_data, &HashMap::new())?;
+
+        including.push(canonical);
+        let mut config_file = ConfigFile::default();
+        let mut aliases = HashMap::new();
+        let dir = path.parent().unwrap_or(Path::new("."));
+        for include in &preamble.include {
+            let (included, included_aliases) = ConfigFile::load(&dir.join(include), including)
+                .context(format!("included by {}", path.display()))?;
+            config_file = config_file.merge(included);
+            aliases.extend(included_aliases);
+        }
+        including.pop();
+
+        for (alias, key) in &preamble.aliases {
+            parse_key(key).context(format!("alias `{}` in {}", alias, path.display()))?;
+        }
+        aliases.extend(preamble.aliases);
+        let own = parse_toml(path, &toml_data, &aliases)?;
+        Ok((config_file.merge(own), aliases))
+    }
+
+    /// This config with `later` loaded on top of it: the settings of
+    /// `later` win, and its mappings are added after these, except that
+    /// its remaps replace those with the same input and layer.
+    fn merge(mut self, later: ConfigFile) -> ConfigFile {
+        let key_set = |keys: &[KeyCodeWrapper]| -> HashSet<KeyCode> {
+            keys.iter().map(|key| key.code).collect()
+        };
+        self.remap.retain(|remap| {
+            !later.remap.iter().any(|other| {
+                key_set(&other.input) == key_set(&remap.input)
+                    && other.layer == remap.layer
+                    && other.when_led == remap.when_led
+                    && other.idle_ms == remap.idle_ms
+                    && key_set(&other.unless) == key_set(&remap.unless)
+                    && other.app == remap.app
+            })
+        });
+        self.remap.extend(later.remap);
+        self.dual_role.extend(later.dual_role);
+        self.tiered.extend(later.tiered);
+        self.key_name_probe.extend(later.key_name_probe);
+        self.ignore.extend(later.ignore);
+        self.invert_modifier.extend(later.invert_modifier);
+        self.one_shot.extend(later.one_shot);
+        self.layer.extend(later.layer);
+        self.layer_lock.extend(later.layer_lock);
+        self.combo.extend(later.combo);
+        self.tap_dance.extend(later.tap_dance);
+        self.deferred_passthrough.extend(later.deferred_passthrough);
+        self.macros.extend(later.macros);
+        self.unicode.extend(later.unicode);
+        self.text.extend(later.text);
+        self.extra_device.extend(later.extra_device);
+        self.device.extend(later.device);
+        self.rel_remap.extend(later.rel_remap);
+        self.key_to_rel.extend(later.key_to_rel);
+        ConfigFile {
+            device_name: later.device_name.or(self.device_name),
+            phys: later.phys.or(self.phys),
+            device_path: later.device_path.or(self.device_path),
+            macro_delay_ms: later.macro_delay_ms.or(self.macro_delay_ms),
+            output_values: later.output_values.or(self.output_values),
+            reconnect_interval_ms: later.reconnect_interval_ms.or(self.reconnect_interval_ms),
+            modifiers: later.modifiers.or(self.modifiers),
+            bypass: later.bypass.or(self.bypass),
+            panic: later.panic.or(self.panic),
+            passthrough: later.passthrough.or(self.passthrough),
+            forward_non_key: later.forward_non_key.or(self.forward_non_key),
+            forward_unknown_values: later.forward_unknown_values.or(self.forward_unknown_values),
+            grab: later.grab.or(self.grab),
+            app_command: later.app_command.or(self.app_command),
+            recovery_hook: later.recovery_hook.or(self.recovery_hook),
+            shared_state: later.shared_state.or(self.shared_state),
+            log_file: later.log_file.or(self.log_file),
+            log_format: later.log_format.or(self.log_format),
+            output_repeat: later.output_repeat.or(self.output_repeat),
+            evaluation_budget: later.evaluation_budget.or(self.evaluation_budget),
+            match_strategy: later.match_strategy.or(self.match_strategy),
+            dual_role_timeout_ms: later.dual_role_timeout_ms.or(self.dual_role_timeout_ms),
+            stuck_key_timeout_ms: later.stuck_key_timeout_ms.or(self.stuck_key_timeout_ms),
+            virtual_devices: later.virtual_devices.or(self.virtual_devices),
+            output_device_name: later.output_device_name.or(self.output_device_name),
+            output_id: later.output_id.or(self.output_id),
+            ..self
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A directory in the temp dir for config files that include one
+    /// another, removed when dropped, also when an assert failed.
+    struct ConfigDir(PathBuf);
+
+    impl ConfigDir {
+        fn new() -> Self {
+            static COUNT: AtomicUsize = AtomicUsize::new(0);
+            let dir = std::env::temp_dir().join(format!(
+                "evremap-test-{}-{}",
+                std::process::id(),
+                COUNT.fetch_add(1, Ordering::Relaxed)
+            ));
+            std::fs::create_dir_all(&dir).unwrap();
+            ConfigDir(dir)
+        }
+
+        fn path(&self, name: &str) -> PathBuf {
+            self.0.join(name)
+        }
+
+        fn write(&self, name: &str, toml_data: &str) {
+            std::fs::write(self.path(name), toml_data).unwrap();
+        }
+    }
+
+    impl Drop for ConfigDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn all_key_names_round_trip_through_parse_key() {
+        let names = all_key_names();
+        assert!(names.iter().any(|(_, name)| name == "KEY_KPENTER"));
+        assert!(names.iter().any(|(_, name)| name == "KEY_102ND"));
+        for (key, name) in names {
+            assert_eq!(
+                parse_key(&name).unwrap(),
+                key,
+                "{} did not round-trip",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn config_is_looked_for_in_xdg_config_home_then_etc() {
+        assert_eq!(
+            config_locations(Some("/xdg".into()), Some("/home/me".into())),
+            [
+                PathBuf::from("/xdg/evremap/config.toml"),
+                PathBuf::from("/etc/evremap.toml")
+            ]
+        );
+        assert_eq!(
+            config_locations(Some("relative".into()), Some("/home/me".into())),
+            [
+                PathBuf::from("/home/me/.config/evremap/config.toml"),
+                PathBuf::from("/etc/evremap.toml")
+            ]
+        );
+        assert_eq!(
+            config_locations(None, None),
+            [PathBuf::from("/etc/evremap.toml")]
+        );
+    }
+
+    #[test]
+    fn malformed_configs_are_errors_rather_than_panics() {
+        assert!(matches!(
+            parse_key("KEY_A\0"),
+            Err(ConfigError::InvalidKey(_))
+        ));
+        let huge = "KEY_".repeat(100_000);
+        assert!(matches!(parse_key(&huge), Err(ConfigError::InvalidKey(_))));
+        for toml in [
+            "remap = 1",
+            "[[remap]]\ninput = [\"\"]\noutput = [\"KEY_B\"]",
+            "[[remap]]\ninput = [\"KEY_A\\u0000\"]\noutput = [\"KEY_B\"]",
+            "[[rel_remap]]\ninput = \"REL_WHEEL\\u0000\"\n",
+            "[aliases]\nx = \"\"\n",
+            "[[remap]]\ninput = [\"KEY_A\"]\noutput = [\"KEY_B\"]\n\u{e9}\u{e9}",
+        ] {
+            assert!(MappingConfig::from_toml(toml).is_err(), "{:?} parsed", toml);
+        }
+        let config =
+            MappingConfig::from_toml("[[remap]]\ninput = [\"KEY_A\"]\noutput = [\"KEY_B\"]\n")
+                .unwrap();
+        assert_eq!(config.mappings.len(), 1);
+    }
+
+    #[test]
+    fn keys_can_be_given_by_raw_code() {
+        assert_eq!(parse_key("code:183").unwrap(), KeyCode::KEY_F13);
+        assert_eq!(parse_key("0x71").unwrap(), KeyCode::KEY_MUTE);
+        for invalid in ["code:99999", "code:", "0xzz", "code:-1"] {
+            assert!(
+                matches!(parse_key(invalid), Err(ConfigError::InvalidKey(_))),
+                "{} was accepted",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn main_device_comes_before_extra_devices() {
+        let config = MappingConfig {
+            device_name: Some("Left half".to_string()),
+            extra_devices: vec![DeviceSelector {
+                device_path: Some(PathBuf::from("/dev/input/event7")),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let selectors = config.device_selectors();
+        assert_eq!(selectors.len(), 2);
+        assert_eq!(selectors[0].device_name.as_deref(), Some("Left half"));
+        assert_eq!(
+            selectors[1].device_path.as_deref(),
+            Some(Path::new("/dev/input/event7"))
+        );
+    }
+
+    #[test]
+    fn device_blocks_have_remaps_of_their_own() {
+        let config = MappingConfig::from_toml(
+            r#"
+                [[device]]
+                device_name = "AT Translated Set 2 keyboard"
+
+                [[device.remap]]
+                input = ["KEY_CAPSLOCK"]
+                output = ["KEY_LEFTCTRL"]
+
+                [[device]]
+                device_name = "External keyboard"
+                phys = "usb-0000:00:14.0-2/input0"
+            "#,
+        )
+        .unwrap();
+        assert!(!config.has_main_device());
+        let names: Vec<_> = config
+            .device_selectors()
+            .into_iter()
+            .map(|selector| selector.device_name.unwrap())
+            .collect();
+        assert_eq!(names, ["AT Translated Set 2 keyboard", "External keyboard"]);
+        assert_eq!(config.devices[0].mappings.len(), 1);
+        assert!(config.devices[1].mappings.is_empty());
+        assert_eq!(
+            config.devices[1].selector.phys.as_deref(),
+            Some("usb-0000:00:14.0-2/input0")
+        );
+    }
+
+    #[test]
+    fn default_device_block_remaps_apply_to_every_device() {
+        let config = MappingConfig::from_toml(
+            r#"
+                device_name = "Laptop keyboard"
+
+                [[remap]]
+                input = ["KEY_CAPSLOCK"]
+                output = ["KEY_ESC"]
+
+                [[device]]
+
+                [[device.remap]]
+                input = ["KEY_CAPSLOCK"]
+                output = ["KEY_LEFTCTRL"]
+
+                [[device]]
+                device_name = "External keyboard"
+
+                [[device.remap]]
+                input = ["KEY_CAPSLOCK"]
+                output = ["KEY_LEFTMETA"]
+            "#,
+        )
+        .unwrap();
+        let remap = |output| Mapping::Remap {
+            input: HashSet::from([KeyCode::KEY_CAPSLOCK]),
+            output: HashSet::from([output]),
+            when: Conditions::default(),
+            on_release: false,
+        };
+        assert_eq!(
+            config.mappings,
+            vec![remap(KeyCode::KEY_LEFTCTRL), remap(KeyCode::KEY_ESC)]
+        );
+        assert_eq!(config.devices.len(), 1);
+        assert_eq!(
+            config.devices[0].mappings,
+            vec![remap(KeyCode::KEY_LEFTMETA)]
+        );
+        assert!(config.has_main_device());
+        assert_eq!(config.device_selectors().len(), 2);
+    }
+
+    #[test]
+    fn layer_remaps_take_precedence() {
+        let config = MappingConfig::from_toml(
+            r#"
+                [[remap]]
+                input = ["KEY_H"]
+                output = ["KEY_X"]
+
+                [[remap]]
+                input = ["KEY_H"]
+                output = ["KEY_LEFT"]
+                layer = "nav"
+
+                [[layer]]
+                input = "KEY_CAPSLOCK"
+                name = "nav"
+
+                [[layer_lock]]
+                input = "KEY_SCROLLLOCK"
+                name = "nav"
+                led = "LED_SCROLLL"
+            "#,
+        )
+        .unwrap();
+        let layers: Vec<Vec<String>> = config
+            .mappings
+            .iter()
+            .filter_map(|mapping| match mapping {
+                Mapping::Remap { when, .. } => Some(when.layers.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(layers, vec![vec!["nav".to_string()], vec![]]);
+        assert!(config.mappings.contains(&Mapping::Layer {
+            input: KeyCode::KEY_CAPSLOCK,
+            layer: "nav".to_string(),
+        }));
+        assert!(config.mappings.contains(&Mapping::LayerLock {
+            input: KeyCode::KEY_SCROLLLOCK,
+            layer: "nav".to_string(),
+            led: Some(LedCode::LED_SCROLLL),
+        }));
+    }
+
+    #[test]
+    fn led_conditioned_remaps_take_precedence() {
+        let config = MappingConfig::from_toml(
+            r#"
+                [[remap]]
+                input = ["KEY_KP1"]
+                output = ["KEY_END"]
+
+                [[remap]]
+                input = ["KEY_KP1"]
+                output = ["KEY_1"]
+                when_led = ["LED_NUML"]
+            "#,
+        )
+        .unwrap();
+        let leds: Vec<Vec<LedCode>> = config
+            .mappings
+            .iter()
+            .filter_map(|mapping| match mapping {
+                Mapping::Remap { when, .. } => Some(when.leds.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(leds, vec![vec![LedCode::LED_NUML], vec![]]);
+    }
+
+    #[test]
+    fn keys_may_be_given_by_alias() {
+        let config = MappingConfig::from_toml(
+            r#"
+                [aliases]
+                hyper = "KEY_F24"
+                mod = "ctrl"
+
+                [[remap]]
+                input = ["mod", "hyper"]
+                output = ["esc"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.mappings,
+            vec![Mapping::Remap {
+                input: HashSet::from([KeyCode::KEY_LEFTCTRL, KeyCode::KEY_F24]),
+                output: HashSet::from([KeyCode::KEY_ESC]),
+                when: Conditions::default(),
+                on_release: false,
+            }]
+        );
+        // The aliases of a config don't outlive its parsing.
+        assert!(parse_key("hyper").is_err());
+    }
+
+    #[test]
+    fn invalid_alias_points_at_list_keys() {
+        let err = MappingConfig::from_toml("[aliases]\nhyper = \"KEY_HYPER\"\n").unwrap_err();
+        let message = format!("{:#}", err);
+        assert!(message.contains("alias `hyper`"), "{}", message);
+        assert!(message.contains("evremap list-keys"), "{}", message);
+    }
+
+    #[test]
+    fn included_remaps_are_overridden_by_input() {
+        let dir = ConfigDir::new();
+        dir.write(
+            "base.toml",
+            r#"
+                device_name = "base keyboard"
+                grab = false
+
+                [output_repeat]
+                delay_ms = 250
+                period_ms = 30
+
+                [output_id]
+                vendor = 0x046d
+                product = 0xc52b
+
+                [[remap]]
+                input = ["KEY_CAPSLOCK"]
+                output = ["KEY_ESC"]
+
+                [[remap]]
+                input = ["KEY_F1"]
+                output = ["KEY_MUTE"]
+            "#,
+        );
+        dir.write(
+            "laptop.toml",
+            r#"
+                include = ["base.toml"]
+                device_name = "laptop keyboard"
+
+                [[remap]]
+                input = ["KEY_CAPSLOCK"]
+                output = ["KEY_LEFTCTRL"]
+            "#,
+        );
+        let config = MappingConfig::from_file(dir.path("laptop.toml")).unwrap();
+        assert_eq!(config.device_name.as_deref(), Some("laptop keyboard"));
+        assert_eq!(config.grab, Some(false));
+        assert_eq!(
+            config.output_id,
+            Some(OutputId {
+                vendor: Some(0x046d),
+                product: Some(0xc52b),
+                ..OutputId::default()
+            })
+        );
+        assert_eq!(
+            config.output_repeat,
+            Some(OutputRepeat {
+                delay_ms: 250,
+                period_ms: 30
+            })
+        );
+        let remap = |input, output| Mapping::Remap {
+            input: HashSet::from([input]),
+            output: HashSet::from([output]),
+            when: Conditions::default(),
+            on_release: false,
+        };
+        assert_eq!(
+            config.mappings,
+            vec![
+                remap(KeyCode::KEY_F1, KeyCode::KEY_MUTE),
+                remap(KeyCode::KEY_CAPSLOCK, KeyCode::KEY_LEFTCTRL),
+            ]
+        );
+    }
+
+    #[test]
+    fn including_config_may_reset_settings_to_their_default() {
+        let dir = ConfigDir::new();
+        dir.write(
+            "base.toml",
+            r#"
+                match_strategy = "longest_match"
+
+                [output_values]
+                press = 3
+            "#,
+        );
+        dir.write(
+            "profile.toml",
+            r#"
+                include = ["base.toml"]
+                match_strategy = "first_match"
+
+                [output_values]
+            "#,
+        );
+        let config = MappingConfig::from_file(dir.path("base.toml")).unwrap();
+        assert_eq!(config.match_strategy, MatchStrategy::LongestMatch);
+        assert_eq!(config.output_values.press, 3);
+        let config = MappingConfig::from_file(dir.path("profile.toml")).unwrap();
+        assert_eq!(config.match_strategy, MatchStrategy::FirstMatch);
+        assert_eq!(config.output_values, OutputValues::default());
+    }
+
+    #[test]
+    fn include_cycle_is_reported_with_its_chain() {
+        let dir = ConfigDir::new();
+        dir.write("a.toml", "include = [\"b.toml\"]\n");
+        dir.write("b.toml", "include = [\"a.toml\"]\n");
+        let err = MappingConfig::from_file(dir.path("a.toml")).unwrap_err();
+        let message = format!("{:#}", err);
+        assert!(
+            message.contains("a.toml -> ") && message.contains("b.toml -> "),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn invalid_key_error_points_at_the_remap() {
+        let err = MappingConfig::from_toml(
+            r#"
+                [[remap]]
+                input = ["KEY_A"]
+                output = ["KEY_B"]
+
+                [[remap]]
+                input = ["KEY_C"]
+                output = ["KEY_NOPE"]
+            "#,
+        )
+        .unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("at line 8, column 26"), "{}", message);
+        assert!(message.contains("in `remap[1].output`"), "{}", message);
+
+        let err = MappingConfig::from_toml(
+            r#"
+                [[remap]]
+                input = ["KEY_A"]
+                output = ["KEY_B"]
+                unless = ["KEY_NOPE"]
+            "#,
+        )
+        .unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("in `remap[0].unless`"), "{}", message);
+    }
+
+    #[test]
+    fn invalid_led_error_points_at_its_field() {
+        let err = MappingConfig::from_toml(
+            r#"
+                [[remap]]
+                input = ["KEY_KP1"]
+                output = ["KEY_1"]
+                when_led = ["LED_NUML", "LED_NOPE"]
+            "#,
+        )
+        .unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("in `remap[0].when_led`"), "{}", message);
+
+        let err = MappingConfig::from_toml(
+            r#"
+                [[layer_lock]]
+                input = "KEY_SCROLLLOCK"
+                name = "nav"
+                led = "LED_NOPE"
+            "#,
+        )
+        .unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("in `layer_lock[0].led`"), "{}", message);
+    }
+
+    #[test]
+    fn invalid_key_error_skips_rel_codes() {
+        let err = MappingConfig::from_toml(
+            r#"
+                [[rel_remap]]
+                input = "REL_WHEEL"
+                positive = ["KEY_VOLUMEUP"]
+
+                [[remap]]
+                input = ["KEY_C"]
+                output = ["KEY_NOPE"]
+            "#,
+        )
+        .unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("in `remap[0].output`"), "{}", message);
+    }
 }
@@ -0,0 +1,345 @@
+use anyhow::{bail, Context, Result};
+use evremap::mapping::MappingConfig;
+use std::ffi::{CString, OsStr};
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::FromRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+/// Name of the file in a profile directory that holds the name of the
+/// active profile. Writing to it switches profiles.
+const CONTROL_FILE: &str = "active";
+
+/// The profile used when none is given and there is no control file.
+const DEFAULT_PROFILE: &str = "default";
+
+/// A change to a file in the profile directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProfileEvent {
+    /// The file was written or moved into the directory.
+    Modified(PathBuf),
+    /// The file was deleted or moved out of the directory.
+    Removed(PathBuf),
+}
+
+/// A directory of `<name>.toml` profiles, one of which is active.
+pub struct Profiles {
+    dir: PathBuf,
+    active: String,
+    /// The last successfully loaded mappings of the active profile.
+    config: MappingConfig,
+}
+
+impl Profiles {
+    /// Load the profile `name` from `dir`, or the one named in the
+    /// control file if `name` is not given.
+    pub fn load(dir: &Path, name: Option<&str>) -> Result<Self> {
+        let active = match name {
+            Some(name) => name.to_string(),
+            None => read_control_file(dir).unwrap_or_else(|| DEFAULT_PROFILE.to_string()),
+        };
+        let path = profile_path(dir, &active);
+        let config = MappingConfig::from_file(&path).context(format!(
+            "loading profile {} from {}",
+            active,
+            path.display()
+        ))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            active,
+            config,
+        })
+    }
+
+    pub fn config(&self) -> &MappingConfig {
+        &self.config
+    }
+
+    /// Apply a change in the profile directory, returning the new mappings
+    /// if the active profile was switched or reloaded. Whenever loading
+    /// fails, including when the active profile was deleted, the last
+    /// loaded mappings stay in effect.
+    pub fn handle_event(&mut self, event: &ProfileEvent) -> Option<&MappingConfig> {
+        match event {
+            ProfileEvent::Modified(path) if *path == self.dir.join(CONTROL_FILE) => {
+                let name = read_control_file(&self.dir)?;
+                self.switch(&name)
+            }
+            ProfileEvent::Modified(path) if *path == profile_path(&self.dir, &self.active) => {
+                let name = self.active.clone();
+                self.switch(&name)
+            }
+            ProfileEvent::Removed(path) if *path == profile_path(&self.dir, &self.active) => {
+                log::warn!(
+                    "Active profile {} was removed; keeping its last loaded mappings",
+                    self.active
+                );
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn switch(&mut self, name: &str) -> Option<&MappingConfig> {
+        match MappingConfig::from_file(profile_path(&self.dir, name)) {
+            Ok(config) => {
+                log::info!("Using profile {}", name);
+                self.active = name.to_string();
+                self.config = config;
+                Some(&self.config)
+            }
+            Err(err) => {
+                log::error!(
+                    "Failed to load profile {}, still using {}: {:#}",
+                    name,
+                    self.active,
+                    err
+                );
+                None
+            }
+        }
+    }
+}
+
+fn profile_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!("{}.toml", name))
+}
+
+fn read_control_file(dir: &Path) -> Option<String> {
+    let name = std::fs::read_to_string(dir.join(CONTROL_FILE)).ok()?;
+    let name = name.trim();
+    if name.is_empty() {
+        None
+    } else {
+        Some(name.to_string())
+    }
+}
+
+/// Make `name` the active profile of whichever evremap is watching `dir`.
+pub fn request_switch(dir: &Path, name: &str) -> Result<()> {
+    let path = profile_path(dir, name);
+    if !path.is_file() {
+        bail!("There is no profile {} at {}", name, path.display());
+    }
+    std::fs::write(dir.join(CONTROL_FILE), format!("{}\n", name))
+        .context(format!("writing {}", dir.join(CONTROL_FILE).display()))
+}
+
+/// Load the profile `name` from `dir` and keep watching the directory.
+/// Returns the initial mappings along with a channel that receives the
+/// mappings again whenever the active profile is switched or edited.
+pub fn watch(dir: &Path, name: Option<&str>) -> Result<(MappingConfig, Receiver<MappingConfig>)> {
+    let mut profiles = Profiles::load(dir, name)?;
+    let initial = profiles.config().clone();
+    let (event_sender, events) = channel();
+    watch_dir(dir, event_sender)?;
+    let (config_sender, configs) = channel();
+    std::thread::spawn(move || {
+        for event in events {
+            log::debug!("{:?}", event);
+            if let Some(config) = profiles.handle_event(&event) {
+                if config_sender.send(config.clone()).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    Ok((initial, configs))
+}
+
+/// Keep watching the config file at `path`. Returns a channel that
+/// receives the mappings again whenever the file changes and still parses.
+pub fn watch_file(path: &Path) -> Result<Receiver<MappingConfig>> {
+    let path = path
+        .canonicalize()
+        .context(format!("resolving {}", path.display()))?;
+    let Some(dir) = path.parent() else {
+        bail!("{} has no parent directory to watch", path.display());
+    };
+    let (event_sender, events) = channel();
+    watch_dir(dir, event_sender)?;
+    let (config_sender, configs) = channel();
+    std::thread::spawn(move || {
+        for event in events {
+            log::debug!("{:?}", event);
+            if let Some(config) = reload(&path, &event) {
+                if config_sender.send(config).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    Ok(configs)
+}
+
+/// Load the config file at `path` again if `event` is a change to it.
+/// When it fails to load, the error is logged and nothing is returned,
+/// so that the mappings in effect stay as they are.
+fn reload(path: &Path, event: &ProfileEvent) -> Option<MappingConfig> {
+    match event {
+        ProfileEvent::Modified(modified) if modified == path => {
+            match MappingConfig::from_file(path) {
+                Ok(config) => {
+                    log::info!("Reloaded {}", path.display());
+                    Some(config)
+                }
+                Err(err) => {
+                    log::error!(
+                        "Failed to reload {}, keeping the current mappings: {:#}",
+                        path.display(),
+                        err
+                    );
+                    None
+                }
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Send a `ProfileEvent` for every file written to, moved into, deleted
+/// from or moved out of `dir`, as reported by inotify.
+fn watch_dir(dir: &Path, sender: Sender<ProfileEvent>) -> Result<()> {
+    let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error()).context("inotify_init1");
+    }
+    // Safety: fd is a freshly created descriptor that nothing else owns.
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    let c_dir = CString::new(dir.as_os_str().as_bytes())?;
+    let mask = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_DELETE | libc::IN_MOVED_FROM;
+    if unsafe { libc::inotify_add_watch(fd, c_dir.as_ptr(), mask) } < 0 {
+        return Err(std::io::Error::last_os_error()).context(format!("watching {}", dir.display()));
+    }
+
+    let dir = dir.to_path_buf();
+    std::thread::spawn(move || {
+        const HEADER: usize = std::mem::size_of::<libc::inotify_event>();
+        let mut buf = [0u8; 4096];
+        loop {
+            let len = match file.read(&mut buf) {
+                Ok(len) => len,
+                Err(err) => {
+                    log::error!("Stopped watching {}: {}", dir.display(), err);
+                    return;
+                }
+            };
+            let mut offset = 0;
+            while offset + HEADER <= len {
+                // Safety: the kernel wrote a whole inotify_event here.
+                let event: libc::inotify_event = unsafe {
+                    std::ptr::read_unaligned(buf.as_ptr().add(offset) as *const libc::inotify_event)
+                };
+                let name = &buf[offset + HEADER..offset + HEADER + event.len as usize];
+                let name = name.split(|b| *b == 0).next().unwrap_or(name);
+                offset += HEADER + event.len as usize;
+
+                let path = dir.join(OsStr::from_bytes(name));
+                let event = if event.mask & (libc::IN_DELETE | libc::IN_MOVED_FROM) != 0 {
+                    ProfileEvent::Removed(path)
+                } else {
+                    ProfileEvent::Modified(path)
+                };
+                if sender.send(event).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile_dir(test_name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "evremap-profiles-{}-{}",
+            test_name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_profile(dir: &Path, name: &str, budget: usize) -> ProfileEvent {
+        let path = profile_path(dir, name);
+        std::fs::write(&path, format!("evaluation_budget = {}\n", budget)).unwrap();
+        ProfileEvent::Modified(path)
+    }
+
+    fn budget(config: Option<&MappingConfig>) -> Option<usize> {
+        config.and_then(|config| config.evaluation_budget)
+    }
+
+    #[test]
+    fn switches_and_reloads_profiles() {
+        let dir = profile_dir("switch");
+        write_profile(&dir, "default", 1);
+        write_profile(&dir, "gaming", 2);
+        let mut profiles = Profiles::load(&dir, None).unwrap();
+        assert_eq!(profiles.config().evaluation_budget, Some(1));
+
+        // Editing an inactive profile changes nothing.
+        let event = write_profile(&dir, "gaming", 3);
+        assert_eq!(budget(profiles.handle_event(&event)), None);
+
+        request_switch(&dir, "gaming").unwrap();
+        let event = ProfileEvent::Modified(dir.join(CONTROL_FILE));
+        assert_eq!(budget(profiles.handle_event(&event)), Some(3));
+
+        let event = write_profile(&dir, "gaming", 4);
+        assert_eq!(budget(profiles.handle_event(&event)), Some(4));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_last_good_mappings() {
+        let dir = profile_dir("last-good");
+        write_profile(&dir, "default", 1);
+        let mut profiles = Profiles::load(&dir, Some("default")).unwrap();
+
+        let path = profile_path(&dir, "default");
+        std::fs::write(&path, "evaluation_budget = \"lots\"\n").unwrap();
+        let event = ProfileEvent::Modified(path.clone());
+        assert_eq!(budget(profiles.handle_event(&event)), None);
+        assert_eq!(profiles.config().evaluation_budget, Some(1));
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            budget(profiles.handle_event(&ProfileEvent::Removed(path))),
+            None
+        );
+        assert_eq!(profiles.config().evaluation_budget, Some(1));
+
+        // Switching to a profile that doesn't exist keeps the current one.
+        std::fs::write(dir.join(CONTROL_FILE), "missing\n").unwrap();
+        let event = ProfileEvent::Modified(dir.join(CONTROL_FILE));
+        assert_eq!(budget(profiles.handle_event(&event)), None);
+        assert_eq!(profiles.config().evaluation_budget, Some(1));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reloads_config_file_unless_broken() {
+        let dir = profile_dir("reload");
+        let event = write_profile(&dir, "config", 1);
+        let path = profile_path(&dir, "config");
+        assert_eq!(budget(reload(&path, &event).as_ref()), Some(1));
+
+        // Changes to other files in the directory are ignored.
+        let other = write_profile(&dir, "other", 2);
+        assert_eq!(budget(reload(&path, &other).as_ref()), None);
+
+        std::fs::write(&path, "evaluation_budget = \"lots\"\n").unwrap();
+        assert_eq!(budget(reload(&path, &event).as_ref()), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
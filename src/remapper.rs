@@ -1,6 +1,44 @@
-pub use driver::run_forever;
+#[cfg(feature = "linux-driver")]
+pub use driver::{dry_run, monitor, record, run_forever};
+pub use event_logic::resolve;
+#[cfg(feature = "bench")]
+pub use event_logic::{
+    apply_mapping_to_held_keys, compute_keys_based_on_state, EvaluationBudget, MappingIndex,
+    Scratch,
+};
+pub use machine::{Machine, DEFAULT_DUAL_ROLE_TIMEOUT, DEFAULT_MACRO_DELAY};
+#[cfg(feature = "metrics")]
+pub use metrics::Metrics;
+pub use recording::{pipe, read_events, replay_file, write_event};
+pub use rel::{RelMachine, RelOutput};
+pub use text::key_for_char;
+pub use transcript::run_transcript;
+pub use types::{EvKeyEvent, KeyEventType};
 
-mod machine;
+#[cfg(feature = "active-app")]
+mod active_app;
+#[cfg(feature = "linux-driver")]
+mod device_machines;
+#[cfg(feature = "linux-driver")]
 mod driver;
-mod types;
 mod event_logic;
+#[cfg(feature = "linux-driver")]
+mod hooks;
+#[cfg(feature = "linux-driver")]
+mod latency;
+mod machine;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(feature = "linux-driver")]
+mod notify;
+#[cfg(feature = "linux-driver")]
+mod overlay;
+mod recording;
+mod rel;
+#[cfg(feature = "linux-driver")]
+mod sink;
+#[cfg(feature = "linux-driver")]
+mod source;
+mod text;
+mod transcript;
+mod types;
@@ -0,0 +1,151 @@
+//! Looking up the foreground application, for remaps with an `app`.
+//!
+//! The lookup runs a command, which takes a few milliseconds, so it is
+//! done on key presses only and its result is used for `ACTIVE_APP_TTL`
+//! before it is looked up again. That leaves a window in which remaps
+//! follow the application that was in the foreground before: for up to
+//! `ACTIVE_APP_TTL` after switching applications, and always for the
+//! keys held across a switch, as a remap that is held isn't reconsidered
+//! until the next key event. A switch by keyboard, such as Alt+Tab, is
+//! usually followed by releasing Alt, which is when the keys held are
+//! reconsidered, but with the application looked up before the switch.
+//!
+//! By default the window class is looked up with `xprop`, which only
+//! works on X11 and needs `DISPLAY` and, when run as another user than
+//! the one logged in, `XAUTHORITY` in the environment of evremap. On
+//! Wayland there is no protocol for it that every compositor speaks, so
+//! `app_command` has to name a command that prints the class, such as
+//! one that asks the compositor over its IPC.
+
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+/// How long a looked up application is used before it is looked up again.
+pub const ACTIVE_APP_TTL: Duration = Duration::from_millis(500);
+
+/// The foreground application as last looked up.
+#[derive(Debug, Default)]
+pub struct ActiveApp {
+    /// When it was looked up, and the window class it had.
+    cached: Option<(Instant, Option<String>)>,
+}
+
+impl ActiveApp {
+    /// Look the application up with `look_up` unless it was looked up less
+    /// than `ACTIVE_APP_TTL` before `now`, returning it if it changed.
+    pub fn poll(
+        &mut self,
+        now: Instant,
+        look_up: impl FnOnce() -> Option<String>,
+    ) -> Option<Option<String>> {
+        if let Some((at, _)) = &self.cached {
+            if now.saturating_duration_since(*at) < ACTIVE_APP_TTL {
+                return None;
+            }
+        }
+        let app = look_up();
+        let previous = self.cached.replace((now, app.clone()));
+        (previous.and_then(|(_, previous)| previous) != app).then_some(app)
+    }
+}
+
+/// The window class of the foreground application, printed by `command`
+/// or else looked up with `xprop`, or `None` if it can't be told.
+pub fn look_up(command: Option<&[String]>) -> Option<String> {
+    match command {
+        Some([program, args @ ..]) => {
+            let output = run(Command::new(program).args(args))?;
+            let app = output.lines().next()?.trim();
+            (!app.is_empty()).then(|| app.to_string())
+        }
+        Some([]) => None,
+        None => {
+            let root = run(Command::new("xprop").args(["-root", "_NET_ACTIVE_WINDOW"]))?;
+            let window = parse_active_window(&root)?;
+            let class = run(Command::new("xprop").args(["-id", window, "WM_CLASS"]))?;
+            parse_wm_class(&class)
+        }
+    }
+}
+
+/// The standard output of `command`, if it ran successfully.
+fn run(command: &mut Command) -> Option<String> {
+    match command.output() {
+        Ok(output) if output.status.success() => {
+            Some(String::from_utf8_lossy(&output.stdout).into_owned())
+        }
+        Ok(output) => {
+            log::debug!("{:?} failed: {}", command, output.status);
+            None
+        }
+        Err(err) => {
+            log::debug!("Failed to run {:?}: {}", command, err);
+            None
+        }
+    }
+}
+
+/// The id of the window in `_NET_ACTIVE_WINDOW(WINDOW): window id # 0x3a00007`,
+/// or `None` when no window has the focus.
+fn parse_active_window(output: &str) -> Option<&str> {
+    let (_, id) = output.split_once('#')?;
+    let id = id.split(',').next()?.trim();
+    (id.starts_with("0x") && id != "0x0").then_some(id)
+}
+
+/// The class in `WM_CLASS(STRING) = "Navigator", "firefox"`, which is the
+/// second of the two strings; the first names the instance.
+fn parse_wm_class(output: &str) -> Option<String> {
+    let (_, values) = output.split_once('=')?;
+    values
+        .split('"')
+        .skip(1)
+        .step_by(2)
+        .nth(1)
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xprop_output_is_parsed() {
+        assert_eq!(
+            parse_active_window("_NET_ACTIVE_WINDOW(WINDOW): window id # 0x3a00007\n"),
+            Some("0x3a00007")
+        );
+        assert_eq!(
+            parse_active_window("_NET_ACTIVE_WINDOW(WINDOW): window id # 0x0\n"),
+            None
+        );
+        assert_eq!(
+            parse_wm_class("WM_CLASS(STRING) = \"Navigator\", \"firefox\"\n").as_deref(),
+            Some("firefox")
+        );
+        assert_eq!(parse_wm_class("WM_CLASS:  not found.\n"), None);
+    }
+
+    #[test]
+    fn lookups_are_cached_for_the_ttl() {
+        let mut active_app = ActiveApp::default();
+        let start = Instant::now();
+        assert_eq!(
+            active_app.poll(start, || Some("kitty".to_string())),
+            Some(Some("kitty".to_string()))
+        );
+        assert_eq!(
+            active_app.poll(start + ACTIVE_APP_TTL / 2, || unreachable!()),
+            None
+        );
+        // Looked up again, but unchanged.
+        assert_eq!(
+            active_app.poll(start + ACTIVE_APP_TTL, || Some("kitty".to_string())),
+            None
+        );
+        assert_eq!(
+            active_app.poll(start + ACTIVE_APP_TTL * 2, || None),
+            Some(None)
+        );
+    }
+}
@@ -0,0 +1,307 @@
+use super::event_logic::modifiers_last;
+use super::machine::Machine;
+use super::types::EvKeyEvent;
+use crate::mapping::{KeyCode, LedCode, MappingConfig, Modifiers};
+use evdev_rs::TimeVal;
+use std::collections::HashSet;
+use std::time::Duration;
+
+/// The machines that turn the key events of several input devices into
+/// output, following `MappingConfig::shared_state`.
+///
+/// By default every device gets a machine of its own, so each device is
+/// remapped as if it were the only one: a key held on one device never
+/// changes what another device emits, and a device that disappears takes
+/// only its own state with it. The price is that chords and layers can't
+/// span devices.
+///
+/// With shared state, one machine takes the events of all the devices,
+/// so the held keys of every device and the active layers make up a
+/// single context that all mappings are matched against.
+/// A layer key on one keyboard then applies to the other, but a device
+/// that disappears resets the state of all of them.
+///
+/// The `MappingConfig::devices` with mappings of their own, which come
+/// after the other devices, always get a machine of their own.
+pub struct DeviceMachines {
+    machines: Vec<Machine>,
+    /// The index of the machine of each device.
+    routes: Vec<usize>,
+    modifiers: Modifiers,
+}
+
+impl DeviceMachines {
+    pub fn from_config(config: &MappingConfig, devices: usize) -> Self {
+        let shared = config.shared_state.unwrap_or(false);
+        let own = config.devices.len().min(devices);
+        let mappings = Machine::shared_mappings(config);
+        let mut machines = vec![];
+        let mut routes = vec![];
+        for _ in own..devices {
+            if !shared || machines.is_empty() {
+                machines.push(Machine::from_config_sharing(config, mappings.clone()));
+            }
+            routes.push(machines.len() - 1);
+        }
+        for device in &config.devices[..own] {
+            let device_config = MappingConfig {
+                mappings: device
+                    .mappings
+                    .iter()
+                    .chain(&config.mappings)
+                    .cloned()
+                    .collect(),
+                ..config.clone()
+            };
+            machines.push(Machine::from_config(&device_config));
+            routes.push(machines.len() - 1);
+        }
+        if machines.is_empty() {
+            machines.push(Machine::from_config_sharing(config, mappings));
+        }
+        Self {
+            machines,
+            routes,
+            modifiers: config.modifiers.clone(),
+        }
+    }
+
+    /// The machine that handles the events of `device`.
+    pub fn machine(&mut self, device: usize) -> &mut Machine {
+        let idx = match self.routes.get(device) {
+            Some(idx) => *idx,
+            None => self.machines.len() - 1,
+        };
+        &mut self.machines[idx]
+    }
+
+    /// Insert an event of `device` and get the resulting events to be
+    /// emitted.
+    pub fn insert(&mut self, device: usize, event: EvKeyEvent) -> Vec<EvKeyEvent> {
+        self.machine(device).insert(event)
+    }
+
+    /// Let the machine of `device` know about `keys` that were already
+    /// held when it was grabbed, and get the resulting events to be
+    /// emitted.
+    pub fn seed_held_keys(
+        &mut self,
+        device: usize,
+        keys: &[KeyCode],
+        time: &TimeVal,
+    ) -> Vec<EvKeyEvent> {
+        self.machine(device).seed_held_keys(keys, time)
+    }
+
+    /// Tick every machine at `now` and get the resulting events to be
+    /// emitted.
+    pub fn tick(&mut self, now: &TimeVal) -> Vec<EvKeyEvent> {
+        self.machines
+            .iter_mut()
+            .flat_map(|machine| machine.tick(now))
+            .collect()
+    }
+
+    /// Let every machine know which LEDs are on. The LEDs are those of
+    /// the virtual devices, which all devices share.
+    pub fn set_leds(&mut self, leds: &HashSet<LedCode>) {
+        for machine in &mut self.machines {
+            machine.set_leds(leds);
+        }
+    }
+
+    /// Let every machine know the window class of the foreground
+    /// application.
+    #[cfg(feature = "active-app")]
+    pub fn set_app(&mut self, app: Option<String>) {
+        for machine in &mut self.machines {
+            machine.set_app(app.clone());
+        }
+    }
+
+    /// The LEDs that indicate the layers locked in any of the machines.
+    pub fn indicator_leds(&self) -> HashSet<LedCode> {
+        self.machines
+            .iter()
+            .flat_map(Machine::indicator_leds)
+            .collect()
+    }
+
+    /// How long after `now` the first of the machines is due a tick, if any
+    /// of them is.
+    pub fn time_until_tick(&self, now: &TimeVal) -> Option<Duration> {
+        self.machines
+            .iter()
+            .filter_map(|machine| machine.time_until_tick(now))
+            .min()
+    }
+
+    /// Release every key that the machine of `device` holds in the output
+    /// and start it over, e.g. because the device disappeared with keys
+    /// held that will never be released.
+    pub fn reset(
+        &mut self,
+        device: usize,
+        config: &MappingConfig,
+        time: &TimeVal,
+    ) -> Vec<EvKeyEvent> {
+        let machine = self.machine(device);
+        let releases = machine.release_all(time);
+        *machine = Machine::from_config_sharing(config, machine.mappings().clone());
+        releases
+    }
+
+    /// The metrics of each machine, in human-readable form.
+    #[cfg(feature = "metrics")]
+    pub fn metrics_report(&self) -> String {
+        self.machines
+            .iter()
+            .enumerate()
+            .map(|(idx, machine)| {
+                let report = machine.metrics().report(machine.mappings());
+                match self.machines.len() {
+                    1 => report,
+                    _ => format!("Machine {}:\n{}", idx + 1, report),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Release every key that any machine holds in the output, modifiers
+    /// last.
+    pub fn release_all(&mut self, time: &TimeVal) -> Vec<EvKeyEvent> {
+        let mut releases: Vec<EvKeyEvent> = self
+            .machines
+            .iter_mut()
+            .flat_map(|machine| machine.release_all(time))
+            .collect();
+        releases.sort_by(|a, b| modifiers_last(&self.modifiers, &a.ev_key, &b.ev_key));
+        // Machines of different devices may hold the same output key.
+        let mut seen = HashSet::new();
+        releases.retain(|release| seen.insert(release.ev_key));
+        releases
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapping::{Conditions, DeviceConfig, KeyCode, Mapping};
+    use crate::remapper::types::KeyEventType;
+
+    const LEFT_HALF: usize = 0;
+    const RIGHT_HALF: usize = 1;
+
+    fn key(ev_key: KeyCode, key_event_type: KeyEventType) -> EvKeyEvent {
+        EvKeyEvent {
+            time: TimeVal::new(0, 0),
+            ev_key,
+            key_event_type,
+        }
+    }
+
+    fn split_keyboard(shared_state: bool) -> DeviceMachines {
+        let config = MappingConfig {
+            mappings: vec![Mapping::Remap {
+                input: [KeyCode::KEY_RIGHTALT, KeyCode::KEY_H].into(),
+                output: [KeyCode::KEY_LEFT].into(),
+                when: Conditions::default(),
+                on_release: false,
+            }],
+            shared_state: Some(shared_state),
+            ..Default::default()
+        };
+        DeviceMachines::from_config(&config, 2)
+    }
+
+    /// Hold the layer key on the left half and press H on the right half.
+    fn press_h_in_layer(machines: &mut DeviceMachines) -> Vec<EvKeyEvent> {
+        machines.insert(LEFT_HALF, key(KeyCode::KEY_RIGHTALT, KeyEventType::Press));
+        machines.insert(RIGHT_HALF, key(KeyCode::KEY_H, KeyEventType::Press))
+    }
+
+    #[test]
+    fn shared_layer_applies_to_other_device() {
+        let mut machines = split_keyboard(true);
+        let output = press_h_in_layer(&mut machines);
+        assert!(output.contains(&key(KeyCode::KEY_LEFT, KeyEventType::Press)));
+        assert!(!output.contains(&key(KeyCode::KEY_H, KeyEventType::Press)));
+    }
+
+    #[test]
+    fn device_mappings_apply_to_their_device_only() {
+        let config = MappingConfig {
+            device_name: Some("Laptop".to_string()),
+            devices: vec![DeviceConfig {
+                selector: Default::default(),
+                mappings: vec![Mapping::Remap {
+                    input: [KeyCode::KEY_CAPSLOCK].into(),
+                    output: [KeyCode::KEY_LEFTCTRL].into(),
+                    when: Conditions::default(),
+                    on_release: false,
+                }],
+            }],
+            shared_state: Some(true),
+            ..Default::default()
+        };
+        let mut machines = DeviceMachines::from_config(&config, 2);
+        assert_eq!(
+            machines.insert(0, key(KeyCode::KEY_CAPSLOCK, KeyEventType::Press)),
+            vec![key(KeyCode::KEY_CAPSLOCK, KeyEventType::Press)]
+        );
+        assert_eq!(
+            machines.insert(1, key(KeyCode::KEY_CAPSLOCK, KeyEventType::Press)),
+            vec![key(KeyCode::KEY_LEFTCTRL, KeyEventType::Press)]
+        );
+    }
+
+    #[test]
+    fn own_device_remaps_come_before_the_default_ones() {
+        let remap = |output| Mapping::Remap {
+            input: [KeyCode::KEY_CAPSLOCK].into(),
+            output: [output].into(),
+            when: Conditions::default(),
+            on_release: false,
+        };
+        // As `MappingConfig` loads a default `[[device]]` block ahead of
+        // the top-level remaps.
+        let config = MappingConfig {
+            device_name: Some("Laptop".to_string()),
+            mappings: vec![
+                remap(KeyCode::KEY_LEFTCTRL),
+                Mapping::Remap {
+                    input: [KeyCode::KEY_F1].into(),
+                    output: [KeyCode::KEY_MUTE].into(),
+                    when: Conditions::default(),
+                    on_release: false,
+                },
+            ],
+            devices: vec![DeviceConfig {
+                selector: Default::default(),
+                mappings: vec![remap(KeyCode::KEY_LEFTMETA)],
+            }],
+            ..Default::default()
+        };
+        let mut machines = DeviceMachines::from_config(&config, 2);
+        assert_eq!(
+            machines.insert(0, key(KeyCode::KEY_CAPSLOCK, KeyEventType::Press)),
+            vec![key(KeyCode::KEY_LEFTCTRL, KeyEventType::Press)]
+        );
+        assert_eq!(
+            machines.insert(1, key(KeyCode::KEY_CAPSLOCK, KeyEventType::Press)),
+            vec![key(KeyCode::KEY_LEFTMETA, KeyEventType::Press)]
+        );
+        assert_eq!(
+            machines.insert(1, key(KeyCode::KEY_F1, KeyEventType::Press)),
+            vec![key(KeyCode::KEY_MUTE, KeyEventType::Press)]
+        );
+    }
+
+    #[test]
+    fn isolated_devices_ignore_each_others_layers() {
+        let mut machines = split_keyboard(false);
+        let output = press_h_in_layer(&mut machines);
+        assert_eq!(output, vec![key(KeyCode::KEY_H, KeyEventType::Press)]);
+    }
+}
@@ -1,101 +1,1740 @@
+#[cfg(feature = "active-app")]
+use super::active_app::{self, ActiveApp};
+use super::device_machines::DeviceMachines;
+use super::hooks::{self, Recovery};
+use super::latency::{LatencyRecorder, SystemMonotonic};
 use super::machine::Machine;
-use super::types::{EvKeyEvent, KeyEventType};
-use crate::mapping::{self, *};
+use super::notify::Notifier;
+use super::overlay::Overlay;
+use super::recording::write_event;
+use super::rel::{coarse_of, hi_res_of, RelMachine, RelOutput};
+use super::sink::{EventSink, RoundRobin, SkipEmptyReports};
+use super::source::{EventSource, ReadResult};
+use super::text::{key_for_char, text_steps, unicode_steps};
+use super::types::{duration_between, EvKeyEvent, KeyEventType};
+use crate::deviceinfo::{devices_named, find_device_path, retry_until, GRAB_RETRY_INTERVAL};
+use crate::mapping::*;
 use anyhow::Result;
 use anyhow::*;
-use evdev_rs::{Device, DeviceWrapper, GrabMode, InputEvent, ReadFlag, TimeVal, UInputDevice};
+use evdev_rs::enums::{int_to_ev_led, EV_MSC, EV_REP, EV_SYN};
+use evdev_rs::{
+    Device, DeviceWrapper, GrabMode, InputEvent, LedState, ReadFlag, TimeVal, UInputDevice,
+    UninitDevice,
+};
+use std::collections::HashSet;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
 
-pub fn run_forever(device_path: PathBuf, mappings: &Vec<mapping::Mapping>) -> Result<()> {
-    let mut devices: EvdevDevices = EvdevDevices::create_and_grab_devices(device_path)?;
-    devices.enable_key_codes_in_mapping(mappings)?;
+/// The device through which the virtual devices are created.
+const UINPUT_PATH: &str = "/dev/uinput";
+
+/// How long to wait between attempts to reopen a device that disappeared.
+pub const DEFAULT_RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Set from the SIGINT/SIGTERM handler to make `run_forever` shut down.
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Set from the SIGUSR1 handler to print the latency report.
+static REPORT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_shutdown(_signal: libc::c_int) {
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn request_report(_signal: libc::c_int) {
+    REPORT_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Call `handler` on `signal`. SA_RESTART is left out on purpose so that
+/// the blocking read gets interrupted and the main loop gets to act.
+fn install_signal_handler(signal: libc::c_int, handler: extern "C" fn(libc::c_int)) -> Result<()> {
+    // Safety: the handlers only store to an atomic, which is
+    // async-signal-safe.
+    let result = unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handler as libc::sighandler_t;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(signal, &action, std::ptr::null_mut())
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error())
+            .context(format!("installing handler for signal {}", signal));
+    }
+    Ok(())
+}
+
+/// Remap events from the devices at `device_paths`, which are those of
+/// `mapping_config.device_selectors()`, until asked to shut down. Their
+/// events all go out of the same virtual device, and with
+/// `shared_state` through the same machine, so that chords and layers may
+/// span devices. Mappings received from
+/// `profile_updates` replace the current ones. Opening and grabbing the
+/// devices is retried until `grab_deadline`, if any.
+pub fn run_forever(
+    device_paths: Vec<PathBuf>,
+    mapping_config: &MappingConfig,
+    profile_updates: Option<Receiver<MappingConfig>>,
+    measure_latency: bool,
+    grab_deadline: Option<Instant>,
+) -> Result<()> {
+    let mut devices: EvdevDevices = retry_until(grab_deadline, GRAB_RETRY_INTERVAL, || {
+        EvdevDevices::create_and_grab_devices(
+            &device_paths,
+            mapping_config,
+            // Profiles loaded later may emit keys the initial one doesn't.
+            profile_updates.is_some(),
+        )
+    })?;
+    let mut notifier = Notifier::from_env();
+    if let Some(notifier) = &notifier {
+        notifier.ready();
+    }
+    install_signal_handler(libc::SIGINT, request_shutdown)?;
+    install_signal_handler(libc::SIGTERM, request_shutdown)?;
+    if measure_latency || cfg!(feature = "metrics") {
+        install_signal_handler(libc::SIGUSR1, request_report)?;
+    }
+    let mut latency = measure_latency.then(|| LatencyRecorder::new(SystemMonotonic));
     log::info!("Going into read loop");
-    let mut machine: Machine = Machine::new(mappings);
+    // The devices to reconnect to are the ones we started out with, even if
+    // a profile that names others is loaded in the meantime.
+    let device_selectors = mapping_config.device_selectors();
+    let mut mapping_config = mapping_config.clone();
+    let mut machines = DeviceMachines::from_config(&mapping_config, device_paths.len());
+    let mut rel_machine = RelMachine::from_config(&mapping_config);
+    // Keys held while grabbing, such as a modifier, are never seen pressed.
+    for idx in 0..device_paths.len() {
+        let held = devices.held_keys(idx, &mapping_config.passthrough);
+        devices.note_held_keys(&held, &now());
+        let seeded = machines.seed_held_keys(idx, &held, &now());
+        devices.write_events(seeded, &mapping_config.output_values)?;
+    }
+    let mut pending_scan = PendingScan::default();
+    let mut last_time = TimeVal::new(0, 0);
+    #[cfg(feature = "active-app")]
+    let mut active_app = ActiveApp::default();
+    #[cfg(not(feature = "active-app"))]
+    if mapping_config.uses_apps() {
+        log::warn!("Remaps with an app never apply, as evremap was built without active-app");
+    }
     loop {
-        let (status, event) = devices
-            .input
-            .next_event(ReadFlag::NORMAL | ReadFlag::BLOCKING)?;
+        if let Some(new_config) = profile_updates
+            .as_ref()
+            .and_then(|updates| updates.try_iter().last())
+        {
+            // Release whatever the old mappings hold before switching.
+            devices.release_all(&mut machines, &last_time, &mapping_config)?;
+            mapping_config = new_config;
+            machines = DeviceMachines::from_config(&mapping_config, device_paths.len());
+            rel_machine = RelMachine::from_config(&mapping_config);
+            // The new machines don't know the application yet.
+            #[cfg(feature = "active-app")]
+            {
+                active_app = ActiveApp::default();
+            }
+        }
+        if REPORT_REQUESTED.swap(false, Ordering::SeqCst) {
+            if let Some(latency) = &latency {
+                println!("{}", latency.report());
+            }
+            #[cfg(feature = "metrics")]
+            println!("{}", machines.metrics_report());
+        }
+        if SHUTDOWN_REQUESTED.load(Ordering::SeqCst) {
+            log::info!("Shutting down");
+            if let Some(notifier) = &notifier {
+                notifier.stopping();
+            }
+            if let Some(latency) = &latency {
+                println!("{}", latency.report());
+            }
+            #[cfg(feature = "metrics")]
+            println!("{}", machines.metrics_report());
+            devices.release_all(&mut machines, &last_time, &mapping_config)?;
+            let grabbed = devices.grabbed();
+            for input in devices.inputs.iter_mut().filter(|_| grabbed) {
+                // The device may be gone already, in which case so is the grab.
+                if let Err(err) = input.grab(GrabMode::Ungrab) {
+                    log::warn!("Failed to release exclusive access: {}", err);
+                }
+            }
+            return Ok(());
+        }
+        // Pending decisions, such as whether a dual role key is a tap or a
+        // hold, may time out with no event to process, so the wait for one
+        // ends in time to tick the machines.
+        let now = now();
+        let ticked = machines.tick(&now);
+        devices.write_events(ticked, &mapping_config.output_values)?;
+        devices.set_indicators(machines.indicator_leds());
+        if let Some(notifier) = &mut notifier {
+            notifier.ping_if_due();
+        }
+        let timeout = machines
+            .time_until_tick(&now)
+            .into_iter()
+            .chain(notifier.as_ref().and_then(Notifier::time_until_ping))
+            .min();
+        let next_event = devices.read_event(timeout)?;
+        if let Some(leds) = devices.take_changed_leds() {
+            machines.set_leds(leds);
+        }
+        let Some((idx, next_event)) = next_event else {
+            continue;
+        };
+        if let Err(err) = &next_event {
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            if err.raw_os_error() == Some(libc::ENODEV) {
+                log::warn!("Input device {} disappeared", device_paths[idx].display());
+                // Whatever was held when the device vanished was never released.
+                let releases = machines.reset(idx, &mapping_config, &last_time);
+                devices.write_events(releases, &mapping_config.output_values)?;
+                let selector = &device_selectors[idx];
+                if devices.reconnect(idx, selector, &mapping_config)? {
+                    let events = hooks::fire(
+                        &mapping_config.recovery_hook,
+                        Recovery::Reconnect,
+                        &last_time,
+                    )?;
+                    devices.write_events(events, &mapping_config.output_values)?;
+                    let held = devices.held_keys(idx, &mapping_config.passthrough);
+                    devices.note_held_keys(&held, &last_time);
+                    let seeded = machines.seed_held_keys(idx, &held, &last_time);
+                    devices.write_events(seeded, &mapping_config.output_values)?;
+                }
+                continue;
+            }
+        }
+        let (status, event) = next_event?;
         match status {
             evdev_rs::ReadStatus::Success => {
-                // We'll only be intercepting EV_KEY events and passing them to the machine for processing.
-                if let EventCode::EV_KEY(ref key) = event.event_code {
-                    log::trace!("IN {:?}", event);
-                    let event_type = KeyEventType::from_value(event.value);
-                    let converted_events_to_write: Vec<EvKeyEvent> = machine.insert(
-                        EvKeyEvent {
-                            time: event.time,
-                            ev_key: key.clone(),
-                            key_event_type: event_type,
-                        }
-                    );
-                    for event in converted_events_to_write {
-                        log::trace!("OUT: {:?}", event);
-                        devices.output.write_event(&event.as_input_event())?;
+                last_time = event.time;
+                // Only a press can start a remap, so only then does the
+                // application matter.
+                #[cfg(feature = "active-app")]
+                if matches!(event.event_code, EventCode::EV_KEY(_))
+                    && KeyEventType::from_value(event.value) == KeyEventType::Press
+                    && mapping_config.uses_apps()
+                {
+                    let command = mapping_config.app_command.as_deref();
+                    if let Some(app) =
+                        active_app.poll(Instant::now(), || active_app::look_up(command))
+                    {
+                        log::debug!("Foreground application is now {:?}", app);
+                        machines.set_app(app);
                     }
-                    devices.generate_sync_event(&event.time)?;
-                } else {
-                    log::trace!("PASSTHRU {:?}", event);
-                    devices.output.write_event(&event)?;
+                }
+                remap_event(
+                    &mut devices,
+                    idx,
+                    &event,
+                    &mapping_config,
+                    &mut machines,
+                    &mut rel_machine,
+                    &mut pending_scan,
+                    latency.as_mut(),
+                )?;
+            }
+            evdev_rs::ReadStatus::Sync => {
+                log::warn!("Input events were dropped; resyncing");
+                let events = resync(
+                    || devices.inputs[idx].next_event(ReadFlag::SYNC),
+                    machines.machine(idx),
+                    &mapping_config.recovery_hook,
+                )?;
+                devices.write_events(events, &mapping_config.output_values)?;
+            }
+        }
+    }
+}
+
+/// Remap `event`, read from input `idx` of `source`, writing the result
+/// back to `source`.
+#[allow(clippy::too_many_arguments)]
+fn remap_event(
+    source: &mut impl EventSource,
+    idx: usize,
+    event: &InputEvent,
+    config: &MappingConfig,
+    machines: &mut DeviceMachines,
+    rel_machine: &mut RelMachine,
+    pending_scan: &mut PendingScan,
+    mut latency: Option<&mut LatencyRecorder<SystemMonotonic>>,
+) -> Result<()> {
+    // Without a grab, the system gets the events of the inputs already.
+    let forward_non_key = source.grabbed() && config.forward_non_key.unwrap_or(true);
+    if let Some(scan) = pending_scan.hold(idx, event) {
+        pass_through(source, &scan, forward_non_key)?;
+    }
+    // We'll only be intercepting EV_KEY events and passing them to the machine for processing.
+    if event.event_code == EventCode::EV_MSC(EV_MSC::MSC_SCAN) {
+        // Held back until the key it goes with is remapped.
+    } else if let Some(moves) = match event.event_code {
+        EventCode::EV_KEY(key) => {
+            rel_machine.insert_key(key, KeyEventType::from_value(event.value))
+        }
+        _ => None,
+    } {
+        log::trace!("IN {:?}", event);
+        write_rel_moves(source, &moves, &event.time)?;
+    } else if let EventCode::EV_KEY(ref key) = event.event_code {
+        if let Some(latency) = &mut latency {
+            latency.event_read();
+        }
+        let event_type = KeyEventType::from_value(event.value);
+        let key_event = EvKeyEvent {
+            time: event.time,
+            ev_key: *key,
+            key_event_type: event_type,
+        };
+        trace_key_event("in", &key_event);
+        source.note_input(&key_event);
+        let converted_events_to_write: Vec<EvKeyEvent> = if config.passthrough.contains(key) {
+            vec![key_event.clone()]
+        } else {
+            machines.insert(idx, key_event.clone())
+        };
+        if let Some(scan) = pending_scan.take_for(idx, &key_event, &converted_events_to_write) {
+            pass_through(source, &scan, forward_non_key)?;
+        }
+        source.write_events(converted_events_to_write, &config.output_values)?;
+        if let Some(latency) = &mut latency {
+            latency.output_written();
+        }
+    } else if let Some(output) = match event.event_code {
+        EventCode::EV_REL(axis) => rel_machine.insert(axis, event.value, &event.time),
+        _ => None,
+    } {
+        log::trace!("IN {:?}", event);
+        match output {
+            RelOutput::Axis(value) => {
+                let moved = InputEvent::new(&event.time, &event.event_code, value);
+                let grabbed = source.grabbed();
+                pass_through(source, &moved, grabbed)?;
+            }
+            RelOutput::Keys(keys) => {
+                source.write_events(keys, &config.output_values)?;
+            }
+        }
+    } else if let EventCode::EV_LED(_) = event.event_code {
+        // The echo of setting the LEDs of the input to those of
+        // the virtual devices.
+    } else {
+        pass_through(source, event, forward_non_key)?;
+    }
+    Ok(())
+}
+
+/// Print what `mapping_config` makes of the key events of the device at
+/// `device_path`, without grabbing it or creating a virtual device, so
+/// that the keyboard keeps working normally while trying out mappings.
+/// Like the main loop, it ticks the machine when a pending decision times
+/// out, printing what that emits as the output of `(timeout)`.
+pub fn dry_run(device_path: &Path, mapping_config: &MappingConfig) -> Result<()> {
+    let input = open_device(device_path)?;
+    let mut machine = Machine::from_config(mapping_config);
+    let print = |input: &str, output: &[EvKeyEvent]| {
+        let output: Vec<String> = output.iter().map(ToString::to_string).collect();
+        println!("IN {} -> OUT [{}]", input, output.join(", "));
+    };
+    loop {
+        let now = now();
+        let ticked = machine.tick(&now);
+        if !ticked.is_empty() {
+            print("(timeout)", &ticked);
+        }
+        if !wait_for_event(&input, machine.time_until_tick(&now))? {
+            continue;
+        }
+        let (status, event) = input.next_event(ReadFlag::NORMAL | ReadFlag::BLOCKING)?;
+        match status {
+            evdev_rs::ReadStatus::Success => {
+                if let EventCode::EV_KEY(key) = event.event_code {
+                    let event = EvKeyEvent {
+                        time: event.time,
+                        ev_key: key,
+                        key_event_type: KeyEventType::from_value(event.value),
+                    };
+                    let output = if mapping_config.passthrough.contains(&key) {
+                        vec![event.clone()]
+                    } else {
+                        machine.insert(event.clone())
+                    };
+                    print(&event.to_string(), &output);
+                }
+            }
+            evdev_rs::ReadStatus::Sync => {
+                // The hook is left out since it may have side effects.
+                let output = resync(
+                    || input.next_event(ReadFlag::SYNC),
+                    &mut machine,
+                    &RecoveryHook::default(),
+                )?;
+                print("(dropped events)", &output);
+            }
+        }
+    }
+}
+
+/// Print the events of the device at `device_path` as they come, key
+/// events by their `KEY_XXX` name and `KeyEventType`. The device is not
+/// grabbed, so it keeps working as usual.
+pub fn monitor(device_path: &Path) -> Result<()> {
+    let input = open_device(device_path)?;
+    loop {
+        let (status, event) = input.next_event(ReadFlag::NORMAL | ReadFlag::BLOCKING)?;
+        match status {
+            evdev_rs::ReadStatus::Success => {
+                if let Some(line) = describe_input(&event) {
+                    println!("{}", line);
+                }
+            }
+            evdev_rs::ReadStatus::Sync => println!("(dropped events)"),
+        }
+    }
+}
+
+/// Record the key events of the device at `device_path` to a new
+/// recording at `out_path`, for `replay` to feed them through a config
+/// later. The device is not grabbed, so it keeps working as usual.
+pub fn record(device_path: &Path, out_path: &Path) -> Result<()> {
+    let input = open_device(device_path)?;
+    let mut out =
+        std::fs::File::create(out_path).context(format!("creating {}", out_path.display()))?;
+    loop {
+        let (status, event) = input.next_event(ReadFlag::NORMAL | ReadFlag::BLOCKING)?;
+        match status {
+            evdev_rs::ReadStatus::Success => {
+                if let EventCode::EV_KEY(key) = event.event_code {
+                    let event = EvKeyEvent {
+                        time: event.time,
+                        ev_key: key,
+                        key_event_type: KeyEventType::from_value(event.value),
+                    };
+                    write_event(&mut out, &event)
+                        .context(format!("writing to {}", out_path.display()))?;
                 }
             }
-            evdev_rs::ReadStatus::Sync => bail!("ReadStatus::Sync!"),
+            evdev_rs::ReadStatus::Sync => log::warn!("Input events were dropped"),
         }
     }
 }
 
+/// A line describing `event` for `monitor`, along with its raw value, or
+/// `None` for the SYN_REPORTs that end every frame.
+fn describe_input(event: &InputEvent) -> Option<String> {
+    let time = format!("{}.{:06}", event.time.tv_sec, event.time.tv_usec);
+    match event.event_code {
+        EventCode::EV_SYN(evdev_rs::enums::EV_SYN::SYN_REPORT) => None,
+        EventCode::EV_KEY(key) => Some(format!(
+            "{} {} (value {})",
+            time,
+            EvKeyEvent {
+                time: event.time,
+                ev_key: key,
+                key_event_type: KeyEventType::from_value(event.value),
+            },
+            event.value
+        )),
+        code => Some(format!("{} {} (value {})", time, code, event.value)),
+    }
+}
+
 struct EvdevDevices {
-    input: Device,
-    output: UInputDevice,
+    inputs: Vec<Device>,
+    output: SkipEmptyReports<RoundRobin<UInputDevice>>,
+    /// The input that the last event was read from, which may have more
+    /// events buffered.
+    reading: Option<usize>,
+    /// The uinput fds of the virtual devices, which the LEDs that the
+    /// system sets on them, such as NumLock, can be read from.
+    led_fds: Vec<RawFd>,
+    /// The LEDs that are on, as last set on the virtual devices.
+    leds: HashSet<LedCode>,
+    /// Whether `leds` changed since `take_changed_leds`.
+    leds_changed: bool,
+    /// LEDs that the mappings turn on as indicators, on the inputs only.
+    indicators: HashSet<LedCode>,
+    /// Whether the virtual devices repeat held keys themselves, so that
+    /// no repeats are written to them.
+    repeats_itself: bool,
+    /// What the virtual devices add to the inputs, when they aren't
+    /// grabbed.
+    overlay: Option<Overlay>,
 }
 
-fn enable_key_code(input: &mut Device, key: KeyCode) -> Result<()> {
+fn enable_key_code<D: DeviceWrapper>(input: &mut D, key: KeyCode) -> Result<()> {
     input
-        .enable(EventCode::EV_KEY(key.clone()))
+        .enable(EventCode::EV_KEY(key))
         .context(format!("enable key {:?}", key))?;
     Ok(())
 }
 
+/// Enable on `template` every key the virtual devices may emit: the keys
+/// of `inputs`, which may be passed through, and those in the mappings,
+/// or every key at all if `all_keys` is set. This has to happen before the
+/// virtual devices are created from `template`, as their capabilities are
+/// fixed from then on; a key enabled later, such as a `KEY_MUTE` output of
+/// a keyboard without one, would never be emitted.
+fn enable_output_keys<D: DeviceWrapper>(
+    template: &mut D,
+    inputs: &[Device],
+    config: &MappingConfig,
+    all_keys: bool,
+) -> Result<()> {
+    for input in inputs {
+        for (key, _) in all_key_names() {
+            if input.has_event_code(&EventCode::EV_KEY(key)) {
+                enable_key_code(template, key)?;
+            }
+        }
+    }
+    let mut mapped: Vec<(KeyCode, String)> = vec![];
+    for mapping in config
+        .mappings
+        .iter()
+        .chain(config.devices.iter().flat_map(|device| &device.mappings))
+    {
+        let emitter = format!("the mapping of {}", mapping_input(mapping));
+        mapped.extend(
+            keys_in_mapping(mapping)
+                .into_iter()
+                .map(|key| (key, emitter.clone())),
+        );
+    }
+    for mapping in &config.rel_mappings {
+        let emitter = format!("the rel_remap of {:?}", mapping.input);
+        mapped.extend(
+            mapping
+                .positive
+                .iter()
+                .chain(&mapping.negative)
+                .map(|key| (*key, emitter.clone())),
+        );
+    }
+    if let Some(key) = config.recovery_hook.key {
+        mapped.push((key, "the recovery_hook".to_string()));
+    }
+    for warning in enable_mapped_keys(&mapped, |key| enable_key_code(template, key)) {
+        log::warn!("{}", warning);
+    }
+    for mapping in &config.key_rel_mappings {
+        let axis = mapping.output;
+        for axis in [Some(axis), hi_res_of(axis), coarse_of(axis)]
+            .into_iter()
+            .flatten()
+        {
+            template
+                .enable(EventCode::EV_REL(axis))
+                .context(format!("enable axis {:?}", axis))?;
+        }
+    }
+    if all_keys {
+        for (key, _) in all_key_names() {
+            enable_key_code(template, key)?;
+        }
+    }
+    if config.output_repeat.is_some() {
+        template
+            .enable(EventType::EV_REP)
+            .context("enable auto-repeat")?;
+    }
+    Ok(())
+}
+
+/// Enable with `enable` each key of `mapped`, which pairs the keys the
+/// config emits with what emits them. A key that fails to enable only
+/// breaks what emits it, so rather than keeping evremap from starting it
+/// makes for a warning, returned, that names the key and what emits it.
+fn enable_mapped_keys(
+    mapped: &[(KeyCode, String)],
+    mut enable: impl FnMut(KeyCode) -> Result<()>,
+) -> Vec<String> {
+    let mut failed: Vec<(KeyCode, Error)> = vec![];
+    for (key, _) in mapped {
+        if failed.iter().any(|(failed, _)| failed == key) {
+            continue;
+        }
+        if let Err(err) = enable(*key) {
+            failed.push((*key, err));
+        }
+    }
+    failed
+        .into_iter()
+        .map(|(key, err)| {
+            let mut emitters: Vec<&str> = mapped
+                .iter()
+                .filter(|(mapped, _)| *mapped == key)
+                .map(|(_, emitter)| emitter.as_str())
+                .collect();
+            emitters.dedup();
+            format!(
+                "{} could not be enabled on the virtual devices, so {} won't \
+                 work: {:#}",
+                key_name(key),
+                emitters.join(" and "),
+                err
+            )
+        })
+        .collect()
+}
+
+/// The input keys of `mapping`, such as `KEY_LEFTCTRL+KEY_F5`, to tell it
+/// apart in warnings.
+fn mapping_input(mapping: &Mapping) -> String {
+    let mut names: Vec<String> = match mapping {
+        Mapping::Remap { input, .. } | Mapping::Combo { input, .. } => {
+            input.iter().map(|key| key_name(*key)).collect()
+        }
+        Mapping::Tiered { tiers } => tiers
+            .first()
+            .into_iter()
+            .flat_map(|tier| &tier.input)
+            .map(|key| key_name(*key))
+            .collect(),
+        Mapping::DualRole { input, .. }
+        | Mapping::KeyNameProbe { input }
+        | Mapping::Ignore { input }
+        | Mapping::InvertModifier { input, .. }
+        | Mapping::OneShot { input, .. }
+        | Mapping::Layer { input, .. }
+        | Mapping::LayerLock { input, .. }
+        | Mapping::DeferredPassthrough { input, .. }
+        | Mapping::TapDance { input, .. }
+        | Mapping::Macro { input, .. }
+        | Mapping::Unicode { input, .. }
+        | Mapping::Text { input, .. } => vec![key_name(*input)],
+    };
+    names.sort();
+    names.join("+")
+}
+
+/// Give `template` the bus type, vendor, product and version of `input`,
+/// other than those that `overrides` sets. Virtual devices modelled on the
+/// input get its ids anyway, but without them a device named by
+/// `output_device_name` has none for tools like libinput to go by.
+fn set_output_id(template: &impl DeviceWrapper, input: &Device, overrides: &OutputId) {
+    template.set_bustype(overrides.bustype.unwrap_or(input.bustype()));
+    template.set_vendor_id(overrides.vendor.unwrap_or(input.vendor_id()));
+    template.set_product_id(overrides.product.unwrap_or(input.product_id()));
+    template.set_version(overrides.version.unwrap_or(input.version()));
+}
+
+/// Create `count` virtual devices with the capabilities of `template`,
+/// naming the `i`th one `name(i)`.
+fn create_outputs<D: DeviceWrapper>(
+    template: &mut D,
+    count: usize,
+    name: impl Fn(usize) -> String,
+) -> Result<Vec<UInputDevice>> {
+    let names: Vec<String> = (0..count).map(name).collect();
+    warn_of_other_instances(&names);
+    let mut outputs = vec![];
+    for name in &names {
+        template.set_name(name);
+        outputs.push(UInputDevice::create_from_device(&*template).map_err(|err| {
+            permission_error(err, format!("opening {}", UINPUT_PATH), uinput_accessible)
+        })?);
+    }
+    Ok(outputs)
+}
+
+/// Set the delay and period with which the kernel repeats the keys held
+/// on `output`, which it takes from EV_REP events written to it.
+fn set_output_repeat(output: &UInputDevice, repeat: &OutputRepeat) -> Result<()> {
+    let time = TimeVal::new(0, 0);
+    for (code, value) in [
+        (EV_REP::REP_DELAY, repeat.delay_ms),
+        (EV_REP::REP_PERIOD, repeat.period_ms),
+    ] {
+        output.write_event(&InputEvent::new(
+            &time,
+            &EventCode::EV_REP(code),
+            value as i32,
+        ))?;
+    }
+    output.write_event(&InputEvent::new(
+        &time,
+        &EventCode::EV_SYN(EV_SYN::SYN_REPORT),
+        0,
+    ))?;
+    Ok(())
+}
+
+/// Warn if a virtual device by one of `names` already exists, which
+/// likely means another evremap is running for the same input. Only one
+/// of them can grab it at a time, but they can still stack up, such as
+/// when one of them is between grabs while its input reconnects.
+fn warn_of_other_instances(names: &[String]) {
+    let existing = devices_named(names).unwrap_or_else(|err| {
+        log::debug!("Not checking for other instances: {:#}", err);
+        vec![]
+    });
+    for dev in existing {
+        log::warn!(
+            "A virtual device named `{}` already exists at {}. Is another \
+             evremap running for the same device? If so, stop it (for \
+             example with `systemctl stop evremap`), or each key press \
+             may be remapped twice.",
+            dev.name,
+            dev.path.display()
+        );
+    }
+}
+
 impl EvdevDevices {
-    fn create_and_grab_devices<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let path = path.as_ref();
-        let f: std::fs::File =
-            std::fs::File::open(path).context(format!("opening {}", path.display()))?;
-        let mut input = Device::new_from_file(f)
-            .with_context(|| format!("failed to create new Device from file {}", path.display()))?;
+    /// Grabs the devices at `paths`, unless the config says not to, and
+    /// creates the configured number of virtual devices to write to, each
+    /// capable of emitting every key of the inputs and the config, or every
+    /// key at all if `all_keys` is set. Unless `output_device_name` is set,
+    /// they also inherit everything else the first input is capable of.
+    fn create_and_grab_devices(
+        paths: &[PathBuf],
+        config: &MappingConfig,
+        all_keys: bool,
+    ) -> Result<Self> {
+        let count = config.virtual_devices.unwrap_or(1);
+        if count == 0 {
+            bail!("virtual_devices must be at least 1");
+        }
+        let mut inputs = paths
+            .iter()
+            .map(|path| open_device(path))
+            .collect::<Result<Vec<_>>>()?;
+        let Some(path) = paths.first() else {
+            bail!("No input device to remap");
+        };
+        let output_id = config.output_id.unwrap_or_default();
+        let outputs = match &config.output_device_name {
+            Some(name) => {
+                let mut template = UninitDevice::new().context("creating the output device")?;
+                set_output_id(&template, &inputs[0], &output_id);
+                enable_output_keys(&mut template, &inputs, config, all_keys)?;
+                create_outputs(&mut template, count, |i| match count {
+                    1 => name.clone(),
+                    _ => format!("{} {}", name, i + 1),
+                })
+                .context(format!("creating UInputDevice {}", name))?
+            }
+            None => {
+                // The virtual devices are modelled on the first input.
+                let Some((input, others)) = inputs.split_first_mut() else {
+                    unreachable!("checked above");
+                };
+                set_output_id(&*input, input, &output_id);
+                enable_output_keys(input, others, config, all_keys)?;
+                create_outputs(input, count, |i| match count {
+                    1 => format!("evremap Virtual input for {}", path.display()),
+                    _ => format!("evremap Virtual input {} for {}", i + 1, path.display()),
+                })
+                .context(format!("creating UInputDevice from {}", path.display()))?
+            }
+        };
+
+        if let Some(repeat) = &config.output_repeat {
+            for output in &outputs {
+                set_output_repeat(output, repeat).context("setting the output auto-repeat")?;
+            }
+        }
 
-        input.set_name(&format!("evremap Virtual input for {}", path.display()));
+        let grabbing = config.grab.unwrap_or(true);
+        if grabbing {
+            for (input, path) in inputs.iter_mut().zip(paths) {
+                grab(input, path)?;
+            }
+        } else {
+            log::warn!(
+                "Not grabbing the input devices: their own events reach the \
+                 system too, and only what the mappings add is emitted"
+            );
+        }
 
-        let output = UInputDevice::create_from_device(&input)
-            .context(format!("creating UInputDevice from {}", path.display()))?;
+        let led_fds: Vec<RawFd> = outputs.iter().filter_map(UInputDevice::fd).collect();
+        for fd in &led_fds {
+            // Safety: fd is the open uinput fd of an output.
+            if unsafe { libc::fcntl(*fd, libc::F_SETFL, libc::O_NONBLOCK) } < 0 {
+                return Err(std::io::Error::last_os_error()).context("reading LEDs set");
+            }
+        }
+        // Until the system sets any, the LEDs are as the first input has them.
+        let leds = all_leds()
+            .filter(|led| inputs[0].event_value(&EventCode::EV_LED(*led)) == Some(1))
+            .collect();
 
-        input
-            .grab(GrabMode::Grab)
-            .context(format!("grabbing exclusive access on {}", path.display()))?;
+        Ok(Self {
+            inputs,
+            output: SkipEmptyReports::new(RoundRobin::new(outputs)),
+            reading: None,
+            led_fds,
+            leds,
+            leds_changed: true,
+            indicators: HashSet::new(),
+            repeats_itself: config.output_repeat.is_some(),
+            overlay: (!grabbing).then(Overlay::default),
+        })
+    }
 
-        Ok(Self { input, output })
+    /// Let the overlay, if any, know that `keys` are held on an input, as
+    /// of opening it.
+    fn note_held_keys(&mut self, keys: &[KeyCode], time: &TimeVal) {
+        for key in keys {
+            self.note_input(&EvKeyEvent {
+                time: *time,
+                ev_key: *key,
+                key_event_type: KeyEventType::Press,
+            });
+        }
     }
 
-    fn enable_key_codes_in_mapping(&mut self, mappings: &Vec<mapping::Mapping>) -> Result<()> {
-        // Ensure that any remapped keys are supported by the generated output device
-        for map in mappings {
-            match map {
-                Mapping::Remap { output, .. } => {
-                    for o in output {
-                        enable_key_code(&mut self.input, o.clone())?;
-                    }
+    /// The keys that are held on input `idx`, as of opening it, other
+    /// than those of `passthrough`, which aren't remapped.
+    fn held_keys(&self, idx: usize, passthrough: &HashSet<KeyCode>) -> Vec<KeyCode> {
+        all_key_names()
+            .into_iter()
+            .map(|(key, _)| key)
+            .filter(|key| {
+                !passthrough.contains(key)
+                    && self.inputs[idx].event_value(&EventCode::EV_KEY(*key)) == Some(1)
+            })
+            .collect()
+    }
+
+    /// The LEDs that are on, if they changed since the last call.
+    fn take_changed_leds(&mut self) -> Option<&HashSet<LedCode>> {
+        std::mem::take(&mut self.leds_changed).then_some(&self.leds)
+    }
+
+    /// Read the LEDs that were set on the virtual devices, and set them on
+    /// the inputs as well, whose own LEDs the system can't reach while they
+    /// are grabbed.
+    fn read_leds(&mut self) -> Result<()> {
+        let mut events = vec![];
+        for fd in &self.led_fds {
+            read_uinput_events(*fd, &mut events).context("reading LEDs set")?;
+        }
+        if !apply_led_events(&mut self.leds, &events) {
+            return Ok(());
+        }
+        self.leds_changed = true;
+        log::debug!("LEDs on: {:?}", self.leds);
+        self.show_leds();
+        Ok(())
+    }
+
+    /// Turn the `indicators` on on the inputs, along with the LEDs of the
+    /// virtual devices, and the previous ones off.
+    fn set_indicators(&mut self, indicators: HashSet<LedCode>) {
+        if indicators == self.indicators {
+            return;
+        }
+        self.indicators = indicators;
+        self.show_leds();
+    }
+
+    fn show_leds(&self) {
+        let shown = self.shown_leds();
+        for input in &self.inputs {
+            set_leds(input, &shown);
+        }
+    }
+
+    /// The LEDs to turn on on the inputs.
+    fn shown_leds(&self) -> HashSet<LedCode> {
+        &self.leds | &self.indicators
+    }
+
+    /// Release every key `machines` hold in the output.
+    fn release_all(
+        &mut self,
+        machines: &mut DeviceMachines,
+        time: &TimeVal,
+        config: &MappingConfig,
+    ) -> Result<()> {
+        let releases = machines.release_all(time);
+        self.write_events(releases, &config.output_values)
+    }
+
+    /// Wait for the device described by `selector` to reappear and grab it
+    /// as input `idx`, trying again every `reconnect_interval_ms`. Returns
+    /// whether it did; it gives up when asked to shut down, leaving that to
+    /// the caller. The other inputs are not read in the meantime.
+    fn reconnect(
+        &mut self,
+        idx: usize,
+        selector: &DeviceSelector,
+        config: &MappingConfig,
+    ) -> Result<bool> {
+        let interval = config
+            .reconnect_interval_ms
+            .map_or(DEFAULT_RECONNECT_INTERVAL, Duration::from_millis);
+        for attempt in 1.. {
+            std::thread::sleep(interval);
+            if SHUTDOWN_REQUESTED.load(Ordering::SeqCst) {
+                break;
+            }
+            let reopened = find_device_path(selector).and_then(|path| {
+                let mut input = open_device(&path)?;
+                if self.grabbed() {
+                    grab(&mut input, &path)?;
                 }
+                Ok(input)
+            });
+            if let Err(err) = &reopened {
+                log::info!("Reconnect attempt {} failed: {:#}", attempt, err);
+                continue;
+            }
+            self.inputs[idx] = reopened?;
+            set_leds(&self.inputs[idx], &self.shown_leds());
+            self.reading = None;
+            log::info!("Reconnected to the input device");
+            return Ok(true);
+        }
+        Ok(false)
+    }
+}
+
+impl EventSink for EvdevDevices {
+    fn write_event(&mut self, event: &InputEvent) -> Result<()> {
+        self.output.write_event(event)
+    }
+}
+
+impl EventSource for EvdevDevices {
+    /// A single thread multiplexes the inputs with poll(2): it keeps the
+    /// machine unshared and every event in one order, without locking.
+    /// The input that was last read from is drained first, since libevdev
+    /// may have more of its events buffered where poll can't see them.
+    fn read_event(&mut self, timeout: Option<Duration>) -> Result<Option<(usize, ReadResult)>> {
+        if let Some(idx) = self.reading.take() {
+            if self.inputs[idx].has_event_pending() {
+                self.reading = Some(idx);
+                return Ok(Some((
+                    idx,
+                    self.inputs[idx].next_event(ReadFlag::NORMAL | ReadFlag::BLOCKING),
+                )));
             }
         }
+        let mut fds: Vec<libc::pollfd> = self
+            .inputs
+            .iter()
+            .map(|input| input.file().as_raw_fd())
+            .chain(self.led_fds.iter().copied())
+            .map(|fd| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        // Safety: fds is a valid array of fds.len() pollfd structs.
+        let ready = unsafe {
+            libc::poll(
+                fds.as_mut_ptr(),
+                fds.len() as libc::nfds_t,
+                poll_timeout_ms(timeout),
+            )
+        };
+        if ready < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                return Ok(None);
+            }
+            return Err(err).context("polling the input devices");
+        }
+        let Some(idx) = fds.iter().position(|fd| fd.revents != 0) else {
+            return Ok(None);
+        };
+        if idx >= self.inputs.len() {
+            self.read_leds()?;
+            return Ok(None);
+        }
+        self.reading = Some(idx);
+        Ok(Some((
+            idx,
+            self.inputs[idx].next_event(ReadFlag::NORMAL | ReadFlag::BLOCKING),
+        )))
+    }
+
+    /// Write the events produced by the machine, in the frames that
+    /// `frames` groups them into.
+    fn write_events(&mut self, mut events: Vec<EvKeyEvent>, values: &OutputValues) -> Result<()> {
+        if self.repeats_itself {
+            events.retain(|event| event.key_event_type != KeyEventType::Repeat);
+        }
+        if let Some(overlay) = &mut self.overlay {
+            events = overlay.difference(events);
+        }
+        write_frames(&mut self.output, frames(events), values)
+    }
+
+    fn note_input(&mut self, event: &EvKeyEvent) {
+        if let Some(overlay) = &mut self.overlay {
+            overlay.input(event);
+        }
+    }
+
+    fn grabbed(&self) -> bool {
+        self.overlay.is_none()
+    }
+}
+
+/// Write the movements of relative axes that keys turned into, in a frame
+/// of their own.
+fn write_rel_moves(
+    output: &mut impl EventSink,
+    moves: &[(RelCode, i32)],
+    time: &TimeVal,
+) -> Result<()> {
+    if moves.is_empty() {
         return Ok(());
     }
+    for (axis, value) in moves {
+        let event = InputEvent::new(time, &EventCode::EV_REL(*axis), *value);
+        log::trace!("OUT: {:?}", event);
+        output.write_event(&event)?;
+    }
+    output.write_event(&InputEvent::new(
+        time,
+        &EventCode::EV_SYN(EV_SYN::SYN_REPORT),
+        0,
+    ))
+}
 
-    fn generate_sync_event(&self, time: &TimeVal) -> Result<()> {
-        self.output.write_event(&InputEvent::new(
-            time,
-            &EventCode::EV_SYN(evdev_rs::enums::EV_SYN::SYN_REPORT),
+/// Trace `event` going `direction`, "in" or "out", with its key, event
+/// type and time as fields for the JSON log format.
+fn trace_key_event(direction: &str, event: &EvKeyEvent) {
+    log::trace!(
+        direction,
+        key = key_name(event.ev_key).as_str(),
+        event_type:% = event.key_event_type,
+        time_us = event.time.tv_sec * 1_000_000 + event.time.tv_usec;
+        "{} {}",
+        direction.to_uppercase(),
+        event
+    );
+}
+
+/// Group `events` into the frames they go out in, ending a frame wherever
+/// their timestamp moves on or a separator pulse completes. The events of
+/// a chord, such as the Ctrl and C of a remap to Ctrl+C, share the time of
+/// the input event and thus a frame, so applications see them at once.
+fn frames(events: Vec<EvKeyEvent>) -> Vec<Vec<EvKeyEvent>> {
+    let mut frames: Vec<Vec<EvKeyEvent>> = vec![];
+    let mut frame_open = false;
+    for event in events {
+        let ends_frame =
+            event.ev_key == SEPARATOR_KEY && event.key_event_type == KeyEventType::Release;
+        match frames.last_mut() {
+            Some(frame) if frame_open && frame[0].time == event.time => frame.push(event),
+            _ => frames.push(vec![event]),
+        }
+        frame_open = !ends_frame;
+    }
+    frames
+}
+
+/// Write each of `frames` followed by a single SYN_REPORT. Frames stamped
+/// with a later time, such as the steps of a macro, are written that much
+/// later.
+fn write_frames(
+    output: &mut impl EventSink,
+    frames: Vec<Vec<EvKeyEvent>>,
+    values: &OutputValues,
+) -> Result<()> {
+    let mut previous: Option<TimeVal> = None;
+    for frame in frames {
+        let time = frame[0].time;
+        if let Some(previous) = previous.replace(time) {
+            std::thread::sleep(duration_between(&previous, &time));
+        }
+        for event in &frame {
+            trace_key_event("out", event);
+            output.write_event(&event.as_input_event(values))?;
+        }
+        output.write_event(&InputEvent::new(
+            &time,
+            &EventCode::EV_SYN(EV_SYN::SYN_REPORT),
             0,
         ))?;
+    }
+    Ok(())
+}
+
+/// `timeout` in milliseconds for poll(2), or -1 to wait indefinitely.
+/// It is rounded up, so that the timeout has passed once poll returns.
+fn poll_timeout_ms(timeout: Option<Duration>) -> libc::c_int {
+    timeout.map_or(-1, |timeout| {
+        timeout
+            .as_micros()
+            .div_ceil(1000)
+            .min(libc::c_int::MAX as u128) as libc::c_int
+    })
+}
+
+/// Wait up to `timeout` for `input` to have an event to read, returning
+/// whether it has one.
+fn wait_for_event(input: &Device, timeout: Option<Duration>) -> Result<bool> {
+    if input.has_event_pending() {
+        return Ok(true);
+    }
+    let mut fd = libc::pollfd {
+        fd: input.file().as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // Safety: fd is a single valid pollfd struct.
+    let ready = unsafe { libc::poll(&mut fd, 1, poll_timeout_ms(timeout)) };
+    if ready < 0 {
+        let err = std::io::Error::last_os_error();
+        if err.kind() == std::io::ErrorKind::Interrupted {
+            return Ok(false);
+        }
+        return Err(err).context("polling the input device");
+    }
+    Ok(ready > 0)
+}
+
+/// The current time on the clock of input event timestamps, which is the
+/// realtime clock unless a different one was asked for.
+fn now() -> TimeVal {
+    let since_epoch = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    TimeVal::new(
+        since_epoch.as_secs() as i64,
+        since_epoch.subsec_micros() as i64,
+    )
+}
+
+/// After the kernel dropped events, libevdev hands out the events that
+/// bring our view of the device up to date until it reports EAGAIN.
+/// Their key events go through `machine`, followed by the recovery hook.
+fn resync(
+    mut next_event: impl FnMut() -> ReadResult,
+    machine: &mut Machine,
+    hook: &RecoveryHook,
+) -> Result<Vec<EvKeyEvent>> {
+    let mut events = vec![];
+    let mut time = TimeVal::new(0, 0);
+    loop {
+        let next = next_event();
+        if let Err(err) = &next {
+            if err.raw_os_error() == Some(libc::EAGAIN) {
+                break;
+            }
+        }
+        let (_, event) = next?;
+        time = event.time;
+        if let EventCode::EV_KEY(key) = event.event_code {
+            log::trace!("SYNC IN {:?}", event);
+            events.extend(machine.insert(EvKeyEvent {
+                time: event.time,
+                ev_key: key,
+                key_event_type: KeyEventType::from_value(event.value),
+            }));
+        }
+    }
+    events.extend(hooks::fire(hook, Recovery::Resync, &time)?);
+    Ok(events)
+}
+
+fn all_leds() -> impl Iterator<Item = LedCode> {
+    (0..=libc::LED_MAX as u32).filter_map(int_to_ev_led)
+}
+
+/// Append the events that can be read from the uinput `fd` right now to
+/// `events`.
+fn read_uinput_events(fd: RawFd, events: &mut Vec<libc::input_event>) -> Result<()> {
+    const EVENT_SIZE: usize = std::mem::size_of::<libc::input_event>();
+    let mut buf = [0u8; EVENT_SIZE * 16];
+    loop {
+        // Safety: buf is valid for writes of buf.len() bytes.
+        let len = unsafe { libc::read(fd, buf.as_mut_ptr().cast(), buf.len()) };
+        if len < 0 {
+            let err = std::io::Error::last_os_error();
+            return match err.kind() {
+                std::io::ErrorKind::WouldBlock => Ok(()),
+                std::io::ErrorKind::Interrupted => continue,
+                _ => Err(err.into()),
+            };
+        }
+        if len == 0 {
+            return Ok(());
+        }
+        for chunk in buf[..len as usize].chunks_exact(EVENT_SIZE) {
+            // Safety: the kernel writes whole input_events, which any bytes
+            // make a valid one of.
+            events.push(unsafe { std::ptr::read_unaligned(chunk.as_ptr().cast()) });
+        }
+    }
+}
+
+/// Turn `leds` on and off as the EV_LED ones among `events` say, returning
+/// whether any of them changed.
+fn apply_led_events(leds: &mut HashSet<LedCode>, events: &[libc::input_event]) -> bool {
+    let mut changed = false;
+    for event in events {
+        if event.type_ != EventType::EV_LED as u16 {
+            continue;
+        }
+        let Some(led) = int_to_ev_led(event.code as u32) else {
+            continue;
+        };
+        changed |= if event.value != 0 {
+            leds.insert(led)
+        } else {
+            leds.remove(&led)
+        };
+    }
+    changed
+}
+
+fn set_leds(input: &Device, leds: &HashSet<LedCode>) {
+    for led in all_leds() {
+        if !input.has_event_code(&EventCode::EV_LED(led)) {
+            continue;
+        }
+        let state = if leds.contains(&led) {
+            LedState::On
+        } else {
+            LedState::Off
+        };
+        if let Err(err) = input.kernel_set_led_value(&EventCode::EV_LED(led), state) {
+            log::warn!("Failed to set {:?} on the input device: {}", led, err);
+        }
+    }
+}
+
+/// The `MSC_SCAN` that a keyboard sends ahead of each of its key events,
+/// held back until that key event shows whether the scancode still fits:
+/// it goes out ahead of a key that is passed on as it is, and is dropped
+/// for one that is remapped, as some applications would otherwise pair
+/// the old scancode with the new key.
+#[derive(Default)]
+struct PendingScan {
+    /// The held back `MSC_SCAN` and the input it came from.
+    scan: Option<(usize, InputEvent)>,
+}
+
+impl PendingScan {
+    /// Hold back `event` of input `idx` if it is an `MSC_SCAN`. Returns the
+    /// scan that was held before, unless `event` is the key event of the
+    /// same input that it goes with, for it to be passed on as it is.
+    fn hold(&mut self, idx: usize, event: &InputEvent) -> Option<InputEvent> {
+        let is_key_of_scan = matches!(event.event_code, EventCode::EV_KEY(_))
+            && self.scan.as_ref().is_some_and(|(from, _)| *from == idx);
+        if is_key_of_scan {
+            return None;
+        }
+        let held = self.scan.take().map(|(_, scan)| scan);
+        if event.event_code == EventCode::EV_MSC(EV_MSC::MSC_SCAN) {
+            self.scan = Some((idx, event.clone()));
+        }
+        held
+    }
+
+    /// The scan to write ahead of `output`, the remapping of `input` from
+    /// input `idx`: the held back scan if `input` is passed on unchanged.
+    fn take_for(
+        &mut self,
+        idx: usize,
+        input: &EvKeyEvent,
+        output: &[EvKeyEvent],
+    ) -> Option<InputEvent> {
+        let (from, scan) = self.scan.take()?;
+        if from == idx && output == std::slice::from_ref(input) {
+            Some(scan)
+        } else {
+            log::trace!("DROP {:?}, as its key was remapped", scan);
+            None
+        }
+    }
+}
+
+/// Write an event that isn't EV_KEY as is, unless `forward` is off, for
+/// example to silence the trackpad part of a combined device.
+fn pass_through(output: &mut impl EventSink, event: &InputEvent, forward: bool) -> Result<()> {
+    if forward {
+        log::trace!("PASSTHRU {:?}", event);
+        output.write_event(event)
+    } else {
+        log::trace!("DROP {:?}", event);
         Ok(())
     }
 }
+
+fn open_device(path: &Path) -> Result<Device> {
+    let f: std::fs::File = std::fs::File::open(path).map_err(|err| {
+        permission_error(
+            err,
+            format!("opening {}", path.display()),
+            uinput_accessible,
+        )
+    })?;
+    Device::new_from_file(f)
+        .with_context(|| format!("failed to create new Device from file {}", path.display()))
+}
+
+fn grab(input: &mut Device, path: &Path) -> Result<()> {
+    input
+        .grab(GrabMode::Grab)
+        .map_err(|err| grab_error(err, path))
+}
+
+fn grab_error(err: std::io::Error, path: &Path) -> Error {
+    let context = format!("grabbing exclusive access on {}", path.display());
+    if err.raw_os_error() == Some(libc::EBUSY) {
+        anyhow!(err).context(format!(
+            "{}: another process already has it grabbed, likely another \
+             evremap or remapper (see `fuser -v {}`); stop it and try again",
+            context,
+            path.display()
+        ))
+    } else {
+        anyhow!(err).context(context)
+    }
+}
+
+/// `err` with `context`, which, when it was a permission error, goes on
+/// to say what access evremap needs and how to grant it, and whether
+/// `/dev/uinput` is accessible as checked by `uinput_accessible`.
+fn permission_error(
+    err: std::io::Error,
+    context: String,
+    uinput_accessible: impl FnOnce() -> bool,
+) -> Error {
+    if err.kind() != std::io::ErrorKind::PermissionDenied {
+        return anyhow!(err).context(context);
+    }
+    let uinput = if uinput_accessible() {
+        format!("{} is accessible", UINPUT_PATH)
+    } else {
+        format!(
+            "{} is not accessible; a udev rule such as \
+             `KERNEL==\"uinput\", GROUP=\"input\", MODE=\"0660\"` in \
+             /etc/udev/rules.d/99-evremap.rules makes it so, after \
+             `sudo udevadm control --reload && sudo udevadm trigger`",
+            UINPUT_PATH
+        )
+    };
+    anyhow!(err).context(format!(
+        "{}: permission denied. evremap needs to read /dev/input/event* \
+         and write {}, so run it as root, or add yourself to the `input` \
+         group (`sudo usermod -aG input $USER`, then log in again). {}",
+        context, UINPUT_PATH, uinput
+    ))
+}
+
+/// Whether this process can open `/dev/uinput` to create devices.
+fn uinput_accessible() -> bool {
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(UINPUT_PATH)
+        .is_ok()
+}
+
+/// The keys that `map` may emit, which the virtual devices need to be
+/// capable of whether the inputs are or not.
+fn keys_in_mapping(map: &Mapping) -> Vec<KeyCode> {
+    let mut keys = vec![];
+    match map {
+        Mapping::DualRole { hold, tap, .. } => {
+            keys.extend(hold.iter().chain(tap));
+        }
+        Mapping::Remap { output, .. } | Mapping::Combo { output, .. } => {
+            keys.extend(output);
+        }
+        Mapping::Tiered { tiers } => {
+            keys.extend(tiers.iter().flat_map(|tier| &tier.output));
+        }
+        Mapping::KeyNameProbe { .. } => {
+            for c in "KEY_ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789".chars() {
+                if let Some((key, _)) = key_for_char(c) {
+                    keys.push(key);
+                }
+            }
+            keys.push(KeyCode::KEY_LEFTSHIFT);
+        }
+        Mapping::InvertModifier { modifier, .. } | Mapping::OneShot { modifier, .. } => {
+            keys.push(*modifier);
+        }
+        Mapping::Layer { .. } | Mapping::LayerLock { .. } | Mapping::Ignore { .. } => {}
+        Mapping::DeferredPassthrough { input, .. } => {
+            keys.push(*input);
+        }
+        Mapping::TapDance { taps, .. } => {
+            keys.extend(taps.iter().flatten());
+        }
+        Mapping::Macro { sequence, .. } => {
+            keys.extend(sequence.iter().map(MacroStep::key));
+        }
+        Mapping::Unicode {
+            codepoint, method, ..
+        } => {
+            keys.extend(
+                unicode_steps(*codepoint, *method)
+                    .iter()
+                    .map(MacroStep::key),
+            );
+        }
+        Mapping::Text { text, .. } => {
+            keys.extend(text_steps(text).iter().map(MacroStep::key));
+        }
+    }
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remapper::sink::RecordingSink;
+    use crate::remapper::source::MemorySource;
+    use evdev_rs::enums::{EV_REL, EV_SYN};
+
+    #[test]
+    fn output_keys_the_inputs_lack_are_enabled_on_the_template() {
+        let config = MappingConfig {
+            mappings: vec![Mapping::Remap {
+                input: [KeyCode::KEY_F1].into(),
+                output: [KeyCode::KEY_MUTE].into(),
+                when: Conditions::default(),
+                on_release: false,
+            }],
+            ..Default::default()
+        };
+        let mut template = UninitDevice::new().unwrap();
+        enable_output_keys(&mut template, &[], &config, false).unwrap();
+        assert!(template.has_event_code(&EventCode::EV_KEY(KeyCode::KEY_MUTE)));
+    }
+
+    #[test]
+    fn mapped_keys_that_fail_to_enable_are_warned_about() {
+        let f5 = Mapping::Remap {
+            input: [KeyCode::KEY_F5].into(),
+            output: [KeyCode::KEY_BRIGHTNESSUP].into(),
+            when: Conditions::default(),
+            on_release: false,
+        };
+        let emitter = format!("the mapping of {}", mapping_input(&f5));
+        let mut mapped: Vec<(KeyCode, String)> = keys_in_mapping(&f5)
+            .into_iter()
+            .map(|key| (key, emitter.clone()))
+            .collect();
+        mapped.push((KeyCode::KEY_ESC, "the recovery_hook".to_string()));
+        let mut enabled = vec![];
+        let warnings = enable_mapped_keys(&mapped, |key| {
+            if key == KeyCode::KEY_BRIGHTNESSUP {
+                bail!("Invalid argument");
+            }
+            enabled.push(key);
+            Ok(())
+        });
+        assert_eq!(enabled, vec![KeyCode::KEY_ESC]);
+        assert_eq!(
+            warnings,
+            vec![
+                "KEY_BRIGHTNESSUP could not be enabled on the virtual devices, \
+                 so the mapping of KEY_F5 won't work: Invalid argument"
+                    .to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn grabbing_a_busy_device_says_why() {
+        let path = Path::new("/dev/input/event3");
+        let busy = grab_error(std::io::Error::from_raw_os_error(libc::EBUSY), path);
+        assert!(busy
+            .to_string()
+            .contains("another process already has it grabbed"));
+        let denied = grab_error(std::io::Error::from_raw_os_error(libc::EACCES), path);
+        assert_eq!(
+            denied.to_string(),
+            "grabbing exclusive access on /dev/input/event3"
+        );
+    }
+
+    #[test]
+    fn permission_errors_say_how_to_grant_access() {
+        let denied = || std::io::Error::from_raw_os_error(libc::EACCES);
+        let context = || "opening /dev/input/event3".to_string();
+        let message = permission_error(denied(), context(), || true).to_string();
+        assert!(message.starts_with("opening /dev/input/event3: permission denied"));
+        assert!(message.contains("`input` group"));
+        assert!(message.ends_with("/dev/uinput is accessible"));
+        assert!(permission_error(denied(), context(), || false)
+            .to_string()
+            .contains("udev rule"));
+
+        let missing = std::io::Error::from_raw_os_error(libc::ENOENT);
+        assert_eq!(
+            permission_error(missing, context(), || unreachable!()).to_string(),
+            "opening /dev/input/event3"
+        );
+    }
+
+    #[test]
+    fn scans_go_out_only_ahead_of_keys_passed_on_as_they_are() {
+        let time = TimeVal::new(0, 0);
+        let scan = InputEvent::new(&time, &EventCode::EV_MSC(EV_MSC::MSC_SCAN), 0x70004);
+        let key_input = |ev_key| InputEvent::new(&time, &EventCode::EV_KEY(ev_key), 1);
+        let key_event = |ev_key| EvKeyEvent {
+            time,
+            ev_key,
+            key_event_type: KeyEventType::Press,
+        };
+        let mut pending = PendingScan::default();
+
+        assert_eq!(pending.hold(0, &scan), None);
+        assert_eq!(pending.hold(0, &key_input(KeyCode::KEY_A)), None);
+        let a = key_event(KeyCode::KEY_A);
+        assert_eq!(
+            pending.take_for(0, &a, std::slice::from_ref(&a)),
+            Some(scan.clone())
+        );
+
+        pending.hold(0, &scan);
+        pending.hold(0, &key_input(KeyCode::KEY_A));
+        assert_eq!(pending.take_for(0, &a, &[key_event(KeyCode::KEY_B)]), None);
+
+        // A scan with no key after it goes out before the next event.
+        pending.hold(0, &scan);
+        let sync = InputEvent::new(&time, &EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0);
+        assert_eq!(pending.hold(0, &sync), Some(scan.clone()));
+        assert_eq!(pending.take_for(0, &a, std::slice::from_ref(&a)), None);
+    }
+
+    #[test]
+    fn read_loop_remaps_keys_and_passes_on_other_events() {
+        let config = MappingConfig {
+            mappings: vec![Mapping::Remap {
+                input: [KeyCode::KEY_CAPSLOCK].into(),
+                output: [KeyCode::KEY_ESC].into(),
+                when: Conditions::default(),
+                on_release: false,
+            }],
+            ..Default::default()
+        };
+        let time = TimeVal::new(1, 0);
+        let event = |code, value| InputEvent::new(&time, &code, value);
+        let syn = EventCode::EV_SYN(EV_SYN::SYN_REPORT);
+        let scan = EventCode::EV_MSC(EV_MSC::MSC_SCAN);
+        let mut source = MemorySource::new([
+            event(scan, 0x3a),
+            event(EventCode::EV_KEY(KeyCode::KEY_CAPSLOCK), 1),
+            event(syn, 0),
+            event(EventCode::EV_REL(EV_REL::REL_X), 3),
+            event(syn, 0),
+            event(scan, 0x1e),
+            event(EventCode::EV_KEY(KeyCode::KEY_A), 1),
+            event(syn, 0),
+        ]);
+        let mut machines = DeviceMachines::from_config(&config, 1);
+        let mut rel_machine = RelMachine::from_config(&config);
+        let mut pending_scan = PendingScan::default();
+        while let Some((idx, read)) = source.read_event(None).unwrap() {
+            let (_, event) = read.unwrap();
+            remap_event(
+                &mut source,
+                idx,
+                &event,
+                &config,
+                &mut machines,
+                &mut rel_machine,
+                &mut pending_scan,
+                None,
+            )
+            .unwrap();
+        }
+        let keys: Vec<_> = source
+            .written_keys
+            .iter()
+            .map(|e| (e.ev_key, e.key_event_type))
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                (KeyCode::KEY_ESC, KeyEventType::Press),
+                (KeyCode::KEY_A, KeyEventType::Press),
+            ]
+        );
+        // The scancode of the remapped key is dropped.
+        let codes: Vec<_> = source.written.iter().map(|e| e.event_code).collect();
+        assert_eq!(
+            codes,
+            vec![syn, EventCode::EV_REL(EV_REL::REL_X), syn, scan, syn]
+        );
+    }
+
+    #[test]
+    fn chords_go_out_in_a_frame_of_their_own_with_one_sync() {
+        let event = |usec, ev_key, key_event_type| EvKeyEvent {
+            time: TimeVal::new(1, usec),
+            ev_key,
+            key_event_type,
+        };
+        use KeyEventType::{Press, Release};
+        let events = vec![
+            event(0, KeyCode::KEY_LEFTCTRL, Press),
+            event(0, KeyCode::KEY_C, Press),
+            // The steps of a macro.
+            event(10, KeyCode::KEY_C, Release),
+            event(20, KeyCode::KEY_LEFTCTRL, Release),
+            // A separator pulse between two taps of the same key.
+            event(30, SEPARATOR_KEY, Press),
+            event(30, SEPARATOR_KEY, Release),
+            event(30, KeyCode::KEY_ESC, Press),
+        ];
+        let grouped = frames(events.clone());
+        assert_eq!(
+            grouped,
+            vec![
+                events[0..2].to_vec(),
+                events[2..3].to_vec(),
+                events[3..4].to_vec(),
+                events[4..6].to_vec(),
+                events[6..7].to_vec(),
+            ]
+        );
+        assert_eq!(frames(vec![]), Vec::<Vec<EvKeyEvent>>::new());
+
+        let mut sink = RecordingSink::default();
+        write_frames(&mut sink, grouped, &OutputValues::default()).unwrap();
+        let syn = EventCode::EV_SYN(EV_SYN::SYN_REPORT);
+        let codes: Vec<_> = sink.events.iter().map(|e| e.event_code).collect();
+        assert_eq!(
+            codes,
+            vec![
+                EventCode::EV_KEY(KeyCode::KEY_LEFTCTRL),
+                EventCode::EV_KEY(KeyCode::KEY_C),
+                syn,
+                EventCode::EV_KEY(KeyCode::KEY_C),
+                syn,
+                EventCode::EV_KEY(KeyCode::KEY_LEFTCTRL),
+                syn,
+                EventCode::EV_KEY(SEPARATOR_KEY),
+                EventCode::EV_KEY(SEPARATOR_KEY),
+                syn,
+                EventCode::EV_KEY(KeyCode::KEY_ESC),
+                syn,
+            ]
+        );
+    }
+
+    #[test]
+    fn led_events_read_from_uinput_turn_leds_on_and_off() {
+        let event = |type_: u16, code: u16, value| libc::input_event {
+            time: libc::timeval {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+            type_,
+            code,
+            value,
+        };
+        let ev_led = EventType::EV_LED as u16;
+        let numl = LedCode::LED_NUML as u16;
+        let mut leds = HashSet::from([LedCode::LED_CAPSL]);
+        assert!(apply_led_events(
+            &mut leds,
+            &[
+                event(ev_led, numl, 1),
+                event(ev_led, LedCode::LED_CAPSL as u16, 0),
+                event(EventType::EV_SYN as u16, 0, 0),
+            ]
+        ));
+        assert_eq!(leds, HashSet::from([LedCode::LED_NUML]));
+        assert!(!apply_led_events(&mut leds, &[event(ev_led, numl, 1)]));
+    }
+
+    #[test]
+    fn non_key_events_are_dropped_unless_forwarded() {
+        let event = InputEvent::new(&TimeVal::new(0, 0), &EventCode::EV_REL(EV_REL::REL_X), 3);
+        let mut sink = RecordingSink::default();
+        pass_through(&mut sink, &event, false).unwrap();
+        assert!(sink.events.is_empty());
+        pass_through(&mut sink, &event, true).unwrap();
+        assert_eq!(sink.events, vec![event]);
+    }
+
+    #[test]
+    fn describes_input_events_with_raw_value() {
+        let time = TimeVal::new(12, 3400);
+        let event = |code, value| InputEvent::new(&time, &code, value);
+        assert_eq!(
+            describe_input(&event(EventCode::EV_KEY(KeyCode::KEY_VOLUMEUP), 1)).as_deref(),
+            Some("12.003400 KEY_VOLUMEUP Press (value 1)")
+        );
+        assert_eq!(
+            describe_input(&event(EventCode::EV_KEY(KeyCode::KEY_A), 7)).as_deref(),
+            Some("12.003400 KEY_A Unknown(7) (value 7)")
+        );
+        assert_eq!(
+            describe_input(&event(EventCode::EV_REL(EV_REL::REL_X), -3)).as_deref(),
+            Some("12.003400 REL_X (value -3)")
+        );
+        assert_eq!(
+            describe_input(&event(
+                EventCode::EV_SYN(evdev_rs::enums::EV_SYN::SYN_REPORT),
+                0
+            )),
+            None
+        );
+    }
+
+    #[test]
+    fn resync_fires_recovery_hook_once() {
+        let status_file =
+            std::env::temp_dir().join(format!("evremap-recovery-{}", std::process::id()));
+        let _ = std::fs::remove_file(&status_file);
+        let hook = RecoveryHook {
+            key: Some(KeyCode::KEY_F24),
+            status_file: Some(status_file.clone()),
+        };
+        let mut machine = Machine::new(&[]);
+        let run_resync = |machine: &mut Machine| {
+            let mut pending = vec![InputEvent::new(
+                &TimeVal::new(1, 0),
+                &EventCode::EV_KEY(KeyCode::KEY_A),
+                1,
+            )];
+            resync(
+                || match pending.pop() {
+                    Some(event) => std::io::Result::Ok((evdev_rs::ReadStatus::Sync, event)),
+                    None => Err(std::io::Error::from_raw_os_error(libc::EAGAIN)),
+                },
+                machine,
+                &hook,
+            )
+            .unwrap()
+        };
+
+        let events = run_resync(&mut machine);
+        let keys: Vec<(KeyCode, KeyEventType)> = events
+            .iter()
+            .map(|e| (e.ev_key, e.key_event_type))
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                (KeyCode::KEY_A, KeyEventType::Press),
+                (KeyCode::KEY_F24, KeyEventType::Press),
+                (KeyCode::KEY_F24, KeyEventType::Release),
+            ]
+        );
+        assert_eq!(std::fs::read_to_string(&status_file).unwrap(), "resync 1\n");
+
+        run_resync(&mut machine);
+        assert_eq!(
+            std::fs::read_to_string(&status_file).unwrap(),
+            "resync 1\nresync 1\n"
+        );
+        std::fs::remove_file(&status_file).unwrap();
+    }
+}
@@ -1,35 +1,44 @@
 use super::types::{EvKeyEvent, KeyEventType};
 use crate::mapping::*;
 use evdev_rs::TimeVal;
-use std::cmp::Ordering;
-use std::collections::HashSet;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
 
-fn is_modifier(key: &KeyCode) -> bool {
-    match key {
-        KeyCode::KEY_FN
-        | KeyCode::KEY_LEFTALT
-        | KeyCode::KEY_RIGHTALT
-        | KeyCode::KEY_LEFTMETA
-        | KeyCode::KEY_RIGHTMETA
-        | KeyCode::KEY_LEFTCTRL
-        | KeyCode::KEY_RIGHTCTRL
-        | KeyCode::KEY_LEFTSHIFT
-        | KeyCode::KEY_RIGHTSHIFT => true,
-        _ => false,
+/// The order that modifiers are pressed in, and released in reverse,
+/// whatever order a mapping lists them in, as some applications care
+/// whether Ctrl or Shift arrives first. Other configured modifiers come
+/// after these, by key code.
+const MODIFIER_ORDER: [KeyCode; 9] = [
+    KeyCode::KEY_LEFTCTRL,
+    KeyCode::KEY_RIGHTCTRL,
+    KeyCode::KEY_LEFTSHIFT,
+    KeyCode::KEY_RIGHTSHIFT,
+    KeyCode::KEY_LEFTALT,
+    KeyCode::KEY_RIGHTALT,
+    KeyCode::KEY_LEFTMETA,
+    KeyCode::KEY_RIGHTMETA,
+    KeyCode::KEY_FN,
+];
+
+fn modifier_rank(key: &KeyCode) -> (usize, u32) {
+    match MODIFIER_ORDER.iter().position(|modifier| modifier == key) {
+        Some(position) => (position, 0),
+        None => (MODIFIER_ORDER.len(), *key as u32),
     }
 }
 
-/// Orders modifier keys ahead of non-modifier keys.
+/// Orders modifier keys ahead of non-modifier keys, and modifiers among
+/// themselves by `MODIFIER_ORDER`.
 /// Unfortunately the underlying type doesn't allow direct
 /// comparison, but that's ok for our purposes.
-fn modifiers_first(a: &KeyCode, b: &KeyCode) -> Ordering {
-    if is_modifier(a) {
-        if is_modifier(b) {
-            Ordering::Equal
+pub fn modifiers_first(modifiers: &Modifiers, a: &KeyCode, b: &KeyCode) -> Ordering {
+    if modifiers.contains(a) {
+        if modifiers.contains(b) {
+            modifier_rank(a).cmp(&modifier_rank(b))
         } else {
             Ordering::Less
         }
-    } else if is_modifier(b) {
+    } else if modifiers.contains(b) {
         Ordering::Greater
     } else {
         // Neither are modifiers
@@ -37,37 +46,320 @@ fn modifiers_first(a: &KeyCode, b: &KeyCode) -> Ordering {
     }
 }
 
-fn modifiers_last(a: &KeyCode, b: &KeyCode) -> Ordering {
-    modifiers_first(a, b).reverse()
+pub fn modifiers_last(modifiers: &Modifiers, a: &KeyCode, b: &KeyCode) -> Ordering {
+    modifiers_first(modifiers, a, b).reverse()
+}
+
+/// Caps how many mappings may be evaluated for a single event, so that
+/// latency stays bounded even with a huge config.
+#[derive(Debug, Clone, Default)]
+pub struct EvaluationBudget {
+    /// Maximum number of mappings to evaluate per event, if any.
+    limit: Option<usize>,
+    /// Number of times evaluation was cut short by the limit.
+    hits: usize,
+}
+
+impl EvaluationBudget {
+    pub fn new(limit: Option<usize>) -> Self {
+        EvaluationBudget { limit, hits: 0 }
+    }
+
+    /// Returns whether another mapping may be looked at after `evaluated`
+    /// were for this event. Records (and warns about, the first time) a
+    /// budget hit.
+    fn allows(&mut self, evaluated: usize) -> bool {
+        match self.limit {
+            Some(limit) if evaluated >= limit => {
+                if self.hits == 0 {
+                    log::warn!(
+                        "Evaluation budget of {} mappings per event was hit; \
+                         remaining mappings are ignored. Your config is too \
+                         large for the configured evaluation_budget.",
+                        limit
+                    );
+                }
+                self.hits += 1;
+                false
+            }
+            _ => true,
+        }
+    }
+}
+
+/// Put `mappings` in the order that `strategy` evaluates them in, which
+/// `apply_mapping_to_held_keys` and `lookup_mapping` then follow.
+pub fn order_mappings(mappings: &mut [Mapping], strategy: MatchStrategy) {
+    if strategy == MatchStrategy::LongestMatch {
+        let specificity = |mapping: &Mapping| match mapping {
+            Mapping::Remap { input, when, .. } => {
+                let conditions = when.layers.len()
+                    + when.leds.len()
+                    + usize::from(when.idle_ms.is_some())
+                    + when.unless.len()
+                    + usize::from(!when.apps.is_empty());
+                (conditions, input.len())
+            }
+            Mapping::Tiered { tiers } => (0, tiers.first().map_or(0, |tier| tier.input.len())),
+            _ => (0, 0),
+        };
+        mappings.sort_by_key(|mapping| std::cmp::Reverse(specificity(mapping)));
+    }
+}
+
+/// Which mappings could apply given a held key, so that evaluating an
+/// event only looks at the mappings that involve the keys at hand rather
+/// than at all of them. Mappings are referred to by their position, and
+/// are still evaluated in that order.
+#[derive(Debug, Default)]
+pub struct MappingIndex {
+    /// The remaps and tiered mappings that require each key to be held.
+    by_key: HashMap<KeyCode, Vec<usize>>,
+    /// The remaps and tiered mappings that require no key at all.
+    unkeyed: Vec<usize>,
+    dual_roles: Vec<usize>,
+    ignores: Vec<usize>,
+}
+
+impl MappingIndex {
+    pub fn new(mappings: &[Mapping]) -> Self {
+        let mut index = MappingIndex::default();
+        for (idx, mapping) in mappings.iter().enumerate() {
+            let input = match mapping {
+                Mapping::Remap { input, .. } => input,
+                // Later tiers only apply along with the first one.
+                Mapping::Tiered { tiers } => match tiers.first() {
+                    Some(tier) => &tier.input,
+                    None => continue,
+                },
+                Mapping::DualRole { .. } => {
+                    index.dual_roles.push(idx);
+                    continue;
+                }
+                Mapping::Ignore { .. } => {
+                    index.ignores.push(idx);
+                    continue;
+                }
+                _ => continue,
+            };
+            if input.is_empty() {
+                index.unkeyed.push(idx);
+            }
+            for key in input {
+                index.by_key.entry(*key).or_default().push(idx);
+            }
+        }
+        index
+    }
+
+    /// An index that has every mapping evaluated for every event, as
+    /// though there were no index.
+    #[cfg(any(test, feature = "bench"))]
+    pub fn exhaustive(mappings: &[Mapping]) -> Self {
+        MappingIndex {
+            by_key: HashMap::new(),
+            unkeyed: (0..mappings.len()).collect(),
+            dual_roles: (0..mappings.len()).collect(),
+            ignores: (0..mappings.len()).collect(),
+        }
+    }
+
+    fn requiring(&self, key: &KeyCode) -> &[usize] {
+        self.by_key.get(key).map_or(&[], Vec::as_slice)
+    }
+}
+
+/// Buffers that computing the keys to emit reuses from one event to the
+/// next, so that steady-state typing doesn't allocate once they have grown
+/// to fit.
+#[derive(Debug, Default)]
+pub struct Scratch {
+    /// The keys that should be held in the output.
+    keys: HashSet<KeyCode>,
+    output_modifiers: HashSet<KeyCode>,
+    tier_held: HashSet<KeyCode>,
+    tier_required: HashSet<KeyCode>,
+    to_release: Vec<KeyCode>,
+    to_press: Vec<KeyCode>,
+    /// Positions of the mappings left to evaluate, smallest first.
+    candidates: BinaryHeap<Reverse<usize>>,
+    /// Positions of the on-release remaps that applied.
+    on_release: Vec<usize>,
+    /// Positions of the mappings that applied.
+    #[cfg(feature = "metrics")]
+    applied: Vec<usize>,
+}
+
+impl Scratch {
+    /// Positions of the on-release remaps that applied to the held keys
+    /// last time, whose output is yet to be tapped.
+    pub fn on_release(&self) -> &[usize] {
+        &self.on_release
+    }
+
+    /// Positions of the mappings that applied to the held keys last time.
+    #[cfg(feature = "metrics")]
+    pub fn applied(&self) -> &[usize] {
+        &self.applied
+    }
 }
 
-fn apply_mapping_to_held_keys(
-    mappings: &Vec<Mapping>,
-    currently_pressed_keys: &HashSet<KeyCode>,
-) -> HashSet<KeyCode> {
-    log::trace!("currently_pressed_keys: {:?}", currently_pressed_keys);
+/// Apply `mappings` to the held keys of `context`, leaving the keys that
+/// should be held in the output in `scratch.keys`.
+///
+/// Only the mappings that `index` lists for keys held at some point are
+/// evaluated: those of the initially held keys, and those of the keys
+/// that applied mappings output in turn, in order of their position.
+pub fn apply_mapping_to_held_keys(
+    mappings: &[Mapping],
+    index: &MappingIndex,
+    modifiers: &Modifiers,
+    context: &MappingContext,
+    budget: &mut EvaluationBudget,
+    scratch: &mut Scratch,
+) {
+    log::trace!("currently_pressed_keys: {:?}", context.held);
+    let Scratch {
+        keys,
+        output_modifiers,
+        tier_held,
+        tier_required,
+        candidates,
+        on_release,
+        ..
+    } = scratch;
+    #[cfg(feature = "metrics")]
+    scratch.applied.clear();
+    on_release.clear();
     // Start with the input keys
-    let mut keys: HashSet<KeyCode> = currently_pressed_keys.clone();
+    keys.clear();
+    keys.extend(context.held.iter().copied());
+
+    // Ignored keys are as good as not held.
+    for &idx in &index.ignores {
+        if let Mapping::Ignore { input } = &mappings[idx] {
+            keys.remove(input);
+        }
+    }
+
+    // DualRole mappings go first as they are likely used to
+    // produce modifiers when held.
+    for &idx in &index.dual_roles {
+        if let Mapping::DualRole { input, hold, .. } = &mappings[idx] {
+            if keys.remove(input) {
+                keys.extend(hold.iter().copied());
+            }
+        }
+    }
+
+    candidates.clear();
+    candidates.extend(index.unkeyed.iter().copied().map(Reverse));
+    for key in keys.iter() {
+        candidates.extend(index.requiring(key).iter().copied().map(Reverse));
+    }
 
     // Arash note: I removed the variable "keys_minus_remapped". Having it caused too early "releases" of modifier keys to be emitted.
-    for Mapping::Remap { input, output } in mappings {
-        if input.is_subset(&keys) {
-            for i in input {
-                if !is_modifier(i) {
-                    keys.remove(i);
-                }
+    output_modifiers.clear();
+    let mut previous = None;
+    let mut evaluated = 0;
+    while let Some(Reverse(idx)) = candidates.pop() {
+        if previous.is_some_and(|previous| idx <= previous) {
+            // Listed for more than one key.
+            continue;
+        }
+        previous = Some(idx);
+        if !budget.allows(evaluated) {
+            break;
+        }
+        evaluated += 1;
+        let mapping = &mappings[idx];
+        match mapping {
+            Mapping::Remap {
+                input,
+                on_release: true,
+                ..
+            } if mapping.matches(&context.with_held(keys)) => {
+                // Its output is tapped once the input is released, so
+                // until then the input is all it does.
+                apply_remap(modifiers, keys, output_modifiers, input, &HashSet::new());
+                on_release.push(idx);
+                #[cfg(feature = "metrics")]
+                scratch.applied.push(idx);
+            }
+            Mapping::Remap { input, output, .. } if mapping.matches(&context.with_held(keys)) => {
+                apply_remap(modifiers, keys, output_modifiers, input, output);
+                queue_later_mappings(index, candidates, idx, output);
+                #[cfg(feature = "metrics")]
+                scratch.applied.push(idx);
             }
-            for o in output {
-                // Outputs that apply are not visible as
-                // inputs for later remap rules
-                if !is_modifier(o) {
-                    keys.insert(o.clone());
+            Mapping::Tiered { tiers } => {
+                // Each tier requires the inputs of all the tiers before it,
+                // so check against the keys as they were before any tier
+                // of this mapping replaced its inputs.
+                tier_held.clone_from(keys);
+                tier_required.clear();
+                for tier in tiers {
+                    tier_required.extend(tier.input.iter().copied());
+                    if !tier_required.is_subset(tier_held) {
+                        break;
+                    }
+                    apply_remap(modifiers, keys, output_modifiers, &tier.input, &tier.output);
+                    queue_later_mappings(index, candidates, idx, &tier.output);
+                }
+                #[cfg(feature = "metrics")]
+                if tiers
+                    .first()
+                    .is_some_and(|tier| tier.input.is_subset(tier_held))
+                {
+                    scratch.applied.push(idx);
                 }
             }
+            _ => {}
         }
     }
 
-    keys
+    keys.extend(output_modifiers.iter().copied());
+}
+
+/// Add the mappings after position `idx` that involve keys of `output`
+/// to `candidates`, as the output of a mapping may be the input of a later
+/// one.
+fn queue_later_mappings(
+    index: &MappingIndex,
+    candidates: &mut BinaryHeap<Reverse<usize>>,
+    idx: usize,
+    output: &HashSet<KeyCode>,
+) {
+    for key in output {
+        let later = index.requiring(key).iter().filter(|&&later| later > idx);
+        candidates.extend(later.copied().map(Reverse));
+    }
+}
+
+/// Replace the non-modifier `input` keys with `output`. Modifiers in the
+/// output, like the Alt of a mouse button mapped to Alt+Left, are added to
+/// `output_modifiers` instead of `keys`.
+fn apply_remap(
+    modifiers: &Modifiers,
+    keys: &mut HashSet<KeyCode>,
+    output_modifiers: &mut HashSet<KeyCode>,
+    input: &HashSet<KeyCode>,
+    output: &HashSet<KeyCode>,
+) {
+    for i in input {
+        if !modifiers.contains(i) {
+            keys.remove(i);
+        }
+    }
+    for o in output {
+        // Outputs that apply are not visible as
+        // inputs for later remap rules
+        if modifiers.contains(o) {
+            output_modifiers.insert(*o);
+        } else {
+            keys.insert(*o);
+        }
+    }
 }
 
 /// Compute the difference between our desired set of keys
@@ -82,48 +374,174 @@ fn apply_mapping_to_held_keys(
 ///
 /// Similarly, when pressing, emit modifiers first so that
 /// we don't emit C and then CTRL for such a mapping.
+#[allow(clippy::too_many_arguments)]
 pub fn compute_keys_based_on_state(
-    mappings: &Vec<Mapping>,
-    currently_pressed_keys: &HashSet<KeyCode>,
+    mappings: &[Mapping],
+    index: &MappingIndex,
+    modifiers: &Modifiers,
+    context: &MappingContext,
     output_keys: &HashSet<KeyCode>,
     time: &TimeVal,
+    budget: &mut EvaluationBudget,
+    scratch: &mut Scratch,
 ) -> Vec<EvKeyEvent> {
-    let desired_keys = apply_mapping_to_held_keys(mappings, currently_pressed_keys);
-    let mut to_release: Vec<KeyCode> = output_keys.difference(&desired_keys).cloned().collect();
-    let mut to_press: Vec<KeyCode> = desired_keys.difference(&output_keys).cloned().collect();
+    apply_mapping_to_held_keys(mappings, index, modifiers, context, budget, scratch);
+    let Scratch {
+        keys: desired_keys,
+        to_release,
+        to_press,
+        ..
+    } = scratch;
+    to_release.clear();
+    to_release.extend(output_keys.difference(desired_keys).copied());
+    to_press.clear();
+    to_press.extend(desired_keys.difference(output_keys).copied());
 
-    to_release.sort_by(modifiers_last);
-    to_press.sort_by(modifiers_first);
+    to_release.sort_by(|a, b| modifiers_last(modifiers, a, b));
+    to_press.sort_by(|a, b| modifiers_first(modifiers, a, b));
 
-    let release_events = to_release.iter().map(|ev_key| EvKeyEvent {
-        time: time.clone(),
-        ev_key: ev_key.clone(),
-        key_event_type: KeyEventType::Release,
-    });
-    let press_events = to_press.iter().map(|ev_key| EvKeyEvent {
-        time: time.clone(),
-        ev_key: ev_key.clone(),
-        key_event_type: KeyEventType::Press,
-    });
+    let event = |ev_key: &KeyCode, key_event_type| EvKeyEvent {
+        time: *time,
+        ev_key: *ev_key,
+        key_event_type,
+    };
+    let mut events = Vec::with_capacity(to_release.len() + to_press.len());
+    events.extend(
+        to_release
+            .iter()
+            .map(|key| event(key, KeyEventType::Release)),
+    );
+    events.extend(to_press.iter().map(|key| event(key, KeyEventType::Press)));
+    events
+}
 
-    release_events.chain(press_events).collect()
+/// The keys that holding `pressed_keys` emits under `mappings`, in the
+/// order they are pressed in: modifiers first. Nothing is held beforehand,
+/// no layer, mode or LED is active, and the modifiers are the default
+/// ones, so this answers "what does this chord do?" without a device or a
+/// `Machine`.
+///
+/// Only remaps, tiered mappings and ignores take part; the mappings that
+/// depend on timing or on what happened before, such as dual roles and
+/// combos, don't.
+pub fn resolve(mappings: &[Mapping], pressed_keys: &HashSet<KeyCode>) -> Vec<KeyCode> {
+    let active = ActiveState::default();
+    compute_keys_based_on_state(
+        mappings,
+        &MappingIndex::new(mappings),
+        &Modifiers::default(),
+        &MappingContext::new(pressed_keys, &active),
+        &HashSet::new(),
+        &TimeVal::new(0, 0),
+        &mut EvaluationBudget::default(),
+        &mut Scratch::default(),
+    )
+    .into_iter()
+    .map(|event| event.ev_key)
+    .collect()
 }
 
+/// The first remap of `code` that matches, which is also the longest one
+/// when the mappings were ordered for `MatchStrategy::LongestMatch`.
 pub fn lookup_mapping<'a>(
-    mappings: &'a Vec<Mapping>,
-    currently_pressed_keys: &HashSet<KeyCode>,
+    mappings: &'a [Mapping],
+    index: &MappingIndex,
+    context: &MappingContext,
     code: KeyCode,
+    budget: &mut EvaluationBudget,
 ) -> Option<&'a Mapping> {
-    // Arash note: I changed the original logic to a simple linear search. We prioritize the first match rather than the one with the most matching "input".
-    mappings.iter().find(|Mapping::Remap { input, .. }| {
-        input.contains(&code) && currently_pressed_keys.is_superset(input)
-    })
+    lookup_mapping_position(mappings, index, context, code, budget).map(|idx| &mappings[idx])
+}
+
+/// The position of the mapping that `lookup_mapping` finds.
+pub fn lookup_mapping_position(
+    mappings: &[Mapping],
+    index: &MappingIndex,
+    context: &MappingContext,
+    code: KeyCode,
+    budget: &mut EvaluationBudget,
+) -> Option<usize> {
+    // Only the remaps that `code` is an input of are searched, in the order
+    // of `mappings`, as `index.requiring(&code)` lists them. We prioritize
+    // the first match rather than the one with the most matching "input".
+    for (evaluated, &idx) in index.requiring(&code).iter().enumerate() {
+        if !budget.allows(evaluated) {
+            return None;
+        }
+        let mapping = &mappings[idx];
+        if let Mapping::Remap { .. } = mapping {
+            if mapping.matches(context) {
+                return Some(idx);
+            }
+        }
+    }
+    None
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::collections::HashSet;
+    use std::sync::LazyLock;
+
+    static NOTHING_ACTIVE: LazyLock<ActiveState> = LazyLock::new(ActiveState::default);
+
+    fn apply_mapping_to_held_keys(
+        mappings: &[Mapping],
+        modifiers: &Modifiers,
+        context: &MappingContext,
+        budget: &mut EvaluationBudget,
+    ) -> HashSet<KeyCode> {
+        let mut scratch = Scratch::default();
+        super::apply_mapping_to_held_keys(
+            mappings,
+            &MappingIndex::new(mappings),
+            modifiers,
+            context,
+            budget,
+            &mut scratch,
+        );
+        scratch.keys
+    }
+
+    fn compute_keys_based_on_state(
+        mappings: &[Mapping],
+        modifiers: &Modifiers,
+        context: &MappingContext,
+        output_keys: &HashSet<KeyCode>,
+        time: &TimeVal,
+        budget: &mut EvaluationBudget,
+    ) -> Vec<EvKeyEvent> {
+        super::compute_keys_based_on_state(
+            mappings,
+            &MappingIndex::new(mappings),
+            modifiers,
+            context,
+            output_keys,
+            time,
+            budget,
+            &mut Scratch::default(),
+        )
+    }
+
+    fn lookup_mapping<'a>(
+        mappings: &'a [Mapping],
+        context: &MappingContext,
+        code: KeyCode,
+        budget: &mut EvaluationBudget,
+    ) -> Option<&'a Mapping> {
+        super::lookup_mapping(
+            mappings,
+            &MappingIndex::new(mappings),
+            context,
+            code,
+            budget,
+        )
+    }
+
+    fn held(keys: &HashSet<KeyCode>) -> MappingContext<'_> {
+        MappingContext::new(keys, &NOTHING_ACTIVE)
+    }
 
     mod apply_mapping_to_held_keys {
         use super::*;
@@ -132,7 +550,12 @@ mod tests {
         fn apply_mapping_to_held_keys_no_mappings() {
             let input_state = HashSet::from([KeyCode::KEY_A, KeyCode::KEY_B]);
             let mappings = vec![];
-            let result = apply_mapping_to_held_keys(&mappings, &input_state);
+            let result = apply_mapping_to_held_keys(
+                &mappings,
+                &Modifiers::default(),
+                &held(&input_state),
+                &mut EvaluationBudget::default(),
+            );
             assert_eq!(result, input_state);
         }
 
@@ -142,8 +565,15 @@ mod tests {
             let mappings = vec![Mapping::Remap {
                 input: HashSet::from([KeyCode::KEY_A]),
                 output: HashSet::from([KeyCode::KEY_B]),
+                when: Conditions::default(),
+                on_release: false,
             }];
-            let result = apply_mapping_to_held_keys(&mappings, &input_state);
+            let result = apply_mapping_to_held_keys(
+                &mappings,
+                &Modifiers::default(),
+                &held(&input_state),
+                &mut EvaluationBudget::default(),
+            );
             let expected_output = HashSet::from([KeyCode::KEY_B]);
             assert_eq!(result, expected_output);
         }
@@ -154,8 +584,15 @@ mod tests {
             let mappings = vec![Mapping::Remap {
                 input: HashSet::from([KeyCode::KEY_C]),
                 output: HashSet::from([KeyCode::KEY_D]),
+                when: Conditions::default(),
+                on_release: false,
             }];
-            let result = apply_mapping_to_held_keys(&mappings, &input_state);
+            let result = apply_mapping_to_held_keys(
+                &mappings,
+                &Modifiers::default(),
+                &held(&input_state),
+                &mut EvaluationBudget::default(),
+            );
             assert_eq!(result, input_state);
         }
 
@@ -166,16 +603,139 @@ mod tests {
                 Mapping::Remap {
                     input: HashSet::from([KeyCode::KEY_A]),
                     output: HashSet::from([KeyCode::KEY_C]),
+                    when: Conditions::default(),
+                    on_release: false,
                 },
                 Mapping::Remap {
                     input: HashSet::from([KeyCode::KEY_B]),
                     output: HashSet::from([KeyCode::KEY_D]),
+                    when: Conditions::default(),
+                    on_release: false,
                 },
             ];
-            let result = apply_mapping_to_held_keys(&mappings, &input_state);
+            let result = apply_mapping_to_held_keys(
+                &mappings,
+                &Modifiers::default(),
+                &held(&input_state),
+                &mut EvaluationBudget::default(),
+            );
             let expected = HashSet::from([KeyCode::KEY_C, KeyCode::KEY_D]);
             assert_eq!(result, expected);
         }
+
+        #[test]
+        fn stops_applying_mappings_once_budget_is_exhausted() {
+            let input_state = HashSet::from([KeyCode::KEY_A, KeyCode::KEY_B, KeyCode::KEY_C]);
+            let mappings = vec![
+                Mapping::Remap {
+                    input: HashSet::from([KeyCode::KEY_A]),
+                    output: HashSet::from([KeyCode::KEY_1]),
+                    when: Conditions::default(),
+                    on_release: false,
+                },
+                Mapping::Remap {
+                    input: HashSet::from([KeyCode::KEY_B]),
+                    output: HashSet::from([KeyCode::KEY_2]),
+                    when: Conditions::default(),
+                    on_release: false,
+                },
+                Mapping::Remap {
+                    input: HashSet::from([KeyCode::KEY_C]),
+                    output: HashSet::from([KeyCode::KEY_3]),
+                    when: Conditions::default(),
+                    on_release: false,
+                },
+            ];
+            let mut budget = EvaluationBudget::new(Some(2));
+            let result = apply_mapping_to_held_keys(
+                &mappings,
+                &Modifiers::default(),
+                &held(&input_state),
+                &mut budget,
+            );
+            let expected = HashSet::from([KeyCode::KEY_1, KeyCode::KEY_2, KeyCode::KEY_C]);
+            assert_eq!(result, expected);
+            assert_eq!(budget.hits, 1);
+        }
+
+        #[test]
+        fn budget_is_not_hit_when_config_fits() {
+            let input_state = HashSet::from([KeyCode::KEY_A]);
+            let mappings = vec![Mapping::Remap {
+                input: HashSet::from([KeyCode::KEY_A]),
+                output: HashSet::from([KeyCode::KEY_1]),
+                when: Conditions::default(),
+                on_release: false,
+            }];
+            let mut budget = EvaluationBudget::new(Some(1));
+            apply_mapping_to_held_keys(
+                &mappings,
+                &Modifiers::default(),
+                &held(&input_state),
+                &mut budget,
+            );
+            assert_eq!(budget.hits, 0);
+        }
+
+        #[test]
+        fn tiered_mapping_adds_output_per_satisfied_tier() {
+            let mappings = vec![Mapping::Tiered {
+                tiers: vec![
+                    MappingTier {
+                        input: HashSet::from([KeyCode::KEY_LEFTCTRL]),
+                        output: HashSet::new(),
+                    },
+                    MappingTier {
+                        input: HashSet::from([KeyCode::KEY_LEFTALT]),
+                        output: HashSet::from([KeyCode::KEY_F13]),
+                    },
+                    MappingTier {
+                        input: HashSet::from([KeyCode::KEY_LEFTSHIFT]),
+                        output: HashSet::from([KeyCode::KEY_F14]),
+                    },
+                ],
+            }];
+            let apply = |keys: &[KeyCode]| {
+                apply_mapping_to_held_keys(
+                    &mappings,
+                    &Modifiers::default(),
+                    &held(&HashSet::from_iter(keys.iter().copied())),
+                    &mut EvaluationBudget::default(),
+                )
+            };
+
+            assert_eq!(
+                apply(&[KeyCode::KEY_LEFTCTRL]),
+                HashSet::from([KeyCode::KEY_LEFTCTRL])
+            );
+            assert_eq!(
+                apply(&[KeyCode::KEY_LEFTCTRL, KeyCode::KEY_LEFTALT]),
+                HashSet::from([
+                    KeyCode::KEY_LEFTCTRL,
+                    KeyCode::KEY_LEFTALT,
+                    KeyCode::KEY_F13
+                ])
+            );
+            assert_eq!(
+                apply(&[
+                    KeyCode::KEY_LEFTCTRL,
+                    KeyCode::KEY_LEFTALT,
+                    KeyCode::KEY_LEFTSHIFT
+                ]),
+                HashSet::from([
+                    KeyCode::KEY_LEFTCTRL,
+                    KeyCode::KEY_LEFTALT,
+                    KeyCode::KEY_LEFTSHIFT,
+                    KeyCode::KEY_F13,
+                    KeyCode::KEY_F14
+                ])
+            );
+            // A later tier doesn't apply without the earlier ones.
+            assert_eq!(
+                apply(&[KeyCode::KEY_LEFTALT, KeyCode::KEY_LEFTSHIFT]),
+                HashSet::from([KeyCode::KEY_LEFTALT, KeyCode::KEY_LEFTSHIFT])
+            );
+        }
     }
 
     mod lookup_mapping {
@@ -184,7 +744,12 @@ mod tests {
         fn should_return_none_when_mappings_list_is_empty() {
             let mappings = vec![];
             let currently_pressed_keys = HashSet::from([KeyCode::KEY_A, KeyCode::KEY_B]);
-            let result = lookup_mapping(&mappings, &currently_pressed_keys, KeyCode::KEY_A);
+            let result = lookup_mapping(
+                &mappings,
+                &held(&currently_pressed_keys),
+                KeyCode::KEY_A,
+                &mut EvaluationBudget::default(),
+            );
             assert!(result.is_none());
         }
 
@@ -193,9 +758,16 @@ mod tests {
             let mappings = vec![Mapping::Remap {
                 input: HashSet::from([KeyCode::KEY_A, KeyCode::KEY_B]),
                 output: HashSet::from([KeyCode::KEY_C]),
+                when: Conditions::default(),
+                on_release: false,
             }];
             let currently_pressed_keys = HashSet::from([KeyCode::KEY_A]);
-            let result = lookup_mapping(&mappings, &currently_pressed_keys, KeyCode::KEY_A);
+            let result = lookup_mapping(
+                &mappings,
+                &held(&currently_pressed_keys),
+                KeyCode::KEY_A,
+                &mut EvaluationBudget::default(),
+            );
             assert!(result.is_none());
         }
 
@@ -205,17 +777,662 @@ mod tests {
             let mappings = vec![Mapping::Remap {
                 input: HashSet::from([KeyCode::KEY_A, KeyCode::KEY_B]),
                 output: HashSet::from([KeyCode::KEY_C]),
+                when: Conditions::default(),
+                on_release: false,
             }];
             let currently_pressed_keys =
                 HashSet::from([KeyCode::KEY_A, KeyCode::KEY_B, KeyCode::KEY_D]);
-            let result = lookup_mapping(&mappings, &currently_pressed_keys, KeyCode::KEY_A);
+            let result = lookup_mapping(
+                &mappings,
+                &held(&currently_pressed_keys),
+                KeyCode::KEY_A,
+                &mut EvaluationBudget::default(),
+            );
             assert!(result.is_some());
             assert_eq!(
                 result.unwrap(),
                 &Mapping::Remap {
                     input: HashSet::from([KeyCode::KEY_A, KeyCode::KEY_B]),
                     output: HashSet::from([KeyCode::KEY_C]),
+                    when: Conditions::default(),
+                    on_release: false,
+                }
+            );
+        }
+
+        #[test]
+        fn should_return_none_when_match_lies_beyond_budget() {
+            let mappings = vec![
+                Mapping::Remap {
+                    input: HashSet::from([KeyCode::KEY_A]),
+                    output: HashSet::from([KeyCode::KEY_C]),
+                    when: Conditions {
+                        layers: vec!["nav".to_string()],
+                        ..Conditions::default()
+                    },
+                    on_release: false,
+                },
+                Mapping::Remap {
+                    input: HashSet::from([KeyCode::KEY_A]),
+                    output: HashSet::from([KeyCode::KEY_D]),
+                    when: Conditions::default(),
+                    on_release: false,
+                },
+            ];
+            let currently_pressed_keys = HashSet::from([KeyCode::KEY_A]);
+            let mut budget = EvaluationBudget::new(Some(1));
+            let result = lookup_mapping(
+                &mappings,
+                &held(&currently_pressed_keys),
+                KeyCode::KEY_A,
+                &mut budget,
+            );
+            assert!(result.is_none());
+            assert_eq!(budget.hits, 1);
+        }
+
+        #[test]
+        fn budget_counts_the_mappings_evaluated_not_their_position() {
+            let mut mappings: Vec<Mapping> = (0..5)
+                .map(|_| Mapping::Remap {
+                    input: HashSet::from([KeyCode::KEY_B]),
+                    output: HashSet::from([KeyCode::KEY_C]),
+                    when: Conditions::default(),
+                    on_release: false,
+                })
+                .collect();
+            mappings.push(Mapping::Remap {
+                input: HashSet::from([KeyCode::KEY_A]),
+                output: HashSet::from([KeyCode::KEY_D]),
+                when: Conditions::default(),
+                on_release: false,
+            });
+            let currently_pressed_keys = HashSet::from([KeyCode::KEY_A]);
+            let mut budget = EvaluationBudget::new(Some(1));
+            assert_eq!(
+                lookup_mapping(
+                    &mappings,
+                    &held(&currently_pressed_keys),
+                    KeyCode::KEY_A,
+                    &mut budget,
+                ),
+                Some(&mappings[5])
+            );
+            assert_eq!(
+                apply_mapping_to_held_keys(
+                    &mappings,
+                    &Modifiers::default(),
+                    &held(&currently_pressed_keys),
+                    &mut budget,
+                ),
+                HashSet::from([KeyCode::KEY_D])
+            );
+            assert_eq!(budget.hits, 0);
+        }
+    }
+
+    mod matches {
+        use super::*;
+
+        // A layer is what serves as a mode, see `Conditions::layers`.
+        fn capslock_led_on_vim_layer() -> Mapping {
+            Mapping::Remap {
+                input: HashSet::from([KeyCode::KEY_H]),
+                output: HashSet::from([KeyCode::KEY_LEFT]),
+                when: Conditions {
+                    layers: vec!["vim".to_string()],
+                    leds: vec![LedCode::LED_CAPSL],
+                    ..Conditions::default()
+                },
+                on_release: false,
+            }
+        }
+
+        fn vim_layer_with_capslock_led() -> ActiveState {
+            ActiveState {
+                layers: HashSet::from(["vim".to_string()]),
+                leds: HashSet::from([LedCode::LED_CAPSL]),
+                ..ActiveState::default()
+            }
+        }
+
+        #[test]
+        fn requires_held_key_layer_and_led_together() {
+            let mapping = capslock_led_on_vim_layer();
+            let keys = HashSet::from([KeyCode::KEY_H]);
+            let active = vim_layer_with_capslock_led();
+            assert!(mapping.matches(&MappingContext::new(&keys, &active)));
+        }
+
+        #[test]
+        fn does_not_match_when_any_condition_is_missing() {
+            let mapping = capslock_led_on_vim_layer();
+            let keys = HashSet::from([KeyCode::KEY_H]);
+
+            let mut no_layer = vim_layer_with_capslock_led();
+            no_layer.layers.clear();
+            assert!(!mapping.matches(&MappingContext::new(&keys, &no_layer)));
+
+            let mut no_led = vim_layer_with_capslock_led();
+            no_led.leds.clear();
+            assert!(!mapping.matches(&MappingContext::new(&keys, &no_led)));
+
+            let other_key = HashSet::from([KeyCode::KEY_J]);
+            let active = vim_layer_with_capslock_led();
+            assert!(!mapping.matches(&MappingContext::new(&other_key, &active)));
+        }
+
+        #[test]
+        fn conditions_gate_remapping() {
+            let mappings = vec![capslock_led_on_vim_layer()];
+            let keys = HashSet::from([KeyCode::KEY_H]);
+            let active = vim_layer_with_capslock_led();
+            let result = apply_mapping_to_held_keys(
+                &mappings,
+                &Modifiers::default(),
+                &MappingContext::new(&keys, &active),
+                &mut EvaluationBudget::default(),
+            );
+            assert_eq!(result, HashSet::from([KeyCode::KEY_LEFT]));
+            assert_eq!(
+                apply_mapping_to_held_keys(
+                    &mappings,
+                    &Modifiers::default(),
+                    &held(&keys),
+                    &mut EvaluationBudget::default()
+                ),
+                keys
+            );
+        }
+    }
+
+    mod apps {
+        use super::*;
+
+        #[test]
+        fn remaps_apply_only_in_their_apps() {
+            let mappings = vec![
+                Mapping::Remap {
+                    input: HashSet::from([KeyCode::KEY_CAPSLOCK]),
+                    output: HashSet::from([KeyCode::KEY_ESC]),
+                    when: Conditions {
+                        apps: HashSet::from(["kitty".to_string()]),
+                        ..Conditions::default()
+                    },
+                    on_release: false,
+                },
+                Mapping::Remap {
+                    input: HashSet::from([KeyCode::KEY_CAPSLOCK]),
+                    output: HashSet::from([KeyCode::KEY_LEFTCTRL]),
+                    when: Conditions::default(),
+                    on_release: false,
+                },
+            ];
+            let keys = HashSet::from([KeyCode::KEY_CAPSLOCK]);
+            for (app, expected) in [
+                (Some("kitty"), &mappings[0]),
+                (Some("firefox"), &mappings[1]),
+                (None, &mappings[1]),
+            ] {
+                let active = ActiveState {
+                    app: app.map(str::to_string),
+                    ..ActiveState::default()
+                };
+                assert_eq!(
+                    lookup_mapping(
+                        &mappings,
+                        &MappingContext::new(&keys, &active),
+                        KeyCode::KEY_CAPSLOCK,
+                        &mut EvaluationBudget::default()
+                    ),
+                    Some(expected),
+                    "{:?}",
+                    app
+                );
+            }
+        }
+    }
+
+    mod unless {
+        use super::*;
+
+        fn unshifted_and_shifted() -> Vec<Mapping> {
+            vec![
+                Mapping::Remap {
+                    input: HashSet::from([KeyCode::KEY_A]),
+                    output: HashSet::from([KeyCode::KEY_B]),
+                    when: Conditions {
+                        unless: HashSet::from([KeyCode::KEY_LEFTSHIFT]),
+                        ..Conditions::default()
+                    },
+                    on_release: false,
+                },
+                Mapping::Remap {
+                    input: HashSet::from([KeyCode::KEY_LEFTSHIFT, KeyCode::KEY_A]),
+                    output: HashSet::from([KeyCode::KEY_HOME]),
+                    when: Conditions::default(),
+                    on_release: false,
+                },
+            ]
+        }
+
+        #[test]
+        fn applies_while_excluded_keys_are_up() {
+            let mappings = unshifted_and_shifted();
+            let keys = HashSet::from([KeyCode::KEY_A]);
+            assert_eq!(
+                apply_mapping_to_held_keys(
+                    &mappings,
+                    &Modifiers::default(),
+                    &held(&keys),
+                    &mut EvaluationBudget::default()
+                ),
+                HashSet::from([KeyCode::KEY_B])
+            );
+            assert_eq!(
+                lookup_mapping(
+                    &mappings,
+                    &held(&keys),
+                    KeyCode::KEY_A,
+                    &mut EvaluationBudget::default()
+                ),
+                Some(&mappings[0])
+            );
+        }
+
+        #[test]
+        fn leaves_keys_to_other_remaps_while_excluded_keys_are_held() {
+            let mappings = unshifted_and_shifted();
+            let keys = HashSet::from([KeyCode::KEY_LEFTSHIFT, KeyCode::KEY_A]);
+            assert_eq!(
+                apply_mapping_to_held_keys(
+                    &mappings,
+                    &Modifiers::default(),
+                    &held(&keys),
+                    &mut EvaluationBudget::default()
+                ),
+                HashSet::from([KeyCode::KEY_LEFTSHIFT, KeyCode::KEY_HOME])
+            );
+            assert_eq!(
+                lookup_mapping(
+                    &mappings,
+                    &held(&keys),
+                    KeyCode::KEY_A,
+                    &mut EvaluationBudget::default()
+                ),
+                Some(&mappings[1])
+            );
+        }
+    }
+
+    mod match_strategy {
+        use super::*;
+
+        fn overlapping(strategy: MatchStrategy) -> Vec<Mapping> {
+            let mut mappings = vec![
+                Mapping::Remap {
+                    input: HashSet::from([KeyCode::KEY_A]),
+                    output: HashSet::from([KeyCode::KEY_B]),
+                    when: Conditions::default(),
+                    on_release: false,
+                },
+                Mapping::Remap {
+                    input: HashSet::from([KeyCode::KEY_LEFTCTRL, KeyCode::KEY_A]),
+                    output: HashSet::from([KeyCode::KEY_HOME]),
+                    when: Conditions::default(),
+                    on_release: false,
+                },
+            ];
+            order_mappings(&mut mappings, strategy);
+            mappings
+        }
+
+        fn apply(mappings: &[Mapping], keys: &HashSet<KeyCode>) -> HashSet<KeyCode> {
+            apply_mapping_to_held_keys(
+                mappings,
+                &Modifiers::default(),
+                &held(keys),
+                &mut EvaluationBudget::default(),
+            )
+        }
+
+        #[test]
+        fn first_match_lets_earlier_remap_win() {
+            let mappings = overlapping(MatchStrategy::FirstMatch);
+            let keys = HashSet::from([KeyCode::KEY_LEFTCTRL, KeyCode::KEY_A]);
+            assert_eq!(
+                apply(&mappings, &keys),
+                HashSet::from([KeyCode::KEY_LEFTCTRL, KeyCode::KEY_B])
+            );
+            assert_eq!(
+                lookup_mapping(
+                    &mappings,
+                    &held(&keys),
+                    KeyCode::KEY_A,
+                    &mut EvaluationBudget::default()
+                ),
+                Some(&mappings[0])
+            );
+        }
+
+        #[test]
+        fn longest_match_lets_chord_win() {
+            let mappings = overlapping(MatchStrategy::LongestMatch);
+            let keys = HashSet::from([KeyCode::KEY_LEFTCTRL, KeyCode::KEY_A]);
+            assert_eq!(
+                apply(&mappings, &keys),
+                HashSet::from([KeyCode::KEY_LEFTCTRL, KeyCode::KEY_HOME])
+            );
+            let chord = lookup_mapping(
+                &mappings,
+                &held(&keys),
+                KeyCode::KEY_A,
+                &mut EvaluationBudget::default(),
+            );
+            assert!(matches!(chord, Some(Mapping::Remap { input, .. }) if input.len() == 2));
+            // Without the modifier the shorter remap still applies.
+            assert_eq!(
+                apply(&mappings, &HashSet::from([KeyCode::KEY_A])),
+                HashSet::from([KeyCode::KEY_B])
+            );
+        }
+    }
+
+    mod configured_modifiers {
+        use super::*;
+
+        fn capslock_not_altgr() -> Modifiers {
+            Modifiers::new([KeyCode::KEY_CAPSLOCK, KeyCode::KEY_LEFTCTRL])
+        }
+
+        #[test]
+        fn orders_configured_modifiers_first_on_press_and_last_on_release() {
+            let time = TimeVal::new(0, 0);
+            let event = |ev_key, key_event_type| EvKeyEvent {
+                time,
+                ev_key,
+                key_event_type,
+            };
+            let modifiers = capslock_not_altgr();
+            let pressed = HashSet::from([KeyCode::KEY_A, KeyCode::KEY_CAPSLOCK]);
+            assert_eq!(
+                compute_keys_based_on_state(
+                    &[],
+                    &modifiers,
+                    &held(&pressed),
+                    &HashSet::new(),
+                    &time,
+                    &mut EvaluationBudget::default(),
+                ),
+                vec![
+                    event(KeyCode::KEY_CAPSLOCK, KeyEventType::Press),
+                    event(KeyCode::KEY_A, KeyEventType::Press),
+                ]
+            );
+            assert_eq!(
+                compute_keys_based_on_state(
+                    &[],
+                    &modifiers,
+                    &held(&HashSet::new()),
+                    &pressed,
+                    &time,
+                    &mut EvaluationBudget::default(),
+                ),
+                vec![
+                    event(KeyCode::KEY_A, KeyEventType::Release),
+                    event(KeyCode::KEY_CAPSLOCK, KeyEventType::Release),
+                ]
+            );
+        }
+
+        #[test]
+        fn remap_consumes_inputs_that_are_not_configured_modifiers() {
+            let mappings = vec![Mapping::Remap {
+                input: HashSet::from([KeyCode::KEY_RIGHTALT, KeyCode::KEY_H]),
+                output: HashSet::from([KeyCode::KEY_LEFT]),
+                when: Conditions::default(),
+                on_release: false,
+            }];
+            let pressed = HashSet::from([KeyCode::KEY_RIGHTALT, KeyCode::KEY_H]);
+            let apply = |modifiers: &Modifiers| {
+                apply_mapping_to_held_keys(
+                    &mappings,
+                    modifiers,
+                    &held(&pressed),
+                    &mut EvaluationBudget::default(),
+                )
+            };
+            assert_eq!(
+                apply(&Modifiers::default()),
+                HashSet::from([KeyCode::KEY_RIGHTALT, KeyCode::KEY_LEFT])
+            );
+            assert_eq!(
+                apply(&capslock_not_altgr()),
+                HashSet::from([KeyCode::KEY_LEFT])
+            );
+        }
+    }
+
+    mod sides {
+        use super::*;
+
+        #[test]
+        fn remap_of_right_modifier_leaves_left_one_alone() {
+            let mappings = vec![Mapping::Remap {
+                input: HashSet::from([KeyCode::KEY_RIGHTALT, KeyCode::KEY_H]),
+                output: HashSet::from([KeyCode::KEY_LEFT]),
+                when: Conditions::default(),
+                on_release: false,
+            }];
+            let apply = |keys: &HashSet<KeyCode>| {
+                apply_mapping_to_held_keys(
+                    &mappings,
+                    &Modifiers::default(),
+                    &held(keys),
+                    &mut EvaluationBudget::default(),
+                )
+            };
+            let left = HashSet::from([KeyCode::KEY_LEFTALT, KeyCode::KEY_H]);
+            assert_eq!(apply(&left), left);
+            assert_eq!(
+                lookup_mapping(
+                    &mappings,
+                    &held(&left),
+                    KeyCode::KEY_H,
+                    &mut EvaluationBudget::default()
+                ),
+                None
+            );
+            let right = HashSet::from([KeyCode::KEY_RIGHTALT, KeyCode::KEY_H]);
+            assert_eq!(
+                apply(&right),
+                HashSet::from([KeyCode::KEY_RIGHTALT, KeyCode::KEY_LEFT])
+            );
+        }
+    }
+
+    mod resolve {
+        use super::*;
+
+        #[test]
+        fn lists_the_emitted_keys_modifiers_first() {
+            let mappings = vec![Mapping::Remap {
+                input: HashSet::from([KeyCode::KEY_CAPSLOCK, KeyCode::KEY_H]),
+                output: HashSet::from([KeyCode::KEY_LEFT, KeyCode::KEY_LEFTSHIFT]),
+                when: Conditions::default(),
+                on_release: false,
+            }];
+            assert_eq!(
+                resolve(
+                    &mappings,
+                    &HashSet::from([KeyCode::KEY_CAPSLOCK, KeyCode::KEY_H])
+                ),
+                vec![KeyCode::KEY_LEFTSHIFT, KeyCode::KEY_LEFT]
+            );
+            assert_eq!(
+                resolve(&mappings, &HashSet::from([KeyCode::KEY_H])),
+                vec![KeyCode::KEY_H]
+            );
+            assert_eq!(resolve(&mappings, &HashSet::new()), vec![]);
+        }
+    }
+
+    mod index {
+        use super::*;
+
+        const KEYS: [KeyCode; 36] = [
+            KeyCode::KEY_A,
+            KeyCode::KEY_B,
+            KeyCode::KEY_C,
+            KeyCode::KEY_D,
+            KeyCode::KEY_E,
+            KeyCode::KEY_F,
+            KeyCode::KEY_G,
+            KeyCode::KEY_H,
+            KeyCode::KEY_I,
+            KeyCode::KEY_J,
+            KeyCode::KEY_K,
+            KeyCode::KEY_L,
+            KeyCode::KEY_M,
+            KeyCode::KEY_N,
+            KeyCode::KEY_O,
+            KeyCode::KEY_P,
+            KeyCode::KEY_Q,
+            KeyCode::KEY_R,
+            KeyCode::KEY_S,
+            KeyCode::KEY_T,
+            KeyCode::KEY_U,
+            KeyCode::KEY_V,
+            KeyCode::KEY_W,
+            KeyCode::KEY_X,
+            KeyCode::KEY_Y,
+            KeyCode::KEY_Z,
+            KeyCode::KEY_0,
+            KeyCode::KEY_1,
+            KeyCode::KEY_2,
+            KeyCode::KEY_3,
+            KeyCode::KEY_4,
+            KeyCode::KEY_5,
+            KeyCode::KEY_6,
+            KeyCode::KEY_7,
+            KeyCode::KEY_8,
+            KeyCode::KEY_9,
+        ];
+
+        /// `count` remaps of two key chords, each onto a key that is the
+        /// input of other remaps so that remaps chain.
+        fn synthetic_config(count: usize) -> Vec<Mapping> {
+            let pairs = (0..KEYS.len()).flat_map(|a| (a + 1..KEYS.len()).map(move |b| (a, b)));
+            pairs
+                .take(count)
+                .enumerate()
+                .map(|(idx, (a, b))| Mapping::Remap {
+                    input: HashSet::from([KEYS[a], KEYS[b]]),
+                    output: HashSet::from([KEYS[idx % KEYS.len()]]),
+                    when: Conditions::default(),
+                    on_release: false,
+                })
+                .collect()
+        }
+
+        fn apply(
+            mappings: &[Mapping],
+            index: &MappingIndex,
+            pressed: &HashSet<KeyCode>,
+        ) -> HashSet<KeyCode> {
+            let mut scratch = Scratch::default();
+            super::super::apply_mapping_to_held_keys(
+                mappings,
+                index,
+                &Modifiers::default(),
+                &held(pressed),
+                &mut EvaluationBudget::default(),
+                &mut scratch,
+            );
+            scratch.keys
+        }
+
+        #[test]
+        fn indexed_evaluation_matches_evaluating_every_mapping() {
+            let mut mappings = synthetic_config(200);
+            mappings.push(Mapping::Remap {
+                input: HashSet::new(),
+                output: HashSet::from([KeyCode::KEY_F1]),
+                when: Conditions::default(),
+                on_release: false,
+            });
+            let indexed = MappingIndex::new(&mappings);
+            let exhaustive = MappingIndex::exhaustive(&mappings);
+            for (a, &key) in KEYS.iter().enumerate() {
+                for b in [a, (a + 7) % KEYS.len(), (a + 13) % KEYS.len()] {
+                    let pressed = HashSet::from([key, KEYS[b], KeyCode::KEY_LEFTSHIFT]);
+                    assert_eq!(
+                        apply(&mappings, &indexed, &pressed),
+                        apply(&mappings, &exhaustive, &pressed),
+                        "pressed {:?}",
+                        pressed
+                    );
                 }
+            }
+        }
+    }
+
+    mod modifier_order {
+        use super::*;
+
+        #[test]
+        fn chord_modifiers_are_emitted_in_fixed_order() {
+            let mappings = vec![Mapping::Remap {
+                input: HashSet::from([KeyCode::KEY_F1]),
+                output: HashSet::from([
+                    KeyCode::KEY_LEFTMETA,
+                    KeyCode::KEY_LEFTSHIFT,
+                    KeyCode::KEY_LEFTCTRL,
+                    KeyCode::KEY_T,
+                ]),
+                when: Conditions::default(),
+                on_release: false,
+            }];
+            let modifiers = Modifiers::default();
+            let time = TimeVal::new(0, 0);
+            let event = |ev_key, key_event_type| EvKeyEvent {
+                time,
+                ev_key,
+                key_event_type,
+            };
+            let pressed = compute_keys_based_on_state(
+                &mappings,
+                &modifiers,
+                &held(&HashSet::from([KeyCode::KEY_F1])),
+                &HashSet::new(),
+                &time,
+                &mut EvaluationBudget::default(),
+            );
+            assert_eq!(
+                pressed,
+                vec![
+                    event(KeyCode::KEY_LEFTCTRL, KeyEventType::Press),
+                    event(KeyCode::KEY_LEFTSHIFT, KeyEventType::Press),
+                    event(KeyCode::KEY_LEFTMETA, KeyEventType::Press),
+                    event(KeyCode::KEY_T, KeyEventType::Press),
+                ]
+            );
+            let output_keys = pressed.iter().map(|event| event.ev_key).collect();
+            assert_eq!(
+                compute_keys_based_on_state(
+                    &mappings,
+                    &modifiers,
+                    &held(&HashSet::new()),
+                    &output_keys,
+                    &time,
+                    &mut EvaluationBudget::default(),
+                ),
+                vec![
+                    event(KeyCode::KEY_T, KeyEventType::Release),
+                    event(KeyCode::KEY_LEFTMETA, KeyEventType::Release),
+                    event(KeyCode::KEY_LEFTSHIFT, KeyEventType::Release),
+                    event(KeyCode::KEY_LEFTCTRL, KeyEventType::Release),
+                ]
             );
         }
     }
@@ -0,0 +1,52 @@
+use super::types::{EvKeyEvent, KeyEventType};
+use crate::mapping::RecoveryHook;
+use anyhow::{Context, Result};
+use evdev_rs::TimeVal;
+use std::io::Write;
+
+/// Something evremap recovered from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    /// The kernel dropped events and the device state was read again.
+    Resync,
+    /// The input device disappeared and was opened again.
+    Reconnect,
+}
+
+impl Recovery {
+    fn name(&self) -> &'static str {
+        match self {
+            Recovery::Resync => "resync",
+            Recovery::Reconnect => "reconnect",
+        }
+    }
+}
+
+/// Signal a recovery as configured by `hook`: append a line to the status
+/// file, if any, and return a tap of the hook key, if any, to be emitted.
+pub fn fire(hook: &RecoveryHook, recovery: Recovery, time: &TimeVal) -> Result<Vec<EvKeyEvent>> {
+    if let Some(path) = &hook.status_file {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .context(format!("opening {}", path.display()))?;
+        writeln!(file, "{} {}", recovery.name(), time.tv_sec)
+            .context(format!("writing {}", path.display()))?;
+    }
+    Ok(match hook.key {
+        Some(ev_key) => vec![
+            EvKeyEvent {
+                time: *time,
+                ev_key,
+                key_event_type: KeyEventType::Press,
+            },
+            EvKeyEvent {
+                time: *time,
+                ev_key,
+                key_event_type: KeyEventType::Release,
+            },
+        ],
+        None => vec![],
+    })
+}
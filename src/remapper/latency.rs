@@ -0,0 +1,139 @@
+use std::time::{Duration, Instant};
+
+/// How many samples go by between periodic percentile reports.
+const REPORT_EVERY: usize = 1000;
+
+/// A monotonic clock; abstracted so that latency measurements can be tested.
+pub trait Monotonic {
+    fn now(&mut self) -> Instant;
+}
+
+pub struct SystemMonotonic;
+
+impl Monotonic for SystemMonotonic {
+    fn now(&mut self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Records how long it takes from reading an input event until the
+/// events it produced have been written.
+pub struct LatencyRecorder<C> {
+    clock: C,
+    read_at: Option<Instant>,
+    samples: Vec<Duration>,
+}
+
+impl<C: Monotonic> LatencyRecorder<C> {
+    pub fn new(clock: C) -> Self {
+        Self {
+            clock,
+            read_at: None,
+            samples: vec![],
+        }
+    }
+
+    /// Note that an input event was just read.
+    pub fn event_read(&mut self) {
+        self.read_at = Some(self.clock.now());
+    }
+
+    /// Note that the output for the last input event was just written.
+    pub fn output_written(&mut self) {
+        if let Some(read_at) = self.read_at.take() {
+            self.samples.push(self.clock.now().duration_since(read_at));
+            if self.samples.len().is_multiple_of(REPORT_EVERY) {
+                log::info!("Latency: {}", self.summary());
+            }
+        }
+    }
+
+    /// The latency that `percent` percent of the samples don't exceed.
+    pub fn percentile(&self, percent: f64) -> Option<Duration> {
+        let mut sorted = self.samples.clone();
+        sorted.sort();
+        let rank = ((percent / 100.0) * sorted.len() as f64).ceil() as usize;
+        sorted.get(rank.clamp(1, sorted.len().max(1)) - 1).copied()
+    }
+
+    pub fn summary(&self) -> String {
+        let show = |percent| match self.percentile(percent) {
+            Some(latency) => format!("{:?}", latency),
+            None => "-".to_string(),
+        };
+        format!(
+            "{} events, p50 {}, p90 {}, p99 {}, max {}",
+            self.samples.len(),
+            show(50.0),
+            show(90.0),
+            show(99.0),
+            show(100.0)
+        )
+    }
+
+    /// The summary followed by a histogram with power of two buckets.
+    pub fn report(&self) -> String {
+        let mut buckets: Vec<usize> = vec![];
+        for sample in &self.samples {
+            let micros = sample.as_micros().max(1);
+            let bucket = (u128::BITS - micros.leading_zeros() - 1) as usize;
+            if buckets.len() <= bucket {
+                buckets.resize(bucket + 1, 0);
+            }
+            buckets[bucket] += 1;
+        }
+        let most = buckets.iter().copied().max().unwrap_or(0).max(1);
+        let mut report = self.summary();
+        for (bucket, count) in buckets.iter().enumerate() {
+            let bar = "#".repeat((count * 40).div_ceil(most));
+            report.push_str(&format!(
+                "\n{:>8}us - {:>8}us {:>8} {}",
+                1u64 << bucket,
+                1u64 << (bucket + 1),
+                count,
+                bar
+            ));
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct ManualClock {
+        now: Instant,
+    }
+
+    impl Monotonic for ManualClock {
+        fn now(&mut self) -> Instant {
+            self.now
+        }
+    }
+
+    #[test]
+    fn records_injected_deltas() {
+        let mut recorder = LatencyRecorder::new(ManualClock {
+            now: Instant::now(),
+        });
+        let deltas = [
+            Duration::from_micros(150),
+            Duration::from_micros(40),
+            Duration::from_millis(3),
+        ];
+        for delta in deltas {
+            recorder.event_read();
+            recorder.clock.now += delta;
+            recorder.output_written();
+        }
+        assert_eq!(recorder.samples, deltas);
+        assert_eq!(recorder.percentile(0.0), Some(Duration::from_micros(40)));
+        assert_eq!(recorder.percentile(50.0), Some(Duration::from_micros(150)));
+        assert_eq!(recorder.percentile(100.0), Some(Duration::from_millis(3)));
+
+        // Writing output without a preceding read records nothing.
+        recorder.output_written();
+        assert_eq!(recorder.samples.len(), 3);
+    }
+}
@@ -1,7 +1,21 @@
-use super::event_logic::compute_keys_based_on_state;
-use super::types::{EvKeyEvent, KeyEventType};
+use super::event_logic::{
+    compute_keys_based_on_state, lookup_mapping, lookup_mapping_position, modifiers_first,
+    modifiers_last, order_mappings, EvaluationBudget, MappingIndex, Scratch,
+};
+use super::text::{text_steps, type_text, unicode_steps};
+use super::types::{duration_between, EvKeyEvent, FrameClock, KeyEventType};
 use crate::mapping::*;
-use std::collections::HashSet;
+use evdev_rs::TimeVal;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long a `Mapping::DualRole` key may be held and still count as a tap.
+pub const DEFAULT_DUAL_ROLE_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Delay between the steps of a `Mapping::Macro`.
+pub const DEFAULT_MACRO_DELAY: Duration = Duration::from_millis(5);
 
 /// The machine you pass in the key events through that gives the "replaced" events one should press instead.
 pub struct Machine {
@@ -11,133 +25,1470 @@ pub struct Machine {
     /// Keys currently pressed down according the output events.
     output_keys: HashSet<KeyCode>,
 
-    /// The (readonly) list of mappings passed at initialization.
-    mappings: Vec<Mapping>,
+    /// When each of `output_keys` was pressed.
+    output_pressed_at: HashMap<KeyCode, TimeVal>,
+
+    /// When the last event came in, of any type.
+    last_input: Option<TimeVal>,
+
+    /// Release everything once a key of `output_keys` has been held this
+    /// long with no event coming in since.
+    stuck_key_timeout: Option<Duration>,
+
+    /// The (readonly) list of mappings passed at initialization, which
+    /// may be shared with other machines.
+    mappings: Arc<Vec<Mapping>>,
+
+    /// Which of `mappings` each key is involved in.
+    index: MappingIndex,
+
+    /// Limits how many mappings are evaluated per event.
+    budget: EvaluationBudget,
+
+    /// Keys pressed first and released last.
+    modifiers: Modifiers,
+
+    /// The layers and LEDs mappings may be conditioned on.
+    active: ActiveState,
+
+    /// The most recently pressed key, used by `Mapping::KeyNameProbe`.
+    previous_key: Option<KeyCode>,
+
+    /// Modifier whose held state gets flipped for the next key press,
+    /// armed by `Mapping::InvertModifier`.
+    armed_inversion: Option<KeyCode>,
+
+    /// A `Mapping::DualRole` key that was pressed but not yet resolved
+    /// into a tap or a hold, along with when it was pressed.
+    /// Auto-repeat of the key doesn't count as a new press, so its
+    /// `Repeat` events leave this timestamp alone.
+    pending_dual_role: Option<(KeyCode, TimeVal)>,
+
+    /// Events of other keys held back until the pending dual role key
+    /// resolves, unless its mode is `DualRoleMode::HoldPreferred`.
+    dual_role_held_back: Vec<EvKeyEvent>,
+
+    /// Releasing a `Mapping::DualRole` key within this long after its
+    /// press, with no other key pressed in between, makes it a tap.
+    dual_role_timeout: Duration,
+
+    /// Held `Mapping::DeferredPassthrough` keys that will be tapped on
+    /// release, i.e. that haven't been cancelled.
+    deferred_keys: HashSet<KeyCode>,
+
+    /// Keys pressed by a `Mapping::Macro` sequence and not released by it,
+    /// kept apart from `output_keys` so regular mappings leave them be.
+    macro_keys: HashSet<KeyCode>,
+
+    /// Delay between the steps of a `Mapping::Macro`.
+    macro_delay: Duration,
+
+    /// Modifiers that a `Mapping::OneShot` tap armed for the next key press.
+    armed_one_shots: Vec<KeyCode>,
+
+    /// Modifiers that a `Mapping::OneShot` double tap locked. They are
+    /// part of `input_state` so that mappings see them as held.
+    locked_one_shots: HashSet<KeyCode>,
+
+    /// When a key was last pressed, to tell how long no key had been
+    /// pressed before the next one.
+    last_press: Option<TimeVal>,
+
+    /// On-release remaps whose input is held, which tap their output once
+    /// a key of the input is released.
+    armed_on_release: Vec<usize>,
+
+    /// Held keys that were pressed while a layer was active.
+    layer_keys: HashSet<KeyCode>,
+
+    /// Layers that a `Mapping::LayerLock` tap locked on. They are part of
+    /// `active.layers` until tapped again.
+    locked_layers: HashSet<String>,
+
+    /// Presses of `Mapping::Combo` keys held back until they either
+    /// complete a combo or turn out not to.
+    pending_combo: Vec<EvKeyEvent>,
+
+    /// The inputs and outputs of the combos that fired and whose inputs
+    /// are all still held.
+    active_combos: Vec<(HashSet<KeyCode>, HashSet<KeyCode>)>,
+
+    /// A `Mapping::TapDance` key that was tapped and may be tapped again,
+    /// along with how many times it was tapped and when last.
+    pending_tap_dance: Option<(KeyCode, usize, TimeVal)>,
+
+    /// Keys that, pressed together, toggle `bypassed`.
+    bypass_chord: HashSet<KeyCode>,
+
+    /// Keys of `bypass_chord` that are held.
+    bypass_held: HashSet<KeyCode>,
+
+    /// Whether events pass through unchanged rather than being remapped.
+    bypassed: bool,
+
+    /// Keys that, pressed together, release everything.
+    panic_chord: HashSet<KeyCode>,
+
+    /// Keys of `panic_chord` that are held.
+    panic_held: HashSet<KeyCode>,
+
+    /// Whether the panic chord fired and some of its keys are still held,
+    /// which are ignored until released.
+    panicked: bool,
+
+    /// Whether key events of an unknown `KeyEventType` are passed on.
+    forward_unknown_values: bool,
+
+    /// Keys that were warned about sending an unknown `KeyEventType`,
+    /// which is only done once per key.
+    warned_unknown: HashSet<KeyCode>,
+
+    #[cfg(feature = "metrics")]
+    metrics: super::metrics::Metrics,
+
+    /// Reused by `get_keys_to_emit` to avoid allocating on every event.
+    held_scratch: HashSet<KeyCode>,
+    scratch: Scratch,
+
+    /// Held keys whose layer was deactivated before they were released.
+    /// Their layer's output was released along with the layer, and they
+    /// are ignored until released so that they don't emit anything else.
+    suppressed_keys: HashSet<KeyCode>,
 }
 
 impl Machine {
-    pub fn new(mappings: &Vec<Mapping>) -> Self {
-        return Machine {
+    #[cfg(test)]
+    pub fn new(mappings: &[Mapping]) -> Self {
+        Machine::with_mappings(Arc::new(mappings.to_vec()))
+    }
+
+    pub fn with_mappings(mappings: Arc<Vec<Mapping>>) -> Self {
+        Machine {
             input_state: HashSet::new(),
-            mappings: mappings.clone(),
+            index: MappingIndex::new(&mappings),
+            mappings,
             output_keys: HashSet::new(),
-        };
+            output_pressed_at: HashMap::new(),
+            last_input: None,
+            stuck_key_timeout: None,
+            budget: EvaluationBudget::default(),
+            modifiers: Modifiers::default(),
+            active: ActiveState::default(),
+            previous_key: None,
+            armed_inversion: None,
+            pending_dual_role: None,
+            dual_role_held_back: vec![],
+            dual_role_timeout: DEFAULT_DUAL_ROLE_TIMEOUT,
+            deferred_keys: HashSet::new(),
+            macro_keys: HashSet::new(),
+            macro_delay: DEFAULT_MACRO_DELAY,
+            armed_one_shots: vec![],
+            locked_one_shots: HashSet::new(),
+            last_press: None,
+            armed_on_release: vec![],
+            layer_keys: HashSet::new(),
+            locked_layers: HashSet::new(),
+            pending_combo: vec![],
+            active_combos: vec![],
+            pending_tap_dance: None,
+            bypass_chord: HashSet::new(),
+            bypass_held: HashSet::new(),
+            bypassed: false,
+            panic_chord: HashSet::new(),
+            panic_held: HashSet::new(),
+            panicked: false,
+            forward_unknown_values: true,
+            warned_unknown: HashSet::new(),
+            suppressed_keys: HashSet::new(),
+            #[cfg(feature = "metrics")]
+            metrics: Default::default(),
+            held_scratch: HashSet::new(),
+            scratch: Scratch::default(),
+        }
+    }
+
+    pub fn from_config(config: &MappingConfig) -> Self {
+        Machine::from_config_sharing(config, Machine::shared_mappings(config))
+    }
+
+    /// The mappings of `config` in the order machines evaluate them, to be
+    /// shared by the machines of `from_config_sharing`.
+    pub fn shared_mappings(config: &MappingConfig) -> Arc<Vec<Mapping>> {
+        let mut mappings = config.mappings.clone();
+        order_mappings(&mut mappings, config.match_strategy);
+        Arc::new(mappings)
+    }
+
+    /// Like `from_config`, but evaluating `mappings` as given by
+    /// `shared_mappings` rather than a copy of its own.
+    pub fn from_config_sharing(config: &MappingConfig, mappings: Arc<Vec<Mapping>>) -> Self {
+        let mut machine = Machine::with_mappings(mappings);
+        machine.budget = EvaluationBudget::new(config.evaluation_budget);
+        machine.modifiers = config.modifiers.clone();
+        if let Some(timeout_ms) = config.dual_role_timeout_ms {
+            machine.dual_role_timeout = Duration::from_millis(timeout_ms);
+        }
+        machine.stuck_key_timeout = config.stuck_key_timeout_ms.map(Duration::from_millis);
+        if let Some(delay_ms) = config.macro_delay_ms {
+            machine.macro_delay = Duration::from_millis(delay_ms);
+        }
+        machine.forward_unknown_values = config.forward_unknown_values.unwrap_or(true);
+        machine.bypass_chord = config.bypass.clone();
+        machine.panic_chord = config.panic.clone();
+        machine
+    }
+
+    pub fn mappings(&self) -> &Arc<Vec<Mapping>> {
+        &self.mappings
     }
 
     // Insert an event and get the resulting events to be emitted.
     pub fn insert(&mut self, incoming_event: EvKeyEvent) -> Vec<EvKeyEvent> {
+        let outgoing_events = self.insert_event(incoming_event);
+        #[cfg(feature = "metrics")]
+        self.metrics.inserted(&outgoing_events);
+        outgoing_events
+    }
+
+    /// Let the machine know about `keys` that were already held when it
+    /// started out, such as a modifier held while the device was grabbed,
+    /// as if they were pressed at `time`. Returns the events that pressing
+    /// them results in.
+    pub fn seed_held_keys(&mut self, keys: &[KeyCode], time: &TimeVal) -> Vec<EvKeyEvent> {
+        let mut keys = keys.to_vec();
+        keys.sort_by(|a, b| modifiers_first(&self.modifiers, a, b));
+        keys.into_iter()
+            .flat_map(|ev_key| {
+                self.insert_event(EvKeyEvent {
+                    time: *time,
+                    ev_key,
+                    key_event_type: KeyEventType::Press,
+                })
+            })
+            .collect()
+    }
+
+    /// Let the machine know which LEDs are on, for mappings conditioned on
+    /// them.
+    pub fn set_leds(&mut self, leds: &HashSet<LedCode>) {
+        self.active.leds.clone_from(leds);
+    }
+
+    /// Let the machine know the window class of the foreground
+    /// application, for mappings conditioned on it.
+    pub fn set_app(&mut self, app: Option<String>) {
+        self.active.app = app;
+    }
+
+    /// The LEDs that indicate the layers locked by a `Mapping::LayerLock`.
+    pub fn indicator_leds(&self) -> HashSet<LedCode> {
+        self.mappings
+            .iter()
+            .filter_map(|m| match m {
+                Mapping::LayerLock {
+                    layer,
+                    led: Some(led),
+                    ..
+                } if self.locked_layers.contains(layer) => Some(*led),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// How much the machine has been doing so far.
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> &super::metrics::Metrics {
+        &self.metrics
+    }
+
+    fn insert_event(&mut self, incoming_event: EvKeyEvent) -> Vec<EvKeyEvent> {
+        self.last_input = Some(incoming_event.time);
+        self.track_idle_time(&incoming_event);
+        if let Some(outgoing_events) = self.handle_panic(&incoming_event) {
+            return outgoing_events;
+        }
+        if let Some(outgoing_events) = self.handle_bypass(&incoming_event) {
+            return outgoing_events;
+        }
+        if let KeyEventType::Unknown(value) = incoming_event.key_event_type {
+            if self.warned_unknown.insert(incoming_event.ev_key) {
+                log::warn!(
+                    "{} sent the unknown value {}, which is {}; please report it",
+                    key_name(incoming_event.ev_key),
+                    value,
+                    if self.forward_unknown_values {
+                        "passed on"
+                    } else {
+                        "dropped"
+                    }
+                );
+            }
+            if !self.forward_unknown_values {
+                return vec![];
+            }
+        }
+        self.dispatch_event(incoming_event)
+    }
+
+    /// Handle an event that `insert_event` let through, or that a tap
+    /// dance or combo it resolved holds back until after its own output.
+    /// The latter mustn't go through `insert_event` again, which would
+    /// take it for another press and so reset its idle time.
+    fn dispatch_event(&mut self, incoming_event: EvKeyEvent) -> Vec<EvKeyEvent> {
+        if let Some(outgoing_events) = self.handle_tap_dance(&incoming_event) {
+            return outgoing_events;
+        }
+        match self.handle_combo(&incoming_event) {
+            Some(outgoing_events) => outgoing_events,
+            None => self.insert_key(incoming_event),
+        }
+    }
+
+    /// Let the machine know that it is `now` and get the events of the
+    /// pending decisions whose time is up by then: held back combo keys go
+    /// through, a dual role key becomes a hold, a tap dance resolves, and
+    /// keys held past the stuck key timeout are released.
+    /// As `insert` only runs when an event comes in, the caller is to call
+    /// this once `time_until_tick` passes without one.
+    pub fn tick(&mut self, now: &TimeVal) -> Vec<EvKeyEvent> {
+        let expired = |remaining: Option<Duration>| remaining.is_some_and(|r| r.is_zero());
+        let mut clock = self.frame_clock(now);
+        let mut outgoing_events = vec![];
+        if expired(self.combo_remaining(now)) {
+            outgoing_events.extend(self.flush_pending_combo(&mut clock));
+        }
+        if expired(self.dual_role_remaining(now)) {
+            if let Some((key, _)) = self.pending_dual_role.take() {
+                let hold = self.get_keys_to_emit(&EvKeyEvent {
+                    time: clock.now(),
+                    ev_key: key,
+                    key_event_type: KeyEventType::Press,
+                });
+                self.track_output(&hold);
+                outgoing_events.extend(hold);
+                outgoing_events.extend(self.flush_dual_role_held_back(&mut clock));
+            }
+        }
+        if expired(self.tap_dance_remaining(now)) {
+            outgoing_events.extend(self.resolve_tap_dance(&mut clock));
+        }
+        if expired(self.stuck_key_remaining(now)) {
+            outgoing_events.extend(self.release_stuck_keys(now));
+        }
+        outgoing_events
+    }
+
+    /// How long after `now` `tick` has something to do, if at all.
+    pub fn time_until_tick(&self, now: &TimeVal) -> Option<Duration> {
+        [
+            self.combo_remaining(now),
+            self.dual_role_remaining(now),
+            self.tap_dance_remaining(now),
+            self.stuck_key_remaining(now),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    /// How long after `now` no combo can complete the held back keys.
+    fn combo_remaining(&self, now: &TimeVal) -> Option<Duration> {
+        let first = self.pending_combo.first()?;
+        let keys: HashSet<KeyCode> = self.pending_combo.iter().map(|e| e.ev_key).collect();
+        let window = self
+            .mappings
+            .iter()
+            .filter_map(|m| match m {
+                Mapping::Combo {
+                    input, window_ms, ..
+                } if keys.is_subset(input) => Some(Duration::from_millis(*window_ms)),
+                _ => None,
+            })
+            .max()?;
+        Some(window.saturating_sub(duration_between(&first.time, now)))
+    }
+
+    /// How long after `now` the pending dual role key is no longer a tap.
+    fn dual_role_remaining(&self, now: &TimeVal) -> Option<Duration> {
+        let (_, pressed_at) = self.pending_dual_role?;
+        Some(
+            self.dual_role_timeout
+                .saturating_sub(duration_between(&pressed_at, now)),
+        )
+    }
+
+    /// How long after `now` the first of the output keys counts as stuck:
+    /// it was held for the stuck key timeout with no event coming in.
+    fn stuck_key_remaining(&self, now: &TimeVal) -> Option<Duration> {
+        let timeout = self.stuck_key_timeout?;
+        self.output_pressed_at
+            .values()
+            .map(|pressed_at| {
+                let since = self.last_input.map_or(*pressed_at, |last_input| {
+                    std::cmp::max_by_key(*pressed_at, last_input, |t| (t.tv_sec, t.tv_usec))
+                });
+                timeout.saturating_sub(duration_between(&since, now))
+            })
+            .min()
+    }
+
+    /// Release every key held in the output, and forget about the held
+    /// keys, as the panic chord does, once one of them has been held for
+    /// the stuck key timeout. With no event coming in for that long, not
+    /// even an auto-repeat, no key is held on the inputs anymore, and the
+    /// releases that would have said so got lost.
+    fn release_stuck_keys(&mut self, now: &TimeVal) -> Vec<EvKeyEvent> {
+        let mut stuck: Vec<String> = self
+            .output_pressed_at
+            .iter()
+            .map(|(key, pressed_at)| {
+                format!(
+                    "{} (held for {:?})",
+                    key_name(*key),
+                    duration_between(pressed_at, now)
+                )
+            })
+            .collect();
+        stuck.sort();
+        log::warn!(
+            "Releasing keys that look stuck, with no input since {:?} ago: {}",
+            self.last_input
+                .map_or(Duration::ZERO, |last_input| duration_between(
+                    &last_input,
+                    now
+                )),
+            stuck.join(", ")
+        );
+        let releases = self.release_all(now);
+        self.forget_held_keys();
+        releases
+    }
+
+    /// How long after `now` the pending tap dance can't be tapped again.
+    fn tap_dance_remaining(&self, now: &TimeVal) -> Option<Duration> {
+        let (key, _, tapped_at) = self.pending_tap_dance?;
+        let (_, timeout) = self.tap_dance_for(key)?;
+        Some(timeout.saturating_sub(duration_between(&tapped_at, now)))
+    }
+
+    /// Handle an event as far as `Mapping::TapDance`s are concerned,
+    /// returning `None` when it should go through the regular processing
+    /// instead. Any event but another tap of the pending key in time
+    /// resolves the pending taps, and is then handled a frame later.
+    fn handle_tap_dance(&mut self, event: &EvKeyEvent) -> Option<Vec<EvKeyEvent>> {
+        let mut tapped = 0;
+        if let Some((key, count, tapped_at)) = self.pending_tap_dance {
+            let in_time = self
+                .tap_dance_for(key)
+                .is_some_and(|(_, timeout)| duration_between(&tapped_at, &event.time) <= timeout);
+            if key != event.ev_key || !in_time {
+                let mut clock = self.frame_clock(&event.time);
+                let mut outgoing_events = self.resolve_tap_dance(&mut clock);
+                outgoing_events.extend(self.dispatch_event(EvKeyEvent {
+                    time: clock.tick(),
+                    ..event.clone()
+                }));
+                return Some(outgoing_events);
+            }
+            tapped = count;
+        }
+        let (taps, _) = self.tap_dance_for(event.ev_key)?;
+        if event.key_event_type != KeyEventType::Press {
+            return Some(vec![]);
+        }
+        tapped += 1;
+        if tapped < taps.len() {
+            self.pending_tap_dance = Some((event.ev_key, tapped, event.time));
+            return Some(vec![]);
+        }
+        // There is no further tap to wait for.
+        self.pending_tap_dance = None;
+        let keys = taps.get(tapped - 1).map_or(&[][..], Vec::as_slice);
+        Some(tap_keys(keys, &mut self.frame_clock(&event.time)))
+    }
+
+    /// Tap the output of the pending tap dance for the number of taps so far.
+    fn resolve_tap_dance(&mut self, clock: &mut FrameClock) -> Vec<EvKeyEvent> {
+        let Some((key, tapped, _)) = self.pending_tap_dance.take() else {
+            return vec![];
+        };
+        match self.tap_dance_for(key) {
+            Some((taps, _)) => tap_keys(&taps[tapped - 1], clock),
+            None => vec![],
+        }
+    }
+
+    fn tap_dance_for(&self, key: KeyCode) -> Option<(Vec<Vec<KeyCode>>, Duration)> {
+        self.mappings.iter().find_map(|m| match m {
+            Mapping::TapDance {
+                input,
+                taps,
+                timeout_ms,
+            } if *input == key => Some((taps.clone(), Duration::from_millis(*timeout_ms))),
+            _ => None,
+        })
+    }
+
+    /// Handle an event as far as `Mapping::Combo`s are concerned, returning
+    /// `None` when it should go through the regular processing instead.
+    /// Presses of combo keys are held back until they complete a combo
+    /// within its window, or turn out not to, which is noticed on the next
+    /// event or `tick`.
+    fn handle_combo(&mut self, event: &EvKeyEvent) -> Option<Vec<EvKeyEvent>> {
+        let key = event.ev_key;
+        if event.key_event_type == KeyEventType::Release {
+            if let Some(idx) = self
+                .active_combos
+                .iter()
+                .position(|(input, _)| input.contains(&key))
+            {
+                // The other inputs stay suppressed until they are released.
+                self.active_combos.remove(idx);
+                self.suppressed_keys.remove(&key);
+                self.input_state.remove(&key);
+                let outgoing_events = self.get_keys_to_emit(event);
+                self.track_output(&outgoing_events);
+                return Some(outgoing_events);
+            }
+        }
+        let Some(first) = self.pending_combo.first() else {
+            if event.key_event_type == KeyEventType::Press
+                && self.combo_for(&[key], Duration::ZERO).is_some()
+            {
+                self.pending_combo.push(event.clone());
+                return Some(vec![]);
+            }
+            return None;
+        };
+        let elapsed = duration_between(&first.time, &event.time);
+        // The held back keys in the order they were pressed in.
+        let mut pressed: Vec<KeyCode> = self.pending_combo.iter().map(|e| e.ev_key).collect();
+        let mut keys: HashSet<KeyCode> = pressed.iter().copied().collect();
+        match event.key_event_type {
+            KeyEventType::Press if keys.insert(key) => {
+                pressed.push(key);
+                if let Some((input, output)) = self.combo_for(&pressed, elapsed) {
+                    self.pending_combo.push(event.clone());
+                    if input != keys {
+                        return Some(vec![]);
+                    }
+                    self.pending_combo.clear();
+                    self.input_state.extend(&input);
+                    self.suppressed_keys.extend(&input);
+                    self.active_combos.push((input, output));
+                    let outgoing_events = self.get_keys_to_emit(event);
+                    self.track_output(&outgoing_events);
+                    return Some(outgoing_events);
+                }
+            }
+            KeyEventType::Repeat
+                if keys.contains(&key) && self.combo_for(&pressed, elapsed).is_some() =>
+            {
+                return Some(vec![]);
+            }
+            _ => {}
+        }
+        // The held back keys go through as they are, followed by the event
+        // a frame later.
+        let mut clock = self.frame_clock(&event.time);
+        let mut outgoing_events = self.flush_pending_combo(&mut clock);
+        outgoing_events.extend(self.dispatch_event(EvKeyEvent {
+            time: clock.tick(),
+            ..event.clone()
+        }));
+        Some(outgoing_events)
+    }
+
+    /// Let the held back combo keys go through as they are.
+    fn flush_pending_combo(&mut self, clock: &mut FrameClock) -> Vec<EvKeyEvent> {
+        let mut outgoing_events = vec![];
+        for pending in std::mem::take(&mut self.pending_combo) {
+            outgoing_events.extend(self.insert_key(EvKeyEvent {
+                time: clock.now(),
+                ..pending
+            }));
+        }
+        outgoing_events
+    }
+
+    /// The input and output of the first combo whose input includes the
+    /// `pressed` keys, in its order if it has one, and whose window is at
+    /// least `elapsed`.
+    fn combo_for(
+        &self,
+        pressed: &[KeyCode],
+        elapsed: Duration,
+    ) -> Option<(HashSet<KeyCode>, HashSet<KeyCode>)> {
+        self.mappings.iter().find_map(|m| match m {
+            Mapping::Combo {
+                input,
+                output,
+                window_ms,
+                order,
+            } if pressed.iter().all(|key| input.contains(key))
+                && order
+                    .as_ref()
+                    .is_none_or(|order| order.starts_with(pressed))
+                && elapsed <= Duration::from_millis(*window_ms) =>
+            {
+                Some((input.clone(), output.clone()))
+            }
+            _ => None,
+        })
+    }
+
+    fn insert_key(&mut self, incoming_event: EvKeyEvent) -> Vec<EvKeyEvent> {
+        if let Some(outgoing_events) = self.hold_back_for_dual_role(&incoming_event) {
+            return outgoing_events;
+        }
+        if self.is_key_name_probe(incoming_event.ev_key) {
+            return match incoming_event.key_event_type {
+                KeyEventType::Press => self.type_previous_key_name(&incoming_event.time),
+                _ => vec![],
+            };
+        }
+        if let Some(modifier) = self.inverted_modifier_for(incoming_event.ev_key) {
+            if incoming_event.key_event_type == KeyEventType::Press {
+                self.armed_inversion = Some(modifier);
+            }
+            return vec![];
+        }
+        if let Some(layer) = self.layer_for(incoming_event.ev_key) {
+            return self.handle_layer_key(layer, &incoming_event);
+        }
+        if let Some(layer) = self.layer_lock_for(incoming_event.ev_key) {
+            return self.handle_layer_lock(layer, &incoming_event);
+        }
+        if self.suppressed_keys.contains(&incoming_event.ev_key) {
+            if incoming_event.key_event_type == KeyEventType::Release {
+                self.suppressed_keys.remove(&incoming_event.ev_key);
+                self.input_state.remove(&incoming_event.ev_key);
+            }
+            return vec![];
+        }
+        if let Some((modifier, lock)) = self.one_shot_for(incoming_event.ev_key) {
+            return match incoming_event.key_event_type {
+                KeyEventType::Press => self.tap_one_shot(modifier, lock, &incoming_event),
+                _ => vec![],
+            };
+        }
+        let inversion = match incoming_event.key_event_type {
+            KeyEventType::Press if !self.modifiers.contains(&incoming_event.ev_key) => {
+                self.armed_inversion.take()
+            }
+            _ => None,
+        };
+        let inverted_modifier_was_held =
+            inversion.is_some_and(|modifier| self.output_keys.contains(&modifier));
+        if incoming_event.key_event_type == KeyEventType::Press {
+            self.previous_key = Some(incoming_event.ev_key);
+        }
+        if let Some(outgoing_events) = self.handle_deferred_passthrough(&incoming_event) {
+            return outgoing_events;
+        }
+        if let Some(outgoing_events) = self.handle_macro(&incoming_event) {
+            return outgoing_events;
+        }
+        if let Some(outgoing_events) = self.handle_dual_role(&incoming_event) {
+            self.track_output(&outgoing_events);
+            return outgoing_events;
+        }
         match incoming_event.key_event_type {
             KeyEventType::Press => {
-                self.input_state.insert(incoming_event.ev_key.clone());
+                // Another key interrupting a pending dual role key makes
+                // the dual role key a hold.
+                self.pending_dual_role = None;
+                self.input_state.insert(incoming_event.ev_key);
+                if !self.active.layers.is_empty()
+                    && !self.modifiers.contains(&incoming_event.ev_key)
+                {
+                    self.layer_keys.insert(incoming_event.ev_key);
+                }
             }
             KeyEventType::Release => {
                 if !self.input_state.remove(&incoming_event.ev_key) {
                     log::trace!("There was nothing to be removed");
                 }
+                self.layer_keys.remove(&incoming_event.ev_key);
             }
             _ => {}
         }
-        let outgoing_events: Vec<EvKeyEvent> = self.get_keys_to_emit(&incoming_event);
-        // Update states of local variables.
-        for ev_key_event in &outgoing_events {
-            match ev_key_event.key_event_type {
-                KeyEventType::Press | KeyEventType::Repeat => {
-                    self.output_keys.insert(ev_key_event.ev_key.clone());
+        let one_shots = match incoming_event.key_event_type {
+            KeyEventType::Press if !self.modifiers.contains(&incoming_event.ev_key) => {
+                std::mem::take(&mut self.armed_one_shots)
+            }
+            _ => vec![],
+        };
+        let mut outgoing_events: Vec<EvKeyEvent> = self.get_keys_to_emit(&incoming_event);
+        #[cfg(feature = "metrics")]
+        if incoming_event.key_event_type == KeyEventType::Press {
+            self.metrics.pressed(
+                &self.mappings,
+                self.scratch.applied(),
+                incoming_event.ev_key,
+            );
+        }
+        self.track_output(&outgoing_events);
+        if incoming_event.key_event_type == KeyEventType::Press
+            && log::log_enabled!(log::Level::Debug)
+        {
+            self.log_fired_remap(incoming_event.ev_key);
+        }
+        for modifier in one_shots {
+            if !self.output_keys.contains(&modifier) {
+                outgoing_events =
+                    invert_modifier_around(modifier, false, outgoing_events, &incoming_event.time);
+            }
+        }
+        match inversion {
+            Some(modifier) => invert_modifier_around(
+                modifier,
+                inverted_modifier_was_held,
+                outgoing_events,
+                &incoming_event.time,
+            ),
+            None => outgoing_events,
+        }
+    }
+
+    /// Log which remap, by its position among the mappings as evaluated,
+    /// fired on pressing `key`, with both as fields for the JSON log
+    /// format.
+    fn log_fired_remap(&mut self, key: KeyCode) {
+        let Some(rule) = lookup_mapping_position(
+            &self.mappings,
+            &self.index,
+            &MappingContext::new(&self.input_state, &self.active),
+            key,
+            &mut self.budget,
+        ) else {
+            return;
+        };
+        let key = key_name(key);
+        log::debug!(rule, key = key.as_str(); "Remap {} fired on {}", rule, key);
+    }
+
+    /// Release every key that is held in the output, modifiers last, e.g.
+    /// before shutting down so that nothing stays stuck.
+    pub fn release_all(&mut self, time: &TimeVal) -> Vec<EvKeyEvent> {
+        let mut keys: Vec<KeyCode> = self.output_keys.drain().collect();
+        self.output_pressed_at.clear();
+        keys.extend(self.macro_keys.drain());
+        for modifier in self.locked_one_shots.drain() {
+            self.input_state.remove(&modifier);
+        }
+        self.armed_one_shots.clear();
+        self.pending_tap_dance = None;
+        keys.sort_by(|a, b| modifiers_last(&self.modifiers, a, b));
+        keys.into_iter()
+            .map(|ev_key| EvKeyEvent {
+                time: *time,
+                ev_key,
+                key_event_type: KeyEventType::Release,
+            })
+            .collect()
+    }
+
+    /// Handle `event` as far as the panic chord is concerned: completing it
+    /// releases every key held in the output and forgets about the held
+    /// keys and locks, whatever state the mappings got into. The keys of
+    /// the chord are then ignored until released. Returns `None` when the
+    /// event is to be processed as usual.
+    fn handle_panic(&mut self, event: &EvKeyEvent) -> Option<Vec<EvKeyEvent>> {
+        if !self.panic_chord.contains(&event.ev_key) {
+            return None;
+        }
+        match event.key_event_type {
+            KeyEventType::Press => {
+                self.panic_held.insert(event.ev_key);
+                if self.panic_held.len() == self.panic_chord.len() {
+                    log::warn!("Panic chord pressed; releasing every key");
+                    self.panicked = true;
+                    let releases = self.release_all(&event.time);
+                    self.locked_layers.clear();
+                    self.forget_held_keys();
+                    return Some(releases);
+                }
+            }
+            KeyEventType::Release => {
+                self.panic_held.remove(&event.ev_key);
+                if self.panicked {
+                    self.panicked = !self.panic_held.is_empty();
+                    return Some(vec![]);
+                }
+            }
+            _ => {}
+        }
+        if self.panicked {
+            Some(vec![])
+        } else {
+            None
+        }
+    }
+
+    /// Handle `event` as far as bypassing is concerned: completing the
+    /// bypass chord toggles it, releasing everything held so that nothing
+    /// stays stuck across the switch, and while bypassed every event passes
+    /// through unchanged. Returns `None` when the event is to be remapped.
+    fn handle_bypass(&mut self, event: &EvKeyEvent) -> Option<Vec<EvKeyEvent>> {
+        if self.bypass_chord.contains(&event.ev_key) {
+            match event.key_event_type {
+                KeyEventType::Press => {
+                    self.bypass_held.insert(event.ev_key);
+                    if self.bypass_held.len() == self.bypass_chord.len() {
+                        self.bypassed = !self.bypassed;
+                        log::info!(
+                            "{} remapping",
+                            if self.bypassed {
+                                "Bypassing"
+                            } else {
+                                "Resuming"
+                            }
+                        );
+                        let releases = self.release_all(&event.time);
+                        self.forget_held_keys();
+                        return Some(releases);
+                    }
+                }
+                KeyEventType::Repeat if self.bypass_held.len() == self.bypass_chord.len() => {
+                    return Some(vec![]);
                 }
                 KeyEventType::Release => {
-                    self.output_keys.remove(&ev_key_event.ev_key);
+                    self.bypass_held.remove(&event.ev_key);
                 }
                 _ => {}
             }
         }
-        outgoing_events
+        if !self.bypassed {
+            return None;
+        }
+        // A key held since before the switch was released in the output
+        // along with everything else, and isn't released again.
+        let pressed = event.key_event_type == KeyEventType::Press;
+        if !pressed && !self.output_keys.contains(&event.ev_key) {
+            return Some(vec![]);
+        }
+        let outgoing_events = vec![event.clone()];
+        self.track_output(&outgoing_events);
+        Some(outgoing_events)
     }
 
-    fn get_keys_to_emit(&self, event: &EvKeyEvent) -> Vec<EvKeyEvent> {
+    /// Keep track of how long no key had been pressed before each held key
+    /// was, for mappings conditioned on `idle_ms`. Auto-repeat doesn't
+    /// count as pressing a key.
+    fn track_idle_time(&mut self, event: &EvKeyEvent) {
         match event.key_event_type {
-            KeyEventType::Press | KeyEventType::Release => compute_keys_based_on_state(
-                &self.mappings,
-                &self.input_state,
-                &self.output_keys,
-                &event.time,
-            ),
-            KeyEventType::Repeat => {
-                match super::event_logic::lookup_mapping(
-                    &self.mappings,
-                    &self.input_state,
-                    event.ev_key,
-                ) {
-                    Some(Mapping::Remap { output, .. }) => output
-                        .iter()
-                        .map(|ev_key| EvKeyEvent {
-                            time: event.time,
-                            ev_key: ev_key.clone(),
-                            key_event_type: KeyEventType::Repeat,
-                        })
-                        .collect(),
-                    None => vec![event.clone()],
-                }
+            KeyEventType::Press => {
+                let idle = self
+                    .last_press
+                    .map_or(Duration::MAX, |last| duration_between(&last, &event.time));
+                self.active.idle_before.insert(event.ev_key, idle);
+                self.last_press = Some(event.time);
             }
-            KeyEventType::Unknown(_) => {
-                vec![event.clone()]
+            KeyEventType::Release => {
+                self.active.idle_before.remove(&event.ev_key);
             }
+            KeyEventType::Repeat | KeyEventType::Unknown(_) => {}
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use evdev_rs::enums::EV_KEY;
-    use evdev_rs::TimeVal;
-    use std::collections::HashSet;
+    /// Forget about the held keys and everything pending on them, once
+    /// `release_all` released their output.
+    fn forget_held_keys(&mut self) {
+        self.input_state.clear();
+        self.active.layers.clone_from(&self.locked_layers);
+        self.active.idle_before.clear();
+        self.layer_keys.clear();
+        self.suppressed_keys.clear();
+        self.deferred_keys.clear();
+        self.pending_dual_role = None;
+        self.dual_role_held_back.clear();
+        self.pending_combo.clear();
+        self.active_combos.clear();
+        self.armed_inversion = None;
+    }
 
-    #[allow(dead_code)]
-    fn init_logger() {
-        let mut builder = env_logger::Builder::new();
-        builder.filter_level(log::LevelFilter::Trace);
-        let env = env_logger::Env::new()
-            .filter("EVREMAP_LOG")
-            .write_style("EVREMAP_LOG_STYLE");
-        builder.parse_env(env);
-        builder.init();
+    /// Keep track of the keys held in the output. A repeat doesn't press a
+    /// key, so that a key that was never pressed is never released either.
+    fn track_output(&mut self, outgoing_events: &[EvKeyEvent]) {
+        for ev_key_event in outgoing_events {
+            match ev_key_event.key_event_type {
+                KeyEventType::Press => {
+                    self.output_keys.insert(ev_key_event.ev_key);
+                    self.output_pressed_at
+                        .entry(ev_key_event.ev_key)
+                        .or_insert(ev_key_event.time);
+                }
+                KeyEventType::Release => {
+                    self.output_keys.remove(&ev_key_event.ev_key);
+                    self.output_pressed_at.remove(&ev_key_event.ev_key);
+                }
+                _ => {}
+            }
+        }
     }
 
-    #[test]
-    fn machine_without_config_is_passthrough_for_press() {
-        let dummy_time = TimeVal {
-            tv_sec: 0,
-            tv_usec: 0,
-        };
-        let mut machine = Machine::new(&vec![]);
-        let dummy_event = EvKeyEvent {
-            time: dummy_time,
-            ev_key: EV_KEY::KEY_1,
-            key_event_type: KeyEventType::Press,
-        };
-        assert_eq!(machine.insert(dummy_event.clone()), vec![dummy_event,]);
+    /// Handle an event for the input key of a `Mapping::DualRole`.
+    /// Returns `None` when the event should go through the regular
+    /// processing instead, which is the case for the release of a key
+    /// that already turned into a hold.
+    fn handle_dual_role(&mut self, event: &EvKeyEvent) -> Option<Vec<EvKeyEvent>> {
+        let (hold, tap) = self.mappings.iter().find_map(|m| match m {
+            Mapping::DualRole {
+                input, hold, tap, ..
+            } if *input == event.ev_key => Some((hold.clone(), tap.clone())),
+            _ => None,
+        })?;
+        match event.key_event_type {
+            KeyEventType::Press => {
+                // A pending dual role key is interrupted by this one.
+                self.pending_dual_role = None;
+                let outgoing_events = self.get_keys_to_emit(event);
+                self.input_state.insert(event.ev_key);
+                self.pending_dual_role = Some((event.ev_key, event.time));
+                Some(outgoing_events)
+            }
+            KeyEventType::Release => match self.pending_dual_role {
+                Some((key, pressed_at)) if key == event.ev_key => {
+                    self.pending_dual_role = None;
+                    self.input_state.remove(&event.ev_key);
+                    let keys =
+                        if duration_between(&pressed_at, &event.time) <= self.dual_role_timeout {
+                            &tap
+                        } else {
+                            &hold
+                        };
+                    let mut clock = self.frame_clock(&event.time);
+                    let mut outgoing_events = tap_keys(keys, &mut clock);
+                    self.track_output(&outgoing_events);
+                    outgoing_events.extend(self.flush_dual_role_held_back(&mut clock));
+                    Some(outgoing_events)
+                }
+                _ => None,
+            },
+            // The hold keys are modifiers in practice, which don't repeat.
+            KeyEventType::Repeat | KeyEventType::Unknown(_) => Some(vec![]),
+        }
     }
 
-    fn create_timeval(sec: i64) -> TimeVal {
-        TimeVal {
-            tv_sec: sec,
-            tv_usec: 0,
+    /// Hold back the events of other keys while a dual role key that
+    /// isn't `DualRoleMode::HoldPreferred` is pending, for them to go
+    /// through once it resolves. With `DualRoleMode::PermissiveHold`, the
+    /// release of a key pressed in the meantime resolves it into a hold.
+    /// Returns `None` when the event is to be processed as usual.
+    fn hold_back_for_dual_role(&mut self, event: &EvKeyEvent) -> Option<Vec<EvKeyEvent>> {
+        let (key, _) = self.pending_dual_role?;
+        let mode = self.mappings.iter().find_map(|m| match m {
+            Mapping::DualRole { input, mode, .. } if *input == key => Some(*mode),
+            _ => None,
+        })?;
+        if event.ev_key == key || mode == DualRoleMode::HoldPreferred {
+            return None;
+        }
+        match event.key_event_type {
+            KeyEventType::Press | KeyEventType::Release => {}
+            // Repeats of a key that is held back would come before its press.
+            KeyEventType::Repeat | KeyEventType::Unknown(_) => return Some(vec![]),
+        }
+        let tapped_meanwhile = event.key_event_type == KeyEventType::Release
+            && self.dual_role_held_back.iter().any(|held_back| {
+                held_back.ev_key == event.ev_key && held_back.key_event_type == KeyEventType::Press
+            });
+        self.dual_role_held_back.push(event.clone());
+        if mode == DualRoleMode::PermissiveHold && tapped_meanwhile {
+            // With the dual role key no longer pending, the held back
+            // events go through along with its hold keys.
+            self.pending_dual_role = None;
+            let mut clock = self.frame_clock(&event.time);
+            return Some(self.flush_dual_role_held_back(&mut clock));
         }
+        Some(vec![])
     }
 
-    macro_rules! assert_machine_insertion_yields_same_event {
-        ($machine:ident, $event:expr) => {
-            let result = $machine.insert($event.clone());
-            assert_eq!(result, vec![$event]);
-        };
+    /// Let the events held back by the dual role key that just resolved go
+    /// through, each a frame after the previous one.
+    fn flush_dual_role_held_back(&mut self, clock: &mut FrameClock) -> Vec<EvKeyEvent> {
+        let mut outgoing_events = vec![];
+        for held_back in std::mem::take(&mut self.dual_role_held_back) {
+            outgoing_events.extend(self.insert_key(EvKeyEvent {
+                time: clock.tick(),
+                ..held_back
+            }));
+        }
+        outgoing_events
     }
 
-    #[test]
-    fn machine_without_config_also_emits_releases() {
-        let mut machine = Machine::new(&vec![]);
-        assert_machine_insertion_yields_same_event!(
-            machine,
-            EvKeyEvent {
+    /// Handle an event for the input key of a `Mapping::DeferredPassthrough`,
+    /// returning `None` for any other key. Pressing another key cancels
+    /// the held deferred keys that list it in their `cancel` set.
+    fn handle_deferred_passthrough(&mut self, event: &EvKeyEvent) -> Option<Vec<EvKeyEvent>> {
+        let deferred = self.mappings.iter().any(
+            |m| matches!(m, Mapping::DeferredPassthrough { input, .. } if *input == event.ev_key),
+        );
+        if !deferred {
+            if event.key_event_type == KeyEventType::Press {
+                for mapping in self.mappings.iter() {
+                    if let Mapping::DeferredPassthrough { input, cancel } = mapping {
+                        if cancel.contains(&event.ev_key) && self.deferred_keys.remove(input) {
+                            log::debug!(
+                                "{} cancelled by {}",
+                                key_name(*input),
+                                key_name(event.ev_key)
+                            );
+                        }
+                    }
+                }
+            }
+            return None;
+        }
+        match event.key_event_type {
+            KeyEventType::Press => {
+                self.deferred_keys.insert(event.ev_key);
+                Some(vec![])
+            }
+            KeyEventType::Release if self.deferred_keys.remove(&event.ev_key) => Some(tap_keys(
+                &[event.ev_key],
+                &mut self.frame_clock(&event.time),
+            )),
+            _ => Some(vec![]),
+        }
+    }
+
+    /// Handle an event for the input key of a `Mapping::Macro`, or of a
+    /// `Mapping::Unicode` or `Mapping::Text` as the macro of its steps,
+    /// returning `None` for
+    /// any other key. Each step is stamped `macro_delay` after
+    /// the previous one, which the driver turns into separate frames.
+    /// Releasing the key releases whatever the sequence left pressed, even
+    /// if that happens before the sequence has been emitted in full.
+    fn handle_macro(&mut self, event: &EvKeyEvent) -> Option<Vec<EvKeyEvent>> {
+        let sequence: Cow<[MacroStep]> = self.mappings.iter().find_map(|m| match m {
+            Mapping::Macro { input, sequence } if *input == event.ev_key => {
+                Some(Cow::Borrowed(sequence.as_slice()))
+            }
+            Mapping::Unicode {
+                input,
+                codepoint,
+                method,
+            } if *input == event.ev_key => Some(Cow::Owned(unicode_steps(*codepoint, *method))),
+            Mapping::Text { input, text } if *input == event.ev_key => {
+                Some(Cow::Owned(text_steps(text)))
+            }
+            _ => None,
+        })?;
+        match event.key_event_type {
+            KeyEventType::Press => {
+                let mut clock = FrameClock::new(&event.time, self.macro_delay);
+                let mut outgoing_events = vec![];
+                for (idx, step) in sequence.iter().enumerate() {
+                    let time = if idx > 0 { clock.tick() } else { clock.now() };
+                    let key_event_type = match step {
+                        MacroStep::Press(key) => {
+                            self.macro_keys.insert(*key);
+                            KeyEventType::Press
+                        }
+                        MacroStep::Release(key) => {
+                            self.macro_keys.remove(key);
+                            KeyEventType::Release
+                        }
+                    };
+                    outgoing_events.push(EvKeyEvent {
+                        time,
+                        ev_key: step.key(),
+                        key_event_type,
+                    });
+                }
+                Some(outgoing_events)
+            }
+            KeyEventType::Release => {
+                let mut keys: Vec<KeyCode> = self.macro_keys.drain().collect();
+                keys.sort_by(|a, b| modifiers_last(&self.modifiers, a, b));
+                Some(
+                    keys.into_iter()
+                        .map(|ev_key| EvKeyEvent {
+                            time: event.time,
+                            ev_key,
+                            key_event_type: KeyEventType::Release,
+                        })
+                        .collect(),
+                )
+            }
+            KeyEventType::Repeat | KeyEventType::Unknown(_) => Some(vec![]),
+        }
+    }
+
+    /// Fill `held_scratch` with the pressed keys that mappings should be
+    /// applied to; a dual role key is left out until it is known to be a
+    /// hold, suppressed keys are left out altogether, and the outputs of
+    /// active combos count as pressed.
+    fn fill_keys_for_mappings(&mut self) {
+        self.held_scratch.clear();
+        self.held_scratch
+            .extend(self.input_state.difference(&self.suppressed_keys).copied());
+        if let Some((key, _)) = self.pending_dual_role {
+            self.held_scratch.remove(&key);
+        }
+        for (_, output) in &self.active_combos {
+            self.held_scratch.extend(output);
+        }
+    }
+
+    fn inverted_modifier_for(&self, key: KeyCode) -> Option<KeyCode> {
+        self.mappings.iter().find_map(|m| match m {
+            Mapping::InvertModifier { input, modifier } if *input == key => Some(*modifier),
+            _ => None,
+        })
+    }
+
+    fn layer_for(&self, key: KeyCode) -> Option<String> {
+        self.mappings.iter().find_map(|m| match m {
+            Mapping::Layer { input, layer } if *input == key => Some(layer.clone()),
+            _ => None,
+        })
+    }
+
+    /// Handle an event for the input key of a `Mapping::Layer`, which
+    /// emits nothing itself but changes what the held keys map to.
+    /// Releasing it releases the output of the keys pressed in the layer,
+    /// which then stay silent until they are released as well.
+    fn handle_layer_key(&mut self, layer: String, event: &EvKeyEvent) -> Vec<EvKeyEvent> {
+        match event.key_event_type {
+            KeyEventType::Press => {
+                self.active.layers.insert(layer);
+            }
+            KeyEventType::Release => {
+                if !self.locked_layers.contains(&layer) {
+                    self.deactivate_layer(&layer);
+                }
+            }
+            KeyEventType::Repeat | KeyEventType::Unknown(_) => return vec![],
+        }
+        let outgoing_events = self.get_keys_to_emit(event);
+        self.track_output(&outgoing_events);
+        outgoing_events
+    }
+
+    fn layer_lock_for(&self, key: KeyCode) -> Option<String> {
+        self.mappings.iter().find_map(|m| match m {
+            Mapping::LayerLock { input, layer, .. } if *input == key => Some(layer.clone()),
+            _ => None,
+        })
+    }
+
+    /// Handle an event for the input key of a `Mapping::LayerLock`, which
+    /// emits nothing itself. Pressing it locks its layer on, or unlocks it
+    /// if locked, which is then deactivated like a released `Mapping::Layer`.
+    fn handle_layer_lock(&mut self, layer: String, event: &EvKeyEvent) -> Vec<EvKeyEvent> {
+        if event.key_event_type != KeyEventType::Press {
+            return vec![];
+        }
+        if self.locked_layers.remove(&layer) {
+            self.deactivate_layer(&layer);
+        } else {
+            self.locked_layers.insert(layer.clone());
+            self.active.layers.insert(layer);
+        }
+        let outgoing_events = self.get_keys_to_emit(event);
+        self.track_output(&outgoing_events);
+        outgoing_events
+    }
+
+    /// Deactivate `layer`. Once no layer is active, the keys pressed in
+    /// one are suppressed until released, their output having been
+    /// released along with the layer.
+    fn deactivate_layer(&mut self, layer: &str) {
+        self.active.layers.remove(layer);
+        if self.active.layers.is_empty() {
+            self.suppressed_keys.extend(self.layer_keys.drain());
+        }
+    }
+
+    fn one_shot_for(&self, key: KeyCode) -> Option<(KeyCode, bool)> {
+        self.mappings.iter().find_map(|m| match m {
+            Mapping::OneShot {
+                input,
+                modifier,
+                lock_on_double_tap,
+            } if *input == key => Some((*modifier, *lock_on_double_tap)),
+            _ => None,
+        })
+    }
+
+    /// Handle a tap of the input of a `Mapping::OneShot`: the first arms
+    /// `modifier`, a second one before the next key press locks it if
+    /// `lock` is set, and another one after that unlocks it again.
+    fn tap_one_shot(
+        &mut self,
+        modifier: KeyCode,
+        lock: bool,
+        event: &EvKeyEvent,
+    ) -> Vec<EvKeyEvent> {
+        let armed = self.armed_one_shots.contains(&modifier);
+        if self.locked_one_shots.remove(&modifier) {
+            self.input_state.remove(&modifier);
+        } else if armed && lock {
+            self.armed_one_shots.retain(|key| *key != modifier);
+            self.locked_one_shots.insert(modifier);
+            self.input_state.insert(modifier);
+        } else {
+            if !armed {
+                self.armed_one_shots.push(modifier);
+            }
+            return vec![];
+        }
+        let outgoing_events = self.get_keys_to_emit(event);
+        self.track_output(&outgoing_events);
+        outgoing_events
+    }
+
+    fn is_ignored(&self, key: KeyCode) -> bool {
+        self.mappings
+            .iter()
+            .any(|m| matches!(m, Mapping::Ignore { input } if *input == key))
+    }
+
+    fn is_key_name_probe(&self, key: KeyCode) -> bool {
+        self.mappings
+            .iter()
+            .any(|m| matches!(m, Mapping::KeyNameProbe { input } if *input == key))
+    }
+
+    /// Type out the canonical name of the previously pressed key.
+    fn type_previous_key_name(&self, time: &TimeVal) -> Vec<EvKeyEvent> {
+        let Some(previous_key) = self.previous_key else {
+            return vec![];
+        };
+        let name = all_key_names()
+            .into_iter()
+            .find(|(key, _)| *key == previous_key)
+            .map(|(_, name)| name)
+            .unwrap_or_else(|| format!("{:?}", previous_key));
+        type_text(&name, &mut self.frame_clock(time))
+    }
+
+    /// Tap the output of the on-release remaps that `event` released a key
+    /// of, and keep track of those still held.
+    fn tap_released_remaps(&mut self, event: &EvKeyEvent) -> Vec<EvKeyEvent> {
+        let mut events = vec![];
+        if event.key_event_type == KeyEventType::Release {
+            let mut clock = self.frame_clock(&event.time);
+            for &idx in &self.armed_on_release {
+                let Mapping::Remap { input, output, .. } = &self.mappings[idx] else {
+                    continue;
+                };
+                if !input.contains(&event.ev_key) || self.scratch.on_release().contains(&idx) {
+                    continue;
+                }
+                let mut keys: Vec<KeyCode> = output.iter().copied().collect();
+                keys.sort_by(|a, b| modifiers_first(&self.modifiers, a, b));
+                events.extend(tap_keys(&keys, &mut clock));
+            }
+        }
+        self.armed_on_release.clear();
+        self.armed_on_release
+            .extend_from_slice(self.scratch.on_release());
+        events
+    }
+
+    /// A clock for the frames of a burst of events caused by an event at
+    /// `time`, spaced like the steps of a macro.
+    fn frame_clock(&self, time: &TimeVal) -> FrameClock {
+        FrameClock::new(time, self.macro_delay)
+    }
+
+    fn get_keys_to_emit(&mut self, event: &EvKeyEvent) -> Vec<EvKeyEvent> {
+        match event.key_event_type {
+            KeyEventType::Press | KeyEventType::Release => {
+                self.fill_keys_for_mappings();
+                let mut events = compute_keys_based_on_state(
+                    &self.mappings,
+                    &self.index,
+                    &self.modifiers,
+                    &MappingContext::new(&self.held_scratch, &self.active),
+                    &self.output_keys,
+                    &event.time,
+                    &mut self.budget,
+                    &mut self.scratch,
+                );
+                events.extend(self.tap_released_remaps(event));
+                events
+            }
+            KeyEventType::Repeat if self.is_ignored(event.ev_key) => vec![],
+            // Only keys held in the output repeat, so that a key consumed
+            // by a mapping that `lookup_mapping` doesn't find, such as a
+            // tiered one or a combo, doesn't leak through.
+            KeyEventType::Repeat => {
+                match lookup_mapping(
+                    &self.mappings,
+                    &self.index,
+                    &MappingContext::new(&self.input_state, &self.active),
+                    event.ev_key,
+                    &mut self.budget,
+                ) {
+                    // Its output is tapped once, on release, or that of a
+                    // leader key, which repeating mustn't trigger again.
+                    Some(Mapping::Remap {
+                        on_release, when, ..
+                    }) if *on_release || when.idle_ms.is_some() => vec![],
+                    Some(Mapping::Remap { output, .. }) => {
+                        // Like real auto-repeat, only the base keys of a
+                        // chord repeat while its modifiers stay held.
+                        let has_base_key = output.iter().any(|key| !self.modifiers.contains(key));
+                        output
+                            .iter()
+                            .filter(|key| !has_base_key || !self.modifiers.contains(key))
+                            .filter(|key| self.output_keys.contains(key))
+                            .map(|ev_key| EvKeyEvent {
+                                time: event.time,
+                                ev_key: *ev_key,
+                                key_event_type: KeyEventType::Repeat,
+                            })
+                            .collect()
+                    }
+                    _ if self.output_keys.contains(&event.ev_key) => vec![event.clone()],
+                    _ => vec![],
+                }
+            }
+            KeyEventType::Unknown(_) => {
+                vec![event.clone()]
+            }
+        }
+    }
+}
+
+/// Press `keys` in order, then release them in reverse order in the next
+/// frame of `clock`. `SEPARATOR_KEY` splits `keys` into groups that are tapped one after
+/// the other, with a pulse of the separator in between.
+fn tap_keys(keys: &[KeyCode], clock: &mut FrameClock) -> Vec<EvKeyEvent> {
+    let event = |time, ev_key: &KeyCode, key_event_type| EvKeyEvent {
+        time,
+        ev_key: *ev_key,
+        key_event_type,
+    };
+    let mut events = vec![];
+    let mut time = clock.now();
+    for (idx, group) in keys.split(|key| *key == SEPARATOR_KEY).enumerate() {
+        if idx > 0 {
+            events.push(event(clock.tick(), &SEPARATOR_KEY, KeyEventType::Press));
+            events.push(event(clock.tick(), &SEPARATOR_KEY, KeyEventType::Release));
+            time = clock.tick();
+        }
+        events.extend(
+            group
+                .iter()
+                .map(|key| event(time, key, KeyEventType::Press)),
+        );
+        let time = clock.tick();
+        events.extend(
+            group
+                .iter()
+                .rev()
+                .map(|key| event(time, key, KeyEventType::Release)),
+        );
+    }
+    events
+}
+
+/// Flip whether `modifier` is held around `events`: if it is held it gets
+/// released before and pressed again after them, otherwise it gets pressed
+/// before and released after them. The output state is left unchanged.
+fn invert_modifier_around(
+    modifier: KeyCode,
+    held: bool,
+    events: Vec<EvKeyEvent>,
+    time: &TimeVal,
+) -> Vec<EvKeyEvent> {
+    let (before, after) = if held {
+        (KeyEventType::Release, KeyEventType::Press)
+    } else {
+        (KeyEventType::Press, KeyEventType::Release)
+    };
+    let modifier_event = |key_event_type| EvKeyEvent {
+        time: *time,
+        ev_key: modifier,
+        key_event_type,
+    };
+    let mut wrapped = vec![modifier_event(before)];
+    wrapped.extend(events);
+    wrapped.push(modifier_event(after));
+    wrapped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evdev_rs::enums::EV_KEY;
+    use evdev_rs::TimeVal;
+    use std::collections::HashSet;
+
+    #[allow(dead_code)]
+    fn init_logger() {
+        let mut builder = env_logger::Builder::new();
+        builder.filter_level(log::LevelFilter::Trace);
+        let env = env_logger::Env::new()
+            .filter("EVREMAP_LOG")
+            .write_style("EVREMAP_LOG_STYLE");
+        builder.parse_env(env);
+        builder.init();
+    }
+
+    #[test]
+    fn machine_without_config_is_passthrough_for_press() {
+        let dummy_time = TimeVal {
+            tv_sec: 0,
+            tv_usec: 0,
+        };
+        let mut machine = Machine::new(&[]);
+        let dummy_event = EvKeyEvent {
+            time: dummy_time,
+            ev_key: EV_KEY::KEY_1,
+            key_event_type: KeyEventType::Press,
+        };
+        assert_eq!(machine.insert(dummy_event.clone()), vec![dummy_event,]);
+    }
+
+    fn create_timeval(sec: i64) -> TimeVal {
+        TimeVal {
+            tv_sec: sec,
+            tv_usec: 0,
+        }
+    }
+
+    fn create_timeval_ms(ms: i64) -> TimeVal {
+        TimeVal {
+            tv_sec: ms / 1000,
+            tv_usec: (ms % 1000) * 1000,
+        }
+    }
+
+    fn key_event_ms(ms: i64, ev_key: EV_KEY, key_event_type: KeyEventType) -> EvKeyEvent {
+        EvKeyEvent {
+            time: create_timeval_ms(ms),
+            ev_key,
+            key_event_type,
+        }
+    }
+
+    /// Press and release `key` at `ms`, returning what the machine emits.
+    fn tap(machine: &mut Machine, ms: i64, key: EV_KEY) -> Vec<EvKeyEvent> {
+        let mut events = machine.insert(key_event_ms(ms, key, KeyEventType::Press));
+        events.extend(machine.insert(key_event_ms(ms, key, KeyEventType::Release)));
+        events
+    }
+
+    macro_rules! assert_machine_insertion_yields_same_event {
+        ($machine:ident, $event:expr) => {
+            let result = $machine.insert($event.clone());
+            assert_eq!(result, vec![$event]);
+        };
+    }
+
+    #[test]
+    fn machine_without_config_also_emits_releases() {
+        let mut machine = Machine::new(&[]);
+        assert_machine_insertion_yields_same_event!(
+            machine,
+            EvKeyEvent {
                 time: create_timeval(100),
                 ev_key: EV_KEY::KEY_0,
                 key_event_type: KeyEventType::Press,
@@ -155,7 +1506,7 @@ mod tests {
 
     #[test]
     fn machine_without_config_passthrough_for_two_presses() {
-        let mut machine = Machine::new(&vec![]);
+        let mut machine = Machine::new(&[]);
         assert_machine_insertion_yields_same_event!(
             machine,
             EvKeyEvent {
@@ -192,7 +1543,7 @@ mod tests {
 
     #[test]
     fn machine_without_config_passthrough_two_quick_presses() {
-        let mut machine = Machine::new(&vec![]);
+        let mut machine = Machine::new(&[]);
         assert_machine_insertion_yields_same_event!(
             machine,
             EvKeyEvent {
@@ -229,9 +1580,11 @@ mod tests {
 
     #[test]
     fn handles_press_and_release_of_mapping_without_modifier() {
-        let mut machine = Machine::new(&vec![Mapping::Remap {
+        let mut machine = Machine::new(&[Mapping::Remap {
             input: HashSet::from([EV_KEY::KEY_0]),
             output: HashSet::from([EV_KEY::KEY_1]),
+            when: Conditions::default(),
+            on_release: false,
         }]);
 
         assert_eq!(
@@ -262,9 +1615,11 @@ mod tests {
 
     #[test]
     fn handles_press_and_release_of_mapping_with_ctrl() {
-        let mut machine = Machine::new(&vec![Mapping::Remap {
+        let mut machine = Machine::new(&[Mapping::Remap {
             input: HashSet::from([EV_KEY::KEY_0, EV_KEY::KEY_LEFTCTRL]),
             output: HashSet::from([EV_KEY::KEY_1]),
+            when: Conditions::default(),
+            on_release: false,
         }]);
 
         assert_eq!(
@@ -316,4 +1671,1836 @@ mod tests {
             }]
         );
     }
+
+    #[test]
+    fn repeat_of_chord_output_repeats_only_base_key() {
+        let mut machine = Machine::new(&[Mapping::Remap {
+            input: HashSet::from([EV_KEY::KEY_F1]),
+            output: HashSet::from([EV_KEY::KEY_LEFTCTRL, EV_KEY::KEY_A]),
+            when: Conditions::default(),
+            on_release: false,
+        }]);
+        assert_eq!(
+            machine.insert(key_event_ms(0, EV_KEY::KEY_F1, KeyEventType::Press)),
+            vec![
+                key_event_ms(0, EV_KEY::KEY_LEFTCTRL, KeyEventType::Press),
+                key_event_ms(0, EV_KEY::KEY_A, KeyEventType::Press),
+            ]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(500, EV_KEY::KEY_F1, KeyEventType::Repeat)),
+            vec![key_event_ms(500, EV_KEY::KEY_A, KeyEventType::Repeat)]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(533, EV_KEY::KEY_F1, KeyEventType::Repeat)),
+            vec![key_event_ms(533, EV_KEY::KEY_A, KeyEventType::Repeat)]
+        );
+    }
+
+    #[test]
+    fn shifted_symbol_output_holds_shift_around_base_key() {
+        let mut machine = Machine::new(&[Mapping::Remap {
+            input: HashSet::from([EV_KEY::KEY_CAPSLOCK]),
+            output: HashSet::from([EV_KEY::KEY_LEFTSHIFT, EV_KEY::KEY_9]),
+            when: Conditions::default(),
+            on_release: false,
+        }]);
+        let mut events = machine.insert(key_event_ms(0, EV_KEY::KEY_CAPSLOCK, KeyEventType::Press));
+        events.extend(machine.insert(key_event_ms(
+            500,
+            EV_KEY::KEY_CAPSLOCK,
+            KeyEventType::Repeat,
+        )));
+        events.extend(machine.insert(key_event_ms(
+            533,
+            EV_KEY::KEY_CAPSLOCK,
+            KeyEventType::Repeat,
+        )));
+        events.extend(machine.insert(key_event_ms(
+            550,
+            EV_KEY::KEY_CAPSLOCK,
+            KeyEventType::Release,
+        )));
+        assert_eq!(
+            events,
+            vec![
+                key_event_ms(0, EV_KEY::KEY_LEFTSHIFT, KeyEventType::Press),
+                key_event_ms(0, EV_KEY::KEY_9, KeyEventType::Press),
+                key_event_ms(500, EV_KEY::KEY_9, KeyEventType::Repeat),
+                key_event_ms(533, EV_KEY::KEY_9, KeyEventType::Repeat),
+                key_event_ms(550, EV_KEY::KEY_9, KeyEventType::Release),
+                key_event_ms(550, EV_KEY::KEY_LEFTSHIFT, KeyEventType::Release),
+            ]
+        );
+    }
+
+    #[test]
+    fn consumed_keys_never_leak_a_repeat_or_release() {
+        let mut machine = Machine::new(&[Mapping::Tiered {
+            tiers: vec![MappingTier {
+                input: HashSet::from([EV_KEY::KEY_CAPSLOCK]),
+                output: HashSet::from([EV_KEY::KEY_ESC]),
+            }],
+        }]);
+        assert_eq!(
+            machine.insert(key_event_ms(0, EV_KEY::KEY_CAPSLOCK, KeyEventType::Press)),
+            vec![key_event_ms(0, EV_KEY::KEY_ESC, KeyEventType::Press)]
+        );
+        // Passing the repeat on as it was used to leave CapsLock held in
+        // the output, to be released along with Esc.
+        assert_eq!(
+            machine.insert(key_event_ms(
+                500,
+                EV_KEY::KEY_CAPSLOCK,
+                KeyEventType::Repeat
+            )),
+            vec![]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(
+                600,
+                EV_KEY::KEY_CAPSLOCK,
+                KeyEventType::Release
+            )),
+            vec![key_event_ms(600, EV_KEY::KEY_ESC, KeyEventType::Release)]
+        );
+    }
+
+    #[test]
+    fn bypass_chord_toggles_passthrough_releasing_held_keys() {
+        let mut machine = Machine::from_config(&MappingConfig {
+            mappings: vec![Mapping::Remap {
+                input: HashSet::from([EV_KEY::KEY_CAPSLOCK]),
+                output: HashSet::from([EV_KEY::KEY_ESC]),
+                when: Conditions::default(),
+                on_release: false,
+            }],
+            bypass: HashSet::from([EV_KEY::KEY_LEFTCTRL, EV_KEY::KEY_PAUSE]),
+            ..Default::default()
+        });
+        let mut insert =
+            |ms, ev_key, key_event_type| machine.insert(key_event_ms(ms, ev_key, key_event_type));
+        insert(0, EV_KEY::KEY_CAPSLOCK, KeyEventType::Press);
+        insert(10, EV_KEY::KEY_LEFTCTRL, KeyEventType::Press);
+        assert_eq!(
+            insert(20, EV_KEY::KEY_PAUSE, KeyEventType::Press),
+            vec![
+                key_event_ms(20, EV_KEY::KEY_ESC, KeyEventType::Release),
+                key_event_ms(20, EV_KEY::KEY_LEFTCTRL, KeyEventType::Release),
+            ]
+        );
+        insert(30, EV_KEY::KEY_PAUSE, KeyEventType::Release);
+        // Its output was released already, and CapsLock never pressed.
+        assert_eq!(
+            insert(40, EV_KEY::KEY_CAPSLOCK, KeyEventType::Release),
+            vec![]
+        );
+        assert_eq!(
+            insert(50, EV_KEY::KEY_CAPSLOCK, KeyEventType::Press),
+            vec![key_event_ms(50, EV_KEY::KEY_CAPSLOCK, KeyEventType::Press)]
+        );
+        assert_eq!(
+            insert(60, EV_KEY::KEY_PAUSE, KeyEventType::Press),
+            vec![key_event_ms(
+                60,
+                EV_KEY::KEY_CAPSLOCK,
+                KeyEventType::Release
+            )]
+        );
+        assert_eq!(
+            insert(70, EV_KEY::KEY_CAPSLOCK, KeyEventType::Release),
+            vec![]
+        );
+        assert_eq!(
+            insert(80, EV_KEY::KEY_CAPSLOCK, KeyEventType::Press),
+            vec![key_event_ms(80, EV_KEY::KEY_ESC, KeyEventType::Press)]
+        );
+    }
+
+    #[test]
+    fn panic_chord_releases_everything_and_is_ignored_until_released() {
+        let mut machine = Machine::from_config(&MappingConfig {
+            mappings: vec![
+                Mapping::Remap {
+                    input: HashSet::from([EV_KEY::KEY_CAPSLOCK]),
+                    output: HashSet::from([EV_KEY::KEY_LEFTCTRL, EV_KEY::KEY_ESC]),
+                    when: Conditions::default(),
+                    on_release: false,
+                },
+                Mapping::LayerLock {
+                    input: EV_KEY::KEY_F12,
+                    layer: "nav".to_string(),
+                    led: None,
+                },
+            ],
+            panic: HashSet::from([EV_KEY::KEY_RIGHTCTRL, EV_KEY::KEY_PAUSE]),
+            ..Default::default()
+        });
+        let mut insert =
+            |ms, ev_key, key_event_type| machine.insert(key_event_ms(ms, ev_key, key_event_type));
+        insert(0, EV_KEY::KEY_F12, KeyEventType::Press);
+        insert(10, EV_KEY::KEY_F12, KeyEventType::Release);
+        insert(20, EV_KEY::KEY_CAPSLOCK, KeyEventType::Press);
+        assert_eq!(
+            insert(30, EV_KEY::KEY_RIGHTCTRL, KeyEventType::Press),
+            vec![key_event_ms(30, EV_KEY::KEY_RIGHTCTRL, KeyEventType::Press)]
+        );
+        assert_eq!(
+            insert(40, EV_KEY::KEY_PAUSE, KeyEventType::Press),
+            vec![
+                key_event_ms(40, EV_KEY::KEY_ESC, KeyEventType::Release),
+                key_event_ms(40, EV_KEY::KEY_RIGHTCTRL, KeyEventType::Release),
+                key_event_ms(40, EV_KEY::KEY_LEFTCTRL, KeyEventType::Release),
+            ]
+        );
+        for (ms, key) in [(50, EV_KEY::KEY_PAUSE), (60, EV_KEY::KEY_RIGHTCTRL)] {
+            assert_eq!(insert(ms, key, KeyEventType::Release), vec![]);
+        }
+        // CapsLock was forgotten, so its release has nothing to release.
+        assert_eq!(
+            insert(70, EV_KEY::KEY_CAPSLOCK, KeyEventType::Release),
+            vec![]
+        );
+        assert_eq!(
+            insert(80, EV_KEY::KEY_RIGHTCTRL, KeyEventType::Press),
+            vec![key_event_ms(80, EV_KEY::KEY_RIGHTCTRL, KeyEventType::Press)]
+        );
+        assert!(machine.indicator_leds().is_empty());
+        assert!(machine.locked_layers.is_empty());
+    }
+
+    #[test]
+    fn led_conditioned_remap_applies_only_while_led_is_on() {
+        let mut machine = Machine::new(&[Mapping::Remap {
+            input: HashSet::from([EV_KEY::KEY_KP1]),
+            output: HashSet::from([EV_KEY::KEY_F1]),
+            when: Conditions {
+                leds: vec![LedCode::LED_NUML],
+                ..Default::default()
+            },
+            on_release: false,
+        }]);
+        let tap = |machine: &mut Machine| {
+            let mut events = machine.insert(key_event_ms(0, EV_KEY::KEY_KP1, KeyEventType::Press));
+            events.extend(machine.insert(key_event_ms(1, EV_KEY::KEY_KP1, KeyEventType::Release)));
+            events
+                .into_iter()
+                .map(|event| event.ev_key)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(tap(&mut machine), [EV_KEY::KEY_KP1, EV_KEY::KEY_KP1]);
+        machine.set_leds(&HashSet::from([LedCode::LED_NUML]));
+        assert_eq!(tap(&mut machine), [EV_KEY::KEY_F1, EV_KEY::KEY_F1]);
+        machine.set_leds(&HashSet::new());
+        assert_eq!(tap(&mut machine), [EV_KEY::KEY_KP1, EV_KEY::KEY_KP1]);
+    }
+
+    #[test]
+    fn ignored_key_emits_nothing() {
+        let mut machine = Machine::new(&[Mapping::Ignore {
+            input: EV_KEY::KEY_INSERT,
+        }]);
+        for (ms, key_event_type) in [
+            (0, KeyEventType::Press),
+            (500, KeyEventType::Repeat),
+            (600, KeyEventType::Release),
+        ] {
+            assert_eq!(
+                machine.insert(key_event_ms(ms, EV_KEY::KEY_INSERT, key_event_type)),
+                vec![]
+            );
+        }
+        assert_eq!(
+            machine.insert(key_event_ms(700, EV_KEY::KEY_A, KeyEventType::Press)),
+            vec![key_event_ms(700, EV_KEY::KEY_A, KeyEventType::Press)]
+        );
+    }
+
+    #[test]
+    fn remaps_mouse_button_to_chord_with_modifier() {
+        let mut machine = Machine::new(&[Mapping::Remap {
+            input: HashSet::from([EV_KEY::BTN_SIDE]),
+            output: HashSet::from([EV_KEY::KEY_LEFTALT, EV_KEY::KEY_LEFT]),
+            when: Conditions::default(),
+            on_release: false,
+        }]);
+
+        assert_eq!(
+            machine.insert(key_event_ms(100, EV_KEY::BTN_SIDE, KeyEventType::Press)),
+            vec![
+                key_event_ms(100, EV_KEY::KEY_LEFTALT, KeyEventType::Press),
+                key_event_ms(100, EV_KEY::KEY_LEFT, KeyEventType::Press),
+            ]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(200, EV_KEY::BTN_SIDE, KeyEventType::Release)),
+            vec![
+                key_event_ms(200, EV_KEY::KEY_LEFT, KeyEventType::Release),
+                key_event_ms(200, EV_KEY::KEY_LEFTALT, KeyEventType::Release),
+            ]
+        );
+    }
+
+    #[test]
+    fn capslock_held_holds_left_ctrl() {
+        let mut machine = Machine::new(&[Mapping::Remap {
+            input: HashSet::from([EV_KEY::KEY_CAPSLOCK]),
+            output: HashSet::from([EV_KEY::KEY_LEFTCTRL]),
+            when: Conditions::default(),
+            on_release: false,
+        }]);
+
+        assert_eq!(
+            machine.insert(key_event_ms(100, EV_KEY::KEY_CAPSLOCK, KeyEventType::Press)),
+            vec![key_event_ms(100, EV_KEY::KEY_LEFTCTRL, KeyEventType::Press)]
+        );
+        assert_machine_insertion_yields_same_event!(
+            machine,
+            key_event_ms(200, EV_KEY::KEY_C, KeyEventType::Press)
+        );
+        // Releasing the other key leaves Ctrl held.
+        assert_machine_insertion_yields_same_event!(
+            machine,
+            key_event_ms(300, EV_KEY::KEY_C, KeyEventType::Release)
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(
+                400,
+                EV_KEY::KEY_CAPSLOCK,
+                KeyEventType::Release
+            )),
+            vec![key_event_ms(
+                400,
+                EV_KEY::KEY_LEFTCTRL,
+                KeyEventType::Release
+            )]
+        );
+    }
+
+    #[test]
+    fn unknown_values_are_dropped_when_configured() {
+        let unknown = key_event_ms(0, EV_KEY::KEY_A, KeyEventType::Unknown(3));
+        let mut machine = Machine::from_config(&MappingConfig::default());
+        assert_eq!(machine.insert(unknown.clone()), vec![unknown.clone()]);
+        let mut machine = Machine::from_config(&MappingConfig {
+            forward_unknown_values: Some(false),
+            ..Default::default()
+        });
+        assert_eq!(machine.insert(unknown), vec![]);
+    }
+
+    fn jk_combo_machine() -> Machine {
+        Machine::new(&[Mapping::Combo {
+            input: HashSet::from([EV_KEY::KEY_J, EV_KEY::KEY_K]),
+            output: HashSet::from([EV_KEY::KEY_ESC]),
+            window_ms: 50,
+            order: None,
+        }])
+    }
+
+    #[test]
+    fn combo_pressed_within_window_emits_output() {
+        let mut machine = jk_combo_machine();
+        assert_eq!(
+            machine.insert(key_event_ms(0, EV_KEY::KEY_J, KeyEventType::Press)),
+            vec![]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(20, EV_KEY::KEY_K, KeyEventType::Press)),
+            vec![key_event_ms(20, EV_KEY::KEY_ESC, KeyEventType::Press)]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(100, EV_KEY::KEY_K, KeyEventType::Release)),
+            vec![key_event_ms(100, EV_KEY::KEY_ESC, KeyEventType::Release)]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(110, EV_KEY::KEY_J, KeyEventType::Release)),
+            vec![]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(200, EV_KEY::KEY_J, KeyEventType::Press)),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn combo_pressed_too_slowly_passes_keys_through() {
+        let mut machine = jk_combo_machine();
+        assert_eq!(
+            machine.insert(key_event_ms(0, EV_KEY::KEY_J, KeyEventType::Press)),
+            vec![]
+        );
+        // K is held back in turn, as it may start a combo of its own.
+        assert_eq!(
+            machine.insert(key_event_ms(100, EV_KEY::KEY_K, KeyEventType::Press)),
+            vec![key_event_ms(100, EV_KEY::KEY_J, KeyEventType::Press)]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(150, EV_KEY::KEY_J, KeyEventType::Release)),
+            vec![
+                key_event_ms(150, EV_KEY::KEY_K, KeyEventType::Press),
+                key_event_ms(155, EV_KEY::KEY_J, KeyEventType::Release),
+            ]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(200, EV_KEY::KEY_K, KeyEventType::Release)),
+            vec![key_event_ms(200, EV_KEY::KEY_K, KeyEventType::Release)]
+        );
+    }
+
+    #[test]
+    fn partial_combo_passes_key_through_on_release() {
+        let mut machine = jk_combo_machine();
+        assert_eq!(
+            machine.insert(key_event_ms(0, EV_KEY::KEY_J, KeyEventType::Press)),
+            vec![]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(100, EV_KEY::KEY_J, KeyEventType::Release)),
+            vec![
+                key_event_ms(100, EV_KEY::KEY_J, KeyEventType::Press),
+                key_event_ms(105, EV_KEY::KEY_J, KeyEventType::Release),
+            ]
+        );
+    }
+
+    #[test]
+    fn ordered_combo_fires_only_in_its_order() {
+        let mut machine = Machine::new(&[Mapping::Combo {
+            input: HashSet::from([EV_KEY::KEY_J, EV_KEY::KEY_K]),
+            output: HashSet::from([EV_KEY::KEY_ESC]),
+            window_ms: 50,
+            order: Some(vec![EV_KEY::KEY_J, EV_KEY::KEY_K]),
+        }]);
+        assert_eq!(
+            machine.insert(key_event_ms(0, EV_KEY::KEY_J, KeyEventType::Press)),
+            vec![]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(20, EV_KEY::KEY_K, KeyEventType::Press)),
+            vec![key_event_ms(20, EV_KEY::KEY_ESC, KeyEventType::Press)]
+        );
+        machine.insert(key_event_ms(40, EV_KEY::KEY_K, KeyEventType::Release));
+        machine.insert(key_event_ms(50, EV_KEY::KEY_J, KeyEventType::Release));
+
+        // K first can't start it, and J after K can't complete it.
+        assert_eq!(
+            machine.insert(key_event_ms(100, EV_KEY::KEY_K, KeyEventType::Press)),
+            vec![key_event_ms(100, EV_KEY::KEY_K, KeyEventType::Press)]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(120, EV_KEY::KEY_J, KeyEventType::Press)),
+            vec![]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(140, EV_KEY::KEY_K, KeyEventType::Release)),
+            vec![
+                key_event_ms(140, EV_KEY::KEY_J, KeyEventType::Press),
+                key_event_ms(145, EV_KEY::KEY_K, KeyEventType::Release),
+            ]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(160, EV_KEY::KEY_J, KeyEventType::Release)),
+            vec![key_event_ms(160, EV_KEY::KEY_J, KeyEventType::Release)]
+        );
+    }
+
+    fn shift_tap_dance_machine() -> Machine {
+        Machine::new(&[Mapping::TapDance {
+            input: EV_KEY::KEY_LEFTSHIFT,
+            taps: vec![vec![EV_KEY::KEY_LEFTSHIFT], vec![EV_KEY::KEY_CAPSLOCK]],
+            timeout_ms: 200,
+        }])
+    }
+
+    #[test]
+    fn tap_dance_single_tap_resolves_on_tick_after_timeout() {
+        let mut machine = shift_tap_dance_machine();
+        assert_eq!(
+            machine.insert(key_event_ms(0, EV_KEY::KEY_LEFTSHIFT, KeyEventType::Press)),
+            vec![]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(
+                50,
+                EV_KEY::KEY_LEFTSHIFT,
+                KeyEventType::Release
+            )),
+            vec![]
+        );
+        let at_ms = |ms: i64| TimeVal::new(0, ms * 1000);
+        assert_eq!(
+            machine.time_until_tick(&at_ms(150)),
+            Some(Duration::from_millis(50))
+        );
+        assert_eq!(machine.tick(&at_ms(150)), vec![]);
+        assert_eq!(
+            machine.tick(&at_ms(200)),
+            vec![
+                key_event_ms(200, EV_KEY::KEY_LEFTSHIFT, KeyEventType::Press),
+                key_event_ms(205, EV_KEY::KEY_LEFTSHIFT, KeyEventType::Release),
+            ]
+        );
+        assert_eq!(machine.time_until_tick(&at_ms(300)), None);
+    }
+
+    #[test]
+    fn tap_dance_double_tap_emits_second_output() {
+        let mut machine = shift_tap_dance_machine();
+        machine.insert(key_event_ms(0, EV_KEY::KEY_LEFTSHIFT, KeyEventType::Press));
+        machine.insert(key_event_ms(
+            50,
+            EV_KEY::KEY_LEFTSHIFT,
+            KeyEventType::Release,
+        ));
+        assert_eq!(
+            machine.insert(key_event_ms(
+                100,
+                EV_KEY::KEY_LEFTSHIFT,
+                KeyEventType::Press
+            )),
+            vec![
+                key_event_ms(100, EV_KEY::KEY_CAPSLOCK, KeyEventType::Press),
+                key_event_ms(105, EV_KEY::KEY_CAPSLOCK, KeyEventType::Release),
+            ]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(
+                150,
+                EV_KEY::KEY_LEFTSHIFT,
+                KeyEventType::Release
+            )),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn tap_dance_key_held_with_auto_repeat_counts_as_one_tap() {
+        let mut machine = shift_tap_dance_machine();
+        machine.insert(key_event_ms(0, EV_KEY::KEY_LEFTSHIFT, KeyEventType::Press));
+        for ms in [30, 60, 90] {
+            assert_eq!(
+                machine.insert(key_event_ms(
+                    ms,
+                    EV_KEY::KEY_LEFTSHIFT,
+                    KeyEventType::Repeat
+                )),
+                vec![]
+            );
+        }
+        machine.insert(key_event_ms(
+            100,
+            EV_KEY::KEY_LEFTSHIFT,
+            KeyEventType::Release,
+        ));
+        assert_eq!(
+            machine.tick(&create_timeval_ms(200)),
+            vec![
+                key_event_ms(200, EV_KEY::KEY_LEFTSHIFT, KeyEventType::Press),
+                key_event_ms(205, EV_KEY::KEY_LEFTSHIFT, KeyEventType::Release),
+            ]
+        );
+    }
+
+    #[test]
+    fn tap_dance_resolves_before_other_key() {
+        let mut machine = shift_tap_dance_machine();
+        machine.insert(key_event_ms(0, EV_KEY::KEY_LEFTSHIFT, KeyEventType::Press));
+        machine.insert(key_event_ms(
+            50,
+            EV_KEY::KEY_LEFTSHIFT,
+            KeyEventType::Release,
+        ));
+        assert_eq!(
+            machine.insert(key_event_ms(100, EV_KEY::KEY_A, KeyEventType::Press)),
+            vec![
+                key_event_ms(100, EV_KEY::KEY_LEFTSHIFT, KeyEventType::Press),
+                key_event_ms(105, EV_KEY::KEY_LEFTSHIFT, KeyEventType::Release),
+                key_event_ms(110, EV_KEY::KEY_A, KeyEventType::Press),
+            ]
+        );
+    }
+
+    fn one_shot_shift_machine(lock_on_double_tap: bool) -> Machine {
+        Machine::new(&[Mapping::OneShot {
+            input: EV_KEY::KEY_CAPSLOCK,
+            modifier: EV_KEY::KEY_LEFTSHIFT,
+            lock_on_double_tap,
+        }])
+    }
+
+    #[test]
+    fn one_shot_modifier_applies_to_next_key_only() {
+        let mut machine = one_shot_shift_machine(false);
+        assert_eq!(tap(&mut machine, 100, EV_KEY::KEY_CAPSLOCK), vec![]);
+        assert_eq!(
+            tap(&mut machine, 200, EV_KEY::KEY_A),
+            vec![
+                key_event_ms(200, EV_KEY::KEY_LEFTSHIFT, KeyEventType::Press),
+                key_event_ms(200, EV_KEY::KEY_A, KeyEventType::Press),
+                key_event_ms(200, EV_KEY::KEY_LEFTSHIFT, KeyEventType::Release),
+                key_event_ms(200, EV_KEY::KEY_A, KeyEventType::Release),
+            ]
+        );
+        assert_eq!(
+            tap(&mut machine, 300, EV_KEY::KEY_B),
+            vec![
+                key_event_ms(300, EV_KEY::KEY_B, KeyEventType::Press),
+                key_event_ms(300, EV_KEY::KEY_B, KeyEventType::Release),
+            ]
+        );
+    }
+
+    #[test]
+    fn one_shot_modifier_waits_out_other_modifiers() {
+        let mut machine = one_shot_shift_machine(false);
+        tap(&mut machine, 100, EV_KEY::KEY_CAPSLOCK);
+        assert_eq!(
+            machine.insert(key_event_ms(200, EV_KEY::KEY_LEFTCTRL, KeyEventType::Press)),
+            vec![key_event_ms(200, EV_KEY::KEY_LEFTCTRL, KeyEventType::Press)]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(300, EV_KEY::KEY_C, KeyEventType::Press)),
+            vec![
+                key_event_ms(300, EV_KEY::KEY_LEFTSHIFT, KeyEventType::Press),
+                key_event_ms(300, EV_KEY::KEY_C, KeyEventType::Press),
+                key_event_ms(300, EV_KEY::KEY_LEFTSHIFT, KeyEventType::Release),
+            ]
+        );
+    }
+
+    #[test]
+    fn one_shot_modifier_locks_on_double_tap() {
+        let mut machine = one_shot_shift_machine(true);
+        assert_eq!(tap(&mut machine, 100, EV_KEY::KEY_CAPSLOCK), vec![]);
+        assert_eq!(
+            tap(&mut machine, 200, EV_KEY::KEY_CAPSLOCK),
+            vec![key_event_ms(
+                200,
+                EV_KEY::KEY_LEFTSHIFT,
+                KeyEventType::Press
+            )]
+        );
+        for ms in [300, 400] {
+            assert_eq!(
+                tap(&mut machine, ms, EV_KEY::KEY_A),
+                vec![
+                    key_event_ms(ms, EV_KEY::KEY_A, KeyEventType::Press),
+                    key_event_ms(ms, EV_KEY::KEY_A, KeyEventType::Release),
+                ]
+            );
+        }
+        assert_eq!(
+            tap(&mut machine, 500, EV_KEY::KEY_CAPSLOCK),
+            vec![key_event_ms(
+                500,
+                EV_KEY::KEY_LEFTSHIFT,
+                KeyEventType::Release
+            )]
+        );
+
+        // Without locking, a second tap leaves it armed.
+        let mut machine = one_shot_shift_machine(false);
+        tap(&mut machine, 100, EV_KEY::KEY_CAPSLOCK);
+        assert_eq!(tap(&mut machine, 200, EV_KEY::KEY_CAPSLOCK), vec![]);
+        assert_eq!(
+            tap(&mut machine, 300, EV_KEY::KEY_A)[0],
+            key_event_ms(300, EV_KEY::KEY_LEFTSHIFT, KeyEventType::Press)
+        );
+    }
+
+    #[test]
+    fn layer_applies_while_its_key_is_held() {
+        let mut machine = Machine::new(&[
+            Mapping::Remap {
+                input: HashSet::from([EV_KEY::KEY_H]),
+                output: HashSet::from([EV_KEY::KEY_LEFT]),
+                when: Conditions {
+                    layers: vec!["nav".to_string()],
+                    ..Default::default()
+                },
+                on_release: false,
+            },
+            Mapping::Layer {
+                input: EV_KEY::KEY_CAPSLOCK,
+                layer: "nav".to_string(),
+            },
+        ]);
+        assert_eq!(
+            tap(&mut machine, 100, EV_KEY::KEY_H),
+            vec![
+                key_event_ms(100, EV_KEY::KEY_H, KeyEventType::Press),
+                key_event_ms(100, EV_KEY::KEY_H, KeyEventType::Release),
+            ]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(200, EV_KEY::KEY_CAPSLOCK, KeyEventType::Press)),
+            vec![]
+        );
+        assert_eq!(
+            tap(&mut machine, 300, EV_KEY::KEY_H),
+            vec![
+                key_event_ms(300, EV_KEY::KEY_LEFT, KeyEventType::Press),
+                key_event_ms(300, EV_KEY::KEY_LEFT, KeyEventType::Release),
+            ]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(
+                400,
+                EV_KEY::KEY_CAPSLOCK,
+                KeyEventType::Release
+            )),
+            vec![]
+        );
+        assert_eq!(
+            tap(&mut machine, 500, EV_KEY::KEY_H),
+            vec![
+                key_event_ms(500, EV_KEY::KEY_H, KeyEventType::Press),
+                key_event_ms(500, EV_KEY::KEY_H, KeyEventType::Release),
+            ]
+        );
+    }
+
+    #[test]
+    fn layer_of_right_alt_is_not_entered_by_left_alt() {
+        let mut machine = Machine::new(&[
+            Mapping::Remap {
+                input: HashSet::from([EV_KEY::KEY_H]),
+                output: HashSet::from([EV_KEY::KEY_LEFT]),
+                when: Conditions {
+                    layers: vec!["nav".to_string()],
+                    ..Default::default()
+                },
+                on_release: false,
+            },
+            Mapping::Layer {
+                input: EV_KEY::KEY_RIGHTALT,
+                layer: "nav".to_string(),
+            },
+        ]);
+        assert_eq!(
+            machine.insert(key_event_ms(100, EV_KEY::KEY_LEFTALT, KeyEventType::Press)),
+            vec![key_event_ms(100, EV_KEY::KEY_LEFTALT, KeyEventType::Press)]
+        );
+        assert_eq!(
+            tap(&mut machine, 200, EV_KEY::KEY_H),
+            vec![
+                key_event_ms(200, EV_KEY::KEY_H, KeyEventType::Press),
+                key_event_ms(200, EV_KEY::KEY_H, KeyEventType::Release),
+            ]
+        );
+        machine.insert(key_event_ms(
+            300,
+            EV_KEY::KEY_LEFTALT,
+            KeyEventType::Release,
+        ));
+
+        assert_eq!(
+            machine.insert(key_event_ms(400, EV_KEY::KEY_RIGHTALT, KeyEventType::Press)),
+            vec![]
+        );
+        assert_eq!(
+            tap(&mut machine, 500, EV_KEY::KEY_H),
+            vec![
+                key_event_ms(500, EV_KEY::KEY_LEFT, KeyEventType::Press),
+                key_event_ms(500, EV_KEY::KEY_LEFT, KeyEventType::Release),
+            ]
+        );
+    }
+
+    #[test]
+    fn releasing_layer_key_first_releases_layer_output() {
+        let mut machine = Machine::new(&[
+            Mapping::Remap {
+                input: HashSet::from([EV_KEY::KEY_H]),
+                output: HashSet::from([EV_KEY::KEY_LEFT]),
+                when: Conditions {
+                    layers: vec!["nav".to_string()],
+                    ..Default::default()
+                },
+                on_release: false,
+            },
+            Mapping::Layer {
+                input: EV_KEY::KEY_CAPSLOCK,
+                layer: "nav".to_string(),
+            },
+        ]);
+        machine.insert(key_event_ms(100, EV_KEY::KEY_CAPSLOCK, KeyEventType::Press));
+        assert_eq!(
+            machine.insert(key_event_ms(200, EV_KEY::KEY_H, KeyEventType::Press)),
+            vec![key_event_ms(200, EV_KEY::KEY_LEFT, KeyEventType::Press)]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(
+                300,
+                EV_KEY::KEY_CAPSLOCK,
+                KeyEventType::Release
+            )),
+            vec![key_event_ms(300, EV_KEY::KEY_LEFT, KeyEventType::Release)]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(400, EV_KEY::KEY_H, KeyEventType::Repeat)),
+            vec![]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(500, EV_KEY::KEY_H, KeyEventType::Release)),
+            vec![]
+        );
+        assert_eq!(
+            tap(&mut machine, 600, EV_KEY::KEY_H)[0],
+            key_event_ms(600, EV_KEY::KEY_H, KeyEventType::Press)
+        );
+    }
+
+    #[test]
+    fn layer_lock_toggles_layer_over_base_remaps() {
+        let remap = |output, layers: &[&str]| Mapping::Remap {
+            input: HashSet::from([EV_KEY::KEY_H]),
+            output: HashSet::from([output]),
+            when: Conditions {
+                layers: layers.iter().map(|layer| layer.to_string()).collect(),
+                ..Default::default()
+            },
+            on_release: false,
+        };
+        let mut machine = Machine::from_config(&MappingConfig {
+            mappings: vec![
+                // In the order `MappingConfig` puts them in, layers first.
+                remap(EV_KEY::KEY_LEFT, &["nav"]),
+                remap(EV_KEY::KEY_BACKSPACE, &[]),
+                Mapping::LayerLock {
+                    input: EV_KEY::KEY_CAPSLOCK,
+                    layer: "nav".to_string(),
+                    led: Some(LedCode::LED_SCROLLL),
+                },
+            ],
+            ..Default::default()
+        });
+        assert_eq!(
+            tap(&mut machine, 100, EV_KEY::KEY_H)[0],
+            key_event_ms(100, EV_KEY::KEY_BACKSPACE, KeyEventType::Press)
+        );
+        assert_eq!(tap(&mut machine, 200, EV_KEY::KEY_CAPSLOCK), vec![]);
+        assert_eq!(
+            machine.indicator_leds(),
+            HashSet::from([LedCode::LED_SCROLLL])
+        );
+        for ms in [300, 400] {
+            assert_eq!(
+                tap(&mut machine, ms, EV_KEY::KEY_H),
+                vec![
+                    key_event_ms(ms, EV_KEY::KEY_LEFT, KeyEventType::Press),
+                    key_event_ms(ms, EV_KEY::KEY_LEFT, KeyEventType::Release),
+                ]
+            );
+        }
+
+        // Unlocking while a key of the layer is held releases its output.
+        machine.insert(key_event_ms(500, EV_KEY::KEY_H, KeyEventType::Press));
+        assert_eq!(
+            tap(&mut machine, 600, EV_KEY::KEY_CAPSLOCK),
+            vec![key_event_ms(600, EV_KEY::KEY_LEFT, KeyEventType::Release)]
+        );
+        assert!(machine.indicator_leds().is_empty());
+        assert_eq!(
+            machine.insert(key_event_ms(700, EV_KEY::KEY_H, KeyEventType::Release)),
+            vec![]
+        );
+        assert_eq!(
+            tap(&mut machine, 800, EV_KEY::KEY_H)[0],
+            key_event_ms(800, EV_KEY::KEY_BACKSPACE, KeyEventType::Press)
+        );
+    }
+
+    #[test]
+    fn on_release_remap_taps_output_once_input_is_released() {
+        let mut machine = Machine::new(&[Mapping::Remap {
+            input: HashSet::from([EV_KEY::KEY_BACK]),
+            output: HashSet::from([EV_KEY::KEY_LEFTALT, EV_KEY::KEY_LEFT]),
+            when: Conditions::default(),
+            on_release: true,
+        }]);
+        assert_eq!(
+            machine.insert(key_event_ms(100, EV_KEY::KEY_BACK, KeyEventType::Press)),
+            vec![]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(200, EV_KEY::KEY_BACK, KeyEventType::Repeat)),
+            vec![]
+        );
+        let released = machine.insert(key_event_ms(300, EV_KEY::KEY_BACK, KeyEventType::Release));
+        assert_eq!(
+            released,
+            vec![
+                key_event_ms(300, EV_KEY::KEY_LEFTALT, KeyEventType::Press),
+                key_event_ms(300, EV_KEY::KEY_LEFT, KeyEventType::Press),
+                key_event_ms(305, EV_KEY::KEY_LEFT, KeyEventType::Release),
+                key_event_ms(305, EV_KEY::KEY_LEFTALT, KeyEventType::Release),
+            ]
+        );
+        assert_eq!(tap(&mut machine, 400, EV_KEY::KEY_A).len(), 2);
+    }
+
+    #[test]
+    fn keys_held_before_grab_are_seeded() {
+        let mut machine = Machine::from_config(&MappingConfig {
+            mappings: vec![Mapping::Remap {
+                input: HashSet::from([EV_KEY::KEY_CAPSLOCK]),
+                output: HashSet::from([EV_KEY::KEY_LEFTCTRL]),
+                when: Conditions::default(),
+                on_release: false,
+            }],
+            ..Default::default()
+        });
+        assert_eq!(
+            machine.seed_held_keys(
+                &[EV_KEY::KEY_CAPSLOCK, EV_KEY::KEY_LEFTSHIFT],
+                &create_timeval_ms(100)
+            ),
+            vec![
+                key_event_ms(100, EV_KEY::KEY_LEFTSHIFT, KeyEventType::Press),
+                key_event_ms(100, EV_KEY::KEY_LEFTCTRL, KeyEventType::Press),
+            ]
+        );
+        assert_eq!(
+            tap(&mut machine, 200, EV_KEY::KEY_C),
+            vec![
+                key_event_ms(200, EV_KEY::KEY_C, KeyEventType::Press),
+                key_event_ms(200, EV_KEY::KEY_C, KeyEventType::Release),
+            ]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(
+                300,
+                EV_KEY::KEY_CAPSLOCK,
+                KeyEventType::Release
+            )),
+            vec![key_event_ms(
+                300,
+                EV_KEY::KEY_LEFTCTRL,
+                KeyEventType::Release
+            )]
+        );
+    }
+
+    #[test]
+    fn idle_conditioned_remap_applies_only_at_the_start_of_a_chord() {
+        let mut machine = Machine::new(&[Mapping::Remap {
+            input: HashSet::from([EV_KEY::KEY_SPACE]),
+            output: HashSet::from([EV_KEY::KEY_F13]),
+            when: Conditions {
+                idle_ms: Some(300),
+                ..Default::default()
+            },
+            on_release: false,
+        }]);
+        machine.insert(key_event_ms(0, EV_KEY::KEY_A, KeyEventType::Press));
+        machine.insert(key_event_ms(50, EV_KEY::KEY_A, KeyEventType::Release));
+        assert_eq!(
+            machine.insert(key_event_ms(100, EV_KEY::KEY_SPACE, KeyEventType::Press)),
+            vec![key_event_ms(100, EV_KEY::KEY_SPACE, KeyEventType::Press)]
+        );
+        machine.insert(key_event_ms(150, EV_KEY::KEY_SPACE, KeyEventType::Release));
+
+        assert_eq!(
+            machine.insert(key_event_ms(1000, EV_KEY::KEY_SPACE, KeyEventType::Press)),
+            vec![key_event_ms(1000, EV_KEY::KEY_F13, KeyEventType::Press)]
+        );
+        // Auto-repeat neither triggers it again nor counts as a press.
+        for ms in [1500, 1600] {
+            assert_eq!(
+                machine.insert(key_event_ms(ms, EV_KEY::KEY_SPACE, KeyEventType::Repeat)),
+                vec![]
+            );
+        }
+        assert_eq!(
+            machine.insert(key_event_ms(1700, EV_KEY::KEY_SPACE, KeyEventType::Release)),
+            vec![key_event_ms(1700, EV_KEY::KEY_F13, KeyEventType::Release)]
+        );
+    }
+
+    #[test]
+    fn idle_conditioned_remap_applies_to_a_key_that_resolves_a_tap_dance() {
+        let mut machine = Machine::new(&[
+            Mapping::TapDance {
+                input: EV_KEY::KEY_F1,
+                taps: vec![vec![EV_KEY::KEY_MUTE], vec![EV_KEY::KEY_PLAYPAUSE]],
+                timeout_ms: 200,
+            },
+            Mapping::Remap {
+                input: HashSet::from([EV_KEY::KEY_SPACE]),
+                output: HashSet::from([EV_KEY::KEY_F13]),
+                when: Conditions {
+                    idle_ms: Some(300),
+                    ..Default::default()
+                },
+                on_release: false,
+            },
+        ]);
+        machine.insert(key_event_ms(0, EV_KEY::KEY_F1, KeyEventType::Press));
+        machine.insert(key_event_ms(50, EV_KEY::KEY_F1, KeyEventType::Release));
+        // The tap dance timed out without a tick, so the press resolves it
+        // and is then handled a frame later, still after a long idle time.
+        assert_eq!(
+            machine.insert(key_event_ms(1000, EV_KEY::KEY_SPACE, KeyEventType::Press)),
+            vec![
+                key_event_ms(1000, EV_KEY::KEY_MUTE, KeyEventType::Press),
+                key_event_ms(1005, EV_KEY::KEY_MUTE, KeyEventType::Release),
+                key_event_ms(1010, EV_KEY::KEY_F13, KeyEventType::Press),
+            ]
+        );
+    }
+
+    #[test]
+    fn key_name_probe_types_name_of_previous_key() {
+        let mut machine = Machine::new(&[Mapping::KeyNameProbe {
+            input: EV_KEY::KEY_F12,
+        }]);
+        machine.insert(EvKeyEvent {
+            time: create_timeval(100),
+            ev_key: EV_KEY::KEY_A,
+            key_event_type: KeyEventType::Press,
+        });
+        machine.insert(EvKeyEvent {
+            time: create_timeval(200),
+            ev_key: EV_KEY::KEY_A,
+            key_event_type: KeyEventType::Release,
+        });
+        let typed = machine.insert(EvKeyEvent {
+            time: create_timeval(300),
+            ev_key: EV_KEY::KEY_F12,
+            key_event_type: KeyEventType::Press,
+        });
+        assert_eq!(
+            typed,
+            type_text(
+                "KEY_A",
+                &mut FrameClock::new(&create_timeval(300), DEFAULT_MACRO_DELAY)
+            )
+        );
+        let pressed: Vec<EV_KEY> = typed
+            .iter()
+            .filter(|e| e.key_event_type == KeyEventType::Press)
+            .map(|e| e.ev_key)
+            .filter(|k| *k != EV_KEY::KEY_LEFTSHIFT)
+            .collect();
+        assert_eq!(
+            pressed,
+            vec![
+                EV_KEY::KEY_K,
+                EV_KEY::KEY_E,
+                EV_KEY::KEY_Y,
+                EV_KEY::KEY_MINUS,
+                EV_KEY::KEY_A
+            ]
+        );
+        assert_eq!(
+            machine.insert(EvKeyEvent {
+                time: create_timeval(400),
+                ev_key: EV_KEY::KEY_F12,
+                key_event_type: KeyEventType::Release,
+            }),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn invert_modifier_adds_shift_to_next_key() {
+        let mut machine = Machine::new(&[Mapping::InvertModifier {
+            input: EV_KEY::KEY_F11,
+            modifier: EV_KEY::KEY_LEFTSHIFT,
+        }]);
+        assert_eq!(
+            machine.insert(key_event_ms(100, EV_KEY::KEY_F11, KeyEventType::Press)),
+            vec![]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(110, EV_KEY::KEY_F11, KeyEventType::Release)),
+            vec![]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(200, EV_KEY::KEY_A, KeyEventType::Press)),
+            vec![
+                key_event_ms(200, EV_KEY::KEY_LEFTSHIFT, KeyEventType::Press),
+                key_event_ms(200, EV_KEY::KEY_A, KeyEventType::Press),
+                key_event_ms(200, EV_KEY::KEY_LEFTSHIFT, KeyEventType::Release),
+            ]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(300, EV_KEY::KEY_A, KeyEventType::Release)),
+            vec![key_event_ms(300, EV_KEY::KEY_A, KeyEventType::Release)]
+        );
+        // The inversion is one-shot.
+        assert_machine_insertion_yields_same_event!(
+            machine,
+            key_event_ms(400, EV_KEY::KEY_B, KeyEventType::Press)
+        );
+    }
+
+    #[test]
+    fn invert_modifier_removes_held_shift_from_next_key() {
+        let mut machine = Machine::new(&[Mapping::InvertModifier {
+            input: EV_KEY::KEY_F11,
+            modifier: EV_KEY::KEY_LEFTSHIFT,
+        }]);
+        assert_machine_insertion_yields_same_event!(
+            machine,
+            key_event_ms(100, EV_KEY::KEY_LEFTSHIFT, KeyEventType::Press)
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(150, EV_KEY::KEY_F11, KeyEventType::Press)),
+            vec![]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(200, EV_KEY::KEY_A, KeyEventType::Press)),
+            vec![
+                key_event_ms(200, EV_KEY::KEY_LEFTSHIFT, KeyEventType::Release),
+                key_event_ms(200, EV_KEY::KEY_A, KeyEventType::Press),
+                key_event_ms(200, EV_KEY::KEY_LEFTSHIFT, KeyEventType::Press),
+            ]
+        );
+        assert_machine_insertion_yields_same_event!(
+            machine,
+            key_event_ms(300, EV_KEY::KEY_A, KeyEventType::Release)
+        );
+        assert_machine_insertion_yields_same_event!(
+            machine,
+            key_event_ms(400, EV_KEY::KEY_LEFTSHIFT, KeyEventType::Release)
+        );
+    }
+
+    fn capslock_dual_role_machine() -> Machine {
+        capslock_dual_role_machine_with(DualRoleMode::HoldPreferred)
+    }
+
+    fn capslock_dual_role_machine_with(mode: DualRoleMode) -> Machine {
+        Machine::new(&[Mapping::DualRole {
+            input: EV_KEY::KEY_CAPSLOCK,
+            hold: vec![EV_KEY::KEY_LEFTCTRL],
+            tap: vec![EV_KEY::KEY_ESC],
+            mode,
+        }])
+    }
+
+    #[test]
+    fn dual_role_quick_tap_emits_tap() {
+        let mut machine = capslock_dual_role_machine();
+        assert_eq!(
+            machine.insert(key_event_ms(0, EV_KEY::KEY_CAPSLOCK, KeyEventType::Press)),
+            vec![]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(
+                100,
+                EV_KEY::KEY_CAPSLOCK,
+                KeyEventType::Release
+            )),
+            vec![
+                key_event_ms(100, EV_KEY::KEY_ESC, KeyEventType::Press),
+                key_event_ms(105, EV_KEY::KEY_ESC, KeyEventType::Release),
+            ]
+        );
+    }
+
+    #[test]
+    fn dual_role_held_past_timeout_emits_hold() {
+        let mut machine = capslock_dual_role_machine();
+        assert_eq!(
+            machine.insert(key_event_ms(0, EV_KEY::KEY_CAPSLOCK, KeyEventType::Press)),
+            vec![]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(
+                100,
+                EV_KEY::KEY_CAPSLOCK,
+                KeyEventType::Repeat
+            )),
+            vec![]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(
+                500,
+                EV_KEY::KEY_CAPSLOCK,
+                KeyEventType::Release
+            )),
+            vec![
+                key_event_ms(500, EV_KEY::KEY_LEFTCTRL, KeyEventType::Press),
+                key_event_ms(505, EV_KEY::KEY_LEFTCTRL, KeyEventType::Release),
+            ]
+        );
+    }
+
+    #[test]
+    fn dual_role_becomes_hold_on_tick_past_timeout() {
+        let mut machine = capslock_dual_role_machine();
+        machine.insert(key_event_ms(0, EV_KEY::KEY_CAPSLOCK, KeyEventType::Press));
+        assert_eq!(
+            machine.time_until_tick(&create_timeval_ms(50)),
+            Some(Duration::from_millis(150))
+        );
+        assert_eq!(machine.tick(&create_timeval_ms(100)), vec![]);
+        assert_eq!(
+            machine.tick(&create_timeval_ms(250)),
+            vec![key_event_ms(250, EV_KEY::KEY_LEFTCTRL, KeyEventType::Press)]
+        );
+        assert_eq!(machine.time_until_tick(&create_timeval_ms(250)), None);
+        assert_eq!(
+            machine.insert(key_event_ms(
+                400,
+                EV_KEY::KEY_CAPSLOCK,
+                KeyEventType::Release
+            )),
+            vec![key_event_ms(
+                400,
+                EV_KEY::KEY_LEFTCTRL,
+                KeyEventType::Release
+            )]
+        );
+    }
+
+    #[test]
+    fn keys_held_with_no_input_past_the_stuck_key_timeout_are_released() {
+        let mut machine = Machine::from_config(&MappingConfig {
+            mappings: vec![Mapping::Remap {
+                input: HashSet::from([EV_KEY::KEY_CAPSLOCK]),
+                output: HashSet::from([EV_KEY::KEY_LEFTCTRL]),
+                when: Conditions::default(),
+                on_release: false,
+            }],
+            stuck_key_timeout_ms: Some(1000),
+            ..Default::default()
+        });
+        machine.insert(key_event_ms(0, EV_KEY::KEY_CAPSLOCK, KeyEventType::Press));
+        assert_eq!(
+            machine.time_until_tick(&create_timeval_ms(400)),
+            Some(Duration::from_millis(600))
+        );
+        // Auto-repeat says the key is still held.
+        machine.insert(key_event_ms(
+            900,
+            EV_KEY::KEY_CAPSLOCK,
+            KeyEventType::Repeat,
+        ));
+        assert_eq!(machine.tick(&create_timeval_ms(1500)), vec![]);
+        assert_eq!(
+            machine.tick(&create_timeval_ms(1900)),
+            vec![key_event_ms(
+                1900,
+                EV_KEY::KEY_LEFTCTRL,
+                KeyEventType::Release
+            )]
+        );
+        assert_eq!(machine.time_until_tick(&create_timeval_ms(1900)), None);
+        // The release that got lost, should it turn up after all.
+        assert_eq!(
+            machine.insert(key_event_ms(
+                2500,
+                EV_KEY::KEY_CAPSLOCK,
+                KeyEventType::Release
+            )),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn dual_role_interrupted_by_rolling_key_becomes_hold() {
+        let mut machine = capslock_dual_role_machine();
+        assert_eq!(
+            machine.insert(key_event_ms(0, EV_KEY::KEY_CAPSLOCK, KeyEventType::Press)),
+            vec![]
+        );
+        // The second key goes down before the dual role key is released.
+        assert_eq!(
+            machine.insert(key_event_ms(50, EV_KEY::KEY_A, KeyEventType::Press)),
+            vec![
+                key_event_ms(50, EV_KEY::KEY_LEFTCTRL, KeyEventType::Press),
+                key_event_ms(50, EV_KEY::KEY_A, KeyEventType::Press),
+            ]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(
+                80,
+                EV_KEY::KEY_CAPSLOCK,
+                KeyEventType::Release
+            )),
+            vec![key_event_ms(
+                80,
+                EV_KEY::KEY_LEFTCTRL,
+                KeyEventType::Release
+            )]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(100, EV_KEY::KEY_A, KeyEventType::Release)),
+            vec![key_event_ms(100, EV_KEY::KEY_A, KeyEventType::Release)]
+        );
+    }
+
+    #[test]
+    fn dual_role_pressed_while_other_key_held_still_taps() {
+        let mut machine = capslock_dual_role_machine();
+        assert_machine_insertion_yields_same_event!(
+            machine,
+            key_event_ms(0, EV_KEY::KEY_A, KeyEventType::Press)
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(10, EV_KEY::KEY_CAPSLOCK, KeyEventType::Press)),
+            vec![]
+        );
+        assert_machine_insertion_yields_same_event!(
+            machine,
+            key_event_ms(20, EV_KEY::KEY_A, KeyEventType::Release)
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(
+                50,
+                EV_KEY::KEY_CAPSLOCK,
+                KeyEventType::Release
+            )),
+            vec![
+                key_event_ms(50, EV_KEY::KEY_ESC, KeyEventType::Press),
+                key_event_ms(55, EV_KEY::KEY_ESC, KeyEventType::Release),
+            ]
+        );
+    }
+
+    #[test]
+    fn dual_role_repeats_do_not_reset_press_time() {
+        let mut machine = capslock_dual_role_machine();
+        assert_eq!(
+            machine.insert(key_event_ms(0, EV_KEY::KEY_CAPSLOCK, KeyEventType::Press)),
+            vec![]
+        );
+        // Were the repeats treated as presses, the release would be
+        // within the tap timeout of the last one.
+        for ms in [250, 280, 310] {
+            assert_eq!(
+                machine.insert(key_event_ms(ms, EV_KEY::KEY_CAPSLOCK, KeyEventType::Repeat)),
+                vec![]
+            );
+        }
+        assert_eq!(
+            machine.insert(key_event_ms(
+                320,
+                EV_KEY::KEY_CAPSLOCK,
+                KeyEventType::Release
+            )),
+            vec![
+                key_event_ms(320, EV_KEY::KEY_LEFTCTRL, KeyEventType::Press),
+                key_event_ms(325, EV_KEY::KEY_LEFTCTRL, KeyEventType::Release),
+            ]
+        );
+    }
+
+    #[test]
+    fn tap_preferred_dual_role_taps_when_released_in_time_despite_other_keys() {
+        let mut machine = capslock_dual_role_machine_with(DualRoleMode::TapPreferred);
+        machine.insert(key_event_ms(0, EV_KEY::KEY_CAPSLOCK, KeyEventType::Press));
+        assert_eq!(
+            machine.insert(key_event_ms(20, EV_KEY::KEY_J, KeyEventType::Press)),
+            vec![]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(40, EV_KEY::KEY_J, KeyEventType::Release)),
+            vec![]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(
+                60,
+                EV_KEY::KEY_CAPSLOCK,
+                KeyEventType::Release
+            )),
+            vec![
+                key_event_ms(60, EV_KEY::KEY_ESC, KeyEventType::Press),
+                key_event_ms(65, EV_KEY::KEY_ESC, KeyEventType::Release),
+                key_event_ms(70, EV_KEY::KEY_J, KeyEventType::Press),
+                key_event_ms(75, EV_KEY::KEY_J, KeyEventType::Release),
+            ]
+        );
+    }
+
+    #[test]
+    fn tap_preferred_dual_role_holds_once_time_is_up() {
+        let mut machine = capslock_dual_role_machine_with(DualRoleMode::TapPreferred);
+        machine.insert(key_event_ms(0, EV_KEY::KEY_CAPSLOCK, KeyEventType::Press));
+        machine.insert(key_event_ms(20, EV_KEY::KEY_J, KeyEventType::Press));
+        assert_eq!(
+            machine.tick(&create_timeval_ms(200)),
+            vec![
+                key_event_ms(200, EV_KEY::KEY_LEFTCTRL, KeyEventType::Press),
+                key_event_ms(205, EV_KEY::KEY_J, KeyEventType::Press),
+            ]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(250, EV_KEY::KEY_J, KeyEventType::Release)),
+            vec![key_event_ms(250, EV_KEY::KEY_J, KeyEventType::Release)]
+        );
+    }
+
+    #[test]
+    fn permissive_hold_dual_role_holds_when_another_key_is_tapped_within() {
+        let mut machine = capslock_dual_role_machine_with(DualRoleMode::PermissiveHold);
+        machine.insert(key_event_ms(0, EV_KEY::KEY_CAPSLOCK, KeyEventType::Press));
+        assert_eq!(
+            machine.insert(key_event_ms(20, EV_KEY::KEY_J, KeyEventType::Press)),
+            vec![]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(40, EV_KEY::KEY_J, KeyEventType::Release)),
+            vec![
+                key_event_ms(45, EV_KEY::KEY_LEFTCTRL, KeyEventType::Press),
+                key_event_ms(45, EV_KEY::KEY_J, KeyEventType::Press),
+                key_event_ms(50, EV_KEY::KEY_J, KeyEventType::Release),
+            ]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(
+                60,
+                EV_KEY::KEY_CAPSLOCK,
+                KeyEventType::Release
+            )),
+            vec![key_event_ms(
+                60,
+                EV_KEY::KEY_LEFTCTRL,
+                KeyEventType::Release
+            )]
+        );
+    }
+
+    #[test]
+    fn permissive_hold_dual_role_taps_when_rolled_over() {
+        let mut machine = capslock_dual_role_machine_with(DualRoleMode::PermissiveHold);
+        machine.insert(key_event_ms(0, EV_KEY::KEY_CAPSLOCK, KeyEventType::Press));
+        machine.insert(key_event_ms(20, EV_KEY::KEY_J, KeyEventType::Press));
+        assert_eq!(
+            machine.insert(key_event_ms(
+                40,
+                EV_KEY::KEY_CAPSLOCK,
+                KeyEventType::Release
+            )),
+            vec![
+                key_event_ms(40, EV_KEY::KEY_ESC, KeyEventType::Press),
+                key_event_ms(45, EV_KEY::KEY_ESC, KeyEventType::Release),
+                key_event_ms(50, EV_KEY::KEY_J, KeyEventType::Press),
+            ]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(60, EV_KEY::KEY_J, KeyEventType::Release)),
+            vec![key_event_ms(60, EV_KEY::KEY_J, KeyEventType::Release)]
+        );
+    }
+
+    #[test]
+    fn nested_permissive_hold_dual_roles_hold_the_outer_and_tap_the_inner() {
+        let mut machine = Machine::new(&[
+            Mapping::DualRole {
+                input: EV_KEY::KEY_CAPSLOCK,
+                hold: vec![EV_KEY::KEY_LEFTCTRL],
+                tap: vec![EV_KEY::KEY_ESC],
+                mode: DualRoleMode::PermissiveHold,
+            },
+            Mapping::DualRole {
+                input: EV_KEY::KEY_SPACE,
+                hold: vec![EV_KEY::KEY_LEFTSHIFT],
+                tap: vec![EV_KEY::KEY_SPACE],
+                mode: DualRoleMode::PermissiveHold,
+            },
+        ]);
+        machine.insert(key_event_ms(0, EV_KEY::KEY_CAPSLOCK, KeyEventType::Press));
+        assert_eq!(
+            machine.insert(key_event_ms(20, EV_KEY::KEY_SPACE, KeyEventType::Press)),
+            vec![]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(40, EV_KEY::KEY_SPACE, KeyEventType::Release)),
+            vec![
+                key_event_ms(45, EV_KEY::KEY_LEFTCTRL, KeyEventType::Press),
+                key_event_ms(50, EV_KEY::KEY_SPACE, KeyEventType::Press),
+                key_event_ms(55, EV_KEY::KEY_SPACE, KeyEventType::Release),
+            ]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(
+                60,
+                EV_KEY::KEY_CAPSLOCK,
+                KeyEventType::Release
+            )),
+            vec![key_event_ms(
+                60,
+                EV_KEY::KEY_LEFTCTRL,
+                KeyEventType::Release
+            )]
+        );
+    }
+
+    #[test]
+    fn nested_permissive_hold_dual_roles_tap_both_when_rolled_over() {
+        let mut machine = Machine::new(&[
+            Mapping::DualRole {
+                input: EV_KEY::KEY_CAPSLOCK,
+                hold: vec![EV_KEY::KEY_LEFTCTRL],
+                tap: vec![EV_KEY::KEY_ESC],
+                mode: DualRoleMode::PermissiveHold,
+            },
+            Mapping::DualRole {
+                input: EV_KEY::KEY_SPACE,
+                hold: vec![EV_KEY::KEY_LEFTSHIFT],
+                tap: vec![EV_KEY::KEY_SPACE],
+                mode: DualRoleMode::PermissiveHold,
+            },
+        ]);
+        machine.insert(key_event_ms(0, EV_KEY::KEY_CAPSLOCK, KeyEventType::Press));
+        machine.insert(key_event_ms(20, EV_KEY::KEY_SPACE, KeyEventType::Press));
+        assert_eq!(
+            machine.insert(key_event_ms(
+                40,
+                EV_KEY::KEY_CAPSLOCK,
+                KeyEventType::Release
+            )),
+            vec![
+                key_event_ms(40, EV_KEY::KEY_ESC, KeyEventType::Press),
+                key_event_ms(45, EV_KEY::KEY_ESC, KeyEventType::Release),
+            ]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(60, EV_KEY::KEY_SPACE, KeyEventType::Release)),
+            vec![
+                key_event_ms(60, EV_KEY::KEY_SPACE, KeyEventType::Press),
+                key_event_ms(65, EV_KEY::KEY_SPACE, KeyEventType::Release),
+            ]
+        );
+    }
+
+    #[test]
+    fn dual_role_tap_emits_separator_pulse_where_requested() {
+        let mut machine = Machine::new(&[Mapping::DualRole {
+            input: EV_KEY::KEY_CAPSLOCK,
+            hold: vec![EV_KEY::KEY_LEFTCTRL],
+            tap: vec![EV_KEY::KEY_ESC, SEPARATOR_KEY, EV_KEY::KEY_ESC],
+            mode: DualRoleMode::HoldPreferred,
+        }]);
+        machine.insert(key_event_ms(0, EV_KEY::KEY_CAPSLOCK, KeyEventType::Press));
+        assert_eq!(
+            machine.insert(key_event_ms(
+                50,
+                EV_KEY::KEY_CAPSLOCK,
+                KeyEventType::Release
+            )),
+            vec![
+                key_event_ms(50, EV_KEY::KEY_ESC, KeyEventType::Press),
+                key_event_ms(55, EV_KEY::KEY_ESC, KeyEventType::Release),
+                key_event_ms(60, EV_KEY::KEY_RESERVED, KeyEventType::Press),
+                key_event_ms(65, EV_KEY::KEY_RESERVED, KeyEventType::Release),
+                key_event_ms(70, EV_KEY::KEY_ESC, KeyEventType::Press),
+                key_event_ms(75, EV_KEY::KEY_ESC, KeyEventType::Release),
+            ]
+        );
+    }
+
+    #[test]
+    fn deferred_passthrough_commits_on_release() {
+        let mut machine = Machine::new(&[Mapping::DeferredPassthrough {
+            input: EV_KEY::KEY_SPACE,
+            cancel: HashSet::from([EV_KEY::KEY_ESC]),
+        }]);
+        assert_eq!(
+            machine.insert(key_event_ms(0, EV_KEY::KEY_SPACE, KeyEventType::Press)),
+            vec![]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(1, EV_KEY::KEY_SPACE, KeyEventType::Repeat)),
+            vec![]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(2, EV_KEY::KEY_SPACE, KeyEventType::Release)),
+            vec![
+                key_event_ms(2, EV_KEY::KEY_SPACE, KeyEventType::Press),
+                key_event_ms(7, EV_KEY::KEY_SPACE, KeyEventType::Release),
+            ]
+        );
+    }
+
+    #[test]
+    fn deferred_passthrough_cancelled_press_emits_nothing() {
+        let mut machine = Machine::new(&[Mapping::DeferredPassthrough {
+            input: EV_KEY::KEY_SPACE,
+            cancel: HashSet::from([EV_KEY::KEY_ESC]),
+        }]);
+        machine.insert(key_event_ms(0, EV_KEY::KEY_SPACE, KeyEventType::Press));
+        assert_eq!(
+            machine.insert(key_event_ms(1, EV_KEY::KEY_ESC, KeyEventType::Press)),
+            vec![key_event_ms(1, EV_KEY::KEY_ESC, KeyEventType::Press)]
+        );
+        machine.insert(key_event_ms(2, EV_KEY::KEY_ESC, KeyEventType::Release));
+        assert_eq!(
+            machine.insert(key_event_ms(3, EV_KEY::KEY_SPACE, KeyEventType::Release)),
+            vec![]
+        );
+
+        // The next press is committed again.
+        machine.insert(key_event_ms(4, EV_KEY::KEY_SPACE, KeyEventType::Press));
+        assert_eq!(
+            machine
+                .insert(key_event_ms(5, EV_KEY::KEY_SPACE, KeyEventType::Release))
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn macro_emits_steps_in_order_with_delay() {
+        let mut machine = Machine::new(&[Mapping::Macro {
+            input: EV_KEY::KEY_F1,
+            sequence: vec![
+                MacroStep::Press(EV_KEY::KEY_H),
+                MacroStep::Release(EV_KEY::KEY_H),
+                MacroStep::Press(EV_KEY::KEY_I),
+                MacroStep::Release(EV_KEY::KEY_I),
+            ],
+        }]);
+        assert_eq!(
+            machine.insert(key_event_ms(0, EV_KEY::KEY_F1, KeyEventType::Press)),
+            vec![
+                key_event_ms(0, EV_KEY::KEY_H, KeyEventType::Press),
+                key_event_ms(5, EV_KEY::KEY_H, KeyEventType::Release),
+                key_event_ms(10, EV_KEY::KEY_I, KeyEventType::Press),
+                key_event_ms(15, EV_KEY::KEY_I, KeyEventType::Release),
+            ]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(1, EV_KEY::KEY_F1, KeyEventType::Release)),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn macro_key_release_releases_keys_left_pressed() {
+        let mut machine = Machine::new(&[Mapping::Macro {
+            input: EV_KEY::KEY_F1,
+            sequence: vec![
+                MacroStep::Press(EV_KEY::KEY_LEFTSHIFT),
+                MacroStep::Press(EV_KEY::KEY_H),
+            ],
+        }]);
+        machine.insert(key_event_ms(0, EV_KEY::KEY_F1, KeyEventType::Press));
+        // Regular keys don't release what the macro holds.
+        assert_eq!(
+            machine.insert(key_event_ms(1, EV_KEY::KEY_A, KeyEventType::Press)),
+            vec![key_event_ms(1, EV_KEY::KEY_A, KeyEventType::Press)]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(2, EV_KEY::KEY_F1, KeyEventType::Release)),
+            vec![
+                key_event_ms(2, EV_KEY::KEY_H, KeyEventType::Release),
+                key_event_ms(2, EV_KEY::KEY_LEFTSHIFT, KeyEventType::Release),
+            ]
+        );
+    }
+
+    #[test]
+    fn unicode_enters_hex_code_once_per_press() {
+        let mut machine = Machine::new(&[Mapping::Unicode {
+            input: EV_KEY::KEY_F1,
+            codepoint: 'é',
+            method: UnicodeMethod::Gtk,
+        }]);
+        let steps: Vec<_> = machine
+            .insert(key_event_ms(0, EV_KEY::KEY_F1, KeyEventType::Press))
+            .into_iter()
+            .map(|event| (event.ev_key, event.key_event_type))
+            .collect();
+        use KeyEventType::{Press, Release};
+        assert_eq!(
+            steps,
+            vec![
+                (EV_KEY::KEY_LEFTCTRL, Press),
+                (EV_KEY::KEY_LEFTSHIFT, Press),
+                (EV_KEY::KEY_U, Press),
+                (EV_KEY::KEY_U, Release),
+                (EV_KEY::KEY_E, Press),
+                (EV_KEY::KEY_E, Release),
+                (EV_KEY::KEY_9, Press),
+                (EV_KEY::KEY_9, Release),
+                (EV_KEY::KEY_LEFTSHIFT, Release),
+                (EV_KEY::KEY_LEFTCTRL, Release),
+            ]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(500, EV_KEY::KEY_F1, KeyEventType::Repeat)),
+            vec![]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(510, EV_KEY::KEY_F1, KeyEventType::Release)),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn text_is_typed_with_shift_where_needed() {
+        let mut machine = Machine::new(&[Mapping::Text {
+            input: EV_KEY::KEY_F1,
+            text: "a@B".to_string(),
+        }]);
+        let steps: Vec<_> = machine
+            .insert(key_event_ms(0, EV_KEY::KEY_F1, KeyEventType::Press))
+            .into_iter()
+            .map(|event| (event.ev_key, event.key_event_type))
+            .collect();
+        use KeyEventType::{Press, Release};
+        assert_eq!(
+            steps,
+            vec![
+                (EV_KEY::KEY_A, Press),
+                (EV_KEY::KEY_A, Release),
+                (EV_KEY::KEY_LEFTSHIFT, Press),
+                (EV_KEY::KEY_2, Press),
+                (EV_KEY::KEY_2, Release),
+                (EV_KEY::KEY_LEFTSHIFT, Release),
+                (EV_KEY::KEY_LEFTSHIFT, Press),
+                (EV_KEY::KEY_B, Press),
+                (EV_KEY::KEY_B, Release),
+                (EV_KEY::KEY_LEFTSHIFT, Release),
+            ]
+        );
+        assert_eq!(
+            machine.insert(key_event_ms(500, EV_KEY::KEY_F1, KeyEventType::Release)),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn release_all_releases_a_held_remapped_chord() {
+        let mut machine = capslock_dual_role_machine();
+        machine.insert(key_event_ms(0, EV_KEY::KEY_CAPSLOCK, KeyEventType::Press));
+        machine.insert(key_event_ms(10, EV_KEY::KEY_C, KeyEventType::Press));
+        assert_eq!(
+            machine.release_all(&create_timeval_ms(20)),
+            vec![
+                key_event_ms(20, EV_KEY::KEY_C, KeyEventType::Release),
+                key_event_ms(20, EV_KEY::KEY_LEFTCTRL, KeyEventType::Release),
+            ]
+        );
+        assert_eq!(machine.release_all(&create_timeval_ms(30)), vec![]);
+    }
+
+    mod stuck_keys {
+        use super::*;
+        use proptest::prelude::*;
+
+        const KEYS: [EV_KEY; 6] = [
+            EV_KEY::KEY_A,
+            EV_KEY::KEY_B,
+            EV_KEY::KEY_C,
+            EV_KEY::KEY_CAPSLOCK,
+            EV_KEY::KEY_LEFTCTRL,
+            EV_KEY::KEY_LEFTSHIFT,
+        ];
+
+        fn key() -> impl Strategy<Value = EV_KEY> {
+            prop::sample::select(KEYS.to_vec())
+        }
+
+        fn keys() -> impl Strategy<Value = HashSet<EV_KEY>> {
+            prop::collection::hash_set(key(), 1..=2)
+        }
+
+        fn mapping() -> impl Strategy<Value = Mapping> {
+            prop_oneof![
+                4 => (keys(), keys(), any::<bool>(), any::<bool>()).prop_map(
+                    |(input, output, in_layer, on_release)| Mapping::Remap {
+                        input,
+                        output,
+                        when: Conditions {
+                            layers: in_layer.then(|| "nav".to_string()).into_iter().collect(),
+                            ..Default::default()
+                        },
+                        on_release,
+                    }
+                ),
+                1 => key().prop_map(|input| Mapping::Layer {
+                    input,
+                    layer: "nav".to_string(),
+                }),
+                1 => (
+                    key(),
+                    key(),
+                    key(),
+                    prop::sample::select(vec![
+                        DualRoleMode::HoldPreferred,
+                        DualRoleMode::TapPreferred,
+                        DualRoleMode::PermissiveHold,
+                    ])
+                )
+                    .prop_map(|(input, hold, tap, mode)| Mapping::DualRole {
+                        input,
+                        hold: vec![hold],
+                        tap: vec![tap],
+                        mode,
+                    }),
+                1 => key().prop_map(|input| Mapping::Ignore { input }),
+                1 => (prop::collection::hash_set(key(), 2), keys()).prop_map(
+                    |(input, output)| Mapping::Combo {
+                        order: None,
+                        input,
+                        output,
+                        window_ms: 50,
+                    }
+                ),
+                1 => (key(), prop::collection::vec(prop::collection::vec(key(), 1..=2), 1..=2))
+                    .prop_map(|(input, taps)| Mapping::TapDance {
+                        input,
+                        taps,
+                        timeout_ms: 200,
+                    }),
+            ]
+        }
+
+        proptest! {
+            /// Whatever the mappings and however the keys are pressed,
+            /// releasing every key leaves no key held in the output.
+            #[test]
+            fn releasing_every_key_releases_every_output_key(
+                mappings in prop::collection::vec(mapping(), 0..6),
+                presses in prop::collection::vec((key(), any::<bool>(), 1..300i64), 0..40),
+            ) {
+                let mut machine = Machine::from_config(&MappingConfig {
+                    mappings,
+                    ..Default::default()
+                });
+                let mut held = HashSet::new();
+                let mut output = HashSet::new();
+                let mut emit = |events: Vec<EvKeyEvent>| {
+                    for event in events {
+                        match event.key_event_type {
+                            KeyEventType::Press => output.insert(event.ev_key),
+                            KeyEventType::Release => output.remove(&event.ev_key),
+                            _ => true,
+                        };
+                    }
+                };
+                let mut ms = 0;
+                for (key, repeat, gap_ms) in presses {
+                    ms += gap_ms;
+                    emit(machine.tick(&create_timeval_ms(ms)));
+                    let key_event_type = if !held.contains(&key) {
+                        held.insert(key);
+                        KeyEventType::Press
+                    } else if repeat {
+                        KeyEventType::Repeat
+                    } else {
+                        held.remove(&key);
+                        KeyEventType::Release
+                    };
+                    emit(machine.insert(key_event_ms(ms, key, key_event_type)));
+                }
+                for key in held {
+                    ms += 10;
+                    emit(machine.insert(key_event_ms(ms, key, KeyEventType::Release)));
+                }
+                // Let whatever is pending, such as a dual role tap, resolve.
+                emit(machine.tick(&create_timeval_ms(ms + 60_000)));
+                prop_assert!(output.is_empty(), "stuck in the output: {:?}", output);
+                prop_assert!(machine.output_keys.is_empty());
+            }
+        }
+    }
 }
@@ -0,0 +1,161 @@
+use super::types::EvKeyEvent;
+use crate::mapping::{key_name, KeyCode, Mapping};
+use std::collections::HashSet;
+
+/// How much a `Machine` has been doing, for tuning a config: how many
+/// events went in and out, and how often each remap fired.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    /// Events inserted into the machine.
+    pub events_in: u64,
+    /// Events the machine emitted for them.
+    pub events_out: u64,
+    /// How many key presses each mapping, by its position, applied to.
+    /// Only `Mapping::Remap` and `Mapping::Tiered` are counted.
+    pub hits: Vec<u64>,
+}
+
+impl Metrics {
+    pub(super) fn inserted(&mut self, outgoing_events: &[EvKeyEvent]) {
+        self.events_in += 1;
+        self.events_out += outgoing_events.len() as u64;
+    }
+
+    /// Count a hit for each of the `applied` mappings that has `pressed`
+    /// in its input.
+    pub(super) fn pressed(&mut self, mappings: &[Mapping], applied: &[usize], pressed: KeyCode) {
+        self.hits.resize(mappings.len(), 0);
+        for &idx in applied {
+            let involved = match &mappings[idx] {
+                Mapping::Remap { input, .. } => input.contains(&pressed),
+                Mapping::Tiered { tiers } => tiers.iter().any(|tier| tier.input.contains(&pressed)),
+                _ => false,
+            };
+            if involved {
+                self.hits[idx] += 1;
+            }
+        }
+    }
+
+    /// The counts in human-readable form, most hit mappings first.
+    pub fn report(&self, mappings: &[Mapping]) -> String {
+        let per_insert = match self.events_in {
+            0 => 0.0,
+            events_in => self.events_out as f64 / events_in as f64,
+        };
+        let mut lines = vec![format!(
+            "{} events in, {} out ({:.2} per event in)",
+            self.events_in, self.events_out, per_insert
+        )];
+        let mut hits: Vec<(usize, u64)> = self.hits.iter().copied().enumerate().collect();
+        hits.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        for (idx, count) in hits {
+            if let Some(description) = describe_mapping(&mappings[idx]) {
+                lines.push(format!("{:>8} {}", count, description));
+            }
+        }
+        lines.join("\n")
+    }
+}
+
+/// The mappings whose hits are counted, by their keys.
+fn describe_mapping(mapping: &Mapping) -> Option<String> {
+    let keys = |keys: &HashSet<KeyCode>| {
+        let mut names: Vec<String> = keys.iter().map(|key| key_name(*key)).collect();
+        names.sort();
+        names.join("+")
+    };
+    match mapping {
+        Mapping::Remap { input, output, .. } => {
+            Some(format!("remap {} -> {}", keys(input), keys(output)))
+        }
+        Mapping::Tiered { tiers } => Some(format!(
+            "tiered {}",
+            tiers
+                .iter()
+                .map(|tier| format!("{} -> {}", keys(&tier.input), keys(&tier.output)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapping::Conditions;
+    use crate::remapper::{KeyEventType, Machine};
+    use evdev_rs::TimeVal;
+
+    #[test]
+    fn counts_presses_each_remap_applied_to() {
+        let remap = |input, output| Mapping::Remap {
+            input: HashSet::from([input]),
+            output: HashSet::from([output]),
+            when: Conditions::default(),
+            on_release: false,
+        };
+        let mut machine = Machine::new(&[
+            remap(KeyCode::KEY_CAPSLOCK, KeyCode::KEY_ESC),
+            remap(KeyCode::KEY_F1, KeyCode::KEY_MUTE),
+        ]);
+        for key_event_type in [
+            KeyEventType::Press,
+            KeyEventType::Release,
+            KeyEventType::Press,
+            KeyEventType::Release,
+        ] {
+            machine.insert(EvKeyEvent {
+                time: TimeVal::new(0, 0),
+                ev_key: KeyCode::KEY_CAPSLOCK,
+                key_event_type,
+            });
+        }
+        let metrics = machine.metrics();
+        assert_eq!((metrics.events_in, metrics.events_out), (4, 4));
+        assert_eq!(metrics.hits, vec![2, 0]);
+        assert_eq!(
+            metrics.report(machine.mappings()),
+            "4 events in, 4 out (1.00 per event in)\n\
+             \x20      2 remap KEY_CAPSLOCK -> KEY_ESC\n\
+             \x20      0 remap KEY_F1 -> KEY_MUTE"
+        );
+    }
+
+    #[test]
+    fn events_handled_again_after_a_combo_or_tap_dance_count_once() {
+        let mut machine = Machine::new(&[
+            Mapping::Combo {
+                input: HashSet::from([KeyCode::KEY_A, KeyCode::KEY_S]),
+                output: HashSet::from([KeyCode::KEY_ESC]),
+                window_ms: 50,
+                order: None,
+            },
+            Mapping::TapDance {
+                input: KeyCode::KEY_F1,
+                taps: vec![vec![KeyCode::KEY_MUTE], vec![KeyCode::KEY_PLAYPAUSE]],
+                timeout_ms: 200,
+            },
+        ]);
+        let mut insert = |ms: i64, ev_key, key_event_type| {
+            machine
+                .insert(EvKeyEvent {
+                    time: TimeVal::new(0, ms * 1000),
+                    ev_key,
+                    key_event_type,
+                })
+                .len()
+        };
+        // Held back in case S follows.
+        assert_eq!(insert(0, KeyCode::KEY_A, KeyEventType::Press), 0);
+        // Lets A through, then D.
+        assert_eq!(insert(100, KeyCode::KEY_D, KeyEventType::Press), 2);
+        assert_eq!(insert(200, KeyCode::KEY_F1, KeyEventType::Press), 0);
+        assert_eq!(insert(250, KeyCode::KEY_F1, KeyEventType::Release), 0);
+        // Taps Mute, then presses B.
+        assert_eq!(insert(300, KeyCode::KEY_B, KeyEventType::Press), 3);
+        let metrics = machine.metrics();
+        assert_eq!((metrics.events_in, metrics.events_out), (5, 5));
+    }
+}
@@ -0,0 +1,117 @@
+use std::ffi::OsString;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::{Duration, Instant};
+
+/// Tells the service manager, systemd in practice, how the service is
+/// doing by the sd_notify protocol: a datagram per state change sent to
+/// the socket in `NOTIFY_SOCKET`. This is what `Type=notify` and
+/// `WatchdogSec=` in a unit rely on.
+pub struct Notifier {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+    /// How often to ping the watchdog, if it is enabled.
+    watchdog: Option<Duration>,
+    last_ping: Instant,
+}
+
+impl Notifier {
+    /// The notifier for the service manager that started us, if it asked
+    /// for notifications and evremap was built with the `systemd` feature.
+    pub fn from_env() -> Option<Notifier> {
+        if !cfg!(feature = "systemd") {
+            return None;
+        }
+        let path = std::env::var_os("NOTIFY_SOCKET")?;
+        // The watchdog is meant for us alone, unless WATCHDOG_PID says
+        // it is meant for another process.
+        let for_us =
+            std::env::var("WATCHDOG_PID").map_or(true, |pid| pid == std::process::id().to_string());
+        let watchdog = std::env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse().ok())
+            .filter(|_| for_us)
+            .map(|usec: u64| Duration::from_micros(usec) / 2);
+        match Notifier::new(path, watchdog) {
+            Ok(notifier) => Some(notifier),
+            Err(err) => {
+                log::warn!("Not notifying the service manager: {}", err);
+                None
+            }
+        }
+    }
+
+    /// A notifier for the socket at `path`, where a leading `@` stands for
+    /// the abstract namespace, pinging the watchdog every `watchdog`.
+    fn new(path: OsString, watchdog: Option<Duration>) -> std::io::Result<Notifier> {
+        let addr = match path.as_bytes().strip_prefix(b"@") {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(&path)?,
+        };
+        Ok(Notifier {
+            socket: UnixDatagram::unbound()?,
+            addr,
+            watchdog,
+            last_ping: Instant::now(),
+        })
+    }
+
+    fn send(&self, state: &str) {
+        if let Err(err) = self.socket.send_to_addr(state.as_bytes(), &self.addr) {
+            log::warn!("Failed to notify the service manager of {}: {}", state, err);
+        }
+    }
+
+    /// The devices are grabbed and the virtual devices are up.
+    pub fn ready(&self) {
+        self.send("READY=1");
+    }
+
+    pub fn stopping(&self) {
+        self.send("STOPPING=1");
+    }
+
+    /// How long until the watchdog is due a ping, if it is enabled.
+    pub fn time_until_ping(&self) -> Option<Duration> {
+        let watchdog = self.watchdog?;
+        Some(watchdog.saturating_sub(self.last_ping.elapsed()))
+    }
+
+    /// Ping the watchdog if it is due one.
+    pub fn ping_if_due(&mut self) {
+        if self
+            .time_until_ping()
+            .is_some_and(|remaining| remaining.is_zero())
+        {
+            self.send("WATCHDOG=1");
+            self.last_ping = Instant::now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn receive(socket: &UnixDatagram) -> String {
+        let mut buf = [0; 64];
+        let len = socket.recv(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    }
+
+    #[test]
+    fn sends_states_and_due_watchdog_pings() {
+        let path = std::env::temp_dir().join(format!("evremap-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let manager = UnixDatagram::bind(&path).unwrap();
+        let mut notifier = Notifier::new(path.clone().into(), Some(Duration::ZERO)).unwrap();
+        notifier.ready();
+        assert_eq!(receive(&manager), "READY=1");
+        notifier.ping_if_due();
+        assert_eq!(receive(&manager), "WATCHDOG=1");
+        notifier.stopping();
+        assert_eq!(receive(&manager), "STOPPING=1");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
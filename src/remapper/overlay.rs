@@ -0,0 +1,102 @@
+use super::types::{EvKeyEvent, KeyEventType};
+use crate::mapping::KeyCode;
+use std::collections::HashSet;
+
+/// Turns what the machines emit into what to add to inputs that aren't
+/// grabbed, whose own events reach the system as well: only the keys that
+/// the input doesn't already hold are pressed, and released again.
+/// A key that is held on the input can't be released for it.
+#[derive(Debug, Default)]
+pub struct Overlay {
+    /// Keys held on the inputs.
+    held: HashSet<KeyCode>,
+    /// Keys held on the virtual devices.
+    injected: HashSet<KeyCode>,
+}
+
+impl Overlay {
+    /// Let the overlay know that an input sent `event`, which the system
+    /// got as well.
+    pub fn input(&mut self, event: &EvKeyEvent) {
+        match event.key_event_type {
+            KeyEventType::Press => {
+                self.held.insert(event.ev_key);
+            }
+            KeyEventType::Release => {
+                self.held.remove(&event.ev_key);
+            }
+            KeyEventType::Repeat | KeyEventType::Unknown(_) => {}
+        }
+    }
+
+    /// The events of `events` that the system doesn't get from the inputs
+    /// already.
+    pub fn difference(&mut self, events: Vec<EvKeyEvent>) -> Vec<EvKeyEvent> {
+        events
+            .into_iter()
+            .filter(|event| match event.key_event_type {
+                KeyEventType::Press => {
+                    !self.held.contains(&event.ev_key) && self.injected.insert(event.ev_key)
+                }
+                KeyEventType::Release => self.injected.remove(&event.ev_key),
+                KeyEventType::Repeat | KeyEventType::Unknown(_) => {
+                    self.injected.contains(&event.ev_key)
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evdev_rs::TimeVal;
+
+    fn event(ev_key: KeyCode, key_event_type: KeyEventType) -> EvKeyEvent {
+        EvKeyEvent {
+            time: TimeVal::new(0, 0),
+            ev_key,
+            key_event_type,
+        }
+    }
+
+    #[test]
+    fn only_keys_the_input_does_not_hold_are_added() {
+        let mut overlay = Overlay::default();
+        let f13 = event(KeyCode::KEY_F13, KeyEventType::Press);
+        overlay.input(&f13);
+        // F13 passed through as is, and remapped to Mute.
+        assert_eq!(
+            overlay.difference(vec![
+                f13.clone(),
+                event(KeyCode::KEY_MUTE, KeyEventType::Press)
+            ]),
+            vec![event(KeyCode::KEY_MUTE, KeyEventType::Press)]
+        );
+        assert_eq!(
+            overlay.difference(vec![event(KeyCode::KEY_MUTE, KeyEventType::Repeat)]),
+            vec![event(KeyCode::KEY_MUTE, KeyEventType::Repeat)]
+        );
+        let f13_up = event(KeyCode::KEY_F13, KeyEventType::Release);
+        overlay.input(&f13_up);
+        assert_eq!(
+            overlay.difference(vec![
+                f13_up,
+                event(KeyCode::KEY_MUTE, KeyEventType::Release)
+            ]),
+            vec![event(KeyCode::KEY_MUTE, KeyEventType::Release)]
+        );
+    }
+
+    #[test]
+    fn keys_held_on_the_input_are_not_released_for_it() {
+        let mut overlay = Overlay::default();
+        let shift = event(KeyCode::KEY_LEFTSHIFT, KeyEventType::Press);
+        overlay.input(&shift);
+        assert_eq!(overlay.difference(vec![shift]), vec![]);
+        assert_eq!(
+            overlay.difference(vec![event(KeyCode::KEY_LEFTSHIFT, KeyEventType::Release)]),
+            vec![]
+        );
+    }
+}
@@ -0,0 +1,274 @@
+use super::machine::Machine;
+use super::types::{duration_between, time_after, EvKeyEvent, KeyEventType};
+use crate::mapping::{key_name, parse_key, MappingConfig};
+use anyhow::{Context, Result};
+use evdev_rs::TimeVal;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::time::Duration;
+
+/// One line of a recording file; a recording is a JSON-lines file
+/// holding one of these per `EvKeyEvent`, eg:
+/// `{"tv_sec":12,"tv_usec":3400,"key":"KEY_A","value":1}`, where `value`
+/// is that of the `KeyEventType`.
+#[derive(Debug, Serialize, Deserialize)]
+struct RecordedEvent {
+    tv_sec: libc::time_t,
+    tv_usec: libc::suseconds_t,
+    key: String,
+    value: i32,
+}
+
+impl From<&EvKeyEvent> for RecordedEvent {
+    fn from(event: &EvKeyEvent) -> Self {
+        RecordedEvent {
+            tv_sec: event.time.tv_sec,
+            tv_usec: event.time.tv_usec,
+            key: key_name(event.ev_key),
+            value: event.key_event_type.value(),
+        }
+    }
+}
+
+impl TryFrom<RecordedEvent> for EvKeyEvent {
+    type Error = anyhow::Error;
+    fn try_from(recorded: RecordedEvent) -> Result<Self> {
+        Ok(EvKeyEvent {
+            time: TimeVal {
+                tv_sec: recorded.tv_sec,
+                tv_usec: recorded.tv_usec,
+            },
+            ev_key: parse_key(&recorded.key)?,
+            key_event_type: KeyEventType::from_value(recorded.value),
+        })
+    }
+}
+
+pub fn read_events<R: BufRead>(reader: R) -> Result<Vec<EvKeyEvent>> {
+    let mut events = vec![];
+    for (idx, line) in reader.lines().enumerate() {
+        if let Some(event) = parse_event(idx, &line?)? {
+            events.push(event);
+        }
+    }
+    Ok(events)
+}
+
+/// The event on line `idx` of a recording, counting from 0, or `None`
+/// for a blank line.
+fn parse_event(idx: usize, line: &str) -> Result<Option<EvKeyEvent>> {
+    if line.trim().is_empty() {
+        return Ok(None);
+    }
+    let recorded: RecordedEvent =
+        serde_json::from_str(line).context(format!("parsing event on line {}", idx + 1))?;
+    Ok(Some(recorded.try_into()?))
+}
+
+/// Append `event` to a recording, flushed so that the recording holds
+/// every event up to the one that went wrong, however it ends.
+pub fn write_event<W: Write>(writer: &mut W, event: &EvKeyEvent) -> Result<()> {
+    serde_json::to_writer(&mut *writer, &RecordedEvent::from(event))?;
+    writeln!(writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Something that can wait; abstracted so that replay timing can be tested.
+pub trait Clock {
+    fn sleep(&mut self, duration: Duration);
+}
+
+struct SystemClock;
+
+impl Clock for SystemClock {
+    fn sleep(&mut self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+/// Feed `events` through `machine`, passing each input event along with
+/// the events it produced to `on_output`. When a `clock` is given, the
+/// original delays between the recorded events are reproduced.
+pub fn replay(
+    machine: &mut Machine,
+    events: &[EvKeyEvent],
+    mut clock: Option<&mut dyn Clock>,
+    mut on_output: impl FnMut(&EvKeyEvent, &[EvKeyEvent]),
+) {
+    let mut previous_time: Option<TimeVal> = None;
+    for event in events {
+        if let (Some(clock), Some(previous_time)) = (clock.as_deref_mut(), previous_time) {
+            clock.sleep(duration_between(&previous_time, &event.time));
+        }
+        previous_time = Some(event.time);
+        let output = machine.insert(event.clone());
+        on_output(event, &output);
+    }
+}
+
+/// Replay a recording through a `Machine` built from `mapping_config`
+/// and print the resulting translation.
+pub fn replay_file(mapping_config: &MappingConfig, path: &Path, realtime: bool) -> Result<()> {
+    let file = std::fs::File::open(path).context(format!("opening {}", path.display()))?;
+    let events = read_events(std::io::BufReader::new(file))
+        .context(format!("reading events from {}", path.display()))?;
+    let mut machine = Machine::from_config(mapping_config);
+    let mut clock = SystemClock;
+    let clock: Option<&mut dyn Clock> = if realtime { Some(&mut clock) } else { None };
+    replay(&mut machine, &events, clock, |input, output| {
+        let output: Vec<String> = output.iter().map(ToString::to_string).collect();
+        println!("IN {} -> OUT [{}]", input, output.join(", "));
+    });
+    Ok(())
+}
+
+/// Feed the events of a recording read from `reader` through a `Machine`
+/// built from `mapping_config` as they come, writing the events it emits
+/// to `writer` in the same format. Time passes as the times of the events
+/// say, so a dual role key held for longer than its timeout becomes a
+/// hold before the next event goes in, and the decisions still pending
+/// at the end of the input time out as they would with no more events.
+pub fn pipe<R: BufRead, W: Write>(
+    mapping_config: &MappingConfig,
+    reader: R,
+    writer: &mut W,
+) -> Result<()> {
+    let mut machine = Machine::from_config(mapping_config);
+    let mut time = None;
+    for (idx, line) in reader.lines().enumerate() {
+        let Some(event) = parse_event(idx, &line?)? else {
+            continue;
+        };
+        time = Some(event.time);
+        if machine.time_until_tick(&event.time) == Some(Duration::ZERO) {
+            for output in machine.tick(&event.time) {
+                write_event(writer, &output)?;
+            }
+        }
+        for output in machine.insert(event) {
+            write_event(writer, &output)?;
+        }
+    }
+    let Some(mut time) = time else {
+        return Ok(());
+    };
+    while let Some(remaining) = machine.time_until_tick(&time) {
+        time = time_after(&time, remaining);
+        for output in machine.tick(&time) {
+            write_event(writer, &output)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evdev_rs::enums::EV_KEY;
+
+    #[derive(Default)]
+    struct ManualClock {
+        sleeps: Vec<Duration>,
+    }
+
+    impl Clock for ManualClock {
+        fn sleep(&mut self, duration: Duration) {
+            self.sleeps.push(duration);
+        }
+    }
+
+    fn event(tv_sec: i64, tv_usec: i64, key_event_type: KeyEventType) -> EvKeyEvent {
+        EvKeyEvent {
+            time: TimeVal { tv_sec, tv_usec },
+            ev_key: EV_KEY::KEY_A,
+            key_event_type,
+        }
+    }
+
+    #[test]
+    fn realtime_replay_honors_inter_event_spacing() {
+        let events = vec![
+            event(10, 0, KeyEventType::Press),
+            event(10, 250_000, KeyEventType::Release),
+            event(12, 0, KeyEventType::Press),
+        ];
+        let mut machine = Machine::new(&[]);
+        let mut clock = ManualClock::default();
+        let mut outputs = 0;
+        replay(&mut machine, &events, Some(&mut clock), |_, _| outputs += 1);
+        assert_eq!(outputs, 3);
+        assert_eq!(
+            clock.sleeps,
+            vec![Duration::from_millis(250), Duration::from_millis(1750)]
+        );
+    }
+
+    #[test]
+    fn piped_events_are_remapped_as_they_come() {
+        let config = MappingConfig::from_toml(
+            r#"
+                [[remap]]
+                input = ["KEY_A"]
+                output = ["KEY_B"]
+
+                [[dual_role]]
+                input = "KEY_CAPSLOCK"
+                hold = ["KEY_LEFTCTRL"]
+                tap = ["KEY_ESC"]
+            "#,
+        )
+        .unwrap();
+        let key = |ev_key, tv_sec, key_event_type| EvKeyEvent {
+            time: TimeVal::new(tv_sec, 0),
+            ev_key,
+            key_event_type,
+        };
+        let mut input = vec![];
+        for event in [
+            key(EV_KEY::KEY_A, 1, KeyEventType::Press),
+            key(EV_KEY::KEY_A, 2, KeyEventType::Release),
+            // Held until the end of the input, well past the timeout.
+            key(EV_KEY::KEY_CAPSLOCK, 3, KeyEventType::Press),
+        ] {
+            write_event(&mut input, &event).unwrap();
+        }
+        let mut output = vec![];
+        pipe(&config, input.as_slice(), &mut output).unwrap();
+        let output: Vec<_> = read_events(output.as_slice())
+            .unwrap()
+            .into_iter()
+            .map(|event| (event.ev_key, event.key_event_type))
+            .collect();
+        assert_eq!(
+            output,
+            vec![
+                (EV_KEY::KEY_B, KeyEventType::Press),
+                (EV_KEY::KEY_B, KeyEventType::Release),
+                (EV_KEY::KEY_LEFTCTRL, KeyEventType::Press),
+            ]
+        );
+    }
+
+    #[test]
+    fn recorded_events_round_trip() {
+        let original = event(5, 42, KeyEventType::Repeat);
+        let line = serde_json::to_string(&RecordedEvent::from(&original)).unwrap();
+        let events = read_events(line.as_bytes()).unwrap();
+        assert_eq!(events, vec![original]);
+    }
+
+    #[test]
+    fn written_events_read_back() {
+        let original = vec![
+            event(10, 0, KeyEventType::Press),
+            event(10, 250_000, KeyEventType::Release),
+        ];
+        let mut recording = vec![];
+        for event in &original {
+            write_event(&mut recording, event).unwrap();
+        }
+        assert_eq!(read_events(recording.as_slice()).unwrap(), original);
+    }
+}
@@ -0,0 +1,321 @@
+use super::machine::DEFAULT_MACRO_DELAY;
+use super::types::{EvKeyEvent, FrameClock, KeyEventType};
+use crate::mapping::{KeyCode, KeyRelMapping, MappingConfig, RelCode, RelMapping};
+use evdev_rs::TimeVal;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// How much a high-resolution scroll axis moves for one detent of its
+/// coarse counterpart.
+const HI_RES_PER_DETENT: i32 = 120;
+
+/// The high-resolution counterpart of a coarse scroll axis.
+pub fn hi_res_of(axis: RelCode) -> Option<RelCode> {
+    match axis {
+        RelCode::REL_WHEEL => Some(RelCode::REL_WHEEL_HI_RES),
+        RelCode::REL_HWHEEL => Some(RelCode::REL_HWHEEL_HI_RES),
+        _ => None,
+    }
+}
+
+/// The coarse counterpart of a high-resolution scroll axis.
+pub fn coarse_of(axis: RelCode) -> Option<RelCode> {
+    match axis {
+        RelCode::REL_WHEEL_HI_RES => Some(RelCode::REL_WHEEL),
+        RelCode::REL_HWHEEL_HI_RES => Some(RelCode::REL_HWHEEL),
+        _ => None,
+    }
+}
+
+/// Add `value` to the high-resolution movement in `remainder`, and take
+/// out and return the whole detents that makes. Turning the other way
+/// starts over from nothing.
+fn detents(remainder: &mut i32, value: i32) -> i32 {
+    if remainder.signum() * value.signum() < 0 {
+        *remainder = 0;
+    }
+    *remainder += value;
+    let detents = *remainder / HI_RES_PER_DETENT;
+    *remainder %= HI_RES_PER_DETENT;
+    detents
+}
+
+/// What an event of a remapped relative axis turns into.
+#[derive(Debug, PartialEq)]
+pub enum RelOutput {
+    /// The movement, to be passed on with this value.
+    Axis(i32),
+    /// Key taps to be emitted instead of the movement.
+    Keys(Vec<EvKeyEvent>),
+}
+
+/// The machine you pass in relative axis events, such as scroll wheel
+/// movement, that gives what to emit instead according to the
+/// `RelMapping`s, and the key events of the `KeyRelMapping`s, that gives
+/// the movement to emit for them.
+pub struct RelMachine {
+    mappings: Vec<RelMapping>,
+    key_mappings: Vec<KeyRelMapping>,
+    /// Delay between the presses and releases of successive taps.
+    tap_delay: Duration,
+    /// The movement of each high-resolution input axis that doesn't make
+    /// a whole detent yet.
+    input_remainders: HashMap<RelCode, i32>,
+    /// The same for the high-resolution movement of `key_mappings`.
+    output_remainders: HashMap<RelCode, i32>,
+}
+
+impl RelMachine {
+    pub fn from_config(config: &MappingConfig) -> Self {
+        Self {
+            mappings: config.rel_mappings.clone(),
+            key_mappings: config.key_rel_mappings.clone(),
+            input_remainders: HashMap::new(),
+            output_remainders: HashMap::new(),
+            tap_delay: config
+                .macro_delay_ms
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_MACRO_DELAY),
+        }
+    }
+
+    /// Insert a movement of `axis` by `value` and get what to emit
+    /// instead, or `None` if `axis` isn't remapped. A high-resolution axis
+    /// mapped to keys taps them once per detent it adds up to.
+    pub fn insert(&mut self, axis: RelCode, value: i32, time: &TimeVal) -> Option<RelOutput> {
+        let find = |axis| self.mappings.iter().find(|m| m.input == axis);
+        // Scrolling is reported at both a coarse and a fine resolution, and
+        // unless the fine one is mapped itself it follows the coarse one.
+        let (mapping, fine) = match (find(axis), axis) {
+            (Some(mapping), _) => (mapping, false),
+            (None, RelCode::REL_WHEEL_HI_RES) => (find(RelCode::REL_WHEEL)?, true),
+            (None, RelCode::REL_HWHEEL_HI_RES) => (find(RelCode::REL_HWHEEL)?, true),
+            (None, _) => return None,
+        };
+        let value = if mapping.invert { -value } else { value };
+        let keys = if value > 0 {
+            &mapping.positive
+        } else {
+            &mapping.negative
+        };
+        if keys.is_empty() {
+            Some(RelOutput::Axis(value))
+        } else if fine {
+            Some(RelOutput::Keys(vec![]))
+        } else {
+            let steps = match coarse_of(mapping.input) {
+                Some(_) => detents(self.input_remainders.entry(axis).or_default(), value),
+                None => value,
+            };
+            Some(RelOutput::Keys(self.taps(keys, steps.unsigned_abs(), time)))
+        }
+    }
+
+    /// Insert an event of `key` and get the movements of relative axes to
+    /// emit instead, or `None` if `key` doesn't move one. Moving a scroll
+    /// axis moves its counterpart of the other resolution along with it,
+    /// as a real wheel does.
+    pub fn insert_key(
+        &mut self,
+        key: KeyCode,
+        key_event_type: KeyEventType,
+    ) -> Option<Vec<(RelCode, i32)>> {
+        let mapping = self.key_mappings.iter().find(|m| m.input == key)?;
+        if !matches!(key_event_type, KeyEventType::Press | KeyEventType::Repeat) {
+            return Some(vec![]);
+        }
+        let (axis, value) = (mapping.output, mapping.value);
+        let mut moves = vec![(axis, value)];
+        if let Some(hi_res) = hi_res_of(axis) {
+            moves.push((hi_res, value * HI_RES_PER_DETENT));
+        } else if let Some(coarse) = coarse_of(axis) {
+            let detents = detents(self.output_remainders.entry(axis).or_default(), value);
+            if detents != 0 {
+                moves.insert(0, (coarse, detents));
+            }
+        }
+        Some(moves)
+    }
+
+    /// `count` taps of `keys`, each a frame of presses followed by a frame
+    /// of releases.
+    fn taps(&self, keys: &[KeyCode], count: u32, time: &TimeVal) -> Vec<EvKeyEvent> {
+        let mut clock = FrameClock::new(time, self.tap_delay);
+        let mut events = vec![];
+        for frame in 0..count * 2 {
+            let time = if frame > 0 { clock.tick() } else { clock.now() };
+            let event = |ev_key: &KeyCode, key_event_type| EvKeyEvent {
+                time,
+                ev_key: *ev_key,
+                key_event_type,
+            };
+            if frame % 2 == 0 {
+                events.extend(keys.iter().map(|key| event(key, KeyEventType::Press)));
+            } else {
+                events.extend(
+                    keys.iter()
+                        .rev()
+                        .map(|key| event(key, KeyEventType::Release)),
+                );
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn machine(mappings: Vec<RelMapping>) -> RelMachine {
+        key_machine(mappings, vec![])
+    }
+
+    fn key_machine(mappings: Vec<RelMapping>, key_mappings: Vec<KeyRelMapping>) -> RelMachine {
+        RelMachine::from_config(&MappingConfig {
+            rel_mappings: mappings,
+            key_rel_mappings: key_mappings,
+            macro_delay_ms: Some(1),
+            ..Default::default()
+        })
+    }
+
+    fn key_event_ms(ms: i64, ev_key: KeyCode, key_event_type: KeyEventType) -> EvKeyEvent {
+        EvKeyEvent {
+            time: TimeVal::new(0, ms * 1000),
+            ev_key,
+            key_event_type,
+        }
+    }
+
+    #[test]
+    fn horizontal_scroll_taps_arrow_keys() {
+        let mut machine = machine(vec![RelMapping {
+            input: RelCode::REL_HWHEEL,
+            invert: false,
+            positive: vec![KeyCode::KEY_RIGHT],
+            negative: vec![KeyCode::KEY_LEFT],
+        }]);
+        let time = TimeVal::new(0, 0);
+        assert_eq!(
+            machine.insert(RelCode::REL_HWHEEL, -2, &time),
+            Some(RelOutput::Keys(vec![
+                key_event_ms(0, KeyCode::KEY_LEFT, KeyEventType::Press),
+                key_event_ms(1, KeyCode::KEY_LEFT, KeyEventType::Release),
+                key_event_ms(2, KeyCode::KEY_LEFT, KeyEventType::Press),
+                key_event_ms(3, KeyCode::KEY_LEFT, KeyEventType::Release),
+            ]))
+        );
+        assert_eq!(
+            machine.insert(RelCode::REL_HWHEEL_HI_RES, 120, &time),
+            Some(RelOutput::Keys(vec![]))
+        );
+        assert_eq!(machine.insert(RelCode::REL_WHEEL, 1, &time), None);
+        assert_eq!(machine.insert(RelCode::REL_WHEEL_HI_RES, 120, &time), None);
+    }
+
+    #[test]
+    fn inverts_scroll_direction() {
+        let mut machine = machine(vec![RelMapping {
+            input: RelCode::REL_WHEEL,
+            invert: true,
+            positive: vec![],
+            negative: vec![],
+        }]);
+        let time = TimeVal::new(0, 0);
+        assert_eq!(
+            machine.insert(RelCode::REL_WHEEL, 1, &time),
+            Some(RelOutput::Axis(-1))
+        );
+        assert_eq!(
+            machine.insert(RelCode::REL_WHEEL_HI_RES, 120, &time),
+            Some(RelOutput::Axis(-120))
+        );
+        assert_eq!(machine.insert(RelCode::REL_HWHEEL, 1, &time), None);
+    }
+
+    #[test]
+    fn high_resolution_scroll_taps_once_per_detent() {
+        let mut machine = machine(vec![RelMapping {
+            input: RelCode::REL_WHEEL_HI_RES,
+            invert: false,
+            positive: vec![KeyCode::KEY_VOLUMEUP],
+            negative: vec![KeyCode::KEY_VOLUMEDOWN],
+        }]);
+        let time = TimeVal::new(0, 0);
+        for _ in 0..3 {
+            assert_eq!(
+                machine.insert(RelCode::REL_WHEEL_HI_RES, 30, &time),
+                Some(RelOutput::Keys(vec![]))
+            );
+        }
+        assert_eq!(
+            machine.insert(RelCode::REL_WHEEL_HI_RES, 60, &time),
+            Some(RelOutput::Keys(vec![
+                key_event_ms(0, KeyCode::KEY_VOLUMEUP, KeyEventType::Press),
+                key_event_ms(1, KeyCode::KEY_VOLUMEUP, KeyEventType::Release),
+            ]))
+        );
+        // Turning back starts over rather than using up the 30 left over.
+        assert_eq!(
+            machine.insert(RelCode::REL_WHEEL_HI_RES, -90, &time),
+            Some(RelOutput::Keys(vec![]))
+        );
+        assert_eq!(
+            machine.insert(RelCode::REL_WHEEL_HI_RES, -30, &time),
+            Some(RelOutput::Keys(vec![
+                key_event_ms(0, KeyCode::KEY_VOLUMEDOWN, KeyEventType::Press),
+                key_event_ms(1, KeyCode::KEY_VOLUMEDOWN, KeyEventType::Release),
+            ]))
+        );
+        // The coarse axis follows the fine one it goes along with.
+        assert_eq!(machine.insert(RelCode::REL_WHEEL, 1, &time), None);
+    }
+
+    #[test]
+    fn keys_scroll_at_both_resolutions() {
+        let mut machine = key_machine(
+            vec![],
+            vec![
+                KeyRelMapping {
+                    input: KeyCode::KEY_PAGEUP,
+                    output: RelCode::REL_WHEEL,
+                    value: 1,
+                },
+                KeyRelMapping {
+                    input: KeyCode::KEY_PAGEDOWN,
+                    output: RelCode::REL_WHEEL_HI_RES,
+                    value: -60,
+                },
+            ],
+        );
+        for key_event_type in [KeyEventType::Press, KeyEventType::Repeat] {
+            assert_eq!(
+                machine.insert_key(KeyCode::KEY_PAGEUP, key_event_type),
+                Some(vec![
+                    (RelCode::REL_WHEEL, 1),
+                    (RelCode::REL_WHEEL_HI_RES, 120)
+                ])
+            );
+        }
+        assert_eq!(
+            machine.insert_key(KeyCode::KEY_PAGEUP, KeyEventType::Release),
+            Some(vec![])
+        );
+        assert_eq!(
+            machine.insert_key(KeyCode::KEY_PAGEDOWN, KeyEventType::Press),
+            Some(vec![(RelCode::REL_WHEEL_HI_RES, -60)])
+        );
+        assert_eq!(
+            machine.insert_key(KeyCode::KEY_PAGEDOWN, KeyEventType::Repeat),
+            Some(vec![
+                (RelCode::REL_WHEEL, -1),
+                (RelCode::REL_WHEEL_HI_RES, -60)
+            ])
+        );
+        assert_eq!(
+            machine.insert_key(KeyCode::KEY_A, KeyEventType::Press),
+            None
+        );
+    }
+}
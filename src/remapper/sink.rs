@@ -0,0 +1,180 @@
+use anyhow::Result;
+use evdev_rs::enums::{EventCode, EV_SYN};
+use evdev_rs::{InputEvent, UInputDevice};
+
+/// Somewhere remapped events are written to.
+pub trait EventSink {
+    fn write_event(&mut self, event: &InputEvent) -> Result<()>;
+}
+
+impl<S: EventSink + ?Sized> EventSink for &mut S {
+    fn write_event(&mut self, event: &InputEvent) -> Result<()> {
+        (**self).write_event(event)
+    }
+}
+
+impl EventSink for UInputDevice {
+    fn write_event(&mut self, event: &InputEvent) -> Result<()> {
+        UInputDevice::write_event(self, event)?;
+        Ok(())
+    }
+}
+
+/// Spreads output over several sinks, one frame at a time, so that a
+/// frame's events and its SYN_REPORT always go to the same device.
+pub struct RoundRobin<S> {
+    sinks: Vec<S>,
+    /// The sink the current or most recent frame went to.
+    current: Option<usize>,
+    /// Whether anything but SYN_REPORT has been written since the last
+    /// SYN_REPORT.
+    in_frame: bool,
+}
+
+impl<S: EventSink> RoundRobin<S> {
+    pub fn new(sinks: Vec<S>) -> Self {
+        assert!(!sinks.is_empty(), "RoundRobin needs at least one sink");
+        Self {
+            sinks,
+            current: None,
+            in_frame: false,
+        }
+    }
+}
+
+impl<S: EventSink> EventSink for RoundRobin<S> {
+    fn write_event(&mut self, event: &InputEvent) -> Result<()> {
+        let is_sync = event.event_code == EventCode::EV_SYN(EV_SYN::SYN_REPORT);
+        // Only a new frame moves on to the next sink; an empty frame, such
+        // as the passed-through SYN_REPORT that follows our own, stays put.
+        if !is_sync && !self.in_frame {
+            let len = self.sinks.len();
+            self.current = Some(self.current.map_or(0, |i| (i + 1) % len));
+            self.in_frame = true;
+        }
+        self.sinks[self.current.unwrap_or(0)].write_event(event)?;
+        if is_sync {
+            self.in_frame = false;
+        }
+        Ok(())
+    }
+}
+
+/// Drops each SYN_REPORT that would end an empty frame, such as the
+/// device's own SYN_REPORT passed through after the one ending our frame,
+/// or one following a key event that was consumed without output.
+pub struct SkipEmptyReports<S> {
+    sink: S,
+    /// Whether anything but SYN_REPORT has been written since the last
+    /// SYN_REPORT.
+    in_frame: bool,
+}
+
+impl<S: EventSink> SkipEmptyReports<S> {
+    pub fn new(sink: S) -> Self {
+        Self {
+            sink,
+            in_frame: false,
+        }
+    }
+}
+
+impl<S: EventSink> EventSink for SkipEmptyReports<S> {
+    fn write_event(&mut self, event: &InputEvent) -> Result<()> {
+        if event.event_code == EventCode::EV_SYN(EV_SYN::SYN_REPORT) {
+            if !std::mem::replace(&mut self.in_frame, false) {
+                return Ok(());
+            }
+        } else {
+            self.in_frame = true;
+        }
+        self.sink.write_event(event)
+    }
+}
+
+/// Keeps whatever is written to it, for tests.
+#[cfg(test)]
+#[derive(Default)]
+pub struct RecordingSink {
+    pub events: Vec<InputEvent>,
+}
+
+#[cfg(test)]
+impl EventSink for RecordingSink {
+    fn write_event(&mut self, event: &InputEvent) -> Result<()> {
+        self.events.push(event.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapping::KeyCode;
+    use evdev_rs::TimeVal;
+
+    fn key(key: KeyCode) -> InputEvent {
+        InputEvent::new(&TimeVal::new(0, 0), &EventCode::EV_KEY(key), 1)
+    }
+
+    fn sync() -> InputEvent {
+        InputEvent::new(
+            &TimeVal::new(0, 0),
+            &EventCode::EV_SYN(EV_SYN::SYN_REPORT),
+            0,
+        )
+    }
+
+    fn codes(sink: &RecordingSink) -> Vec<EventCode> {
+        sink.events.iter().map(|e| e.event_code).collect()
+    }
+
+    #[test]
+    fn distributes_frames_round_robin() {
+        let mut sinks: Vec<RecordingSink> = (0..3).map(|_| RecordingSink::default()).collect();
+        {
+            let mut round_robin = RoundRobin::new(sinks.iter_mut().collect());
+            for k in [
+                KeyCode::KEY_A,
+                KeyCode::KEY_B,
+                KeyCode::KEY_C,
+                KeyCode::KEY_D,
+            ] {
+                round_robin.write_event(&key(k)).unwrap();
+                round_robin.write_event(&sync()).unwrap();
+                // The device's own SYN_REPORT, passed through.
+                round_robin.write_event(&sync()).unwrap();
+            }
+        }
+        let syn = EventCode::EV_SYN(EV_SYN::SYN_REPORT);
+        let frame = |k| vec![EventCode::EV_KEY(k), syn, syn];
+        assert_eq!(
+            codes(&sinks[0]),
+            [frame(KeyCode::KEY_A), frame(KeyCode::KEY_D)].concat()
+        );
+        assert_eq!(codes(&sinks[1]), frame(KeyCode::KEY_B));
+        assert_eq!(codes(&sinks[2]), frame(KeyCode::KEY_C));
+    }
+
+    #[test]
+    fn skips_reports_of_empty_frames() {
+        let mut sink = RecordingSink::default();
+        {
+            let mut skip = SkipEmptyReports::new(&mut sink);
+            skip.write_event(&sync()).unwrap();
+            skip.write_event(&key(KeyCode::KEY_A)).unwrap();
+            skip.write_event(&key(KeyCode::KEY_B)).unwrap();
+            skip.write_event(&sync()).unwrap();
+            skip.write_event(&sync()).unwrap();
+        }
+        let syn = EventCode::EV_SYN(EV_SYN::SYN_REPORT);
+        assert_eq!(
+            codes(&sink),
+            vec![
+                EventCode::EV_KEY(KeyCode::KEY_A),
+                EventCode::EV_KEY(KeyCode::KEY_B),
+                syn
+            ]
+        );
+    }
+}
@@ -0,0 +1,84 @@
+use super::sink::EventSink;
+use super::types::EvKeyEvent;
+use crate::mapping::OutputValues;
+use anyhow::Result;
+use evdev_rs::InputEvent;
+use std::time::Duration;
+
+/// The outcome of reading an event from an input device.
+pub type ReadResult = std::io::Result<(evdev_rs::ReadStatus, InputEvent)>;
+
+/// Where the read loop of `run_forever` gets the events to remap from, and
+/// writes the remapped ones to: several inputs, told apart by their index,
+/// and the outputs that events other than key events are written to as
+/// an `EventSink`. The evdev implementation reads grabbed devices and
+/// writes to uinput devices.
+pub trait EventSource: EventSink {
+    /// Read the next event from whichever input has one, returning the
+    /// index of that input along with the result of reading, or `None`
+    /// when interrupted by a signal or once `timeout` passed.
+    fn read_event(&mut self, timeout: Option<Duration>) -> Result<Option<(usize, ReadResult)>>;
+
+    /// Write the key events produced by the machines, with the `values`
+    /// configured for them.
+    fn write_events(&mut self, events: Vec<EvKeyEvent>, values: &OutputValues) -> Result<()>;
+
+    /// Let the source know that an input sent `event`, before it is
+    /// remapped.
+    fn note_input(&mut self, _event: &EvKeyEvent) {}
+
+    /// Whether the system only gets the events written, rather than those
+    /// of the inputs as well.
+    fn grabbed(&self) -> bool {
+        true
+    }
+}
+
+/// Hands out events queued up beforehand, and keeps whatever is written to
+/// it, for tests.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemorySource {
+    /// The events to read, along with the index of their input.
+    pub pending: std::collections::VecDeque<(usize, InputEvent)>,
+    /// The key events written.
+    pub written_keys: Vec<EvKeyEvent>,
+    /// The other events written.
+    pub written: Vec<InputEvent>,
+}
+
+#[cfg(test)]
+impl MemorySource {
+    /// A source with `events` to read from input 0.
+    pub fn new(events: impl IntoIterator<Item = InputEvent>) -> Self {
+        MemorySource {
+            pending: events.into_iter().map(|event| (0, event)).collect(),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+impl EventSink for MemorySource {
+    fn write_event(&mut self, event: &InputEvent) -> Result<()> {
+        self.written.push(event.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl EventSource for MemorySource {
+    fn read_event(&mut self, _timeout: Option<Duration>) -> Result<Option<(usize, ReadResult)>> {
+        Ok(self.pending.pop_front().map(|(idx, event)| {
+            (
+                idx,
+                std::io::Result::Ok((evdev_rs::ReadStatus::Success, event)),
+            )
+        }))
+    }
+
+    fn write_events(&mut self, events: Vec<EvKeyEvent>, _values: &OutputValues) -> Result<()> {
+        self.written_keys.extend(events);
+        Ok(())
+    }
+}
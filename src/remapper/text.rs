@@ -0,0 +1,168 @@
+use super::types::{EvKeyEvent, FrameClock, KeyEventType};
+use crate::mapping::{KeyCode, MacroStep, UnicodeMethod};
+
+/// Resolve a character to the key that types it on a US layout,
+/// along with whether Shift needs to be held for it.
+pub fn key_for_char(c: char) -> Option<(KeyCode, bool)> {
+    let lower = c.to_ascii_lowercase();
+    let key = match lower {
+        'a' => KeyCode::KEY_A,
+        'b' => KeyCode::KEY_B,
+        'c' => KeyCode::KEY_C,
+        'd' => KeyCode::KEY_D,
+        'e' => KeyCode::KEY_E,
+        'f' => KeyCode::KEY_F,
+        'g' => KeyCode::KEY_G,
+        'h' => KeyCode::KEY_H,
+        'i' => KeyCode::KEY_I,
+        'j' => KeyCode::KEY_J,
+        'k' => KeyCode::KEY_K,
+        'l' => KeyCode::KEY_L,
+        'm' => KeyCode::KEY_M,
+        'n' => KeyCode::KEY_N,
+        'o' => KeyCode::KEY_O,
+        'p' => KeyCode::KEY_P,
+        'q' => KeyCode::KEY_Q,
+        'r' => KeyCode::KEY_R,
+        's' => KeyCode::KEY_S,
+        't' => KeyCode::KEY_T,
+        'u' => KeyCode::KEY_U,
+        'v' => KeyCode::KEY_V,
+        'w' => KeyCode::KEY_W,
+        'x' => KeyCode::KEY_X,
+        'y' => KeyCode::KEY_Y,
+        'z' => KeyCode::KEY_Z,
+        _ => return key_for_symbol(c),
+    };
+    Some((key, c.is_ascii_uppercase()))
+}
+
+fn key_for_symbol(c: char) -> Option<(KeyCode, bool)> {
+    Some(match c {
+        '1' => (KeyCode::KEY_1, false),
+        '2' => (KeyCode::KEY_2, false),
+        '3' => (KeyCode::KEY_3, false),
+        '4' => (KeyCode::KEY_4, false),
+        '5' => (KeyCode::KEY_5, false),
+        '6' => (KeyCode::KEY_6, false),
+        '7' => (KeyCode::KEY_7, false),
+        '8' => (KeyCode::KEY_8, false),
+        '9' => (KeyCode::KEY_9, false),
+        '0' => (KeyCode::KEY_0, false),
+        '!' => (KeyCode::KEY_1, true),
+        '@' => (KeyCode::KEY_2, true),
+        '#' => (KeyCode::KEY_3, true),
+        '$' => (KeyCode::KEY_4, true),
+        '%' => (KeyCode::KEY_5, true),
+        '^' => (KeyCode::KEY_6, true),
+        '&' => (KeyCode::KEY_7, true),
+        '*' => (KeyCode::KEY_8, true),
+        '(' => (KeyCode::KEY_9, true),
+        ')' => (KeyCode::KEY_0, true),
+        '-' => (KeyCode::KEY_MINUS, false),
+        '_' => (KeyCode::KEY_MINUS, true),
+        '=' => (KeyCode::KEY_EQUAL, false),
+        '+' => (KeyCode::KEY_EQUAL, true),
+        '[' => (KeyCode::KEY_LEFTBRACE, false),
+        '{' => (KeyCode::KEY_LEFTBRACE, true),
+        ']' => (KeyCode::KEY_RIGHTBRACE, false),
+        '}' => (KeyCode::KEY_RIGHTBRACE, true),
+        '\\' => (KeyCode::KEY_BACKSLASH, false),
+        '|' => (KeyCode::KEY_BACKSLASH, true),
+        ';' => (KeyCode::KEY_SEMICOLON, false),
+        ':' => (KeyCode::KEY_SEMICOLON, true),
+        '\'' => (KeyCode::KEY_APOSTROPHE, false),
+        '"' => (KeyCode::KEY_APOSTROPHE, true),
+        '`' => (KeyCode::KEY_GRAVE, false),
+        '~' => (KeyCode::KEY_GRAVE, true),
+        ',' => (KeyCode::KEY_COMMA, false),
+        '<' => (KeyCode::KEY_COMMA, true),
+        '.' => (KeyCode::KEY_DOT, false),
+        '>' => (KeyCode::KEY_DOT, true),
+        '/' => (KeyCode::KEY_SLASH, false),
+        '?' => (KeyCode::KEY_SLASH, true),
+        ' ' => (KeyCode::KEY_SPACE, false),
+        '\t' => (KeyCode::KEY_TAB, false),
+        '\n' => (KeyCode::KEY_ENTER, false),
+        _ => return None,
+    })
+}
+
+/// Produce the press/release events that type out `text`, pressing each
+/// character in a frame of its own from `clock` and releasing it in the next.
+/// Characters that can't be typed are skipped with a warning.
+pub fn type_text(text: &str, clock: &mut FrameClock) -> Vec<EvKeyEvent> {
+    let mut events = vec![];
+    let event = |time, ev_key: KeyCode, key_event_type: KeyEventType| EvKeyEvent {
+        time,
+        ev_key,
+        key_event_type,
+    };
+    for (idx, c) in text.chars().enumerate() {
+        match key_for_char(c) {
+            Some((key, shifted)) => {
+                let time = if idx > 0 { clock.tick() } else { clock.now() };
+                if shifted {
+                    events.push(event(time, KeyCode::KEY_LEFTSHIFT, KeyEventType::Press));
+                }
+                events.push(event(time, key, KeyEventType::Press));
+                let time = clock.tick();
+                events.push(event(time, key, KeyEventType::Release));
+                if shifted {
+                    events.push(event(time, KeyCode::KEY_LEFTSHIFT, KeyEventType::Release));
+                }
+            }
+            None => log::warn!("Cannot type {:?}: no key produces it", c),
+        }
+    }
+    events
+}
+
+/// The macro steps that type `text`, holding Shift around the characters
+/// that take it and skipping those that no key types.
+pub fn text_steps(text: &str) -> Vec<MacroStep> {
+    let mut steps = vec![];
+    for (key, shifted) in text.chars().filter_map(key_for_char) {
+        if shifted {
+            steps.push(MacroStep::Press(KeyCode::KEY_LEFTSHIFT));
+        }
+        steps.push(MacroStep::Press(key));
+        steps.push(MacroStep::Release(key));
+        if shifted {
+            steps.push(MacroStep::Release(KeyCode::KEY_LEFTSHIFT));
+        }
+    }
+    steps
+}
+
+/// The macro steps that enter `codepoint` by its hex code as `method`
+/// takes it.
+pub fn unicode_steps(codepoint: char, method: UnicodeMethod) -> Vec<MacroStep> {
+    let tap = |key| [MacroStep::Press(key), MacroStep::Release(key)];
+    let digits = format!("{:x}", codepoint as u32)
+        .chars()
+        .filter_map(key_for_char)
+        .flat_map(|(key, _)| tap(key))
+        .collect::<Vec<_>>();
+    let mut steps = vec![
+        MacroStep::Press(KeyCode::KEY_LEFTCTRL),
+        MacroStep::Press(KeyCode::KEY_LEFTSHIFT),
+    ];
+    steps.extend(tap(KeyCode::KEY_U));
+    let release_modifiers = [
+        MacroStep::Release(KeyCode::KEY_LEFTSHIFT),
+        MacroStep::Release(KeyCode::KEY_LEFTCTRL),
+    ];
+    match method {
+        UnicodeMethod::Ibus => {
+            steps.extend(release_modifiers);
+            steps.extend(digits);
+            steps.extend(tap(KeyCode::KEY_SPACE));
+        }
+        UnicodeMethod::Gtk => {
+            steps.extend(digits);
+            steps.extend(release_modifiers);
+        }
+    }
+    steps
+}
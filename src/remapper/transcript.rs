@@ -0,0 +1,149 @@
+use super::machine::Machine;
+use super::types::{EvKeyEvent, KeyEventType};
+use crate::mapping::{key_name, parse_key, KeyCode, MappingConfig};
+use anyhow::{bail, Result};
+use evdev_rs::TimeVal;
+use std::collections::HashSet;
+
+/// Human-friendly names for keys, used both to parse actions like
+/// "Ctrl+0" and to describe what got emitted.
+const FRIENDLY_NAMES: &[(&str, KeyCode)] = &[
+    ("Ctrl", KeyCode::KEY_LEFTCTRL),
+    ("RightCtrl", KeyCode::KEY_RIGHTCTRL),
+    ("Shift", KeyCode::KEY_LEFTSHIFT),
+    ("RightShift", KeyCode::KEY_RIGHTSHIFT),
+    ("Alt", KeyCode::KEY_LEFTALT),
+    ("AltGr", KeyCode::KEY_RIGHTALT),
+    ("Meta", KeyCode::KEY_LEFTMETA),
+    ("RightMeta", KeyCode::KEY_RIGHTMETA),
+];
+
+fn parse_action_key(name: &str) -> Result<KeyCode> {
+    if let Some((_, key)) = FRIENDLY_NAMES
+        .iter()
+        .find(|(friendly, _)| friendly.eq_ignore_ascii_case(name))
+    {
+        return Ok(*key);
+    }
+    let upper = name.to_ascii_uppercase();
+    if upper.starts_with("KEY_") || upper.starts_with("BTN_") {
+        return Ok(parse_key(&upper)?);
+    }
+    Ok(parse_key(&format!("KEY_{}", upper))?)
+}
+
+fn describe_key(key: KeyCode) -> String {
+    match FRIENDLY_NAMES.iter().find(|(_, k)| *k == key) {
+        Some((friendly, _)) => friendly.to_string(),
+        None => {
+            let name = key_name(key);
+            name.strip_prefix("KEY_").unwrap_or(&name).to_string()
+        }
+    }
+}
+
+/// Turn an action like "Ctrl+0" into presses of each key in order,
+/// followed by releases in the reverse order.
+fn action_to_events(action: &str, time: &mut TimeVal) -> Result<Vec<EvKeyEvent>> {
+    let keys = action
+        .split('+')
+        .map(|part| parse_action_key(part.trim()))
+        .collect::<Result<Vec<KeyCode>>>()?;
+    if keys.is_empty() {
+        bail!("empty action `{}`", action);
+    }
+    let mut event = |ev_key: KeyCode, key_event_type: KeyEventType| {
+        time.tv_usec += 1000;
+        EvKeyEvent {
+            time: *time,
+            ev_key,
+            key_event_type,
+        }
+    };
+    let mut events: Vec<EvKeyEvent> = keys
+        .iter()
+        .map(|key| event(*key, KeyEventType::Press))
+        .collect();
+    events.extend(
+        keys.iter()
+            .rev()
+            .map(|key| event(*key, KeyEventType::Release)),
+    );
+    Ok(events)
+}
+
+/// Summarize emitted events as the chords they produce, eg: "Ctrl+1".
+/// A chord is recorded whenever a key is pressed while other keys are
+/// held, and a lone tap of held keys is recorded when they are released
+/// without having been used in a chord.
+fn summarize(events: &[EvKeyEvent]) -> String {
+    let mut held: Vec<KeyCode> = vec![];
+    let mut used_in_chord: HashSet<KeyCode> = HashSet::new();
+    let mut chords: Vec<String> = vec![];
+    for event in events {
+        match event.key_event_type {
+            KeyEventType::Press => held.push(event.ev_key),
+            KeyEventType::Release => {
+                if !used_in_chord.contains(&event.ev_key) {
+                    let chord: Vec<String> = held.iter().map(|k| describe_key(*k)).collect();
+                    chords.push(chord.join("+"));
+                    used_in_chord.extend(held.iter().copied());
+                }
+                held.retain(|k| *k != event.ev_key);
+                used_in_chord.remove(&event.ev_key);
+            }
+            _ => {}
+        }
+    }
+    chords.join(" ")
+}
+
+/// Run human-readable `actions` through a `Machine` built from
+/// `mapping_config`, returning a human-readable summary of what was
+/// emitted for each action. Useful to generate documentation.
+pub fn run_transcript(mapping_config: &MappingConfig, actions: &[String]) -> Result<Vec<String>> {
+    let mut machine = Machine::from_config(mapping_config);
+    let mut time = TimeVal {
+        tv_sec: 0,
+        tv_usec: 0,
+    };
+    let mut results = vec![];
+    for action in actions {
+        let mut emitted = vec![];
+        for event in action_to_events(action, &mut time)? {
+            emitted.extend(machine.insert(event));
+        }
+        results.push(summarize(&emitted));
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapping::{Conditions, Mapping};
+
+    #[test]
+    fn transcript_describes_remapped_chord() {
+        let config = MappingConfig {
+            mappings: vec![Mapping::Remap {
+                input: HashSet::from([KeyCode::KEY_0, KeyCode::KEY_LEFTCTRL]),
+                output: HashSet::from([KeyCode::KEY_1]),
+                when: Conditions::default(),
+                on_release: false,
+            }],
+            ..Default::default()
+        };
+        let actions = vec!["Ctrl+0".to_string(), "0".to_string(), "Shift".to_string()];
+        assert_eq!(
+            run_transcript(&config, &actions).unwrap(),
+            vec!["Ctrl+1", "0", "Shift"]
+        );
+    }
+
+    #[test]
+    fn transcript_rejects_unknown_keys() {
+        let actions = vec!["Ctrl+NOPE".to_string()];
+        assert!(run_transcript(&MappingConfig::default(), &actions).is_err());
+    }
+}
@@ -1,7 +1,10 @@
-use evdev_rs::{InputEvent, TimeVal};
+use crate::mapping::{key_name, OutputValues};
 use evdev_rs::enums::{EventCode, EV_KEY};
+use evdev_rs::{InputEvent, TimeVal};
+use std::fmt;
+use std::time::Duration;
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum KeyEventType {
     Release,
     Press,
@@ -27,6 +30,28 @@ impl KeyEventType {
             Self::Unknown(n) => *n,
         }
     }
+
+    /// The value to write for this event, as configured by `values`.
+    pub fn output_value(&self, values: &OutputValues) -> i32 {
+        match self {
+            Self::Release => values.release,
+            Self::Press => values.press,
+            Self::Repeat => values.repeat,
+            Self::Unknown(n) => *n,
+        }
+    }
+}
+
+/// As `Press`, `Release`, `Repeat` or `Unknown(n)` for the value `n`.
+impl fmt::Display for KeyEventType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Release => write!(f, "Release"),
+            Self::Press => write!(f, "Press"),
+            Self::Repeat => write!(f, "Repeat"),
+            Self::Unknown(n) => write!(f, "Unknown({})", n),
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -38,11 +63,117 @@ pub struct EvKeyEvent {
 }
 
 impl EvKeyEvent {
-    pub fn as_input_event(&self) -> InputEvent {
+    pub fn as_input_event(&self, values: &OutputValues) -> InputEvent {
         InputEvent {
             time: self.time,
             event_code: EventCode::EV_KEY(self.ev_key),
-            value: self.key_event_type.value(),
+            value: self.key_event_type.output_value(values),
         }
     }
 }
+
+/// The name of the key followed by the `KeyEventType`, eg: `KEY_A Press`.
+impl fmt::Display for EvKeyEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", key_name(self.ev_key), self.key_event_type)
+    }
+}
+
+/// Time elapsed from `earlier` to `later`; zero if `later` is not later.
+pub fn duration_between(earlier: &TimeVal, later: &TimeVal) -> Duration {
+    let micros = (later.tv_sec - earlier.tv_sec) * 1_000_000 + (later.tv_usec - earlier.tv_usec);
+    Duration::from_micros(micros.max(0) as u64)
+}
+
+/// The time `duration` after `time`.
+pub fn time_after(time: &TimeVal, duration: Duration) -> TimeVal {
+    let micros = time.tv_usec + duration.as_micros() as i64;
+    TimeVal::new(time.tv_sec + micros / 1_000_000, micros % 1_000_000)
+}
+
+/// Hands out increasing times for the frames of a burst of events that a
+/// single input event turns into, such as the press and release of a tap,
+/// each `step` after the previous one. The driver emits events of different
+/// times as separate frames, so a key is never pressed and released within
+/// one frame, and consumers that look at event timing see them in order.
+#[derive(Debug, Clone)]
+pub struct FrameClock {
+    time: TimeVal,
+    step: Duration,
+}
+
+impl FrameClock {
+    pub fn new(start: &TimeVal, step: Duration) -> Self {
+        FrameClock { time: *start, step }
+    }
+
+    /// The time of the current frame.
+    pub fn now(&self) -> TimeVal {
+        self.time
+    }
+
+    /// Move on to the next frame, returning its time.
+    pub fn tick(&mut self) -> TimeVal {
+        self.time = time_after(&self.time, self.step);
+        self.time
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn as_input_event_uses_configured_values() {
+        let event = |key_event_type| EvKeyEvent {
+            time: TimeVal::new(0, 0),
+            ev_key: EV_KEY::KEY_A,
+            key_event_type,
+        };
+        let standard = OutputValues::default();
+        assert_eq!(
+            event(KeyEventType::Press).as_input_event(&standard).value,
+            1
+        );
+        assert_eq!(
+            event(KeyEventType::Release).as_input_event(&standard).value,
+            0
+        );
+
+        let custom = OutputValues {
+            press: 5,
+            ..OutputValues::default()
+        };
+        assert_eq!(event(KeyEventType::Press).as_input_event(&custom).value, 5);
+        assert_eq!(
+            event(KeyEventType::Release).as_input_event(&custom).value,
+            0
+        );
+        assert_eq!(event(KeyEventType::Repeat).as_input_event(&custom).value, 2);
+    }
+
+    #[test]
+    fn events_display_as_key_name_and_type() {
+        let event = |key_event_type| EvKeyEvent {
+            time: TimeVal::new(0, 0),
+            ev_key: EV_KEY::KEY_A,
+            key_event_type,
+        };
+        assert_eq!(event(KeyEventType::Press).to_string(), "KEY_A Press");
+        assert_eq!(event(KeyEventType::Release).to_string(), "KEY_A Release");
+        assert_eq!(event(KeyEventType::Repeat).to_string(), "KEY_A Repeat");
+        assert_eq!(
+            event(KeyEventType::Unknown(7)).to_string(),
+            "KEY_A Unknown(7)"
+        );
+    }
+
+    #[test]
+    fn frame_clock_advances_by_step() {
+        let mut clock = FrameClock::new(&TimeVal::new(1, 999_000), Duration::from_millis(1));
+        assert_eq!(clock.now(), TimeVal::new(1, 999_000));
+        assert_eq!(clock.tick(), TimeVal::new(2, 0));
+        assert_eq!(clock.tick(), TimeVal::new(2, 1_000));
+        assert_eq!(clock.now(), TimeVal::new(2, 1_000));
+    }
+}
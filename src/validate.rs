@@ -0,0 +1,280 @@
+use crate::mapping::{key_name, Conditions, KeyCode, Mapping, MappingConfig, MatchStrategy};
+use crate::remapper::key_for_char;
+use std::collections::HashSet;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// Likely not what was intended, but the config works.
+    Warning,
+    /// The config can't work as written.
+    Error,
+}
+
+/// Something questionable about the mapping at index `mapping`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub severity: Severity,
+    pub mapping: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(
+            f,
+            "{}: mapping {}: {}",
+            severity, self.mapping, self.message
+        )
+    }
+}
+
+fn describe_keys(keys: &HashSet<KeyCode>) -> String {
+    let mut names: Vec<String> = keys.iter().map(|key| key_name(*key)).collect();
+    names.sort();
+    names.join("+")
+}
+
+/// Whether every condition of `broad` is also one of `narrow`, so that
+/// `broad` applies wherever `narrow` does.
+fn conditions_cover(broad: &Conditions, narrow: &Conditions) -> bool {
+    broad.layers.iter().all(|l| narrow.layers.contains(l))
+        && broad.leds.iter().all(|led| narrow.leds.contains(led))
+        && broad.idle_ms.is_none_or(|broad_ms| {
+            narrow
+                .idle_ms
+                .is_some_and(|narrow_ms| narrow_ms >= broad_ms)
+        })
+        && broad.unless.is_subset(&narrow.unless)
+        && (broad.apps.is_empty() || !narrow.apps.is_empty() && narrow.apps.is_subset(&broad.apps))
+}
+
+/// Check the remaps of `config` for mistakes that parse just fine:
+/// empty inputs or outputs, remaps that are ambiguous because they share
+/// their input and conditions, remaps that can never fire because an
+/// earlier one consumes their keys first, and remaps whose output is the
+/// input of another remap, which may chain in surprising ways, or that
+/// need a `passthrough` key, which remaps never see. Also texts with
+/// characters that no key types on a US layout. Mappings are numbered
+/// by their index in `config.mappings`.
+pub fn validate(config: &MappingConfig) -> Vec<Finding> {
+    let remaps: Vec<_> = config
+        .mappings
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, mapping)| match mapping {
+            Mapping::Remap {
+                input,
+                output,
+                when,
+                ..
+            } => Some((idx, input, output, when)),
+            _ => None,
+        })
+        .collect();
+
+    let mut findings = vec![];
+    for (i, &(idx, input, output, when)) in remaps.iter().enumerate() {
+        if input.is_empty() {
+            findings.push(Finding {
+                severity: Severity::Error,
+                mapping: idx,
+                message: "remap has no input keys".to_string(),
+            });
+        }
+        if output.is_empty() {
+            findings.push(Finding {
+                severity: Severity::Error,
+                mapping: idx,
+                message: "remap has no output keys".to_string(),
+            });
+        }
+        let passed_through: HashSet<KeyCode> =
+            input.intersection(&config.passthrough).copied().collect();
+        if !passed_through.is_empty() {
+            findings.push(Finding {
+                severity: Severity::Warning,
+                mapping: idx,
+                message: format!(
+                    "remap of {} can never fire: {} is passed through",
+                    describe_keys(input),
+                    describe_keys(&passed_through)
+                ),
+            });
+        }
+        for &(other_idx, other_input, _, other_when) in &remaps[..i] {
+            if input == other_input && when == other_when {
+                findings.push(Finding {
+                    severity: Severity::Error,
+                    mapping: idx,
+                    message: format!(
+                        "remap of {} is ambiguous with mapping {}",
+                        describe_keys(input),
+                        other_idx
+                    ),
+                });
+            }
+        }
+        if config.match_strategy == MatchStrategy::FirstMatch {
+            for &(other_idx, other_input, other_output, other_when) in &remaps[..i] {
+                // An earlier remap with a subset of the input takes the
+                // keys it doesn't leave held before this one is looked at.
+                let consumed: HashSet<KeyCode> = other_input
+                    .iter()
+                    .filter(|key| !config.modifiers.contains(key) && !other_output.contains(key))
+                    .copied()
+                    .collect();
+                let identical = input == other_input && when == other_when;
+                if !identical
+                    && !consumed.is_empty()
+                    && other_input.is_subset(input)
+                    && conditions_cover(other_when, when)
+                {
+                    findings.push(Finding {
+                        severity: Severity::Warning,
+                        mapping: idx,
+                        message: format!(
+                            "remap of {} can never fire: mapping {} takes {} first",
+                            describe_keys(input),
+                            other_idx,
+                            describe_keys(&consumed)
+                        ),
+                    });
+                }
+            }
+        }
+        for &(other_idx, other_input, _, _) in &remaps {
+            if other_idx != idx && !other_input.is_empty() && other_input.is_subset(output) {
+                findings.push(Finding {
+                    severity: Severity::Warning,
+                    mapping: idx,
+                    message: format!(
+                        "output {} is the input of mapping {}",
+                        describe_keys(output),
+                        other_idx
+                    ),
+                });
+            }
+        }
+    }
+    for (idx, mapping) in config.mappings.iter().enumerate() {
+        if let Mapping::Text { text, .. } = mapping {
+            let untypeable: String = text
+                .chars()
+                .filter(|c| key_for_char(*c).is_none())
+                .collect();
+            if !untypeable.is_empty() {
+                findings.push(Finding {
+                    severity: Severity::Warning,
+                    mapping: idx,
+                    message: format!(
+                        "text has characters that no key types on a US layout, \
+                         which are skipped: {:?}",
+                        untypeable
+                    ),
+                });
+            }
+        }
+    }
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapping::Conditions;
+
+    fn remap(input: &[KeyCode], output: &[KeyCode]) -> Mapping {
+        Mapping::Remap {
+            input: input.iter().copied().collect(),
+            output: output.iter().copied().collect(),
+            when: Conditions::default(),
+            on_release: false,
+        }
+    }
+
+    fn findings(mappings: Vec<Mapping>) -> Vec<(Severity, usize)> {
+        let config = MappingConfig {
+            mappings,
+            ..Default::default()
+        };
+        validate(&config)
+            .into_iter()
+            .map(|finding| (finding.severity, finding.mapping))
+            .collect()
+    }
+
+    #[test]
+    fn reports_offending_mappings() {
+        assert_eq!(
+            findings(vec![
+                remap(&[KeyCode::KEY_A], &[KeyCode::KEY_B]),
+                remap(&[KeyCode::KEY_C], &[KeyCode::KEY_D]),
+                remap(&[KeyCode::KEY_A], &[KeyCode::KEY_E]),
+                remap(&[KeyCode::KEY_F], &[]),
+            ]),
+            vec![(Severity::Error, 2), (Severity::Error, 3)]
+        );
+    }
+
+    #[test]
+    fn warns_about_chained_remaps() {
+        assert_eq!(
+            findings(vec![
+                remap(&[KeyCode::KEY_A], &[KeyCode::KEY_B]),
+                remap(&[KeyCode::KEY_B], &[KeyCode::KEY_A]),
+            ]),
+            vec![(Severity::Warning, 0), (Severity::Warning, 1)]
+        );
+    }
+
+    #[test]
+    fn warns_about_remaps_of_passed_through_keys() {
+        let config = MappingConfig {
+            mappings: vec![
+                remap(
+                    &[KeyCode::KEY_LEFTCTRL, KeyCode::KEY_VOLUMEUP],
+                    &[KeyCode::KEY_F1],
+                ),
+                remap(&[KeyCode::KEY_CAPSLOCK], &[KeyCode::KEY_ESC]),
+            ],
+            passthrough: HashSet::from([KeyCode::KEY_VOLUMEUP]),
+            ..Default::default()
+        };
+        let findings: Vec<_> = validate(&config)
+            .into_iter()
+            .map(|finding| (finding.severity, finding.mapping))
+            .collect();
+        assert_eq!(findings, vec![(Severity::Warning, 0)]);
+    }
+
+    #[test]
+    fn warns_about_shadowed_remaps() {
+        assert_eq!(
+            findings(vec![
+                remap(&[KeyCode::KEY_A], &[KeyCode::KEY_B]),
+                remap(&[KeyCode::KEY_LEFTCTRL, KeyCode::KEY_A], &[KeyCode::KEY_C]),
+                // Modifiers are left held, so this one doesn't shadow.
+                remap(&[KeyCode::KEY_LEFTALT], &[KeyCode::KEY_D]),
+                remap(&[KeyCode::KEY_LEFTALT, KeyCode::KEY_E], &[KeyCode::KEY_F]),
+            ]),
+            vec![(Severity::Warning, 1)]
+        );
+    }
+
+    #[test]
+    fn warns_about_text_that_cannot_be_typed() {
+        let text = |text: &str| Mapping::Text {
+            input: KeyCode::KEY_F1,
+            text: text.to_string(),
+        };
+        assert_eq!(
+            findings(vec![text("me@example.com"), text("café")]),
+            vec![(Severity::Warning, 1)]
+        );
+    }
+}